@@ -4,3 +4,137 @@ pub const AUTH_COOKIE_NAME: &str = "auth_data";
 pub const AUTH_EMAIL_STRING: &str = "user_email";
 pub const AUTH_PASSWORD_STRING: &str = "user_password";
 pub const AUTH_DOMAIN_STRING: &str = "user_domain";
+
+// Notifications config
+// IDLE has to be re-issued before the 29 minute server timeout (RFC 2177)
+pub const IDLE_RENEW_INTERVAL_SECS: u64 = 25 * 60;
+// How often a watcher wakes up to send a keep-alive and check the client is still connected
+pub const IDLE_READ_TIMEOUT_SECS: u64 = 30;
+// Polling interval for servers without IDLE
+pub const NOOP_POLL_INTERVAL_SECS: u64 = 30;
+pub const NOTIFICATION_CHANNEL_SIZE: usize = 64;
+// Every watched mailbox holds an IMAP connection and a thread, providers limit connections
+pub const NOTIFICATION_MAX_MAILBOXES: usize = 5;
+
+// Sync config
+// Upper bound of UIDs a client can send as its known state
+pub const MAX_SYNC_UIDS: usize = 100_000;
+
+// Cache config
+pub const MESSAGE_CACHE_FILE_NAME: &str = "message_cache.sqlite";
+pub const CACHE_SYNC_INTERVAL_SECS: u64 = 5 * 60;
+// Matches the session TTL, after that the stored credentials are no longer used
+pub const CACHE_ACCOUNT_IDLE_SECS: u64 = 2 * 60 * 60;
+// How many message summaries are fetched with one command during the sync
+pub const CACHE_SYNC_CHUNK_SIZE: usize = 200;
+
+// Search config
+// Bigger emails are searchable only by their sender, subject and attachment names
+pub const SEARCH_MAX_INDEXED_SIZE: u32 = 1024 * 1024;
+// How many email texts one background sync downloads for the index at most
+pub const SEARCH_TEXTS_PER_SYNC: usize = 200;
+pub const SEARCH_TEXT_CHUNK_SIZE: usize = 20;
+pub const SEARCH_SNIPPET_WORDS: usize = 30;
+
+// Preview config
+// Only the beginning of the text part is downloaded for the preview
+pub const PREVIEW_FETCH_OCTETS: u32 = 2048;
+pub const PREVIEW_LENGTH: usize = 200;
+// How many previews one background sync downloads at most
+pub const PREVIEWS_PER_SYNC: usize = 500;
+
+// Threads config
+// Only this many newest emails of every mailbox are threaded
+pub const THREAD_MAX_EMAILS: u32 = 2000;
+
+// Archive config
+// Upper bound of UIDs a client can select for an export
+pub const ARCHIVE_MAX_UIDS: usize = 100_000;
+// How many whole messages are downloaded with one command during an export
+pub const ARCHIVE_FETCH_CHUNK_SIZE: usize = 50;
+// An import reports its progress after this many messages
+pub const ARCHIVE_PROGRESS_INTERVAL: usize = 25;
+// Largest message taken from an imported Maildir ZIP, bigger entries abort the import
+pub const ARCHIVE_MAX_MESSAGE_BYTES: u64 = 64 * 1024 * 1024;
+pub const ARCHIVE_CHANNEL_SIZE: usize = 16;
+
+// Email detail config
+// Inline images of HTML bodies are loaded from here
+pub const INLINE_PART_PATH: &str = "/api/email/cid";
+// Inline parts never change under their UID, browsers may keep them for a day
+pub const INLINE_PART_MAX_AGE_SECS: u32 = 24 * 60 * 60;
+// Parts are served from the API origin, only images which cannot carry script are shown
+// inline, everything else is downloaded as an attachment
+pub const INLINE_PART_CONTENT_TYPES: [&str; 5] = [
+    "image/png",
+    "image/jpeg",
+    "image/jpg",
+    "image/gif",
+    "image/webp",
+];
+
+// Attachments config
+// Longest name of a file in a ZIP of attachments, without the extension
+pub const ATTACHMENT_NAME_MAX_LENGTH: usize = 120;
+// How many emails of a mailbox are described with one command by the attachment search
+pub const ATTACHMENT_SEARCH_CHUNK_SIZE: usize = 200;
+pub const ATTACHMENT_SEARCH_DEFAULT_LIMIT: usize = 200;
+pub const ATTACHMENT_SEARCH_MAX_LIMIT: usize = 2000;
+// Every mailbox is searched on its own, one request must not walk through the whole account
+pub const ATTACHMENT_SEARCH_MAX_MAILBOXES: usize = 10;
+// Edge lengths thumbnails are generated at, requested sizes are rounded up to one of them
+pub const ATTACHMENT_PREVIEW_SIZES: [u32; 4] = [64, 128, 256, 512];
+pub const ATTACHMENT_PREVIEW_DEFAULT_SIZE: u32 = 256;
+// Bigger attachments are not downloaded for a preview
+pub const ATTACHMENT_PREVIEW_MAX_OCTETS: u32 = 25 * 1024 * 1024;
+pub const ATTACHMENT_PREVIEW_MAX_DIMENSION: u32 = 16384;
+pub const ATTACHMENT_PREVIEW_MAX_ALLOC: u64 = 256 * 1024 * 1024;
+pub const ATTACHMENT_PREVIEW_TEXT_LENGTH: usize = 4000;
+pub const ATTACHMENT_PREVIEW_MAX_AGE_SECS: u32 = 7 * 24 * 60 * 60;
+
+// Attachment scanning config
+// Size of the chunks an attachment is streamed to clamd in
+pub const CLAMD_CHUNK_SIZE: usize = 64 * 1024;
+pub const CLAMD_TIMEOUT_SECS: u64 = 60;
+// Refused unless ATTACHMENT_BLOCKED_EXTENSIONS or ATTACHMENT_BLOCKED_CONTENT_TYPES replace them
+pub const BLOCKED_ATTACHMENT_EXTENSIONS: [&str; 22] = [
+    "ade", "adp", "apk", "appx", "bat", "chm", "cmd", "com", "cpl", "dll", "exe", "hta", "jar",
+    "js", "jse", "lnk", "msi", "ps1", "scr", "vbe", "vbs", "wsf",
+];
+pub const BLOCKED_ATTACHMENT_CONTENT_TYPES: [&str; 6] = [
+    "application/x-msdownload",
+    "application/x-msdos-program",
+    "application/x-ms-installer",
+    "application/x-bat",
+    "application/hta",
+    "application/java-archive",
+];
+pub const SCAN_REFUSALS_DEFAULT_LIMIT: u32 = 50;
+pub const SCAN_REFUSALS_MAX_LIMIT: u32 = 500;
+
+// Contacts config
+pub const CONTACT_SUGGESTIONS_DEFAULT_LIMIT: usize = 10;
+pub const CONTACT_SUGGESTIONS_MAX_LIMIT: usize = 50;
+// Biggest vCard file accepted by the import
+pub const CONTACT_IMPORT_MAX_BYTES: usize = 10 * 1024 * 1024;
+
+// DAV config
+pub const DAV_TIMEOUT_SECS: u64 = 30;
+pub const DAV_MAX_REDIRECTS: usize = 5;
+// Bigger responses are cut off, an address book listing with etags stays far below
+pub const DAV_MAX_RESPONSE_BYTES: u64 = 64 * 1024 * 1024;
+// How many vCards are requested with one addressbook-multiget
+pub const DAV_MULTIGET_CHUNK_SIZE: usize = 50;
+
+// Templates config
+// Biggest default attachment stored with a template
+pub const TEMPLATE_ATTACHMENT_MAX_BYTES: usize = 25 * 1024 * 1024;
+
+// Bulk send config
+// Biggest CSV or JSON recipient list accepted
+pub const BULK_SEND_MAX_BYTES: usize = 5 * 1024 * 1024;
+pub const BULK_SEND_MAX_RECIPIENTS: usize = 5000;
+// Used unless BULK_SEND_RATE_PER_MINUTE or BULK_SEND_CONCURRENCY replace them, a request may
+// only go lower
+pub const BULK_SEND_DEFAULT_RATE_PER_MINUTE: u32 = 60;
+pub const BULK_SEND_DEFAULT_CONCURRENCY: usize = 2;
@@ -0,0 +1,88 @@
+use std::io::{Error, ErrorKind};
+
+use url::Url;
+
+use crate::utils::utils_xml::escape_xml;
+
+use super::{
+    client::DavClient, dav_error, delete_resource, discover_collections, member_url,
+    parse_multistatus, resource_name, CollectionKind, CALDAV_NAMESPACE,
+};
+
+/// Writes the event into the calendar which already holds an event with its UID, or into
+/// the first calendar of the user taking events. `calendar` is a whole VCALENDAR object.
+pub fn store_event(client: &DavClient, url: &Url, uid: &str, calendar: &str) -> Result<(), Error> {
+    let calendars = discover_collections(client, url, CollectionKind::Calendar)?;
+    let (href, etag) = match find_event(client, &calendars, uid)? {
+        Some(found) => found,
+        None => {
+            let calendar_url = calendars
+                .first()
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, "No calendar found"))?;
+            (member_url(calendar_url, &resource_name(uid, "ics"))?, None)
+        }
+    };
+
+    let mut headers = vec![("Content-Type", "text/calendar; charset=utf-8")];
+    if let Some(etag) = etag.as_deref() {
+        headers.push(("If-Match", etag));
+    }
+    let response = client.request("PUT", &href, &headers, calendar.as_bytes())?;
+    if !response.is_success() {
+        return Err(dav_error(&response));
+    }
+    Ok(())
+}
+
+/// Removes the event with the UID from the calendars of the user, if there is one.
+pub fn remove_event(client: &DavClient, url: &Url, uid: &str) -> Result<(), Error> {
+    let calendars = discover_collections(client, url, CollectionKind::Calendar)?;
+    if let Some((href, _)) = find_event(client, &calendars, uid)? {
+        delete_resource(client, &href, None)?;
+    }
+    Ok(())
+}
+
+/// Looks the UID up with a calendar-query report, as servers may keep the event under any
+/// name. Calendars rejecting the query are skipped.
+fn find_event(
+    client: &DavClient,
+    calendars: &[Url],
+    uid: &str,
+) -> Result<Option<(Url, Option<String>)>, Error> {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+         <cal:calendar-query xmlns:d=\"DAV:\" xmlns:cal=\"{}\">\
+         <d:prop><d:getetag/></d:prop>\
+         <cal:filter><cal:comp-filter name=\"VCALENDAR\"><cal:comp-filter name=\"VEVENT\">\
+         <cal:prop-filter name=\"UID\">\
+         <cal:text-match collation=\"i;octet\">{}</cal:text-match>\
+         </cal:prop-filter></cal:comp-filter></cal:comp-filter></cal:filter>\
+         </cal:calendar-query>",
+        CALDAV_NAMESPACE,
+        escape_xml(uid)
+    );
+    for calendar in calendars.iter() {
+        let response = client.request(
+            "REPORT",
+            calendar,
+            &[
+                ("Depth", "1"),
+                ("Content-Type", "application/xml; charset=utf-8"),
+            ],
+            body.as_bytes(),
+        )?;
+        let multistatus = match parse_multistatus(&response) {
+            Ok(multistatus) => multistatus,
+            Err(err) => {
+                println!("calendar-query failed on {}: {}", calendar, err);
+                continue;
+            }
+        };
+        if let Some(entry) = multistatus.entries.into_iter().next() {
+            let etag = entry.etag();
+            return Ok(Some((entry.href, etag)));
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,183 @@
+use std::io::Error;
+
+use url::Url;
+
+use crate::{constants::DAV_MULTIGET_CHUNK_SIZE, utils::utils_xml::escape_xml};
+
+use super::{
+    client::DavClient, dav_error, href_element, parse_multistatus, propfind, same_resource,
+    CARDDAV_NAMESPACE, DAV_NAMESPACE,
+};
+
+/// Members of an address book which changed since the last sync.
+pub struct CollectionChanges {
+    /// Added or modified cards with their etag
+    pub changed: Vec<(Url, Option<String>)>,
+    pub removed: Vec<Url>,
+    /// Token for the next sync, `None` when the server does not support sync-collection
+    pub sync_token: Option<String>,
+    /// `changed` lists every member, local cards not in it are gone from the server
+    pub complete: bool,
+}
+
+pub struct RemoteCard {
+    pub href: Url,
+    pub etag: Option<String>,
+    pub vcard: String,
+}
+
+pub enum PutCondition<'a> {
+    /// Only when the name is still free
+    Create,
+    /// Only when the card still has the etag, unconditionally without one
+    Replace(Option<&'a str>),
+}
+
+pub enum PutOutcome {
+    Stored {
+        etag: Option<String>,
+    },
+    /// The card changed on the server in the meantime, its version wins
+    Conflict,
+}
+
+/// Asks for the changes since `sync_token` with a sync-collection report (RFC 6578). Without
+/// a token, with an expired one or without server support every member is listed.
+pub fn collection_changes(
+    client: &DavClient,
+    collection: &Url,
+    sync_token: Option<&str>,
+) -> Result<CollectionChanges, Error> {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+         <d:sync-collection xmlns:d=\"DAV:\">\
+         <d:sync-token>{}</d:sync-token><d:sync-level>1</d:sync-level>\
+         <d:prop><d:getetag/></d:prop></d:sync-collection>",
+        escape_xml(sync_token.unwrap_or_default())
+    );
+    let response = client.request(
+        "REPORT",
+        collection,
+        &[
+            ("Depth", "0"),
+            ("Content-Type", "application/xml; charset=utf-8"),
+        ],
+        body.as_bytes(),
+    )?;
+
+    if response.status != 207 {
+        // An expired token is answered with the valid-sync-token precondition
+        if sync_token.is_some() && response.text().contains("valid-sync-token") {
+            return collection_changes(client, collection, None);
+        }
+        println!(
+            "sync-collection failed with {} on {}, listing all cards",
+            response.status, collection
+        );
+        return list_members(client, collection);
+    }
+
+    let multistatus = parse_multistatus(&response)?;
+    let mut changes = CollectionChanges {
+        changed: vec![],
+        removed: vec![],
+        sync_token: multistatus.sync_token,
+        complete: sync_token.is_none(),
+    };
+    for entry in multistatus.entries.into_iter() {
+        if same_resource(&entry.href, collection) {
+            continue;
+        }
+        if entry.status == Some(404) {
+            changes.removed.push(entry.href);
+        } else {
+            let etag = entry.etag();
+            changes.changed.push((entry.href, etag));
+        }
+    }
+    Ok(changes)
+}
+
+fn list_members(client: &DavClient, collection: &Url) -> Result<CollectionChanges, Error> {
+    let listing = propfind(client, collection, "1", "<d:getetag/><d:resourcetype/>")?;
+    Ok(CollectionChanges {
+        changed: listing
+            .entries
+            .into_iter()
+            .filter(|entry| {
+                !same_resource(&entry.href, collection)
+                    && !entry.has_resource_type(DAV_NAMESPACE, "collection")
+            })
+            .map(|entry| {
+                let etag = entry.etag();
+                (entry.href, etag)
+            })
+            .collect(),
+        removed: vec![],
+        sync_token: None,
+        complete: true,
+    })
+}
+
+/// Downloads the cards with addressbook-multiget reports. Cards the server does not return
+/// are left out.
+pub fn fetch_cards(
+    client: &DavClient,
+    collection: &Url,
+    hrefs: &[Url],
+) -> Result<Vec<RemoteCard>, Error> {
+    let mut cards = vec![];
+    for chunk in hrefs.chunks(DAV_MULTIGET_CHUNK_SIZE) {
+        let body = format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+             <card:addressbook-multiget xmlns:d=\"DAV:\" xmlns:card=\"{}\">\
+             <d:prop><d:getetag/><card:address-data/></d:prop>{}</card:addressbook-multiget>",
+            CARDDAV_NAMESPACE,
+            chunk.iter().map(href_element).collect::<String>()
+        );
+        let response = client.request(
+            "REPORT",
+            collection,
+            &[
+                ("Depth", "1"),
+                ("Content-Type", "application/xml; charset=utf-8"),
+            ],
+            body.as_bytes(),
+        )?;
+        for entry in parse_multistatus(&response)?.entries.into_iter() {
+            let vcard = match entry.property(CARDDAV_NAMESPACE, "address-data") {
+                Some(data) => data.text.clone(),
+                None => continue,
+            };
+            let etag = entry.etag();
+            cards.push(RemoteCard {
+                href: entry.href,
+                etag,
+                vcard,
+            });
+        }
+    }
+    Ok(cards)
+}
+
+pub fn put_card(
+    client: &DavClient,
+    href: &Url,
+    vcard: &str,
+    condition: PutCondition,
+) -> Result<PutOutcome, Error> {
+    let mut headers = vec![("Content-Type", "text/vcard; charset=utf-8")];
+    match condition {
+        PutCondition::Create => headers.push(("If-None-Match", "*")),
+        PutCondition::Replace(Some(etag)) => headers.push(("If-Match", etag)),
+        PutCondition::Replace(None) => {}
+    }
+    let response = client.request("PUT", href, &headers, vcard.as_bytes())?;
+    match response.status {
+        412 => Ok(PutOutcome::Conflict),
+        _ if response.is_success() => Ok(PutOutcome::Stored {
+            etag: response.header("etag").map(str::to_string),
+        }),
+        _ => Err(dav_error(&response)),
+    }
+}
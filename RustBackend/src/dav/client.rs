@@ -0,0 +1,265 @@
+use std::{
+    io::{Error, ErrorKind, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use url::{Host, Origin, Position, Url};
+
+use crate::constants::{DAV_MAX_REDIRECTS, DAV_MAX_RESPONSE_BYTES, DAV_TIMEOUT_SECS};
+
+/// Minimal blocking HTTP/1.1 client for WebDAV servers, one connection per request. Requests
+/// stay on the origin of the configured server. Plain `http` is only accepted for loopback
+/// hosts, so a local stand-in server can be used without sending the password in clear.
+pub struct DavClient {
+    authorization: String,
+    origin: Origin,
+    timeout: Duration,
+}
+
+pub struct DavResponse {
+    pub status: u16,
+    /// Lowercase names
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Where the response came from after following redirects
+    pub url: Url,
+}
+
+impl DavResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).to_string()
+    }
+}
+
+impl DavClient {
+    /// Authenticates every request to the origin of `server` with Basic auth.
+    pub fn new(username: &str, password: &str, server: &Url) -> DavClient {
+        DavClient {
+            authorization: format!(
+                "Basic {}",
+                data_encoding::BASE64.encode(format!("{}:{}", username, password).as_bytes())
+            ),
+            origin: server.origin(),
+            timeout: Duration::from_secs(DAV_TIMEOUT_SECS),
+        }
+    }
+
+    /// Whether the URL is on the configured server, the only one that gets the credentials.
+    fn is_same_origin(&self, url: &Url) -> bool {
+        url.origin() == self.origin
+    }
+
+    /// Sends the request and follows redirects, as servers announce their DAV root through
+    /// `/.well-known/carddav` and `/.well-known/caldav`. Redirects to another origin, which
+    /// includes a change from `https` to `http`, are refused.
+    pub fn request(
+        &self,
+        method: &str,
+        url: &Url,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<DavResponse, Error> {
+        let mut url = url.clone();
+        for _ in 0..=DAV_MAX_REDIRECTS {
+            if !self.is_same_origin(&url) {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("{} is not on the configured DAV server", url),
+                ));
+            }
+            if url.scheme() != "https" && !is_loopback(&url) {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("Refusing to send credentials over plain http to {}", url),
+                ));
+            }
+            let response = self.send(method, &url, headers, body)?;
+            let location = match response.status {
+                301 | 302 | 303 | 307 | 308 => response.header("location"),
+                _ => None,
+            };
+            match location {
+                Some(location) => {
+                    url = url.join(location).map_err(|err| {
+                        Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Invalid redirect {}: {}", location, err),
+                        )
+                    })?;
+                }
+                None => return Ok(response),
+            }
+        }
+        Err(Error::other(format!("Too many redirects for {}", url)))
+    }
+
+    fn send(
+        &self,
+        method: &str,
+        url: &Url,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<DavResponse, Error> {
+        let host = url
+            .host_str()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("No host in {}", url)))?;
+        let port = url
+            .port_or_known_default()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("No port for {}", url)))?;
+        let address = (host, port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Unknown host {}", host)))?;
+        let stream = TcpStream::connect_timeout(&address, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        let host_header = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nAuthorization: {}\r\nUser-Agent: RustEmailClient\r\n\
+             Connection: close\r\nContent-Length: {}\r\n",
+            method,
+            &url[Position::BeforePath..Position::AfterQuery],
+            host_header,
+            self.authorization,
+            body.len()
+        );
+        for (name, value) in headers.iter() {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        let mut request = request.into_bytes();
+        request.extend_from_slice(body);
+
+        let data = match url.scheme() {
+            "http" => exchange(stream, &request)?,
+            "https" => {
+                let tls = native_tls::TlsConnector::builder()
+                    .build()
+                    .map_err(|err| Error::other(format!("TlsConnector build failed: {:?}", err)))?;
+                let stream = tls
+                    .connect(host, stream)
+                    .map_err(|err| Error::other(format!("DAV connect failed: {:?}", err)))?;
+                exchange(stream, &request)?
+            }
+            scheme => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Unsupported scheme {}", scheme),
+                ))
+            }
+        };
+        parse_response(&data, url)
+    }
+}
+
+fn is_loopback(url: &Url) -> bool {
+    match url.host() {
+        Some(Host::Domain(domain)) => domain.eq_ignore_ascii_case("localhost"),
+        Some(Host::Ipv4(address)) => address.is_loopback(),
+        Some(Host::Ipv6(address)) => address.is_loopback(),
+        None => false,
+    }
+}
+
+fn exchange<S: Read + Write>(mut stream: S, request: &[u8]) -> Result<Vec<u8>, Error> {
+    stream.write_all(request)?;
+    stream.flush()?;
+    // The connection is closed after the response, its end is the end of the body
+    let mut data = vec![];
+    stream.take(DAV_MAX_RESPONSE_BYTES).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+fn parse_response(data: &[u8], url: &Url) -> Result<DavResponse, Error> {
+    let mut header_buffer = [httparse::EMPTY_HEADER; 64];
+    let mut response = httparse::Response::new(&mut header_buffer);
+    let header_length = match response.parse(data) {
+        Ok(httparse::Status::Complete(length)) => length,
+        Ok(httparse::Status::Partial) => {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Incomplete DAV response",
+            ))
+        }
+        Err(err) => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid DAV response: {}", err),
+            ))
+        }
+    };
+    let status = response.code.unwrap_or_default();
+    let headers: Vec<(String, String)> = response
+        .headers
+        .iter()
+        .map(|header| {
+            (
+                header.name.to_lowercase(),
+                String::from_utf8_lossy(header.value).trim().to_string(),
+            )
+        })
+        .collect();
+
+    let mut dav_response = DavResponse {
+        status,
+        headers,
+        body: vec![],
+        url: url.clone(),
+    };
+    let body = &data[header_length..];
+    let is_chunked = dav_response
+        .header("transfer-encoding")
+        .is_some_and(|encoding| encoding.to_lowercase().contains("chunked"));
+    let content_length = dav_response
+        .header("content-length")
+        .and_then(|length| length.parse::<usize>().ok());
+    dav_response.body = if is_chunked {
+        decode_chunked(body)?
+    } else {
+        match content_length {
+            Some(length) => body[..length.min(body.len())].to_vec(),
+            None => body.to_vec(),
+        }
+    };
+    Ok(dav_response)
+}
+
+fn decode_chunked(mut body: &[u8]) -> Result<Vec<u8>, Error> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "Invalid chunked DAV response");
+    let mut decoded = vec![];
+    loop {
+        let line_end = body
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .ok_or_else(invalid)?;
+        // Chunk extensions after `;` are ignored
+        let size_line = String::from_utf8_lossy(&body[..line_end]);
+        let size_text = size_line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size_text, 16).map_err(|_| invalid())?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(decoded);
+        }
+        if body.len() < size {
+            return Err(invalid());
+        }
+        decoded.extend_from_slice(&body[..size]);
+        body = body.get(size + 2..).unwrap_or_default();
+    }
+}
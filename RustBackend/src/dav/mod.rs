@@ -0,0 +1,354 @@
+pub mod caldav;
+pub mod carddav;
+pub mod client;
+#[cfg(test)]
+mod tests;
+
+use std::{
+    env,
+    io::{Error, ErrorKind},
+};
+
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use percent_encoding::percent_decode_str;
+use url::Url;
+
+use crate::utils::utils_xml::{escape_xml, parse_xml, XmlElement};
+
+use self::client::{DavClient, DavResponse};
+
+pub const DAV_NAMESPACE: &str = "DAV:";
+pub const CARDDAV_NAMESPACE: &str = "urn:ietf:params:xml:ns:carddav";
+pub const CALDAV_NAMESPACE: &str = "urn:ietf:params:xml:ns:caldav";
+
+/// DAV servers of the deployment, the signed in user authenticates with the email account.
+pub struct DavConfig {
+    pub carddav_url: Option<Url>,
+    pub caldav_url: Option<Url>,
+}
+
+impl DavConfig {
+    /// `CARDDAV_URL` and `CALDAV_URL` may point to the server root, a principal, a home set or
+    /// straight to one collection. Invalid URLs are reported and left out.
+    pub fn from_env() -> DavConfig {
+        let url = |name: &str| {
+            let value = env::var(name).ok()?;
+            if value.trim().is_empty() {
+                return None;
+            }
+            match Url::parse(value.trim()) {
+                Ok(url) => Some(url),
+                Err(err) => {
+                    println!("Ignoring {}: {}", name, err);
+                    None
+                }
+            }
+        };
+        DavConfig {
+            carddav_url: url("CARDDAV_URL"),
+            caldav_url: url("CALDAV_URL"),
+        }
+    }
+}
+
+/// One `response` of a multistatus.
+pub struct DavEntry {
+    pub href: Url,
+    /// Status of the whole response, set for removed members of a sync report
+    pub status: Option<u16>,
+    /// Properties the server returned with a 2xx status
+    pub properties: Vec<XmlElement>,
+}
+
+impl DavEntry {
+    pub fn property(&self, namespace: &str, name: &str) -> Option<&XmlElement> {
+        self.properties
+            .iter()
+            .find(|property| property.is(namespace, name))
+    }
+
+    pub fn etag(&self) -> Option<String> {
+        self.property(DAV_NAMESPACE, "getetag")
+            .map(|etag| etag.text.trim().to_string())
+            .filter(|etag| !etag.is_empty())
+    }
+
+    /// Whether `resourcetype` contains the element, e.g. CardDAV `addressbook`.
+    pub fn has_resource_type(&self, namespace: &str, name: &str) -> bool {
+        self.property(DAV_NAMESPACE, "resourcetype")
+            .is_some_and(|resource_type| resource_type.child(namespace, name).is_some())
+    }
+
+    /// Resolved `href` inside a property such as `current-user-principal`.
+    pub fn property_href(&self, namespace: &str, name: &str) -> Option<Url> {
+        let href = self
+            .property(namespace, name)?
+            .child(DAV_NAMESPACE, "href")?;
+        self.href
+            .join(href.text.trim())
+            .ok()
+            .filter(|url| url.origin() == self.href.origin())
+    }
+}
+
+pub struct Multistatus {
+    pub entries: Vec<DavEntry>,
+    /// New token of a sync-collection report
+    pub sync_token: Option<String>,
+}
+
+pub enum CollectionKind {
+    AddressBook,
+    Calendar,
+}
+
+pub fn parse_multistatus(response: &DavResponse) -> Result<Multistatus, Error> {
+    if response.status != 207 {
+        return Err(dav_error(response));
+    }
+    let root = parse_xml(&response.text())?;
+    if !root.is(DAV_NAMESPACE, "multistatus") {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "DAV response is no multistatus",
+        ));
+    }
+
+    let mut entries = vec![];
+    for entry in root.children_named(DAV_NAMESPACE, "response") {
+        let href = match entry.child(DAV_NAMESPACE, "href") {
+            Some(href) => href.text.trim(),
+            None => continue,
+        };
+        // Members on another host are skipped, later requests to them would carry the
+        // credentials there
+        let href = match response.url.join(href) {
+            Ok(href) if href.origin() == response.url.origin() => href,
+            _ => continue,
+        };
+        let mut properties = vec![];
+        for propstat in entry.children_named(DAV_NAMESPACE, "propstat") {
+            let found = propstat
+                .child(DAV_NAMESPACE, "status")
+                .and_then(|status| parse_status_line(&status.text))
+                .is_some_and(|status| (200..300).contains(&status));
+            if let (true, Some(prop)) = (found, propstat.child(DAV_NAMESPACE, "prop")) {
+                properties.extend(prop.children.iter().cloned());
+            }
+        }
+        entries.push(DavEntry {
+            href,
+            status: entry
+                .child(DAV_NAMESPACE, "status")
+                .and_then(|status| parse_status_line(&status.text)),
+            properties,
+        });
+    }
+    Ok(Multistatus {
+        entries,
+        sync_token: root
+            .child(DAV_NAMESPACE, "sync-token")
+            .map(|token| token.text.trim().to_string())
+            .filter(|token| !token.is_empty()),
+    })
+}
+
+pub fn propfind(
+    client: &DavClient,
+    url: &Url,
+    depth: &str,
+    properties: &str,
+) -> Result<Multistatus, Error> {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+         <d:propfind xmlns:d=\"DAV:\" xmlns:card=\"{}\" xmlns:cal=\"{}\">\
+         <d:prop>{}</d:prop></d:propfind>",
+        CARDDAV_NAMESPACE, CALDAV_NAMESPACE, properties
+    );
+    let response = client.request(
+        "PROPFIND",
+        url,
+        &[
+            ("Depth", depth),
+            ("Content-Type", "application/xml; charset=utf-8"),
+        ],
+        body.as_bytes(),
+    )?;
+    parse_multistatus(&response)
+}
+
+/// Finds the address books or calendars of the user (RFC 6352 and RFC 4791 discovery):
+/// the configured URL leads to the principal, the principal to the home set and the home set
+/// lists the collections. A URL pointing to a collection is used as it is.
+pub fn discover_collections(
+    client: &DavClient,
+    url: &Url,
+    kind: CollectionKind,
+) -> Result<Vec<Url>, Error> {
+    let (namespace, collection_type, home_set) = match kind {
+        CollectionKind::AddressBook => (CARDDAV_NAMESPACE, "addressbook", "addressbook-home-set"),
+        CollectionKind::Calendar => (CALDAV_NAMESPACE, "calendar", "calendar-home-set"),
+    };
+    let home_set_property = match kind {
+        CollectionKind::AddressBook => "<card:addressbook-home-set/>",
+        CollectionKind::Calendar => "<cal:calendar-home-set/>",
+    };
+    let collection_properties = match kind {
+        CollectionKind::AddressBook => "<d:resourcetype/>",
+        CollectionKind::Calendar => "<d:resourcetype/><cal:supported-calendar-component-set/>",
+    };
+
+    let start = propfind(
+        client,
+        url,
+        "0",
+        &format!(
+            "<d:resourcetype/><d:current-user-principal/>{}",
+            home_set_property
+        ),
+    )?;
+    let start_entry = start.entries.first();
+    if let Some(entry) =
+        start_entry.filter(|entry| entry.has_resource_type(namespace, collection_type))
+    {
+        return Ok(vec![entry.href.clone()]);
+    }
+
+    let mut home = start_entry.and_then(|entry| entry.property_href(namespace, home_set));
+    if home.is_none() {
+        if let Some(principal) = start_entry
+            .and_then(|entry| entry.property_href(DAV_NAMESPACE, "current-user-principal"))
+        {
+            let principal_status = propfind(client, &principal, "0", home_set_property)?;
+            home = principal_status
+                .entries
+                .first()
+                .and_then(|entry| entry.property_href(namespace, home_set));
+        }
+    }
+    // Servers without discovery are configured with the home set itself
+    let home = home.unwrap_or_else(|| url.clone());
+
+    let listing = propfind(client, &home, "1", collection_properties)?;
+    Ok(listing
+        .entries
+        .iter()
+        .filter(|entry| entry.has_resource_type(namespace, collection_type))
+        .filter(|entry| match kind {
+            CollectionKind::AddressBook => true,
+            // Calendars without the set accept every component
+            CollectionKind::Calendar => entry
+                .property(CALDAV_NAMESPACE, "supported-calendar-component-set")
+                .map(|components| {
+                    components
+                        .children_named(CALDAV_NAMESPACE, "comp")
+                        .any(|component| component.attribute("name") == Some("VEVENT"))
+                })
+                .unwrap_or(true),
+        })
+        .map(|entry| entry.href.clone())
+        .collect())
+}
+
+/// Member URL of a collection, for new resources.
+pub fn member_url(collection: &Url, name: &str) -> Result<Url, Error> {
+    let mut collection = collection.clone();
+    if !collection.path().ends_with('/') {
+        collection.set_path(&format!("{}/", collection.path()));
+    }
+    collection.join(name).map_err(|err| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid resource name {}: {}", name, err),
+        )
+    })
+}
+
+/// Hrefs may differ in a trailing slash or in percent encoding of the same path.
+pub fn same_resource(first: &Url, second: &Url) -> bool {
+    let normalize = |url: &Url| {
+        percent_decode_str(url.path())
+            .decode_utf8_lossy()
+            .trim_end_matches('/')
+            .to_string()
+    };
+    first.host_str() == second.host_str() && normalize(first) == normalize(second)
+}
+
+/// Random version 4 UUID for resources created here.
+pub fn new_uid() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Path segment for a resource named after a UID, without characters needing escapes.
+pub fn resource_name(uid: &str, extension: &str) -> String {
+    let name: String = uid
+        .chars()
+        .map(|char| {
+            if char.is_ascii_alphanumeric() || matches!(char, '-' | '_' | '.' | '@') {
+                char
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{}.{}", name, extension)
+}
+
+/// Removes the resource unless it changed on the server, returns false in that case. A
+/// resource which is already gone counts as removed.
+pub fn delete_resource(client: &DavClient, href: &Url, etag: Option<&str>) -> Result<bool, Error> {
+    let headers: Vec<(&str, &str)> = etag.map(|etag| ("If-Match", etag)).into_iter().collect();
+    let response = client.request("DELETE", href, &headers, b"")?;
+    match response.status {
+        404 => Ok(true),
+        412 => Ok(false),
+        _ if response.is_success() => Ok(true),
+        _ => Err(dav_error(&response)),
+    }
+}
+
+pub fn parse_href(href: &str) -> Result<Url, Error> {
+    Url::parse(href).map_err(|err| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid stored href {}: {}", href, err),
+        )
+    })
+}
+
+pub fn href_element(url: &Url) -> String {
+    format!("<d:href>{}</d:href>", escape_xml(url.path()))
+}
+
+/// `HTTP/1.1 404 Not Found`
+fn parse_status_line(line: &str) -> Option<u16> {
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+pub fn dav_error(response: &DavResponse) -> Error {
+    let kind = match response.status {
+        401 | 403 => ErrorKind::PermissionDenied,
+        404 => ErrorKind::NotFound,
+        _ => ErrorKind::Other,
+    };
+    Error::new(
+        kind,
+        format!(
+            "DAV request to {} failed with {}",
+            response.url, response.status
+        ),
+    )
+}
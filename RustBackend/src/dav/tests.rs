@@ -0,0 +1,410 @@
+//! Discovery, sync and conflict handling against an in-process stand-in DAV server.
+
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::TcpListener,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use url::Url;
+
+use super::{
+    carddav::{collection_changes, put_card, PutCondition, PutOutcome},
+    client::DavClient,
+    delete_resource, discover_collections, CollectionKind,
+};
+
+#[derive(Clone)]
+struct Request {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+type Requests = Arc<Mutex<Vec<Request>>>;
+
+/// Serves every connection with the handler and records the requests.
+fn serve<F>(handler: F) -> (Url, Requests)
+where
+    F: Fn(&Request) -> Vec<u8> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+    let requests: Requests = Arc::default();
+    let recorded = requests.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let request = match read_request(&mut stream) {
+                Some(request) => request,
+                None => continue,
+            };
+            recorded.lock().unwrap().push(request.clone());
+            let _ = stream.write_all(&handler(&request));
+        }
+    });
+    (url, requests)
+}
+
+fn read_request<S: Read>(stream: &mut S) -> Option<Request> {
+    let mut data = vec![];
+    let mut buffer = [0u8; 4096];
+    loop {
+        let read = stream.read(&mut buffer).ok()?;
+        if read == 0 {
+            return None;
+        }
+        data.extend_from_slice(&buffer[..read]);
+
+        let mut header_buffer = [httparse::EMPTY_HEADER; 32];
+        let mut request = httparse::Request::new(&mut header_buffer);
+        let header_length = match request.parse(&data).ok()? {
+            httparse::Status::Complete(length) => length,
+            httparse::Status::Partial => continue,
+        };
+        let headers: Vec<(String, String)> = request
+            .headers
+            .iter()
+            .map(|header| {
+                (
+                    header.name.to_string(),
+                    String::from_utf8_lossy(header.value).to_string(),
+                )
+            })
+            .collect();
+        let content_length = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, value)| value.parse::<usize>().ok())
+            .unwrap_or_default();
+        if data.len() < header_length + content_length {
+            continue;
+        }
+        return Some(Request {
+            method: request.method?.to_string(),
+            path: request.path?.to_string(),
+            headers,
+            body: String::from_utf8_lossy(&data[header_length..header_length + content_length])
+                .to_string(),
+        });
+    }
+}
+
+fn reply(status: u16, headers: &[(&str, &str)], body: &str) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 {} Stand-in\r\nContent-Length: {}\r\n",
+        status,
+        body.len()
+    );
+    for (name, value) in headers.iter() {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str("\r\n");
+    response.push_str(body);
+    response.into_bytes()
+}
+
+/// Sends the body in two chunks, with an extension on the first one.
+fn reply_chunked(status: u16, body: &str) -> Vec<u8> {
+    let (first, second) = body.split_at(body.len() / 2);
+    format!(
+        "HTTP/1.1 {} Stand-in\r\nTransfer-Encoding: chunked\r\n\r\n{:x};name=value\r\n{}\r\n\
+         {:x}\r\n{}\r\n0\r\n\r\n",
+        status,
+        first.len(),
+        first,
+        second.len(),
+        second
+    )
+    .into_bytes()
+}
+
+fn multistatus(responses: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+         <D:multistatus xmlns:D=\"DAV:\" xmlns:C=\"urn:ietf:params:xml:ns:carddav\">{}\
+         </D:multistatus>",
+        responses
+    )
+}
+
+fn found(href: &str, properties: &str) -> String {
+    format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>{}</D:prop>\
+         <D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+        href, properties
+    )
+}
+
+fn client(url: &Url) -> DavClient {
+    DavClient::new("user@example.com", "secret", url)
+}
+
+#[test]
+fn discovers_address_books_through_principal_and_home_set() {
+    let (url, requests) = serve(
+        |request| match (request.method.as_str(), request.path.as_str()) {
+            ("PROPFIND", "/.well-known/carddav") => reply(301, &[("Location", "/dav/")], ""),
+            ("PROPFIND", "/dav/") => reply(
+                207,
+                &[],
+                &multistatus(&found(
+                    "/dav/",
+                    "<D:resourcetype><D:collection/></D:resourcetype>\
+                     <D:current-user-principal><D:href>/principals/user/</D:href>\
+                     </D:current-user-principal>",
+                )),
+            ),
+            ("PROPFIND", "/principals/user/") => reply_chunked(
+                207,
+                &multistatus(&found(
+                    "/principals/user/",
+                    "<C:addressbook-home-set><D:href>/addressbooks/user/</D:href>\
+                     </C:addressbook-home-set>",
+                )),
+            ),
+            ("PROPFIND", "/addressbooks/user/") => reply(
+                207,
+                &[],
+                &multistatus(&format!(
+                    "{}{}{}{}",
+                    found(
+                        "/addressbooks/user/",
+                        "<D:resourcetype><D:collection/></D:resourcetype>"
+                    ),
+                    found(
+                        "/addressbooks/user/contacts/",
+                        "<D:resourcetype><D:collection/><C:addressbook/></D:resourcetype>"
+                    ),
+                    found(
+                        "/addressbooks/user/files/",
+                        "<D:resourcetype><D:collection/></D:resourcetype>"
+                    ),
+                    // Members on another host are never used
+                    found(
+                        "https://other.example.com/addressbooks/user/contacts/",
+                        "<D:resourcetype><D:collection/><C:addressbook/></D:resourcetype>"
+                    ),
+                )),
+            ),
+            _ => reply(404, &[], ""),
+        },
+    );
+
+    let start = url.join(".well-known/carddav").unwrap();
+    let address_books =
+        discover_collections(&client(&url), &start, CollectionKind::AddressBook).unwrap();
+    assert_eq!(
+        address_books,
+        vec![url.join("/addressbooks/user/contacts/").unwrap()]
+    );
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 4);
+    assert!(requests
+        .iter()
+        .all(|request| request.header("Authorization").is_some()));
+    assert_eq!(requests[1].header("Depth"), Some("0"));
+    assert_eq!(requests[3].header("Depth"), Some("1"));
+}
+
+#[test]
+fn uses_a_configured_collection_as_it_is() {
+    let (url, _) = serve(|request| {
+        reply(
+            207,
+            &[],
+            &multistatus(&found(
+                &request.path,
+                "<D:resourcetype><D:collection/><C:addressbook/></D:resourcetype>",
+            )),
+        )
+    });
+
+    let collection = url.join("/addressbooks/user/contacts/").unwrap();
+    let address_books =
+        discover_collections(&client(&url), &collection, CollectionKind::AddressBook).unwrap();
+    assert_eq!(address_books, vec![collection]);
+}
+
+#[test]
+fn syncs_changes_since_the_token() {
+    let (url, requests) = serve(|_| {
+        reply(
+            207,
+            &[],
+            &multistatus(&format!(
+                "{}<D:response><D:href>/contacts/2.vcf</D:href>\
+                 <D:status>HTTP/1.1 404 Not Found</D:status></D:response>\
+                 <D:sync-token>token-2</D:sync-token>",
+                found("/contacts/1.vcf", "<D:getetag>\"e2\"</D:getetag>"),
+            )),
+        )
+    });
+
+    let collection = url.join("/contacts/").unwrap();
+    let changes = collection_changes(&client(&url), &collection, Some("token-1")).unwrap();
+    assert_eq!(
+        changes.changed,
+        vec![(
+            url.join("/contacts/1.vcf").unwrap(),
+            Some("\"e2\"".to_string())
+        )]
+    );
+    assert_eq!(changes.removed, vec![url.join("/contacts/2.vcf").unwrap()]);
+    assert_eq!(changes.sync_token.as_deref(), Some("token-2"));
+    assert!(!changes.complete);
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests[0].method, "REPORT");
+    assert!(requests[0]
+        .body
+        .contains("<d:sync-token>token-1</d:sync-token>"));
+}
+
+#[test]
+fn starts_over_when_the_token_expired() {
+    let (url, requests) = serve(|request| {
+        if request
+            .body
+            .contains("<d:sync-token>expired</d:sync-token>")
+        {
+            return reply(
+                403,
+                &[],
+                "<D:error xmlns:D=\"DAV:\"><D:valid-sync-token/></D:error>",
+            );
+        }
+        reply(
+            207,
+            &[],
+            &multistatus(&format!(
+                "{}{}<D:sync-token>token-1</D:sync-token>",
+                found("/contacts/", ""),
+                found("/contacts/1.vcf", "<D:getetag>\"e1\"</D:getetag>"),
+            )),
+        )
+    });
+
+    let collection = url.join("/contacts/").unwrap();
+    let changes = collection_changes(&client(&url), &collection, Some("expired")).unwrap();
+    assert_eq!(changes.changed.len(), 1);
+    assert_eq!(changes.sync_token.as_deref(), Some("token-1"));
+    assert!(changes.complete);
+    assert_eq!(requests.lock().unwrap().len(), 2);
+}
+
+#[test]
+fn lists_every_member_without_sync_collection() {
+    let (url, requests) = serve(|request| match request.method.as_str() {
+        "REPORT" => reply(501, &[], ""),
+        _ => reply(
+            207,
+            &[],
+            &multistatus(&format!(
+                "{}{}{}",
+                found(
+                    "/contacts/",
+                    "<D:resourcetype><D:collection/></D:resourcetype>"
+                ),
+                found("/contacts/1.vcf", "<D:getetag>\"e1\"</D:getetag>"),
+                found(
+                    "/contacts/nested/",
+                    "<D:resourcetype><D:collection/></D:resourcetype>"
+                ),
+            )),
+        ),
+    });
+
+    let collection = url.join("/contacts/").unwrap();
+    let changes = collection_changes(&client(&url), &collection, None).unwrap();
+    assert_eq!(
+        changes.changed,
+        vec![(
+            url.join("/contacts/1.vcf").unwrap(),
+            Some("\"e1\"".to_string())
+        )]
+    );
+    assert!(changes.sync_token.is_none());
+    assert!(changes.complete);
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests[1].method, "PROPFIND");
+    assert_eq!(requests[1].header("Depth"), Some("1"));
+}
+
+#[test]
+fn writes_only_over_the_expected_etag() {
+    let (url, requests) = serve(|request| {
+        let current = "\"e2\"";
+        match request.method.as_str() {
+            "PUT" if request.header("If-None-Match") == Some("*") => reply(412, &[], ""),
+            "PUT" | "DELETE" if request.header("If-Match") != Some(current) => reply(412, &[], ""),
+            "PUT" => reply(204, &[("ETag", "\"e3\"")], ""),
+            _ => reply(204, &[], ""),
+        }
+    });
+
+    let client = client(&url);
+    let href = url.join("/contacts/1.vcf").unwrap();
+    let vcard = "BEGIN:VCARD\r\nVERSION:3.0\r\nFN:Test\r\nEND:VCARD\r\n";
+    assert!(matches!(
+        put_card(&client, &href, vcard, PutCondition::Replace(Some("\"e1\""))).unwrap(),
+        PutOutcome::Conflict
+    ));
+    assert!(matches!(
+        put_card(&client, &href, vcard, PutCondition::Create).unwrap(),
+        PutOutcome::Conflict
+    ));
+    match put_card(&client, &href, vcard, PutCondition::Replace(Some("\"e2\""))).unwrap() {
+        PutOutcome::Stored { etag } => assert_eq!(etag.as_deref(), Some("\"e3\"")),
+        PutOutcome::Conflict => panic!("Expected the card to be stored"),
+    }
+    assert!(!delete_resource(&client, &href, Some("\"e1\"")).unwrap());
+    assert!(delete_resource(&client, &href, Some("\"e2\"")).unwrap());
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests[2].body, vcard);
+    assert_eq!(
+        requests[2].header("Content-Type"),
+        Some("text/vcard; charset=utf-8")
+    );
+}
+
+#[test]
+fn refuses_redirects_to_another_origin() {
+    let (other, other_requests) = serve(|_| reply(207, &[], &multistatus("")));
+    let location = other.join("/dav/").unwrap().to_string();
+    let (url, _) = serve(move |_| reply(302, &[("Location", &location)], ""));
+
+    let err = client(&url)
+        .request("PROPFIND", &url, &[], b"")
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    assert!(other_requests.lock().unwrap().is_empty());
+}
+
+#[test]
+fn refuses_plain_http_to_remote_hosts() {
+    let url = Url::parse("http://dav.example.com/").unwrap();
+    let err = client(&url)
+        .request("PROPFIND", &url, &[], b"")
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+}
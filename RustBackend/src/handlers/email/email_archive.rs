@@ -0,0 +1,446 @@
+use std::{
+    fs::{remove_file, File},
+    io::{BufReader, Error},
+    thread,
+};
+
+use actix_multipart::Multipart;
+use actix_session::Session;
+use actix_web::{
+    http::header::{
+        CacheControl, CacheDirective, ContentDisposition, DispositionParam, DispositionType,
+    },
+    web::{self, Bytes},
+    HttpResponse,
+};
+use chrono::Utc;
+use futures_util::Stream;
+use imap_proto::{AttributeValue, Response};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use utf7_imap::encode_utf7_imap;
+
+use crate::{
+    constants::{
+        ARCHIVE_CHANNEL_SIZE, ARCHIVE_FETCH_CHUNK_SIZE, ARCHIVE_MAX_MESSAGE_BYTES,
+        ARCHIVE_MAX_UIDS, ARCHIVE_PROGRESS_INTERVAL,
+    },
+    storage::message_cache::MessageCache,
+    utils::{
+        utils_archive::{
+            maildir_file_name, parse_maildir_path, to_lf_line_endings, to_mboxrd_entry,
+            ArchivedMessage, MboxReader,
+        },
+        utils_imap::{
+            format_uid_set, parse_internal_date, parse_uid_set, quote_imap_string, CommandPart,
+            ImapConnection,
+        },
+        utils_session::check_is_valid_session,
+        utils_transports::create_imap_connection,
+        utils_zip::{ZipReader, ZipWriter},
+    },
+};
+
+use super::{
+    email_cache::parse_select_response,
+    email_imap::{parse_fetch_flags, parse_fetch_text, parse_fetch_uid},
+    email_import::{
+        append_arguments, format_append_date, normalize_line_endings, parse_internal_date_of,
+        read_import_upload, ImportUpload,
+    },
+    models::{ArchiveExportInDTO, ArchiveFormat, ArchiveImportEventOutDTO, ArchiveImportEventType},
+};
+
+/// Streams a whole mailbox, the given UIDs or the result of a text search as an mboxrd file
+/// or a zipped Maildir. Messages are downloaded in chunks on a separate thread, so only a
+/// chunk is held in memory at a time.
+async fn export_mailbox(
+    session: Session,
+    request: web::Query<ArchiveExportInDTO>,
+) -> Result<HttpResponse, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let mut imap_connection = create_imap_connection(
+        &credentials.email,
+        &credentials.password,
+        &credentials.get_imap_string(),
+    )
+    .await?;
+    // Read-only, the export must not mark anything as seen
+    let examine_response = imap_connection.command(&format!(
+        "EXAMINE {}",
+        quote_imap_string(&encode_utf7_imap(request.mailbox_name.clone()))
+    ))?;
+    let (exists, uid_validity) = parse_select_response(&examine_response.untagged);
+
+    let uids = match (&request.uids, &request.search_text) {
+        (Some(uid_set), _) => parse_uid_set(uid_set, ARCHIVE_MAX_UIDS)?,
+        (None, Some(search_text)) => {
+            // A literal, the text may contain anything a quoted string can not
+            let criteria = [
+                CommandPart::Text("CHARSET UTF-8 TEXT ".to_string()),
+                CommandPart::Literal(search_text.as_bytes()),
+            ];
+            search_uids(&mut imap_connection, &criteria)?
+        }
+        (None, None) if exists > 0 => search_uids(
+            &mut imap_connection,
+            &[CommandPart::Text("ALL".to_string())],
+        )?,
+        (None, None) => vec![],
+    };
+
+    let base_name = archive_base_name(&request.mailbox_name);
+    let (file_name, content_type) = match request.format {
+        ArchiveFormat::Mbox => (format!("{}.mbox", base_name), "application/mbox"),
+        ArchiveFormat::Maildir => (format!("{}.zip", base_name), "application/zip"),
+    };
+
+    let format = request.format.clone();
+    let stream = stream_from_thread(move |sender| {
+        let result = export_messages(
+            &mut imap_connection,
+            &format,
+            &base_name,
+            uid_validity,
+            &uids,
+            &sender,
+        );
+        if let Err(err) = result {
+            println!("Exporting mailbox {} failed: {}", base_name, err);
+            // Aborts the download, so the client does not keep a truncated archive
+            let _ = sender.blocking_send(Err(err));
+        }
+        let _ = imap_connection.logout();
+    });
+
+    let content_disposition = ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(file_name)],
+    };
+    Ok(HttpResponse::Ok()
+        .insert_header(content_disposition)
+        .content_type(content_type)
+        .streaming(stream))
+}
+
+/// Runs `produce` on a thread of its own and streams the chunks it sends as the response body,
+/// so blocking IMAP commands and compression stay off the workers. The bounded channel keeps
+/// the thread from running far ahead of a slow client.
+pub fn stream_from_thread<F>(produce: F) -> impl Stream<Item = Result<Bytes, Error>>
+where
+    F: FnOnce(Sender<Result<Bytes, Error>>) + Send + 'static,
+{
+    let (sender, receiver) = channel(ARCHIVE_CHANNEL_SIZE);
+    thread::spawn(move || produce(sender));
+    futures_util::stream::unfold(
+        receiver,
+        |mut receiver: Receiver<Result<Bytes, Error>>| async {
+            receiver.recv().await.map(|chunk| (chunk, receiver))
+        },
+    )
+}
+
+pub fn search_uids(
+    imap_connection: &mut ImapConnection,
+    criteria: &[CommandPart],
+) -> Result<Vec<u32>, Error> {
+    let mut command = vec![CommandPart::Text("UID SEARCH ".to_string())];
+    command.extend(criteria.iter().cloned());
+    let mut uids = vec![];
+    for line in imap_connection.command_parts(&command)?.untagged.iter() {
+        if let Ok((_, Response::IDs(ids))) = imap_proto::parse_response(line) {
+            uids.extend(ids);
+        }
+    }
+    uids.sort_unstable();
+    Ok(uids)
+}
+
+fn export_messages(
+    imap_connection: &mut ImapConnection,
+    format: &ArchiveFormat,
+    base_name: &str,
+    uid_validity: u32,
+    uids: &[u32],
+    sender: &Sender<Result<Bytes, Error>>,
+) -> Result<(), Error> {
+    let mut zip_writer = ZipWriter::new();
+    if *format == ArchiveFormat::Maildir {
+        let now = Utc::now().naive_utc();
+        let mut directories = zip_writer.add_directory(base_name, now);
+        for directory in ["cur", "new", "tmp"] {
+            directories
+                .extend(zip_writer.add_directory(&format!("{}/{}", base_name, directory), now));
+        }
+        if sender.blocking_send(Ok(Bytes::from(directories))).is_err() {
+            return Ok(());
+        }
+    }
+
+    for chunk in uids.chunks(ARCHIVE_FETCH_CHUNK_SIZE) {
+        let fetch_response = imap_connection.command(&format!(
+            "UID FETCH {} (UID FLAGS INTERNALDATE BODY.PEEK[])",
+            format_uid_set(chunk)
+        ))?;
+
+        let mut output = vec![];
+        for line in fetch_response.untagged.iter() {
+            let attributes = match imap_proto::parse_response(line) {
+                Ok((_, Response::Fetch(_, attributes))) => attributes,
+                _ => continue,
+            };
+            let (uid, message) = match (parse_fetch_uid(&attributes), parse_fetch_text(&attributes))
+            {
+                (Some(uid), Some(message)) => (uid, message),
+                _ => continue,
+            };
+            let flags: Vec<String> = parse_fetch_flags(&attributes)
+                .into_iter()
+                .filter(|flag| !flag.eq_ignore_ascii_case("\\Recent"))
+                .collect();
+            let internal_date = attributes
+                .iter()
+                .find_map(|attribute| match attribute {
+                    AttributeValue::InternalDate(date) => parse_internal_date(date),
+                    _ => None,
+                })
+                .unwrap_or_else(|| Utc::now().into());
+
+            match format {
+                ArchiveFormat::Mbox => {
+                    output.extend(to_mboxrd_entry(message, &flags, internal_date))
+                }
+                ArchiveFormat::Maildir => output.extend(zip_writer.add_file(
+                    &format!(
+                        "{}/cur/{}",
+                        base_name,
+                        maildir_file_name(internal_date, uid, uid_validity, &flags)
+                    ),
+                    &to_lf_line_endings(message),
+                    internal_date.naive_local(),
+                )?),
+            }
+        }
+
+        // The client went away, there is nobody to export to
+        if sender.blocking_send(Ok(Bytes::from(output))).is_err() {
+            return Ok(());
+        }
+    }
+
+    if *format == ArchiveFormat::Maildir {
+        let _ = sender.blocking_send(Ok(Bytes::from(zip_writer.finish())));
+    }
+    Ok(())
+}
+
+/// Name of the downloaded file and the Maildir directory, hierarchy separators become dots
+/// like in Maildir++.
+fn archive_base_name(mailbox_name: &str) -> String {
+    let name: String = mailbox_name
+        .chars()
+        .map(|char| if char == '/' { '.' } else { char })
+        .filter(|char| char.is_alphanumeric() || matches!(char, ' ' | '-' | '_' | '.'))
+        .collect();
+    let name = name.trim().trim_matches('.');
+    if name.is_empty() {
+        "mailbox".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+/// Imports an mbox file or a zipped Maildir into a mailbox with APPEND. Flags and arrival dates
+/// stored by the format are kept. The progress is streamed as Server-Sent Events, the import
+/// goes on even when the client disconnects.
+async fn import_archive(
+    mut payload: Multipart,
+    session: Session,
+    cache: web::Data<MessageCache>,
+) -> Result<HttpResponse, Error> {
+    let credentials = check_is_valid_session(&session)?;
+
+    let mut upload = ImportUpload {
+        mailbox_name: None,
+        flags: String::new(),
+        format: None,
+        files: vec![],
+    };
+    if let Err(err) = read_import_upload(&mut payload, &mut upload).await {
+        remove_upload_files(&upload);
+        return Err(err);
+    }
+
+    let (mailbox_name, (file_name, path)) = match (&upload.mailbox_name, upload.files.first()) {
+        (Some(mailbox_name), Some(file)) => (mailbox_name.clone(), file.clone()),
+        _ => {
+            remove_upload_files(&upload);
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "mailbox_name and a file have to be set",
+            ));
+        }
+    };
+    let format = match upload.format.as_deref() {
+        Some("mbox") => ArchiveFormat::Mbox,
+        Some("maildir") => ArchiveFormat::Maildir,
+        Some(other) => {
+            remove_upload_files(&upload);
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unknown archive format {}", other),
+            ));
+        }
+        None if file_name.to_lowercase().ends_with(".zip") => ArchiveFormat::Maildir,
+        None => ArchiveFormat::Mbox,
+    };
+
+    let mut imap_connection = match create_imap_connection(
+        &credentials.email,
+        &credentials.password,
+        &credentials.get_imap_string(),
+    )
+    .await
+    {
+        Ok(imap_connection) => imap_connection,
+        Err(err) => {
+            remove_upload_files(&upload);
+            return Err(err);
+        }
+    };
+    // Only the first file is imported
+    for (_, other_path) in upload.files.iter().skip(1) {
+        let _ = remove_file(other_path);
+    }
+
+    let stream = stream_from_thread(move |sender| {
+        let mut progress = ArchiveImportEventOutDTO {
+            event_type: ArchiveImportEventType::Progress,
+            mailbox_name: mailbox_name.clone(),
+            processed: 0,
+            imported: 0,
+            failed: 0,
+            message: None,
+        };
+        let result = append_archive(&mut imap_connection, &format, &path, &sender, &mut progress);
+        let _ = imap_connection.logout();
+        let _ = remove_file(&path);
+        // The cache lists the new emails after the next sync
+        if progress.imported > 0 {
+            if let Err(err) = cache.mark_unsynced(&credentials.email, &mailbox_name) {
+                println!("Couldnt update the cache of {}: {:?}", mailbox_name, err);
+            }
+        }
+
+        match result {
+            Ok(()) => progress.event_type = ArchiveImportEventType::Done,
+            Err(err) => {
+                println!("Importing into mailbox {} failed: {}", mailbox_name, err);
+                progress.event_type = ArchiveImportEventType::Error;
+                progress.message = Some(err.to_string());
+            }
+        }
+        let _ = sender.blocking_send(Ok(Bytes::from(format_event(&progress))));
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        .streaming(stream))
+}
+
+fn remove_upload_files(upload: &ImportUpload) {
+    for (_, path) in upload.files.iter() {
+        let _ = remove_file(path);
+    }
+}
+
+fn append_archive(
+    imap_connection: &mut ImapConnection,
+    format: &ArchiveFormat,
+    path: &str,
+    sender: &Sender<Result<Bytes, Error>>,
+    progress: &mut ArchiveImportEventOutDTO,
+) -> Result<(), Error> {
+    let mailbox = quote_imap_string(&encode_utf7_imap(progress.mailbox_name.clone()));
+    let file = File::open(path)?;
+
+    match format {
+        ArchiveFormat::Mbox => {
+            let mut reader = MboxReader::new(BufReader::new(file));
+            while let Some(message) = reader.next_message()? {
+                append_message(imap_connection, &mailbox, message, sender, progress)?;
+            }
+        }
+        ArchiveFormat::Maildir => {
+            let mut reader = ZipReader::new(file, ARCHIVE_MAX_MESSAGE_BYTES)?;
+            let mut entries: Vec<_> = reader
+                .entry_names()?
+                .into_iter()
+                .filter_map(|(index, name)| {
+                    parse_maildir_path(&name).map(|message| (index, name, message))
+                })
+                .collect();
+            // Names start with the delivery time, so messages are appended in their order
+            entries.sort_by(|(_, first, _), (_, second, _)| first.cmp(second));
+            for (index, _, mut message) in entries.into_iter() {
+                message.message = reader.read_entry(index)?;
+                append_message(imap_connection, &mailbox, message, sender, progress)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn append_message(
+    imap_connection: &mut ImapConnection,
+    mailbox: &str,
+    message: ArchivedMessage,
+    sender: &Sender<Result<Bytes, Error>>,
+    progress: &mut ArchiveImportEventOutDTO,
+) -> Result<(), Error> {
+    let content = normalize_line_endings(&message.message);
+    progress.processed += 1;
+
+    if content.is_empty() {
+        progress.failed += 1;
+    } else {
+        // The archive keeps the arrival date, the Date header is only a fallback
+        let internal_date = message
+            .received
+            .map(format_append_date)
+            .or_else(|| parse_internal_date_of(&content));
+        let arguments = append_arguments(&message.flags, internal_date);
+        match imap_connection.append(mailbox, &arguments, &content) {
+            Ok(_) => progress.imported += 1,
+            // A refused message does not stop the others, a broken connection does
+            Err(err) if err.kind() == std::io::ErrorKind::Other => {
+                println!("Importing message {} failed: {}", progress.processed, err);
+                progress.failed += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    if progress.processed.is_multiple_of(ARCHIVE_PROGRESS_INTERVAL) {
+        let _ = sender.blocking_send(Ok(Bytes::from(format_event(progress))));
+    }
+    Ok(())
+}
+
+fn format_event(event: &ArchiveImportEventOutDTO) -> String {
+    let event_name = match event.event_type {
+        ArchiveImportEventType::Progress => "progress",
+        ArchiveImportEventType::Done => "done",
+        ArchiveImportEventType::Error => "error",
+    };
+    format!(
+        "event: {}\ndata: {}\n\n",
+        event_name,
+        serde_json::to_string(event).unwrap_or_default()
+    )
+}
+
+pub fn email_archive_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/archive/export").route(web::get().to(export_mailbox)))
+        .service(web::resource("/archive/import").route(web::post().to(import_archive)));
+}
@@ -0,0 +1,273 @@
+use std::io::{Error, ErrorKind};
+
+use actix_session::Session;
+use actix_web::{
+    http::header::{self, CacheControl, CacheDirective, TryIntoHeaderValue},
+    web, HttpResponse,
+};
+use encoding_rs::{Encoding, UTF_8};
+use image::ImageFormat;
+use imap_proto::{AttributeValue, BodyStructure, Response};
+use utf7_imap::encode_utf7_imap;
+
+use crate::{
+    constants::{
+        ATTACHMENT_PREVIEW_DEFAULT_SIZE, ATTACHMENT_PREVIEW_MAX_AGE_SECS,
+        ATTACHMENT_PREVIEW_MAX_OCTETS, ATTACHMENT_PREVIEW_SIZES, ATTACHMENT_PREVIEW_TEXT_LENGTH,
+    },
+    scanning::AttachmentScanners,
+    storage::message_cache::MessageCache,
+    utils::{
+        utils_imap::quote_imap_string,
+        utils_preview::{
+            extract_pdf_text, make_thumbnail, shorten_preview_text, thumbnail_content_type,
+            thumbnail_format,
+        },
+        utils_session::check_is_valid_session,
+        utils_transports::create_imap_connection,
+    },
+};
+
+use super::{
+    email_cache::parse_select_response,
+    email_imap::{
+        attachment_file_name, decide_encoding, decode_part_bytes, find_body_part, parse_fetch_text,
+    },
+    email_scanning::{part_scan_target, scan_attachment},
+    email_source::validate_section,
+    helper_models::EncodingType,
+    models::{AttachmentPreviewInDTO, AttachmentTextPreviewOutDTO},
+};
+
+// Previews are cached next to the parts of the email, with the part id appended
+pub const CACHE_THUMBNAIL_SECTION: &str = "THUMBNAIL";
+pub const CACHE_TEXT_PREVIEW_SECTION: &str = "TEXT-PREVIEW";
+
+enum PreviewKind {
+    Thumbnail(ImageFormat),
+    PdfText,
+    /// With the charset parameter of the part
+    PlainText(Option<String>),
+}
+
+/// Thumbnail of an image attachment, or the beginning of the text of a PDF or plain text
+/// attachment as JSON. Previews are generated once and kept in the message cache for the
+/// mailboxes the background sync watches, only attachments all scanners let pass are decoded.
+async fn get_attachment_preview(
+    session: Session,
+    scanners: web::Data<AttachmentScanners>,
+    cache: web::Data<MessageCache>,
+    request: web::Query<AttachmentPreviewInDTO>,
+) -> Result<HttpResponse, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let account = credentials.email.clone();
+    let part_id = validate_section(request.part_id.trim())?;
+    let size = thumbnail_size(request.size);
+    let thumbnail_section = format!("{}-{}/{}", CACHE_THUMBNAIL_SECTION, size, part_id);
+    let text_section = format!("{}/{}", CACHE_TEXT_PREVIEW_SECTION, part_id);
+
+    let mut imap_connection = create_imap_connection(
+        &credentials.email,
+        &credentials.password,
+        &credentials.get_imap_string(),
+    )
+    .await?;
+    // Read-only, a preview must not mark the email as seen
+    let examine_response = imap_connection.command(&format!(
+        "EXAMINE {}",
+        quote_imap_string(&encode_utf7_imap(request.mailbox_name.clone()))
+    ))?;
+    let (_, uid_validity) = parse_select_response(&examine_response.untagged);
+    cache.validate_mailbox(&account, &request.mailbox_name, uid_validity)?;
+
+    // Cached previews of a UID are only valid as long as the mailbox's UIDVALIDITY is
+    if let Some(thumbnail) = cache.part(
+        &account,
+        &request.mailbox_name,
+        request.uid,
+        &thumbnail_section,
+    )? {
+        imap_connection.logout()?;
+        return preview_response(thumbnail_content_type(&thumbnail), thumbnail);
+    }
+    if let Some(text) = cache.part(&account, &request.mailbox_name, request.uid, &text_section)? {
+        imap_connection.logout()?;
+        return preview_response("application/json", text);
+    }
+
+    let structure_response =
+        imap_connection.command(&format!("UID FETCH {} (UID BODYSTRUCTURE)", request.uid))?;
+    let mut found_part = None;
+    for line in structure_response.untagged.iter() {
+        if let Ok((_, Response::Fetch(_, attributes))) = imap_proto::parse_response(line) {
+            for attribute in attributes.iter() {
+                if let AttributeValue::BodyStructure(structure) = attribute {
+                    found_part = find_body_part(structure, part_id).map(|part| {
+                        (
+                            part_scan_target(
+                                part,
+                                part_id,
+                                &request.mailbox_name,
+                                Some(request.uid),
+                            ),
+                            describe_preview_part(part),
+                        )
+                    });
+                }
+            }
+        }
+    }
+    let (target, (kind, content_type, octets, encoding)) = match found_part {
+        Some((target, Some(part))) => (target, part),
+        Some((_, None)) => {
+            imap_connection.logout()?;
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "No preview can be made of this attachment",
+            ));
+        }
+        None => {
+            imap_connection.logout()?;
+            return Err(Error::new(ErrorKind::NotFound, "Attachment not found"));
+        }
+    };
+    if octets > ATTACHMENT_PREVIEW_MAX_OCTETS {
+        imap_connection.logout()?;
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "The attachment is too big for a preview",
+        ));
+    }
+
+    let fetch_response = imap_connection.command(&format!(
+        "UID FETCH {} (UID BODY.PEEK[{}])",
+        request.uid, part_id
+    ))?;
+    imap_connection.logout()?;
+    let data = fetch_response
+        .untagged
+        .iter()
+        .find_map(|line| match imap_proto::parse_response(line) {
+            Ok((_, Response::Fetch(_, attributes))) => {
+                parse_fetch_text(&attributes).map(|data| decode_part_bytes(data, &encoding))
+            }
+            _ => None,
+        })
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "Attachment not found"))?;
+    if let Some(refusal) =
+        scan_attachment(&scanners, &cache, &account, target, data.clone()).await?
+    {
+        return Ok(refusal);
+    }
+
+    // Decoding images and parsing PDFs takes a while, it must not block the worker
+    let (section, content_type, preview) = match kind {
+        PreviewKind::Thumbnail(format) => {
+            let thumbnail = web::block(move || make_thumbnail(&data, format, size))
+                .await
+                .map_err(Error::other)??;
+            (
+                thumbnail_section,
+                thumbnail_content_type(&thumbnail),
+                thumbnail,
+            )
+        }
+        PreviewKind::PdfText | PreviewKind::PlainText(_) => {
+            let text = match kind {
+                PreviewKind::PlainText(charset) => {
+                    // Unknown charsets are read as UTF-8, invalid bytes become U+FFFD
+                    let encoding = charset
+                        .and_then(|charset| Encoding::for_label(charset.as_bytes()))
+                        .unwrap_or(UTF_8);
+                    encoding.decode(&data).0.into_owned()
+                }
+                _ => web::block(move || extract_pdf_text(&data))
+                    .await
+                    .map_err(Error::other)??,
+            };
+            let (text, truncated) = shorten_preview_text(&text, ATTACHMENT_PREVIEW_TEXT_LENGTH);
+            let preview = serde_json::to_vec(&AttachmentTextPreviewOutDTO {
+                content_type,
+                text,
+                truncated,
+            })?;
+            (text_section, "application/json", preview)
+        }
+    };
+
+    // Parts of other mailboxes would never be cleared after a UIDVALIDITY change
+    if cache.is_watched(&account, &request.mailbox_name) {
+        cache.store_part(
+            &account,
+            &request.mailbox_name,
+            request.uid,
+            &section,
+            &preview,
+        )?;
+    }
+    preview_response(content_type, preview)
+}
+
+/// What preview can be made of the part, with its content type, size and transfer encoding.
+/// Parts sent as `application/octet-stream` are recognised by the extension of their name.
+fn describe_preview_part(part: &BodyStructure) -> Option<(PreviewKind, String, u32, EncodingType)> {
+    let (common, other) = match part {
+        BodyStructure::Basic { common, other, .. } | BodyStructure::Text { common, other, .. } => {
+            (common, other)
+        }
+        _ => return None,
+    };
+    let mut content_type = format!("{}/{}", common.ty.ty, common.ty.subtype).to_lowercase();
+    if content_type == "application/octet-stream" {
+        if let Some(guessed) = attachment_file_name(part)
+            .and_then(|file_name| mime_guess::from_path(file_name).first())
+        {
+            content_type = guessed.essence_str().to_string();
+        }
+    }
+
+    let kind = match (thumbnail_format(&content_type), content_type.as_str()) {
+        (Some(format), _) => PreviewKind::Thumbnail(format),
+        (None, "application/pdf") => PreviewKind::PdfText,
+        (None, "text/plain") => PreviewKind::PlainText(
+            common
+                .ty
+                .params
+                .iter()
+                .flatten()
+                .find(|(name, _)| name.eq_ignore_ascii_case("charset"))
+                .map(|(_, charset)| charset.to_string()),
+        ),
+        _ => return None,
+    };
+    Some((kind, content_type, other.octets, decide_encoding(other)))
+}
+
+/// Rounds the requested size up to the next generated one, so the cache holds a few sizes only.
+fn thumbnail_size(requested: Option<u32>) -> u32 {
+    let requested = requested.unwrap_or(ATTACHMENT_PREVIEW_DEFAULT_SIZE);
+    ATTACHMENT_PREVIEW_SIZES
+        .iter()
+        .copied()
+        .find(|size| *size >= requested)
+        .unwrap_or(ATTACHMENT_PREVIEW_SIZES[ATTACHMENT_PREVIEW_SIZES.len() - 1])
+}
+
+fn preview_response(content_type: &str, preview: Vec<u8>) -> Result<HttpResponse, Error> {
+    // A message never changes under its UID
+    let cache_control = CacheControl(vec![
+        CacheDirective::Private,
+        CacheDirective::MaxAge(ATTACHMENT_PREVIEW_MAX_AGE_SECS),
+    ]);
+    Ok(HttpResponse::Ok()
+        .insert_header((
+            header::CACHE_CONTROL,
+            cache_control.try_into_value().map_err(Error::other)?,
+        ))
+        .content_type(content_type)
+        .body(preview))
+}
+
+pub fn email_attachment_preview_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/attachments/preview").route(web::get().to(get_attachment_preview)));
+}
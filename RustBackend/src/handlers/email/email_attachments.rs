@@ -0,0 +1,541 @@
+use std::{collections::HashSet, io::Error};
+
+use actix_session::Session;
+use actix_web::{
+    http::header::{ContentDisposition, DispositionParam, DispositionType},
+    web::{self, Bytes},
+    HttpResponse,
+};
+use chrono::{NaiveDate, NaiveDateTime, Utc};
+use imap_proto::{AttributeValue, BodyStructure, Envelope, Response};
+use tokio::sync::mpsc::Sender;
+use utf7_imap::encode_utf7_imap;
+
+use crate::{
+    constants::{
+        ATTACHMENT_NAME_MAX_LENGTH, ATTACHMENT_SEARCH_CHUNK_SIZE, ATTACHMENT_SEARCH_DEFAULT_LIMIT,
+        ATTACHMENT_SEARCH_MAX_LIMIT, ATTACHMENT_SEARCH_MAX_MAILBOXES,
+    },
+    scanning::AttachmentScanners,
+    storage::{message_cache::MessageCache, scan_log::ScanDirection},
+    utils::{
+        utils_imap::{
+            format_uid_set, parse_internal_date, quote_imap_string, CommandPart, ImapConnection,
+        },
+        utils_session::check_is_valid_session,
+        utils_transports::create_imap_connection,
+        utils_zip::ZipWriter,
+    },
+};
+
+use super::{
+    email_archive::{search_uids, stream_from_thread},
+    email_imap::{
+        attachment_file_name, body_parts, decide_encoding, decode_part_bytes, parse_fetch_text,
+        parse_fetch_uid, parse_sender_and_subject,
+    },
+    email_scanning::{scan_attachment_blocking, ScanTarget},
+    email_source::{connect_and_examine, decode_header_value, fetch_command, validate_section},
+    helper_models::EncodingType,
+    models::{
+        AttachmentRefusedOutDTO, AttachmentSearchInDTO, AttachmentSearchOutDTO,
+        AttachmentSearchResultOutDTO, AttachmentsZipInDTO,
+    },
+};
+
+struct ZipAttachment {
+    section: String,
+    /// Name inside the archive
+    file_name: String,
+    /// Name and content type the scanners see
+    original_file_name: String,
+    content_type: String,
+    encoding: EncodingType,
+}
+
+/// Lists attachments of one or more mailboxes. Only envelopes and body structures are fetched,
+/// the content of an attachment is downloaded when it is requested by its part id. Emails are
+/// looked at newest first, a mailbox is left once it found `limit` attachments.
+async fn search_attachments(
+    session: Session,
+    request: web::Query<AttachmentSearchInDTO>,
+) -> Result<AttachmentSearchOutDTO, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let limit = request
+        .limit
+        .unwrap_or(ATTACHMENT_SEARCH_DEFAULT_LIMIT)
+        .clamp(1, ATTACHMENT_SEARCH_MAX_LIMIT);
+    let mailbox_names: Vec<String> = match &request.mailbox_names {
+        Some(mailbox_names) => mailbox_names
+            .split(',')
+            .map(|mailbox_name| mailbox_name.trim().to_string())
+            .filter(|mailbox_name| !mailbox_name.is_empty())
+            .collect(),
+        None => vec!["INBOX".to_string()],
+    };
+    if mailbox_names.len() > ATTACHMENT_SEARCH_MAX_MAILBOXES {
+        return Err(Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "At most {} mailboxes can be searched at once",
+                ATTACHMENT_SEARCH_MAX_MAILBOXES
+            ),
+        ));
+    }
+    let content_type = request
+        .content_type
+        .as_ref()
+        .map(|content_type| content_type.trim().to_lowercase());
+    let file_name_pattern = request
+        .file_name
+        .as_ref()
+        .map(|file_name| file_name.trim().to_lowercase());
+
+    let mut imap_connection = create_imap_connection(
+        &credentials.email,
+        &credentials.password,
+        &credentials.get_imap_string(),
+    )
+    .await?;
+
+    let criteria = search_criteria(request.since, request.before, &request.from);
+    let mut attachments = vec![];
+    let mut truncated = false;
+    for mailbox_name in mailbox_names.iter() {
+        // Read-only, listing must not mark anything as seen
+        imap_connection.command(&format!(
+            "EXAMINE {}",
+            quote_imap_string(&encode_utf7_imap(mailbox_name.clone()))
+        ))?;
+        let uids = search_uids(&mut imap_connection, &criteria)?;
+
+        // Older emails of the mailbox could not make it past the newer ones into the result
+        let mailbox_start = attachments.len();
+        for chunk in uids.rchunks(ATTACHMENT_SEARCH_CHUNK_SIZE) {
+            if attachments.len() - mailbox_start >= limit {
+                truncated = true;
+                break;
+            }
+            let fetch_response = imap_connection.command(&format!(
+                "UID FETCH {} (UID INTERNALDATE ENVELOPE BODYSTRUCTURE)",
+                format_uid_set(chunk)
+            ))?;
+            for line in fetch_response.untagged.iter() {
+                let attributes = match imap_proto::parse_response(line) {
+                    Ok((_, Response::Fetch(_, attributes))) => attributes,
+                    _ => continue,
+                };
+                let uid = match parse_fetch_uid(&attributes) {
+                    Some(uid) => uid,
+                    None => continue,
+                };
+
+                let mut send_date = NaiveDateTime::default();
+                let mut envelope: Option<&Envelope> = None;
+                let mut structure: Option<&BodyStructure> = None;
+                for attribute in attributes.iter() {
+                    match attribute {
+                        AttributeValue::InternalDate(date) => {
+                            if let Some(date) = parse_internal_date(date) {
+                                send_date = date.naive_local();
+                            }
+                        }
+                        AttributeValue::Envelope(value) => envelope = Some(value),
+                        AttributeValue::BodyStructure(value) => structure = Some(value),
+                        _ => {}
+                    }
+                }
+                let structure = match structure {
+                    Some(structure) => structure,
+                    None => continue,
+                };
+                let (from_address, subject) = envelope
+                    .map(|envelope| parse_sender_and_subject(envelope))
+                    .unwrap_or_default();
+
+                for (section, part) in body_parts(structure).into_iter() {
+                    let (file_name, common, other) = match (attachment_file_name(part), part) {
+                        (
+                            Some(file_name),
+                            BodyStructure::Basic { common, other, .. }
+                            | BodyStructure::Text { common, other, .. }
+                            | BodyStructure::Message { common, other, .. },
+                        ) => (decode_header_value(file_name.as_bytes()), common, other),
+                        _ => continue,
+                    };
+                    let part_content_type =
+                        format!("{}/{}", common.ty.ty, common.ty.subtype).to_lowercase();
+                    if let Some(content_type) = &content_type {
+                        if !matches_content_type(&part_content_type, content_type) {
+                            continue;
+                        }
+                    }
+                    if let Some(pattern) = &file_name_pattern {
+                        if !matches_file_name(&file_name.to_lowercase(), pattern) {
+                            continue;
+                        }
+                    }
+
+                    attachments.push(AttachmentSearchResultOutDTO {
+                        mailbox_name: mailbox_name.clone(),
+                        uid,
+                        part_id: section,
+                        file_name,
+                        size_octets: other.octets,
+                        content_type: part_content_type,
+                        from_address: from_address.clone(),
+                        subject: subject.clone(),
+                        send_date,
+                    });
+                }
+            }
+        }
+    }
+    imap_connection.logout()?;
+
+    // Newest first, parts of one email stay in their order
+    attachments.sort_by_key(|attachment| std::cmp::Reverse(attachment.send_date));
+    truncated |= attachments.len() > limit;
+    attachments.truncate(limit);
+    Ok(AttachmentSearchOutDTO {
+        attachments,
+        truncated,
+    })
+}
+
+/// `UID SEARCH` criteria for the date range and sender, `ALL` without any filter. The sender
+/// is sent as a literal, so it may contain anything a quoted string can not.
+fn search_criteria<'a>(
+    since: Option<NaiveDate>,
+    before: Option<NaiveDate>,
+    from: &'a Option<String>,
+) -> Vec<CommandPart<'a>> {
+    let mut criteria = vec![];
+    if let Some(since) = since {
+        criteria.push(format!("SINCE {}", since.format("%d-%b-%Y")));
+    }
+    if let Some(before) = before {
+        criteria.push(format!("BEFORE {}", before.format("%d-%b-%Y")));
+    }
+    let from = from
+        .as_deref()
+        .map(str::trim)
+        .filter(|from| !from.is_empty());
+
+    match from {
+        Some(from) => {
+            criteria.insert(0, "CHARSET UTF-8".to_string());
+            criteria.push("FROM ".to_string());
+            vec![
+                CommandPart::Text(criteria.join(" ")),
+                CommandPart::Literal(from.as_bytes()),
+            ]
+        }
+        None if criteria.is_empty() => vec![CommandPart::Text("ALL".to_string())],
+        None => vec![CommandPart::Text(criteria.join(" "))],
+    }
+}
+
+/// `image/*` matches every image, anything else has to be the whole content type.
+fn matches_content_type(content_type: &str, filter: &str) -> bool {
+    match filter.strip_suffix("/*") {
+        Some(ty) => content_type
+            .split_once('/')
+            .is_some_and(|(part_ty, _)| part_ty == ty),
+        None => content_type == filter,
+    }
+}
+
+/// Patterns with `*` or `?` have to match the whole name, others are searched for inside it.
+fn matches_file_name(file_name: &str, pattern: &str) -> bool {
+    if !pattern.contains(['*', '?']) {
+        return file_name.contains(pattern);
+    }
+
+    let name: Vec<char> = file_name.chars().collect();
+    let pattern: Vec<char> = pattern.chars().collect();
+    let (mut name_index, mut pattern_index) = (0, 0);
+    // Position of the last `*` and the name position it was tried at, to backtrack to
+    let mut star: Option<(usize, usize)> = None;
+    while name_index < name.len() {
+        match pattern.get(pattern_index) {
+            Some('*') => {
+                star = Some((pattern_index, name_index));
+                pattern_index += 1;
+            }
+            Some(char) if *char == '?' || *char == name[name_index] => {
+                name_index += 1;
+                pattern_index += 1;
+            }
+            _ => match star {
+                Some((star_index, star_name_index)) => {
+                    pattern_index = star_index + 1;
+                    name_index = star_name_index + 1;
+                    star = Some((star_index, name_index));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[pattern_index..].iter().all(|char| *char == '*')
+}
+
+/// Streams a ZIP with all attachments of an email, or only the given part ids. Every part is
+/// downloaded and compressed on its own, so only one attachment is held in memory at a time.
+/// Attachments the scanners refuse are replaced by a text file with the reason, the response
+/// has already started when they are found.
+async fn download_attachments_zip(
+    session: Session,
+    scanners: web::Data<AttachmentScanners>,
+    cache: web::Data<MessageCache>,
+    request: web::Query<AttachmentsZipInDTO>,
+) -> Result<HttpResponse, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let fetch_command = fetch_command(request.uid, request.sequence_number)?;
+    let part_ids = match &request.part_ids {
+        Some(part_ids) => Some(
+            part_ids
+                .split(',')
+                .map(|part_id| validate_section(part_id.trim()).map(str::to_string))
+                .collect::<Result<HashSet<String>, Error>>()?,
+        ),
+        None => None,
+    };
+
+    let mut imap_connection = connect_and_examine(&session, &request.mailbox_name).await?;
+    let structure_response = imap_connection.command(&format!(
+        "{} (UID INTERNALDATE BODYSTRUCTURE)",
+        fetch_command
+    ))?;
+
+    let mut uid = None;
+    let mut modified = Utc::now().naive_utc();
+    let mut attachments = vec![];
+    let mut used_names = HashSet::new();
+    for line in structure_response.untagged.iter() {
+        if let Ok((_, Response::Fetch(_, attributes))) = imap_proto::parse_response(line) {
+            // Skips flag updates of other emails
+            uid = match parse_fetch_uid(&attributes) {
+                Some(uid) => Some(uid),
+                None => continue,
+            };
+            for attribute in attributes.iter() {
+                match attribute {
+                    AttributeValue::InternalDate(date) => {
+                        if let Some(date) = parse_internal_date(date) {
+                            modified = date.naive_local();
+                        }
+                    }
+                    AttributeValue::BodyStructure(structure) => {
+                        (attachments, used_names) = list_attachments(structure, &part_ids)
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    let uid = match uid {
+        Some(uid) => uid,
+        None => {
+            imap_connection.logout()?;
+            return Err(Error::new(std::io::ErrorKind::NotFound, "Email not found"));
+        }
+    };
+    if attachments.is_empty() {
+        imap_connection.logout()?;
+        return Err(Error::new(
+            std::io::ErrorKind::NotFound,
+            "The email has no such attachments",
+        ));
+    }
+
+    let account = credentials.email.clone();
+    let mailbox_name = request.mailbox_name.clone();
+    let stream = stream_from_thread(move |sender| {
+        let scan = |attachment: &ZipAttachment, data: &[u8]| {
+            let target = ScanTarget {
+                direction: ScanDirection::Download,
+                mailbox_name: Some(mailbox_name.clone()),
+                uid: Some(uid),
+                part_id: Some(attachment.section.clone()),
+                file_name: attachment.original_file_name.clone(),
+                content_type: attachment.content_type.clone(),
+            };
+            scan_attachment_blocking(&scanners, &cache, &account, target, data)
+        };
+        let result = write_attachments(
+            &mut imap_connection,
+            uid,
+            &attachments,
+            &mut used_names,
+            modified,
+            &sender,
+            scan,
+        );
+        if let Err(err) = result {
+            println!("Zipping attachments of {} failed: {}", uid, err);
+            // Aborts the download, so the client does not keep a truncated archive
+            let _ = sender.blocking_send(Err(err));
+        }
+        let _ = imap_connection.logout();
+    });
+
+    let content_disposition = ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(format!(
+            "attachments-{}.zip",
+            uid
+        ))],
+    };
+    Ok(HttpResponse::Ok()
+        .insert_header(content_disposition)
+        .content_type("application/zip")
+        .streaming(stream))
+}
+
+/// Every part with a file name, in the order of the email, and the names taken in the archive.
+/// Names are made safe for file systems and unique inside the archive.
+fn list_attachments(
+    structure: &BodyStructure,
+    part_ids: &Option<HashSet<String>>,
+) -> (Vec<ZipAttachment>, HashSet<String>) {
+    let mut used_names = HashSet::new();
+    let mut attachments = vec![];
+    for (section, part) in body_parts(structure).into_iter() {
+        if let Some(part_ids) = part_ids {
+            if !part_ids.contains(&section) {
+                continue;
+            }
+        }
+        let (file_name, common, other) = match (attachment_file_name(part), part) {
+            (
+                Some(file_name),
+                BodyStructure::Basic { common, other, .. }
+                | BodyStructure::Text { common, other, .. }
+                | BodyStructure::Message { common, other, .. },
+            ) => (file_name, common, other),
+            _ => continue,
+        };
+
+        attachments.push(ZipAttachment {
+            file_name: unique_file_name(&sanitize_file_name(&file_name), &mut used_names),
+            original_file_name: decode_header_value(file_name.as_bytes()),
+            content_type: format!("{}/{}", common.ty.ty, common.ty.subtype).to_lowercase(),
+            section,
+            encoding: decide_encoding(other),
+        });
+    }
+    (attachments, used_names)
+}
+
+/// Refused attachments are replaced by a text file, named like the other entries so it can not
+/// clash with any of them.
+fn write_attachments<S>(
+    imap_connection: &mut ImapConnection,
+    uid: u32,
+    attachments: &[ZipAttachment],
+    used_names: &mut HashSet<String>,
+    modified: NaiveDateTime,
+    sender: &Sender<Result<Bytes, Error>>,
+    scan: S,
+) -> Result<(), Error>
+where
+    S: Fn(&ZipAttachment, &[u8]) -> Result<Option<AttachmentRefusedOutDTO>, Error>,
+{
+    let mut zip_writer = ZipWriter::new();
+    for attachment in attachments.iter() {
+        let fetch_response = imap_connection.command(&format!(
+            "UID FETCH {} (UID BODY.PEEK[{}])",
+            uid, attachment.section
+        ))?;
+        let data = fetch_response
+            .untagged
+            .iter()
+            .find_map(|line| match imap_proto::parse_response(line) {
+                Ok((_, Response::Fetch(_, attributes))) => {
+                    parse_fetch_text(&attributes).map(|data| data.to_vec())
+                }
+                _ => None,
+            })
+            // Leaving it out or adding an empty file would pass for a complete archive
+            .ok_or_else(|| {
+                Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("Attachment {} not found", attachment.section),
+                )
+            })?;
+
+        let content = decode_part_bytes(&data, &attachment.encoding);
+        let entry = match scan(attachment, &content)? {
+            Some(refusal) => zip_writer.add_file(
+                &unique_file_name(&format!("{}.refused.txt", attachment.file_name), used_names),
+                format!(
+                    "{} was left out, refused by {}: {}\r\n",
+                    refusal.file_name, refusal.scanner, refusal.reason
+                )
+                .as_bytes(),
+                modified,
+            )?,
+            None => zip_writer.add_file(&attachment.file_name, &content, modified)?,
+        };
+        // The client went away, there is nobody to send the rest to
+        if sender.blocking_send(Ok(Bytes::from(entry))).is_err() {
+            return Ok(());
+        }
+    }
+
+    let _ = sender.blocking_send(Ok(Bytes::from(zip_writer.finish())));
+    Ok(())
+}
+
+/// Decodes encoded words and drops directories and characters Windows or unzip tools reject.
+fn sanitize_file_name(file_name: &str) -> String {
+    let decoded = decode_header_value(file_name.as_bytes());
+    let base_name = decoded
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let name: String = base_name
+        .chars()
+        .filter(|char| {
+            !char.is_control() && !matches!(char, '<' | '>' | ':' | '"' | '|' | '?' | '*')
+        })
+        .collect();
+    let name = name.trim().trim_matches('.').trim();
+
+    // Long names are cut before the extension, so the file can still be opened
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() && extension.len() <= 10 => {
+            (stem, format!(".{}", extension))
+        }
+        _ => (name, String::new()),
+    };
+    let stem: String = stem.chars().take(ATTACHMENT_NAME_MAX_LENGTH).collect();
+    if stem.is_empty() {
+        format!("attachment{}", extension)
+    } else {
+        format!("{}{}", stem.trim_end(), extension)
+    }
+}
+
+/// Appends ` (2)`, ` (3)` and so on to names already in the archive, ignoring case.
+fn unique_file_name(file_name: &str, used_names: &mut HashSet<String>) -> String {
+    let (stem, extension) = match file_name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (file_name, String::new()),
+    };
+
+    let mut candidate = file_name.to_string();
+    let mut counter = 1;
+    while !used_names.insert(candidate.to_lowercase()) {
+        counter += 1;
+        candidate = format!("{} ({}){}", stem, counter, extension);
+    }
+    candidate
+}
+
+pub fn email_attachments_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/attachments").route(web::get().to(search_attachments)));
+    cfg.service(web::resource("/attachments/zip").route(web::get().to(download_attachments_zip)));
+}
@@ -0,0 +1,548 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    io::{Error, ErrorKind},
+    sync::Mutex,
+    time::Duration,
+};
+
+use actix_session::Session;
+use actix_web::{
+    web::{self, Bytes},
+    HttpMessage, HttpRequest, HttpResponse,
+};
+use chrono::Utc;
+use futures_util::{stream, StreamExt};
+use lettre::{message::Mailbox, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use tokio::time::{interval, Interval, MissedTickBehavior};
+
+use crate::{
+    constants::{
+        BULK_SEND_DEFAULT_CONCURRENCY, BULK_SEND_DEFAULT_RATE_PER_MINUTE, BULK_SEND_MAX_BYTES,
+        BULK_SEND_MAX_RECIPIENTS,
+    },
+    handlers::auth::models::SignInMessage,
+    storage::{
+        bulk_sends::{BulkRecipient, BulkRecipientStatus, BulkSend},
+        message_cache::MessageCache,
+        templates::{MessageTemplate, TemplateAttachmentFile},
+    },
+    utils::{
+        utils_csv::parse_csv, utils_session::check_is_valid_session,
+        utils_transports::create_smtp_transport,
+    },
+};
+
+use super::{
+    email_contacts::resolve_recipients,
+    email_smtp::{attach_template_files, message_body},
+    email_templates::{json_variables, render_message, template_not_found},
+    models::{
+        BulkRecipientInDTO, BulkRecipientOutDTO, BulkSendIdInDTO, BulkSendInDTO,
+        BulkSendListOutDTO, BulkSendOutDTO, BulkSendRecipientListOutDTO,
+    },
+};
+
+// Header names of the CSV column holding the recipient
+const ADDRESS_COLUMNS: [&str; 2] = ["to_address", "email"];
+
+/// Upper bounds of bulk sends, with the sends in progress.
+pub struct BulkSender {
+    rate_per_minute: u32,
+    concurrency: usize,
+    running: Mutex<HashSet<i64>>,
+}
+
+impl BulkSender {
+    /// `BULK_SEND_RATE_PER_MINUTE` and `BULK_SEND_CONCURRENCY` replace the defaults. Invalid
+    /// values are reported and ignored.
+    pub fn from_env() -> BulkSender {
+        fn limit<T: std::str::FromStr + PartialOrd + From<u8>>(name: &str, default: T) -> T {
+            match env::var(name) {
+                Ok(value) if !value.trim().is_empty() => match value.trim().parse::<T>() {
+                    Ok(limit) if limit >= T::from(1) => limit,
+                    _ => {
+                        println!("Ignoring {}: not a positive number", name);
+                        default
+                    }
+                },
+                _ => default,
+            }
+        }
+        BulkSender {
+            rate_per_minute: limit(
+                "BULK_SEND_RATE_PER_MINUTE",
+                BULK_SEND_DEFAULT_RATE_PER_MINUTE,
+            ),
+            concurrency: limit("BULK_SEND_CONCURRENCY", BULK_SEND_DEFAULT_CONCURRENCY),
+            running: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Claims the send, false when it is running already.
+    fn start(&self, id: i64) -> bool {
+        self.running.lock().unwrap().insert(id)
+    }
+
+    fn finish(&self, id: i64) {
+        self.running.lock().unwrap().remove(&id);
+    }
+
+    fn is_running(&self, id: i64) -> bool {
+        self.running.lock().unwrap().contains(&id)
+    }
+}
+
+async fn list_bulk_sends(
+    session: Session,
+    cache: web::Data<MessageCache>,
+    sender: web::Data<BulkSender>,
+) -> Result<BulkSendListOutDTO, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    Ok(BulkSendListOutDTO {
+        bulk_sends: cache
+            .bulk_sends(&credentials.email)?
+            .into_iter()
+            .map(|(id, bulk_send)| {
+                bulk_send_out(&cache, &sender, &credentials.email, id, bulk_send)
+            })
+            .collect::<Result<Vec<BulkSendOutDTO>, Error>>()?,
+    })
+}
+
+/// Sends the template to every recipient of the list in the body, a CSV file or, with a JSON
+/// content type, an array of `to_address` and `variables` objects. A CSV file needs a header
+/// with a `to_address` or `email` column, every column is a variable of its row. Each row goes
+/// to one address or contact, rows naming a group or several addresses fail. The emails go out
+/// in the background, the response reports the send as started.
+async fn create_bulk_send(
+    http_request: HttpRequest,
+    session: Session,
+    cache: web::Data<MessageCache>,
+    sender: web::Data<BulkSender>,
+    query: web::Query<BulkSendInDTO>,
+    body: Bytes,
+) -> Result<BulkSendOutDTO, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let template = cache
+        .template(&credentials.email, query.template_id)?
+        .ok_or_else(template_not_found)?;
+
+    let recipients = if http_request.content_type().contains("json") {
+        parse_json_recipients(&body)?
+    } else {
+        parse_csv_recipients(&String::from_utf8_lossy(&body))?
+    };
+    if recipients.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "No recipient given"));
+    }
+    if recipients.len() > BULK_SEND_MAX_RECIPIENTS {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "At most {} recipients can be sent to at once",
+                BULK_SEND_MAX_RECIPIENTS
+            ),
+        ));
+    }
+
+    let bulk_send = BulkSend {
+        template_id: query.template_id,
+        template_name: template.name,
+        rate_per_minute: query
+            .rate_per_minute
+            .unwrap_or(sender.rate_per_minute)
+            .clamp(1, sender.rate_per_minute),
+        concurrency: query
+            .concurrency
+            .unwrap_or(sender.concurrency)
+            .clamp(1, sender.concurrency),
+        created_at: Utc::now().naive_utc(),
+    };
+    let id = cache.insert_bulk_send(&credentials.email, &bulk_send, &recipients)?;
+    sender.start(id);
+    tokio::spawn(run_bulk_send(
+        cache.clone(),
+        sender.clone(),
+        credentials.clone(),
+        id,
+        bulk_send.clone(),
+    ));
+    bulk_send_out(&cache, &sender, &credentials.email, id, bulk_send)
+}
+
+/// Status of every recipient of the send.
+async fn list_bulk_recipients(
+    session: Session,
+    cache: web::Data<MessageCache>,
+    sender: web::Data<BulkSender>,
+    query: web::Query<BulkSendIdInDTO>,
+) -> Result<BulkSendRecipientListOutDTO, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let bulk_send = cache
+        .bulk_send(&credentials.email, query.id)?
+        .ok_or_else(bulk_send_not_found)?;
+    let recipients = cache
+        .bulk_send_recipients(&credentials.email, query.id, None)?
+        .into_iter()
+        .map(|(id, status, recipient)| BulkRecipientOutDTO {
+            id,
+            to_address: recipient.to_address,
+            variables: recipient.variables,
+            status,
+            error: recipient.error,
+            attempts: recipient.attempts,
+            sent_at: recipient.sent_at,
+        })
+        .collect();
+    Ok(BulkSendRecipientListOutDTO {
+        bulk_send: bulk_send_out(&cache, &sender, &credentials.email, query.id, bulk_send)?,
+        recipients,
+    })
+}
+
+/// Sends again to the failed recipients, and to those left pending when the server stopped
+/// during the send. The template is read again, so it may have been fixed meanwhile.
+async fn retry_bulk_send(
+    session: Session,
+    cache: web::Data<MessageCache>,
+    sender: web::Data<BulkSender>,
+    query: web::Query<BulkSendIdInDTO>,
+) -> Result<BulkSendOutDTO, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let bulk_send = cache
+        .bulk_send(&credentials.email, query.id)?
+        .ok_or_else(bulk_send_not_found)?;
+    if !sender.start(query.id) {
+        return Err(bulk_send_running());
+    }
+    let pending = cache
+        .reset_failed_bulk_recipients(&credentials.email, query.id)
+        .and_then(|_| cache.bulk_send_counts(&credentials.email, query.id))
+        .map(|counts| counts.pending);
+    match pending {
+        Ok(pending) if pending > 0 => {}
+        Ok(_) => {
+            sender.finish(query.id);
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Every recipient got the email already",
+            ));
+        }
+        Err(err) => {
+            sender.finish(query.id);
+            return Err(err);
+        }
+    }
+
+    tokio::spawn(run_bulk_send(
+        cache.clone(),
+        sender.clone(),
+        credentials.clone(),
+        query.id,
+        bulk_send.clone(),
+    ));
+    bulk_send_out(&cache, &sender, &credentials.email, query.id, bulk_send)
+}
+
+async fn delete_bulk_send(
+    session: Session,
+    cache: web::Data<MessageCache>,
+    sender: web::Data<BulkSender>,
+    query: web::Query<BulkSendIdInDTO>,
+) -> Result<HttpResponse, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    // Claimed while deleting, so no retry starts meanwhile
+    if !sender.start(query.id) {
+        return Err(bulk_send_running());
+    }
+    let deleted = cache.delete_bulk_send(&credentials.email, query.id);
+    sender.finish(query.id);
+    if !deleted? {
+        return Err(bulk_send_not_found());
+    }
+    Ok(HttpResponse::Ok().body("Ok"))
+}
+
+async fn run_bulk_send(
+    cache: web::Data<MessageCache>,
+    sender: web::Data<BulkSender>,
+    credentials: SignInMessage,
+    id: i64,
+    bulk_send: BulkSend,
+) {
+    if let Err(err) = send_pending(&cache, &credentials, id, &bulk_send).await {
+        println!("Bulk send {} stopped: {}", id, err);
+        // Reported on every recipient not tried, a retry sends to them
+        if let Err(err) = fail_pending(&cache, &credentials.email, id, &err.to_string()) {
+            println!("Couldnt update bulk send {}: {:?}", id, err);
+        }
+    }
+    sender.finish(id);
+}
+
+/// Sends to the pending recipients, at most `concurrency` at once and no faster than
+/// `rate_per_minute`. A recipient whose email fails is marked failed, the others go on.
+async fn send_pending(
+    cache: &MessageCache,
+    credentials: &SignInMessage,
+    id: i64,
+    bulk_send: &BulkSend,
+) -> Result<(), Error> {
+    let account = credentials.email.as_str();
+    let template = cache
+        .template(account, bulk_send.template_id)?
+        .ok_or_else(template_not_found)?;
+    let attachments = cache.template_attachment_files(account, bulk_send.template_id)?;
+    let from: Mailbox = account
+        .parse()
+        .map_err(|err| Error::other(format!("Invalid sender address {:?}", err)))?;
+    let transport = create_smtp_transport(
+        &credentials.email,
+        &credentials.password,
+        &credentials.get_smtp_string(),
+    )
+    .await?;
+
+    let recipients = cache.bulk_send_recipients(account, id, Some(BulkRecipientStatus::Pending))?;
+    let rate_limit = tokio::sync::Mutex::new(rate_limit(bulk_send.rate_per_minute));
+    let email = BulkEmail {
+        template: &template,
+        attachments: &attachments,
+        from: &from,
+        transport: &transport,
+        rate_limit: &rate_limit,
+    };
+    stream::iter(recipients)
+        .for_each_concurrent(
+            bulk_send.concurrency.max(1),
+            |(recipient_id, _, mut recipient)| {
+                let email = &email;
+                async move {
+                    recipient.attempts += 1;
+                    // Recipients of a mail merge are not added to the address book
+                    let status = match email.send(cache, account, &recipient).await {
+                        Ok(()) => {
+                            recipient.error = None;
+                            recipient.sent_at = Some(Utc::now().naive_utc());
+                            BulkRecipientStatus::Sent
+                        }
+                        Err(err) => {
+                            recipient.error = Some(err.to_string());
+                            BulkRecipientStatus::Failed
+                        }
+                    };
+                    if let Err(err) =
+                        cache.update_bulk_recipient(account, recipient_id, status, &recipient)
+                    {
+                        println!("Couldnt update bulk recipient {}: {:?}", recipient_id, err);
+                    }
+                }
+            },
+        )
+        .await;
+    Ok(())
+}
+
+/// What every email of one bulk send shares.
+struct BulkEmail<'a> {
+    template: &'a MessageTemplate,
+    attachments: &'a [TemplateAttachmentFile],
+    from: &'a Mailbox,
+    transport: &'a AsyncSmtpTransport<Tokio1Executor>,
+    rate_limit: &'a tokio::sync::Mutex<Interval>,
+}
+
+impl BulkEmail<'_> {
+    /// Writes and sends the email of one row. The row's variables are meant for one person,
+    /// so a group or a list of addresses fails the row. Rows failing to render do not use up
+    /// the rate.
+    async fn send(
+        &self,
+        cache: &MessageCache,
+        account: &str,
+        recipient: &BulkRecipient,
+    ) -> Result<(), Error> {
+        let mut mailboxes = resolve_recipients(cache, account, &recipient.to_address)?;
+        let mailbox = match mailboxes.pop() {
+            Some(mailbox) if mailboxes.is_empty() => mailbox,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "A row has to name exactly one recipient",
+                ))
+            }
+        };
+        let rendered = render_message(self.template, &recipient.variables)?;
+        let body = attach_template_files(
+            message_body(rendered.text_body, rendered.html_body),
+            self.attachments.to_vec(),
+        )?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(mailbox)
+            .subject(rendered.subject)
+            .multipart(body)
+            .map_err(|err| Error::other(format!("Couldnt build message {:?}", err)))?;
+
+        self.rate_limit.lock().await.tick().await;
+        self.transport
+            .send(message)
+            .await
+            .map_err(|err| Error::other(format!("Sending failed: {}", err)))?;
+        Ok(())
+    }
+}
+
+/// Ticks once per email, the first one right away.
+fn rate_limit(rate_per_minute: u32) -> Interval {
+    let mut rate_limit = interval(Duration::from_millis(
+        60_000 / u64::from(rate_per_minute.max(1)),
+    ));
+    // A slow server must not be followed by a burst
+    rate_limit.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    rate_limit
+}
+
+fn fail_pending(cache: &MessageCache, account: &str, id: i64, reason: &str) -> Result<(), Error> {
+    for (recipient_id, _, mut recipient) in
+        cache.bulk_send_recipients(account, id, Some(BulkRecipientStatus::Pending))?
+    {
+        recipient.error = Some(reason.to_string());
+        cache.update_bulk_recipient(
+            account,
+            recipient_id,
+            BulkRecipientStatus::Failed,
+            &recipient,
+        )?;
+    }
+    Ok(())
+}
+
+fn parse_csv_recipients(text: &str) -> Result<Vec<BulkRecipient>, Error> {
+    let mut records = parse_csv(text)?.into_iter();
+    let header: Vec<String> = match records.next() {
+        Some(header) => header.iter().map(|name| name.trim().to_string()).collect(),
+        None => return Ok(vec![]),
+    };
+    let address_column = header
+        .iter()
+        .position(|name| {
+            ADDRESS_COLUMNS
+                .iter()
+                .any(|column| name.eq_ignore_ascii_case(column))
+        })
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "The recipient list needs a to_address or email column",
+            )
+        })?;
+
+    records
+        .enumerate()
+        .map(|(index, record)| {
+            let row = index + 1;
+            if record.len() > header.len() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Row {} has more fields than the header", row),
+                ));
+            }
+            // Missing trailing fields are missing variables
+            let variables = header
+                .iter()
+                .zip(record.iter())
+                .filter(|(name, _)| !name.is_empty())
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect();
+            bulk_recipient(
+                row,
+                record.get(address_column).map(String::as_str),
+                variables,
+            )
+        })
+        .collect()
+}
+
+fn parse_json_recipients(data: &[u8]) -> Result<Vec<BulkRecipient>, Error> {
+    let rows: Vec<BulkRecipientInDTO> = serde_json::from_slice(data).map_err(|err| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid recipient list: {}", err),
+        )
+    })?;
+    rows.into_iter()
+        .enumerate()
+        .map(|(index, row)| {
+            bulk_recipient(
+                index + 1,
+                Some(&row.to_address),
+                json_variables(row.variables)?,
+            )
+        })
+        .collect()
+}
+
+fn bulk_recipient(
+    row: usize,
+    to_address: Option<&str>,
+    variables: HashMap<String, String>,
+) -> Result<BulkRecipient, Error> {
+    let to_address = to_address.unwrap_or_default().trim();
+    if to_address.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Row {} has no recipient", row),
+        ));
+    }
+    Ok(BulkRecipient {
+        to_address: to_address.to_string(),
+        variables,
+        error: None,
+        attempts: 0,
+        sent_at: None,
+    })
+}
+
+fn bulk_send_out(
+    cache: &MessageCache,
+    sender: &BulkSender,
+    account: &str,
+    id: i64,
+    bulk_send: BulkSend,
+) -> Result<BulkSendOutDTO, Error> {
+    let counts = cache.bulk_send_counts(account, id)?;
+    Ok(BulkSendOutDTO {
+        id,
+        template_id: bulk_send.template_id,
+        template_name: bulk_send.template_name,
+        rate_per_minute: bulk_send.rate_per_minute,
+        concurrency: bulk_send.concurrency,
+        created_at: bulk_send.created_at,
+        running: sender.is_running(id),
+        pending: counts.pending,
+        sent: counts.sent,
+        failed: counts.failed,
+    })
+}
+
+fn bulk_send_not_found() -> Error {
+    Error::new(ErrorKind::NotFound, "Bulk send not found")
+}
+
+fn bulk_send_running() -> Error {
+    Error::new(ErrorKind::AlreadyExists, "The bulk send is running")
+}
+
+pub fn email_bulk_send_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/bulk-sends")
+            .app_data(web::PayloadConfig::new(BULK_SEND_MAX_BYTES))
+            .route(web::get().to(list_bulk_sends))
+            .route(web::post().to(create_bulk_send))
+            .route(web::delete().to(delete_bulk_send)),
+    )
+    .service(web::resource("/bulk-sends/recipients").route(web::get().to(list_bulk_recipients)))
+    .service(web::resource("/bulk-sends/retry").route(web::post().to(retry_bulk_send)));
+}
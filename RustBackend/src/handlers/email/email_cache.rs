@@ -0,0 +1,346 @@
+use std::{collections::HashSet, io::Error, time::Duration};
+
+use actix_web::web;
+use imap_proto::{AttributeValue, MailboxDatum, Response, ResponseCode};
+use rustyknife::rfc2047::encoded_word;
+use utf7_imap::encode_utf7_imap;
+
+use crate::{
+    constants::{
+        CACHE_ACCOUNT_IDLE_SECS, CACHE_SYNC_CHUNK_SIZE, CACHE_SYNC_INTERVAL_SECS,
+        PREVIEWS_PER_SYNC, SEARCH_MAX_INDEXED_SIZE, SEARCH_TEXTS_PER_SYNC, SEARCH_TEXT_CHUNK_SIZE,
+    },
+    handlers::auth::models::SignInMessage,
+    storage::{message_cache::MessageCache, search_index::SearchDocument},
+    utils::{
+        utils_imap::{format_uid_set, quote_imap_string, ImapConnection},
+        utils_transports::connect_imap,
+    },
+};
+
+use super::{
+    email_imap::{
+        parse_email_detail, parse_fetch_flags, parse_fetch_size, parse_fetch_text, parse_fetch_uid,
+    },
+    email_preview::{fetch_previews, CACHE_PREVIEW_SECTION},
+    models::EmailDetailOutDTO,
+};
+
+// Everything the list and the detail need apart from the body itself
+pub const CACHE_SUMMARY_ITEMS: &str = "(UID FLAGS ENVELOPE INTERNALDATE RFC822.SIZE BODYSTRUCTURE)";
+pub const CACHE_TEXT_SECTION: &str = "TEXT";
+
+/// Keeps all watched mailboxes fresh, runs for the whole lifetime of the server.
+pub async fn run_cache_sync(cache: web::Data<MessageCache>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(CACHE_SYNC_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+        for (credentials, mailbox_names) in
+            cache.accounts_to_sync(Duration::from_secs(CACHE_ACCOUNT_IDLE_SECS))
+        {
+            tokio::spawn(sync_account(cache.clone(), credentials, mailbox_names));
+        }
+    }
+}
+
+/// Adds the mailbox to the background sync and fills the cache right away when the mailbox
+/// was not watched yet.
+pub fn watch_cached_mailbox(
+    cache: &web::Data<MessageCache>,
+    credentials: &SignInMessage,
+    mailbox_name: &str,
+) {
+    if cache.register_mailbox(credentials, mailbox_name) {
+        tokio::spawn(sync_account(
+            cache.clone(),
+            credentials.clone(),
+            vec![mailbox_name.to_string()],
+        ));
+    }
+}
+
+async fn sync_account(
+    cache: web::Data<MessageCache>,
+    credentials: SignInMessage,
+    mailbox_names: Vec<String>,
+) {
+    // Connecting and SQLite both block, neither may hold up the workers
+    let sync = web::block(move || {
+        let mut imap_connection = match connect_imap(
+            &credentials.email,
+            &credentials.password,
+            &credentials.get_imap_string(),
+        ) {
+            Ok(connection) => connection,
+            Err(err) => {
+                println!("Cache sync of {} failed: {}", credentials.email, err);
+                return;
+            }
+        };
+
+        for mailbox_name in mailbox_names.iter() {
+            if let Err(err) = sync_cached_mailbox(
+                &cache,
+                &mut imap_connection,
+                &credentials.email,
+                mailbox_name,
+            ) {
+                println!("Cache sync of mailbox {} failed: {}", mailbox_name, err);
+            }
+        }
+        let _ = imap_connection.logout();
+    });
+    if let Err(err) = sync.await {
+        println!("Cache sync task failed: {}", err);
+    }
+}
+
+fn sync_cached_mailbox(
+    cache: &MessageCache,
+    imap_connection: &mut ImapConnection,
+    account: &str,
+    mailbox_name: &str,
+) -> Result<(), Error> {
+    let select_response = imap_connection.command(&format!(
+        "SELECT {}",
+        quote_imap_string(&encode_utf7_imap(mailbox_name.to_string()))
+    ))?;
+    let (exists, uid_validity) = parse_select_response(&select_response.untagged);
+    cache.validate_mailbox(account, mailbox_name, uid_validity)?;
+
+    let mut server_uids = vec![];
+    if exists > 0 {
+        for line in imap_connection.command("UID SEARCH ALL")?.untagged.iter() {
+            if let Ok((_, Response::IDs(uids))) = imap_proto::parse_response(line) {
+                server_uids.extend(uids);
+            }
+        }
+    }
+    let server_uid_set: HashSet<u32> = server_uids.iter().cloned().collect();
+
+    let cached_uids = cache.uids(account, mailbox_name)?;
+    let vanished_uids: Vec<u32> = cached_uids
+        .iter()
+        .filter(|uid| !server_uid_set.contains(*uid))
+        .cloned()
+        .collect();
+    cache.remove_messages(account, mailbox_name, &vanished_uids)?;
+
+    let cached_uid_set: HashSet<u32> = cached_uids.into_iter().collect();
+    let mut missing_uids: Vec<u32> = server_uids
+        .into_iter()
+        .filter(|uid| !cached_uid_set.contains(uid))
+        .collect();
+    missing_uids.sort_unstable();
+
+    // Newest messages first, those are the ones on the first pages
+    for chunk in missing_uids.rchunks(CACHE_SYNC_CHUNK_SIZE) {
+        let fetch_response = imap_connection.command(&format!(
+            "UID FETCH {} {}",
+            format_uid_set(chunk),
+            CACHE_SUMMARY_ITEMS
+        ))?;
+        for line in fetch_response.untagged.iter() {
+            store_summary(cache, account, mailbox_name, line)?;
+        }
+    }
+
+    if exists > 0 && !cached_uid_set.is_empty() {
+        let mut flags = vec![];
+        for line in imap_connection
+            .command("UID FETCH 1:* (UID FLAGS)")?
+            .untagged
+            .iter()
+        {
+            if let Ok((_, Response::Fetch(_, attributes))) = imap_proto::parse_response(line) {
+                if let Some(uid) = parse_fetch_uid(&attributes) {
+                    if cached_uid_set.contains(&uid) {
+                        flags.push((uid, parse_fetch_flags(&attributes)));
+                    }
+                }
+            }
+        }
+        cache.update_flags(account, mailbox_name, &flags)?;
+    }
+
+    // Texts for the full-text index, newest first and only a limited amount per sync
+    let mut text_uids = vec![];
+    for uid in cache
+        .uids_without_part(account, mailbox_name, CACHE_TEXT_SECTION)?
+        .into_iter()
+        .rev()
+    {
+        if text_uids.len() >= SEARCH_TEXTS_PER_SYNC {
+            break;
+        }
+        if let Some(message) = cache.message(account, mailbox_name, uid)? {
+            if let Ok((_, Response::Fetch(_, attributes))) =
+                imap_proto::parse_response(&message.summary)
+            {
+                if parse_fetch_size(&attributes) <= SEARCH_MAX_INDEXED_SIZE {
+                    text_uids.push(uid);
+                }
+            }
+        }
+    }
+    text_uids.sort_unstable();
+
+    for chunk in text_uids.rchunks(SEARCH_TEXT_CHUNK_SIZE) {
+        let fetch_response = imap_connection.command(&format!(
+            "UID FETCH {} (UID BODY.PEEK[TEXT])",
+            format_uid_set(chunk)
+        ))?;
+        for line in fetch_response.untagged.iter() {
+            if let Ok((_, Response::Fetch(_, attributes))) = imap_proto::parse_response(line) {
+                if let (Some(uid), Some(text)) =
+                    (parse_fetch_uid(&attributes), parse_fetch_text(&attributes))
+                {
+                    store_text(cache, account, mailbox_name, uid, text)?;
+                }
+            }
+        }
+    }
+
+    // Previews for the list, from the cached summaries and newest first as well
+    let mut summaries = vec![];
+    for uid in cache
+        .uids_without_part(account, mailbox_name, CACHE_PREVIEW_SECTION)?
+        .into_iter()
+        .rev()
+        .take(PREVIEWS_PER_SYNC)
+    {
+        if let Some(message) = cache.message(account, mailbox_name, uid)? {
+            summaries.push(message.summary);
+        }
+    }
+    for chunk in summaries.chunks(CACHE_SYNC_CHUNK_SIZE) {
+        let summary_lines: Vec<&[u8]> = chunk.iter().map(|summary| summary.as_slice()).collect();
+        fetch_previews(
+            imap_connection,
+            cache,
+            account,
+            mailbox_name,
+            &summary_lines,
+        )?;
+    }
+
+    cache.mark_synced(account, mailbox_name)
+}
+
+/// Returns EXISTS and UIDVALIDITY from the untagged responses of SELECT.
+pub fn parse_select_response(untagged: &[Vec<u8>]) -> (u32, u32) {
+    let mut exists = 0;
+    let mut uid_validity = 0;
+    for line in untagged.iter() {
+        match imap_proto::parse_response(line) {
+            Ok((_, Response::MailboxData(MailboxDatum::Exists(count)))) => exists = count,
+            Ok((
+                _,
+                Response::Data {
+                    code: Some(ResponseCode::UidValidity(validity)),
+                    ..
+                },
+            )) => uid_validity = validity,
+            _ => {}
+        }
+    }
+    (exists, uid_validity)
+}
+
+/// Stores a FETCH response of `CACHE_SUMMARY_ITEMS`. Other responses, like unsolicited flag
+/// updates, are skipped.
+pub fn store_summary(
+    cache: &MessageCache,
+    account: &str,
+    mailbox_name: &str,
+    line: &[u8],
+) -> Result<(), Error> {
+    let attributes = match imap_proto::parse_response(line) {
+        Ok((_, Response::Fetch(_, attributes))) => attributes,
+        _ => return Ok(()),
+    };
+    let is_summary = attributes
+        .iter()
+        .any(|attribute| matches!(attribute, AttributeValue::BodyStructure(_)))
+        && attributes
+            .iter()
+            .any(|attribute| matches!(attribute, AttributeValue::Envelope(_)));
+
+    let uid = match parse_fetch_uid(&attributes) {
+        Some(uid) if is_summary => uid,
+        _ => return Ok(()),
+    };
+    cache.store_message(
+        account,
+        mailbox_name,
+        uid,
+        &parse_fetch_flags(&attributes),
+        line,
+    )?;
+
+    // Until the text is downloaded the email is at least searchable by its headers
+    if !cache.is_indexed(account, mailbox_name, uid)? {
+        index_email(
+            cache,
+            account,
+            mailbox_name,
+            &parse_email_detail(&attributes, b"", mailbox_name),
+        )?;
+    }
+    Ok(())
+}
+
+/// Stores the text of an already cached email and adds it to the full-text index.
+fn store_text(
+    cache: &MessageCache,
+    account: &str,
+    mailbox_name: &str,
+    uid: u32,
+    text: &[u8],
+) -> Result<(), Error> {
+    cache.store_part(account, mailbox_name, uid, CACHE_TEXT_SECTION, text)?;
+    if let Some(message) = cache.message(account, mailbox_name, uid)? {
+        if let Ok((_, Response::Fetch(_, attributes))) =
+            imap_proto::parse_response(&message.summary)
+        {
+            index_email(
+                cache,
+                account,
+                mailbox_name,
+                &parse_email_detail(&attributes, text, mailbox_name),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+pub fn index_email(
+    cache: &MessageCache,
+    account: &str,
+    mailbox_name: &str,
+    email: &EmailDetailOutDTO,
+) -> Result<(), Error> {
+    let attachments = email
+        .attachments
+        .iter()
+        .map(
+            |attachment| match encoded_word(attachment.file_name.as_bytes()) {
+                Ok((_, decoded)) => decoded,
+                Err(_) => attachment.file_name.clone(),
+            },
+        )
+        .collect();
+
+    cache.index_document(
+        account,
+        &SearchDocument {
+            mailbox_name: mailbox_name.to_string(),
+            uid: email.uid,
+            from_address: email.from_address.clone(),
+            subject: email.subject.clone(),
+            send_date: email.send_date,
+            body: email.body_text.clone(),
+            attachments,
+        },
+    )
+}
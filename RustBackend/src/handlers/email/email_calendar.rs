@@ -0,0 +1,424 @@
+use std::io::{Error, ErrorKind};
+
+use actix_session::Session;
+use actix_web::{
+    web::{self, Json},
+    HttpResponse,
+};
+use chrono::Utc;
+use imap_proto::{AttributeValue, BodyStructure, Response};
+use lettre::{
+    message::{header::ContentType, MultiPart, SinglePart},
+    AsyncTransport, Message,
+};
+use utf7_imap::encode_utf7_imap;
+
+use crate::{
+    dav::{
+        caldav::{remove_event, store_event},
+        client::DavClient,
+        DavConfig,
+    },
+    utils::{
+        utils_icalendar::{
+            escape_text, format_utc_date_time, parse_calendar, parse_duration, resolve_date_time,
+            write_component, CalendarComponent, CalendarProperty,
+        },
+        utils_imap::quote_imap_string,
+        utils_session::check_is_valid_session,
+        utils_transports::{create_imap_connection, create_smtp_transport},
+    },
+};
+
+use super::{
+    email_imap::{body_parts, decide_encoding, decode_part_bytes, parse_fetch_text},
+    email_source::validate_section,
+    helper_models::EncodingType,
+    models::{CalendarRsvpInDTO, EmailInvitationOutDTO, InvitationParticipantOutDTO, RsvpResponse},
+};
+
+const RSVP_PRODUCT_ID: &str = "-//RustEmailClient//RSVP//EN";
+
+/// Invites come as text/calendar, some clients attach them as an .ics file instead.
+pub fn is_calendar_content_type(content_type: &str) -> bool {
+    matches!(content_type, "text/calendar" | "application/ics")
+}
+
+/// The event of an iCalendar part. Exceptions of a recurring event carry a RECURRENCE-ID,
+/// the master event is preferred over them.
+pub fn parse_invitation(text: &str, section: &str) -> Option<EmailInvitationOutDTO> {
+    let calendar = parse_calendar(text)?;
+    let event = main_event(&calendar)?;
+
+    let start = event
+        .property("DTSTART")
+        .and_then(|property| resolve_date_time(property, &calendar));
+    let end = match event.property("DTEND") {
+        Some(property) => resolve_date_time(property, &calendar).map(|(end, _)| end),
+        None => match (&start, event.property("DURATION")) {
+            (Some((start, _)), Some(duration)) => parse_duration(&duration.value)
+                .and_then(|duration| start.checked_add_signed(duration)),
+            _ => None,
+        },
+    };
+
+    Some(EmailInvitationOutDTO {
+        section: section.to_string(),
+        method: calendar
+            .property("METHOD")
+            .map(|property| property.value.trim().to_uppercase()),
+        uid: event.text("UID").unwrap_or_default(),
+        sequence: event
+            .property("SEQUENCE")
+            .and_then(|property| property.value.trim().parse().ok())
+            .unwrap_or(0),
+        summary: event.text("SUMMARY").unwrap_or_default(),
+        description: event.text("DESCRIPTION"),
+        location: event.text("LOCATION"),
+        status: event
+            .property("STATUS")
+            .map(|property| property.value.trim().to_uppercase()),
+        organizer: event.property("ORGANIZER").map(participant),
+        attendees: event.properties("ATTENDEE").map(participant).collect(),
+        all_day: start.as_ref().is_some_and(|(_, all_day)| *all_day),
+        start: start.map(|(start, _)| start),
+        end,
+        time_zone: event
+            .property("DTSTART")
+            .and_then(|property| property.param("TZID"))
+            .map(str::to_string),
+        recurrence: event
+            .property("RRULE")
+            .map(|property| property.value.trim().to_string()),
+    })
+}
+
+fn main_event(calendar: &CalendarComponent) -> Option<&CalendarComponent> {
+    calendar
+        .components("VEVENT")
+        .find(|event| event.property("RECURRENCE-ID").is_none())
+        .or_else(|| calendar.components("VEVENT").next())
+}
+
+fn participant(property: &CalendarProperty) -> InvitationParticipantOutDTO {
+    InvitationParticipantOutDTO {
+        email: calendar_address(&property.value),
+        name: property.param("CN").map(str::to_string),
+        participation_status: property.param("PARTSTAT").map(str::to_uppercase),
+        role: property.param("ROLE").map(str::to_uppercase),
+        rsvp: property
+            .param("RSVP")
+            .is_some_and(|rsvp| rsvp.eq_ignore_ascii_case("TRUE")),
+    }
+}
+
+/// `mailto:jane@example.com` without the scheme.
+fn calendar_address(value: &str) -> String {
+    let value = value.trim();
+    match value.get(..7) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("mailto:") => value[7..].to_string(),
+        _ => value.to_string(),
+    }
+}
+
+/// Answers an invitation with an iTIP REPLY (RFC 5546) to the organizer. The reply names
+/// only the signed in attendee, who may also reply when invited through an alias. With a
+/// CalDAV server configured, accepted events are written to the user's calendar and declined
+/// ones removed from it.
+async fn send_rsvp(
+    session: Session,
+    dav_config: web::Data<DavConfig>,
+    request: Json<CalendarRsvpInDTO>,
+) -> Result<HttpResponse, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let mut imap_connection = create_imap_connection(
+        &credentials.email,
+        &credentials.password,
+        &credentials.get_imap_string(),
+    )
+    .await?;
+    // Read-only, answering must not change the invitation email
+    imap_connection.command(&format!(
+        "EXAMINE {}",
+        quote_imap_string(&encode_utf7_imap(request.mailbox_name.clone()))
+    ))?;
+
+    let structure_response =
+        imap_connection.command(&format!("UID FETCH {} (UID BODYSTRUCTURE)", request.uid))?;
+    let mut calendar_part = None;
+    for line in structure_response.untagged.iter() {
+        if let Ok((_, Response::Fetch(_, attributes))) = imap_proto::parse_response(line) {
+            for attribute in attributes.iter() {
+                if let AttributeValue::BodyStructure(structure) = attribute {
+                    calendar_part = find_calendar_part(structure, &request.section)?;
+                }
+            }
+        }
+    }
+    let (section, encoding) = match calendar_part {
+        Some(part) => part,
+        None => {
+            imap_connection.logout()?;
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                "The email has no invitation",
+            ));
+        }
+    };
+
+    let fetch_response = imap_connection.command(&format!(
+        "UID FETCH {} (UID BODY.PEEK[{}])",
+        request.uid, section
+    ))?;
+    imap_connection.logout()?;
+    let text = fetch_response
+        .untagged
+        .iter()
+        .find_map(|line| match imap_proto::parse_response(line) {
+            Ok((_, Response::Fetch(_, attributes))) => parse_fetch_text(&attributes).map(|data| {
+                String::from_utf8_lossy(&decode_part_bytes(data, &encoding)).to_string()
+            }),
+            _ => None,
+        })
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "The email has no invitation"))?;
+
+    let calendar = parse_calendar(&text)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "The invitation can not be read"))?;
+    let method = calendar
+        .property("METHOD")
+        .map(|property| property.value.trim().to_uppercase());
+    if method.as_deref().is_some_and(|method| method != "REQUEST") {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Only invitations can be answered",
+        ));
+    }
+    let event = main_event(&calendar)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "The invitation has no event"))?;
+    let organizer = event
+        .property("ORGANIZER")
+        .map(|property| calendar_address(&property.value))
+        .filter(|organizer| !organizer.is_empty())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "The invitation has no organizer"))?;
+
+    let reply = build_reply(
+        event,
+        &credentials.email,
+        &request.response,
+        &request.comment,
+    );
+    let summary = event.text("SUMMARY").unwrap_or_default();
+    let (subject_prefix, answer) = match request.response {
+        RsvpResponse::Accepted => ("Accepted", "accepted"),
+        RsvpResponse::Tentative => ("Tentative", "tentatively accepted"),
+        RsvpResponse::Declined => ("Declined", "declined"),
+    };
+    let mut text_body = format!(
+        "{} has {} the invitation \"{}\".",
+        credentials.email, answer, summary
+    );
+    if let Some(comment) = request
+        .comment
+        .as_ref()
+        .filter(|comment| !comment.trim().is_empty())
+    {
+        text_body.push_str(&format!("\n\n{}", comment.trim()));
+    }
+
+    let calendar_type = ContentType::parse("text/calendar; method=REPLY; charset=UTF-8")
+        .map_err(|err| Error::other(format!("Invalid content type {:?}", err)))?;
+    let message = Message::builder()
+        .to(organizer.parse().map_err(|err| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid organizer {:?}", err),
+            )
+        })?)
+        .from(
+            credentials
+                .email
+                .parse()
+                .map_err(|err| Error::other(format!("Invalid sender {:?}", err)))?,
+        )
+        .subject(format!("{}: {}", subject_prefix, summary))
+        .multipart(
+            MultiPart::alternative()
+                .singlepart(
+                    SinglePart::builder()
+                        .content_type(ContentType::TEXT_PLAIN)
+                        .body(text_body),
+                )
+                .singlepart(
+                    SinglePart::builder()
+                        .content_type(calendar_type)
+                        .body(reply),
+                ),
+        )
+        .map_err(|err| Error::other(format!("Couldnt build message {:?}", err)))?;
+
+    let smtp_transport = create_smtp_transport(
+        &credentials.email,
+        &credentials.password,
+        &credentials.get_smtp_string(),
+    )
+    .await?;
+    smtp_transport
+        .send(message)
+        .await
+        .map_err(|err| Error::other(format!("Couldnt send reply {:?}", err)))?;
+
+    let uid = event.text("UID").unwrap_or_default();
+    let save_to_calendar = request.save_to_calendar.unwrap_or(true) && !uid.trim().is_empty();
+    if let (Some(url), true) = (dav_config.caldav_url.clone(), save_to_calendar) {
+        let stored = match request.response {
+            RsvpResponse::Declined => None,
+            _ => Some(calendar_copy(
+                &calendar,
+                &credentials.email,
+                participation_status(&request.response),
+            )),
+        };
+        let saved = web::block(move || {
+            let client = DavClient::new(&credentials.email, &credentials.password, &url);
+            match stored {
+                Some(stored) => store_event(&client, &url, &uid, &stored),
+                None => remove_event(&client, &url, &uid),
+            }
+        })
+        .await
+        .map_err(Error::other)?;
+        // The reply is already sent, a calendar that can not be written is only reported
+        if let Err(err) = saved {
+            println!("Updating the calendar failed: {}", err);
+        }
+    }
+
+    Ok(HttpResponse::Ok().body("Ok"))
+}
+
+/// The invitation as a calendar resource, without METHOD, which CalDAV servers refuse
+/// (RFC 4791), and with the answer as the participation status of the user.
+fn calendar_copy(calendar: &CalendarComponent, email: &str, status: &str) -> String {
+    let mut copy = calendar.clone();
+    copy.properties.retain(|property| property.name != "METHOD");
+    for event in copy
+        .components
+        .iter_mut()
+        .filter(|component| component.name == "VEVENT")
+    {
+        for attendee in event.properties.iter_mut().filter(|property| {
+            property.name == "ATTENDEE"
+                && calendar_address(&property.value).eq_ignore_ascii_case(email)
+        }) {
+            attendee
+                .params
+                .retain(|(param, _)| param != "PARTSTAT" && param != "RSVP");
+            attendee
+                .params
+                .push(("PARTSTAT".to_string(), status.to_string()));
+        }
+    }
+    write_component(&copy)
+}
+
+fn participation_status(response: &RsvpResponse) -> &'static str {
+    match response {
+        RsvpResponse::Accepted => "ACCEPTED",
+        RsvpResponse::Tentative => "TENTATIVE",
+        RsvpResponse::Declined => "DECLINED",
+    }
+}
+
+/// Section and encoding of the requested calendar part, or of the first one.
+fn find_calendar_part(
+    structure: &BodyStructure,
+    section: &Option<String>,
+) -> Result<Option<(String, EncodingType)>, Error> {
+    let section = match section {
+        Some(section) => Some(validate_section(section.trim())?),
+        None => None,
+    };
+    Ok(body_parts(structure)
+        .into_iter()
+        .find_map(|(part_section, part)| match part {
+            BodyStructure::Basic { common, other, .. }
+            | BodyStructure::Text { common, other, .. } => {
+                let content_type = format!("{}/{}", common.ty.ty, common.ty.subtype).to_lowercase();
+                let matches = match section {
+                    Some(section) => part_section == section,
+                    None => is_calendar_content_type(&content_type),
+                };
+                matches.then(|| (part_section, decide_encoding(other)))
+            }
+            _ => None,
+        }))
+}
+
+/// The REPLY keeps what identifies the event and its instance, so the organizer's client
+/// can match it, and lists the signed in user as the only attendee.
+fn build_reply(
+    event: &CalendarComponent,
+    email: &str,
+    response: &RsvpResponse,
+    comment: &Option<String>,
+) -> String {
+    let attendee = event
+        .properties("ATTENDEE")
+        .find(|attendee| calendar_address(&attendee.value).eq_ignore_ascii_case(email));
+    let mut params = vec![(
+        "PARTSTAT".to_string(),
+        participation_status(response).to_string(),
+    )];
+    if let Some(name) = attendee.and_then(|attendee| attendee.param("CN")) {
+        params.push(("CN".to_string(), name.to_string()));
+    }
+    let attendee = CalendarProperty {
+        name: "ATTENDEE".to_string(),
+        params,
+        value: attendee
+            .map(|attendee| attendee.value.trim().to_string())
+            .unwrap_or_else(|| format!("mailto:{}", email)),
+    };
+
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        format!("PRODID:{}", RSVP_PRODUCT_ID),
+        "VERSION:2.0".to_string(),
+        "METHOD:REPLY".to_string(),
+        "BEGIN:VEVENT".to_string(),
+    ];
+    // Times are left out, they are optional in a REPLY and would need the VTIMEZONE too
+    for name in ["UID", "SEQUENCE", "RECURRENCE-ID", "SUMMARY", "ORGANIZER"] {
+        if let Some(property) = event.property(name) {
+            lines.push(property.to_line());
+        }
+    }
+    lines.push(format!(
+        "DTSTAMP:{}",
+        format_utc_date_time(Utc::now().naive_utc())
+    ));
+    lines.push(attendee.to_line());
+    if let Some(comment) = comment
+        .as_ref()
+        .filter(|comment| !comment.trim().is_empty())
+    {
+        lines.push(
+            CalendarProperty {
+                name: "COMMENT".to_string(),
+                params: vec![],
+                value: escape_text(comment.trim()),
+            }
+            .to_line(),
+        );
+    }
+    lines.push("END:VEVENT".to_string());
+    lines.push("END:VCALENDAR".to_string());
+
+    let mut reply = lines.join("\r\n");
+    reply.push_str("\r\n");
+    reply
+}
+
+pub fn email_calendar_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/calendar/rsvp").route(web::post().to(send_rsvp)));
+}
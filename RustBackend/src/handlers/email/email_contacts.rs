@@ -0,0 +1,481 @@
+use std::{
+    cmp::Reverse,
+    io::{Error, ErrorKind},
+};
+
+use actix_session::Session;
+use actix_web::{
+    http::header::{ContentDisposition, DispositionParam, DispositionType},
+    web::{self, Bytes, Json},
+    HttpResponse,
+};
+use chrono::NaiveDateTime;
+use lettre::{message::Mailbox, Address};
+
+use crate::{
+    constants::{
+        CONTACT_IMPORT_MAX_BYTES, CONTACT_SUGGESTIONS_DEFAULT_LIMIT, CONTACT_SUGGESTIONS_MAX_LIMIT,
+    },
+    storage::{contacts::Contact, message_cache::MessageCache},
+    utils::{
+        utils_session::check_is_valid_session,
+        utils_vcard::{parse_vcards, write_vcards},
+    },
+};
+
+use super::models::{
+    ContactAutocompleteInDTO, ContactAutocompleteOutDTO, ContactExportInDTO, ContactIdInDTO,
+    ContactImportOutDTO, ContactInDTO, ContactListInDTO, ContactListOutDTO, ContactOutDTO,
+    ContactSuggestionKind, ContactSuggestionOutDTO, VCardVersion,
+};
+
+/// Contacts of the signed in account ordered by name, optionally only one group.
+async fn list_contacts(
+    session: Session,
+    cache: web::Data<MessageCache>,
+    request: web::Query<ContactListInDTO>,
+) -> Result<ContactListOutDTO, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let mut contacts: Vec<(i64, Contact)> = cache
+        .contacts(&credentials.email)?
+        .into_iter()
+        .filter(|(_, contact)| match request.group.as_deref() {
+            Some(group) => contact.in_group(group),
+            None => true,
+        })
+        .collect();
+    contacts.sort_by_key(|(_, contact)| sort_name(contact));
+    Ok(ContactListOutDTO {
+        contacts: contacts
+            .into_iter()
+            .map(|(id, contact)| contact_out(id, contact))
+            .collect(),
+    })
+}
+
+async fn create_contact(
+    session: Session,
+    cache: web::Data<MessageCache>,
+    request: Json<ContactInDTO>,
+) -> Result<ContactOutDTO, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let mut contact = Contact::default();
+    apply_contact_input(&mut contact, request.into_inner())?;
+    contact.pending_push = true;
+    let id = cache.insert_contact(&credentials.email, &contact)?;
+    Ok(contact_out(id, contact))
+}
+
+/// Replaces what the user can edit, the vCard UID and the usage counts are kept.
+async fn update_contact(
+    session: Session,
+    cache: web::Data<MessageCache>,
+    query: web::Query<ContactIdInDTO>,
+    request: Json<ContactInDTO>,
+) -> Result<ContactOutDTO, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let mut contact = cache
+        .contact(&credentials.email, query.id)?
+        .ok_or_else(contact_not_found)?;
+    apply_contact_input(&mut contact, request.into_inner())?;
+    contact.pending_push = true;
+    if !cache.update_contact(&credentials.email, query.id, &contact)? {
+        return Err(contact_not_found());
+    }
+    Ok(contact_out(query.id, contact))
+}
+
+async fn delete_contact(
+    session: Session,
+    cache: web::Data<MessageCache>,
+    query: web::Query<ContactIdInDTO>,
+) -> Result<HttpResponse, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let contact = cache
+        .contact(&credentials.email, query.id)?
+        .ok_or_else(contact_not_found)?;
+    if !cache.delete_contact(&credentials.email, query.id)? {
+        return Err(contact_not_found());
+    }
+    if let Some(remote) = contact.remote.as_ref() {
+        cache.queue_dav_deletion(&credentials.email, remote)?;
+    }
+    Ok(HttpResponse::Ok().body("Ok"))
+}
+
+/// Contacts and groups matching the start of a name, a word of it or an address, the ones
+/// written to most often first. Every address of a contact is a suggestion of its own.
+async fn autocomplete_contacts(
+    session: Session,
+    cache: web::Data<MessageCache>,
+    request: web::Query<ContactAutocompleteInDTO>,
+) -> Result<ContactAutocompleteOutDTO, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let query = request.query.trim().to_lowercase();
+    let limit = request
+        .limit
+        .unwrap_or(CONTACT_SUGGESTIONS_DEFAULT_LIMIT)
+        .clamp(1, CONTACT_SUGGESTIONS_MAX_LIMIT);
+    if query.is_empty() {
+        return Ok(ContactAutocompleteOutDTO {
+            suggestions: vec![],
+        });
+    }
+
+    let contacts = cache.contacts(&credentials.email)?;
+    let mut suggestions: Vec<(ContactSuggestionOutDTO, Option<NaiveDateTime>)> = vec![];
+    for (id, contact) in contacts.iter() {
+        let name_matches = matches_query(&contact.name, &query);
+        for email in contact.emails.iter() {
+            if !name_matches && !matches_query(email, &query) {
+                continue;
+            }
+            suggestions.push((
+                ContactSuggestionOutDTO {
+                    kind: ContactSuggestionKind::Contact,
+                    contact_id: Some(*id),
+                    name: contact.name.clone(),
+                    email: Some(email.clone()),
+                    recipient: format_recipient(&contact.name, email),
+                    times_contacted: contact.times_contacted,
+                },
+                contact.last_contacted,
+            ));
+        }
+    }
+
+    // A group is used as often as its members together
+    let mut groups: Vec<(ContactSuggestionOutDTO, Option<NaiveDateTime>)> = vec![];
+    for (_, contact) in contacts
+        .iter()
+        .filter(|(_, contact)| !contact.emails.is_empty())
+    {
+        for group in contact.groups.iter() {
+            if !matches_query(group, &query) {
+                continue;
+            }
+            match groups
+                .iter_mut()
+                .find(|(suggestion, _)| suggestion.name.to_lowercase() == group.to_lowercase())
+            {
+                Some((suggestion, last_contacted)) => {
+                    suggestion.times_contacted += contact.times_contacted;
+                    *last_contacted = (*last_contacted).max(contact.last_contacted);
+                }
+                None => groups.push((
+                    ContactSuggestionOutDTO {
+                        kind: ContactSuggestionKind::Group,
+                        contact_id: None,
+                        name: group.clone(),
+                        email: None,
+                        recipient: quote_recipient_name(group),
+                        times_contacted: contact.times_contacted,
+                    },
+                    contact.last_contacted,
+                )),
+            }
+        }
+    }
+    suggestions.extend(groups);
+
+    suggestions.sort_by_key(|(suggestion, last_contacted)| {
+        (
+            Reverse(suggestion.times_contacted),
+            Reverse(*last_contacted),
+            suggestion.name.to_lowercase(),
+        )
+    });
+    suggestions.truncate(limit);
+    Ok(ContactAutocompleteOutDTO {
+        suggestions: suggestions
+            .into_iter()
+            .map(|(suggestion, _)| suggestion)
+            .collect(),
+    })
+}
+
+/// Takes a vCard file as the request body. Contacts already in the address book are updated.
+async fn import_contacts(
+    session: Session,
+    cache: web::Data<MessageCache>,
+    body: Bytes,
+) -> Result<ContactImportOutDTO, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let text = String::from_utf8_lossy(&body).to_string();
+    let (contacts, skipped) = parse_vcards(&text);
+    let counts = cache.import_contacts(&credentials.email, contacts)?;
+    Ok(ContactImportOutDTO {
+        created: counts.created,
+        updated: counts.updated,
+        skipped,
+    })
+}
+
+async fn export_contacts(
+    session: Session,
+    cache: web::Data<MessageCache>,
+    request: web::Query<ContactExportInDTO>,
+) -> Result<HttpResponse, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let mut contacts: Vec<Contact> = cache
+        .contacts(&credentials.email)?
+        .into_iter()
+        .map(|(_, contact)| contact)
+        .filter(|contact| match request.group.as_deref() {
+            Some(group) => contact.in_group(group),
+            None => true,
+        })
+        .collect();
+    contacts.sort_by_key(sort_name);
+    let version = request.version.unwrap_or(VCardVersion::V4);
+
+    let content_disposition = ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename("contacts.vcf".to_string())],
+    };
+    Ok(HttpResponse::Ok()
+        .insert_header(content_disposition)
+        .content_type("text/vcard; charset=utf-8")
+        .body(write_vcards(&contacts, version)))
+}
+
+/// Turns the `to_address` of a sent email into its recipients. Entries are separated by
+/// commas or semicolons and are either addresses, with or without a display name, or the
+/// name of a group or of a contact, which is replaced by the preferred addresses.
+pub fn resolve_recipients(
+    cache: &MessageCache,
+    account: &str,
+    to_address: &str,
+) -> Result<Vec<Mailbox>, Error> {
+    let entries = split_address_list(to_address);
+    let contacts = if entries.iter().any(|entry| !entry.contains('@')) {
+        cache.contacts(account)?
+    } else {
+        vec![]
+    };
+
+    let mut recipients: Vec<Mailbox> = vec![];
+    for entry in entries.iter() {
+        let resolved = if entry.contains('@') {
+            vec![parse_mailbox(entry)?]
+        } else {
+            resolve_name(&contacts, entry.trim_matches('"').trim())?
+        };
+        for mailbox in resolved.into_iter() {
+            if !recipients
+                .iter()
+                .any(|recipient| recipient.email == mailbox.email)
+            {
+                recipients.push(mailbox);
+            }
+        }
+    }
+
+    if recipients.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "No recipient given"));
+    }
+    Ok(recipients)
+}
+
+/// Groups win over contacts of the same name. A contact name has to be unique.
+fn resolve_name(contacts: &[(i64, Contact)], name: &str) -> Result<Vec<Mailbox>, Error> {
+    let mut matched: Vec<&Contact> = contacts
+        .iter()
+        .map(|(_, contact)| contact)
+        .filter(|contact| contact.in_group(name))
+        .collect();
+    if matched.is_empty() {
+        matched = contacts
+            .iter()
+            .map(|(_, contact)| contact)
+            .filter(|contact| contact.name.to_lowercase() == name.to_lowercase())
+            .collect();
+        if matched.len() > 1 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("More than one contact is named {}", name),
+            ));
+        }
+    }
+
+    let mailboxes: Vec<Mailbox> = matched
+        .into_iter()
+        .filter_map(|contact| {
+            let email = contact.emails.first()?.parse::<Address>().ok()?;
+            let name = Some(contact.name.clone()).filter(|name| !name.is_empty());
+            Some(Mailbox::new(name, email))
+        })
+        .collect();
+    if mailboxes.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Unknown recipient {}", name),
+        ));
+    }
+    Ok(mailboxes)
+}
+
+/// `address` or `Display Name <address>` with the name optionally quoted.
+fn parse_mailbox(entry: &str) -> Result<Mailbox, Error> {
+    let (name, address) = match entry
+        .strip_suffix('>')
+        .and_then(|rest| rest.rsplit_once('<'))
+    {
+        Some((name, address)) => (name.trim().trim_matches('"').trim(), address.trim()),
+        None => ("", entry),
+    };
+    let address = address.parse::<Address>().map_err(|err| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid recipient {}: {}", entry, err),
+        )
+    })?;
+    let name = Some(name.replace("\\\"", "\"")).filter(|name| !name.is_empty());
+    Ok(Mailbox::new(name, address))
+}
+
+/// Splits at commas and semicolons outside of quoted display names and angle brackets.
+fn split_address_list(value: &str) -> Vec<String> {
+    let mut entries = vec![];
+    let mut entry = String::new();
+    let mut in_quotes = false;
+    let mut in_brackets = false;
+    for char in value.chars() {
+        match char {
+            '"' => in_quotes = !in_quotes,
+            '<' if !in_quotes => in_brackets = true,
+            '>' if !in_quotes => in_brackets = false,
+            ',' | ';' if !in_quotes && !in_brackets => {
+                entries.push(std::mem::take(&mut entry));
+                continue;
+            }
+            _ => {}
+        }
+        entry.push(char);
+    }
+    entries.push(entry);
+    entries
+        .into_iter()
+        .map(|entry| entry.trim().to_string())
+        .filter(|entry| !entry.is_empty())
+        .collect()
+}
+
+fn apply_contact_input(contact: &mut Contact, input: ContactInDTO) -> Result<(), Error> {
+    let clean = |values: Vec<String>| -> Vec<String> {
+        let mut cleaned: Vec<String> = vec![];
+        for value in values.into_iter() {
+            let value = value.trim().to_string();
+            if !value.is_empty() && !cleaned.contains(&value) {
+                cleaned.push(value);
+            }
+        }
+        cleaned
+    };
+    let emails = clean(input.emails);
+    if let Some(email) = emails
+        .iter()
+        .find(|email| email.parse::<Address>().is_err())
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid address {}", email),
+        ));
+    }
+    let name = input.name.trim().to_string();
+    if name.is_empty() && emails.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "A contact needs a name or an address",
+        ));
+    }
+
+    contact.name = name;
+    contact.emails = emails;
+    contact.phones = clean(input.phones);
+    contact.organization = input
+        .organization
+        .map(|organization| organization.trim().to_string())
+        .filter(|organization| !organization.is_empty());
+    contact.note = input.note.filter(|note| !note.trim().is_empty());
+    contact.groups = clean(input.groups);
+    Ok(())
+}
+
+fn matches_query(text: &str, query: &str) -> bool {
+    let text = text.to_lowercase();
+    text.starts_with(query)
+        || text
+            .split(|char: char| char.is_whitespace() || matches!(char, '.' | '-' | '_' | '@'))
+            .any(|word| word.starts_with(query))
+}
+
+fn format_recipient(name: &str, email: &str) -> String {
+    match email.parse::<Address>() {
+        Ok(address) => Mailbox::new(
+            Some(name.to_string()).filter(|name| !name.is_empty()),
+            address,
+        )
+        .to_string(),
+        Err(_) => email.to_string(),
+    }
+}
+
+fn quote_recipient_name(name: &str) -> String {
+    if name.contains([',', ';', '"']) {
+        format!("\"{}\"", name.replace('"', ""))
+    } else {
+        name.to_string()
+    }
+}
+
+fn contact_out(id: i64, contact: Contact) -> ContactOutDTO {
+    ContactOutDTO {
+        id,
+        uid: contact.uid,
+        name: contact.name,
+        emails: contact.emails,
+        phones: contact.phones,
+        organization: contact.organization,
+        note: contact.note,
+        groups: contact.groups,
+        times_contacted: contact.times_contacted,
+        last_contacted: contact.last_contacted,
+        synced: contact.remote.is_some(),
+        pending_push: contact.pending_push,
+    }
+}
+
+fn sort_name(contact: &Contact) -> String {
+    if contact.name.is_empty() {
+        contact
+            .emails
+            .first()
+            .cloned()
+            .unwrap_or_default()
+            .to_lowercase()
+    } else {
+        contact.name.to_lowercase()
+    }
+}
+
+fn contact_not_found() -> Error {
+    Error::new(ErrorKind::NotFound, "Contact not found")
+}
+
+pub fn email_contacts_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/contacts")
+            .route(web::get().to(list_contacts))
+            .route(web::post().to(create_contact))
+            .route(web::put().to(update_contact))
+            .route(web::delete().to(delete_contact)),
+    )
+    .service(web::resource("/contacts/autocomplete").route(web::get().to(autocomplete_contacts)))
+    .service(
+        web::resource("/contacts/import")
+            .app_data(web::PayloadConfig::new(CONTACT_IMPORT_MAX_BYTES))
+            .route(web::post().to(import_contacts)),
+    )
+    .service(web::resource("/contacts/export").route(web::get().to(export_contacts)));
+}
@@ -0,0 +1,307 @@
+use std::io::{Error, ErrorKind};
+
+use actix_session::Session;
+use actix_web::web;
+use url::Url;
+
+use crate::{
+    dav::{
+        carddav::{
+            collection_changes, fetch_cards, put_card, PutCondition, PutOutcome, RemoteCard,
+        },
+        client::DavClient,
+        delete_resource, discover_collections, member_url, new_uid, parse_href, resource_name,
+        same_resource, CollectionKind, DavConfig,
+    },
+    storage::{
+        contacts::{Contact, RemoteContact},
+        message_cache::MessageCache,
+    },
+    utils::{
+        utils_session::check_is_valid_session,
+        utils_vcard::{parse_vcards, update_vcard, write_vcards},
+    },
+};
+
+use super::models::{ContactSyncOutDTO, VCardVersion};
+
+/// Syncs the contacts with the address books on the CardDAV server, signing in with the
+/// email account. Local edits are written first, then the changes of the server are pulled.
+async fn sync_dav_contacts(
+    session: Session,
+    cache: web::Data<MessageCache>,
+    dav_config: web::Data<DavConfig>,
+) -> Result<ContactSyncOutDTO, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let url = dav_config
+        .carddav_url
+        .clone()
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "No CardDAV server configured"))?;
+    web::block(move || {
+        let client = DavClient::new(&credentials.email, &credentials.password, &url);
+        sync_contacts(&cache, &client, &credentials.email, &url)
+    })
+    .await
+    .map_err(Error::other)?
+}
+
+/// New contacts are created in the first address book. When the user and the server both
+/// changed a card, the version of the server wins.
+fn sync_contacts(
+    cache: &MessageCache,
+    client: &DavClient,
+    account: &str,
+    url: &Url,
+) -> Result<ContactSyncOutDTO, Error> {
+    let address_books = discover_collections(client, url, CollectionKind::AddressBook)?;
+    let mut summary = ContactSyncOutDTO {
+        address_books: address_books.len(),
+        ..ContactSyncOutDTO::default()
+    };
+    for (index, address_book) in address_books.iter().enumerate() {
+        push_deletions(cache, client, account, address_book, &mut summary)?;
+        push_contacts(
+            cache,
+            client,
+            account,
+            address_book,
+            index == 0,
+            &mut summary,
+        )?;
+        pull_changes(cache, client, account, address_book, &mut summary)?;
+    }
+    Ok(summary)
+}
+
+fn push_deletions(
+    cache: &MessageCache,
+    client: &DavClient,
+    account: &str,
+    collection: &Url,
+    summary: &mut ContactSyncOutDTO,
+) -> Result<(), Error> {
+    let mut state = cache.dav_collection_state(account, collection.as_str())?;
+    if state.pending_deletions.is_empty() {
+        return Ok(());
+    }
+    let mut remaining = vec![];
+    for remote in state.pending_deletions.into_iter() {
+        let deleted = parse_href(&remote.href)
+            .and_then(|href| delete_resource(client, &href, remote.etag.as_deref()));
+        match deleted {
+            Ok(true) => summary.pushed += 1,
+            // Changed on the server, the pull brings the contact back
+            Ok(false) => summary.conflicts += 1,
+            Err(err) => {
+                println!("Removing card {} failed: {}", remote.href, err);
+                summary.failed += 1;
+                remaining.push(remote);
+            }
+        }
+    }
+    state.pending_deletions = remaining;
+    cache.set_dav_collection_state(account, collection.as_str(), &state)
+}
+
+fn push_contacts(
+    cache: &MessageCache,
+    client: &DavClient,
+    account: &str,
+    collection: &Url,
+    takes_new_contacts: bool,
+    summary: &mut ContactSyncOutDTO,
+) -> Result<(), Error> {
+    for (id, mut contact) in cache.contacts(account)?.into_iter() {
+        let belongs_here = match contact.remote.as_ref() {
+            Some(remote) => remote.collection == collection.as_str(),
+            None => takes_new_contacts,
+        };
+        if !contact.pending_push || !belongs_here {
+            continue;
+        }
+        match push_contact(client, collection, &mut contact) {
+            Ok(true) => {
+                contact.pending_push = false;
+                cache.update_contact(account, id, &contact)?;
+                summary.pushed += 1;
+            }
+            Ok(false) => summary.conflicts += 1,
+            Err(err) => {
+                println!("Writing contact {} failed: {}", id, err);
+                summary.failed += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes the contact to its card, or to a new card named after its UID. Returns false when
+/// the card changed on the server in the meantime.
+fn push_contact(
+    client: &DavClient,
+    collection: &Url,
+    contact: &mut Contact,
+) -> Result<bool, Error> {
+    let (href, vcard, condition) = match contact.remote.as_ref() {
+        Some(remote) => (
+            parse_href(&remote.href)?,
+            update_vcard(&remote.vcard, contact),
+            PutCondition::Replace(remote.etag.as_deref()),
+        ),
+        None => {
+            let uid = contact.uid.get_or_insert_with(new_uid).clone();
+            (
+                member_url(collection, &resource_name(&uid, "vcf"))?,
+                write_vcards(std::slice::from_ref(contact), VCardVersion::V3),
+                PutCondition::Create,
+            )
+        }
+    };
+    match put_card(client, &href, &vcard, condition)? {
+        PutOutcome::Stored { etag } => {
+            contact.remote = Some(RemoteContact {
+                collection: collection.to_string(),
+                href: href.to_string(),
+                etag,
+                vcard,
+            });
+            Ok(true)
+        }
+        PutOutcome::Conflict => Ok(false),
+    }
+}
+
+fn pull_changes(
+    cache: &MessageCache,
+    client: &DavClient,
+    account: &str,
+    collection: &Url,
+    summary: &mut ContactSyncOutDTO,
+) -> Result<(), Error> {
+    let sync_token = cache
+        .dav_collection_state(account, collection.as_str())?
+        .sync_token;
+    let changes = collection_changes(client, collection, sync_token.as_deref())?;
+    let mut contacts = cache.contacts(account)?;
+
+    // Cards listed with the etag of the local copy are the ones written by the push
+    let outdated: Vec<Url> = changes
+        .changed
+        .iter()
+        .filter(|(href, etag)| {
+            let local_etag = contacts
+                .iter()
+                .find(|(_, contact)| is_card(contact, href))
+                .map(|(_, contact)| {
+                    contact
+                        .remote
+                        .as_ref()
+                        .and_then(|remote| remote.etag.clone())
+                });
+            match (local_etag, etag) {
+                (Some(Some(local_etag)), Some(etag)) => local_etag != *etag,
+                _ => true,
+            }
+        })
+        .map(|(href, _)| href.clone())
+        .collect();
+    for card in fetch_cards(client, collection, &outdated)?.into_iter() {
+        apply_card(cache, account, collection, &mut contacts, card, summary)?;
+    }
+
+    for (id, contact) in contacts.iter() {
+        let in_collection = contact
+            .remote
+            .as_ref()
+            .is_some_and(|remote| remote.collection == collection.as_str());
+        if !in_collection {
+            continue;
+        }
+        let removed = changes.removed.iter().any(|href| is_card(contact, href))
+            || (changes.complete
+                && !changes
+                    .changed
+                    .iter()
+                    .any(|(href, _)| is_card(contact, href)));
+        if removed && cache.delete_contact(account, *id)? {
+            summary.deleted += 1;
+        }
+    }
+
+    // Read again, a contact may have been deleted meanwhile
+    let mut state = cache.dav_collection_state(account, collection.as_str())?;
+    state.sync_token = changes.sync_token;
+    cache.set_dav_collection_state(account, collection.as_str(), &state)
+}
+
+/// Stores a card of the server. It updates the contact synced with it or, the first time, a
+/// local contact with the same address. The usage counts stay local.
+fn apply_card(
+    cache: &MessageCache,
+    account: &str,
+    collection: &Url,
+    contacts: &mut Vec<(i64, Contact)>,
+    card: RemoteCard,
+    summary: &mut ContactSyncOutDTO,
+) -> Result<(), Error> {
+    // Group cards and cards without name and address are left out
+    let remote_contact = match parse_vcards(&card.vcard).0.into_iter().next() {
+        Some(contact) => contact,
+        None => return Ok(()),
+    };
+    let remote = RemoteContact {
+        collection: collection.to_string(),
+        href: card.href.to_string(),
+        etag: card.etag,
+        vcard: card.vcard,
+    };
+    let existing = contacts
+        .iter()
+        .position(|(_, contact)| is_card(contact, &card.href))
+        .or_else(|| {
+            contacts.iter().position(|(_, contact)| {
+                contact.remote.is_none()
+                    && remote_contact
+                        .emails
+                        .iter()
+                        .any(|email| contact.has_email(email))
+            })
+        });
+
+    match existing {
+        Some(index) => {
+            let (id, contact) = &mut contacts[index];
+            *contact = Contact {
+                times_contacted: contact.times_contacted,
+                last_contacted: contact.last_contacted,
+                remote: Some(remote),
+                pending_push: false,
+                ..remote_contact
+            };
+            cache.update_contact(account, *id, contact)?;
+            summary.updated += 1;
+        }
+        None => {
+            let contact = Contact {
+                remote: Some(remote),
+                ..remote_contact
+            };
+            let id = cache.insert_contact(account, &contact)?;
+            contacts.push((id, contact));
+            summary.created += 1;
+        }
+    }
+    Ok(())
+}
+
+fn is_card(contact: &Contact, href: &Url) -> bool {
+    contact
+        .remote
+        .as_ref()
+        .and_then(|remote| Url::parse(&remote.href).ok())
+        .is_some_and(|remote_href| same_resource(&remote_href, href))
+}
+
+pub fn email_dav_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/contacts/sync").route(web::post().to(sync_dav_contacts)));
+}
@@ -1,277 +1,833 @@
-use std::{ops::Add, vec, cmp::{min, max}};
+use std::{
+    cmp::min,
+    collections::{HashMap, HashSet},
+    vec,
+};
 
 use actix_session::Session;
-use actix_web::{
-    http::header::{ContentDisposition, ContentEncoding, DispositionParam, DispositionType},
-    web, Error, HttpResponse, Responder,
-};
-use imap::types::{Fetch, Flag, NameAttribute};
+use actix_web::{http::header::DispositionType, web, Error, HttpRequest, HttpResponse};
+use chrono::{DateTime, NaiveDateTime};
+use imap_proto::{AttributeValue, Envelope, MailboxDatum, Response, StatusAttribute};
 use quoted_printable::ParseMode;
 use regex::bytes::Regex;
 
 use crate::{
+    constants::INLINE_PART_PATH,
     handlers::email::{
-        helper_models::{EmailPartDescription, EncodingType},
+        email_cache::{
+            index_email, parse_select_response, store_summary, watch_cached_mailbox,
+            CACHE_SUMMARY_ITEMS, CACHE_TEXT_SECTION,
+        },
+        email_calendar::{is_calendar_content_type, parse_invitation},
+        email_preview::{self, cached_preview, fetch_previews},
+        email_scanning::{part_scan_target, scan_part},
+        email_sort::sorted_uids,
+        email_source::{part_response, validate_section},
+        helper_models::{EmailPartDescription, EmbeddedMessageDescription, EncodingType},
         models::{
-            EmailDetailAttachmentOutDTO, EmailDetailOutDTO, EmailInspectOutDTO, EmailListOutDTO,
+            EmailDetailAttachmentOutDTO, EmailDetailInlinePartOutDTO, EmailDetailMessageOutDTO,
+            EmailDetailOutDTO, EmailInspectOutDTO, EmailListOutDTO, EmailSortKey, SortOrder,
         },
     },
-    utils::{utils_session::check_is_valid_session, utils_transports::create_imap_session},
+    scanning::AttachmentScanners,
+    storage::message_cache::MessageCache,
+    utils::{
+        utils_imap::{format_uid_set, parse_internal_date, quote_imap_string},
+        utils_session::check_is_valid_session,
+        utils_transports::{create_imap_connection, create_imap_session},
+    },
 };
 
 use super::{
     helper_models::EmailAnalysis,
     models::{
-        EmailAttachmentInDTO, EmailDeleteInDTO, EmailDetailInDTO, EmailListInDTO, MailboxListOutDTO, MailboxOutInfoDTO,
+        AttachmentDisposition, EmailAttachmentInDTO, EmailDeleteInDTO, EmailDetailInDTO,
+        EmailListInDTO, MailboxListInDTO, MailboxListOutDTO, MailboxOutInfoDTO,
     },
 };
 
 use rustyknife::rfc2047::encoded_word;
 use utf7_imap::{decode_utf7_imap, encode_utf7_imap};
 
+const MAILBOX_STATUS_ITEMS: &str = "(MESSAGES UNSEEN RECENT UIDNEXT UIDVALIDITY)";
+
 async fn get_email_in_detail_from_inbox(
     session: Session,
+    cache: web::Data<MessageCache>,
     request: web::Query<EmailDetailInDTO>,
-) -> impl Responder {
-    let credentials = check_is_valid_session(&session).unwrap();
-    let mut imap_session = create_imap_session(
+) -> Result<EmailDetailOutDTO, std::io::Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let account = credentials.email.clone();
+    watch_cached_mailbox(&cache, &credentials, &request.mailbox_name);
+
+    println!("Request: {:?}", request);
+    let mark_read = request.mark_read.unwrap_or(false);
+    if let (Some(uid), false) = (request.uid, request.fresh.unwrap_or(false) || mark_read) {
+        let cached_message = cache.message(&account, &request.mailbox_name, uid)?;
+        let cached_text = cache.part(&account, &request.mailbox_name, uid, CACHE_TEXT_SECTION)?;
+        if let (Some(message), Some(text)) = (cached_message, cached_text) {
+            if let Ok((_, Response::Fetch(_, attributes))) =
+                imap_proto::parse_response(&message.summary)
+            {
+                return Ok(parse_email_detail(
+                    &attributes,
+                    &text,
+                    &request.mailbox_name,
+                ));
+            }
+        }
+    }
+
+    let mut imap_connection = create_imap_connection(
         &credentials.email,
         &credentials.password,
         &credentials.get_imap_string(),
     )
-    .await
-    .unwrap();
-
-    println!("Request: {:?}", request);
-    imap_session
-        .select(&encode_utf7_imap(request.mailbox_name.clone()))
-        .unwrap();
-    let email_message_raw = &imap_session
-        .fetch(
-            format!("{}", request.sequence_number),
-            "(FLAGS BODYSTRUCTURE BODY[TEXT] ENVELOPE INTERNALDATE)",
-        )
-        .unwrap()[0];
+    .await?;
+    let select_response = imap_connection.command(&format!(
+        "SELECT {}",
+        quote_imap_string(&encode_utf7_imap(request.mailbox_name.clone()))
+    ))?;
+    let (_, uid_validity) = parse_select_response(&select_response.untagged);
+    cache.validate_mailbox(&account, &request.mailbox_name, uid_validity)?;
 
-    let structure = email_message_raw.bodystructure().unwrap();
-
-    let mut description = EmailAnalysis {
-        plain_text_octets: 0,
-        attachments: vec![],
+    let (fetch_command, store_command) = match (request.uid, request.sequence_number) {
+        (Some(uid), _) => (format!("UID FETCH {}", uid), format!("UID STORE {}", uid)),
+        (None, Some(sequence_number)) => (
+            format!("FETCH {}", sequence_number),
+            format!("STORE {}", sequence_number),
+        ),
+        (None, None) => {
+            return Err(std::io::Error::other(
+                "Either uid or sequence_number has to be set",
+            ))
+        }
     };
-    parse_body_structure(
-        structure,
-        email_message_raw,
-        &mut description,
-        String::new(),
-        0,
-    );
-
-    let send_date = email_message_raw
-        .internal_date()
-        .unwrap_or_default()
-        .naive_utc();
+    let mut commands = vec![format!("{} (UID BODY.PEEK[TEXT])", fetch_command)];
+    // Flags are changed before the summary is fetched, so the cache gets the new ones
+    if mark_read {
+        commands.push(format!("{} +FLAGS.SILENT (\\Seen)", store_command));
+    }
+    commands.push(format!("{} {}", fetch_command, CACHE_SUMMARY_ITEMS));
+    let mut responses = imap_connection.pipeline(&commands)?.into_iter();
+    let text_response = responses.next().unwrap()?;
+    if mark_read {
+        responses.next().unwrap()?;
+    }
+    let summary_response = responses.next().unwrap()?;
+    imap_connection.logout()?;
 
-    let sender_bytes = email_message_raw
-        .envelope()
-        .unwrap()
-        .from
-        .as_ref()
-        .unwrap_or(&vec![])[0]
-        .mailbox
-        .unwrap_or_default();
+    let text = text_response
+        .untagged
+        .iter()
+        .find_map(|line| match imap_proto::parse_response(line) {
+            Ok((_, Response::Fetch(_, attributes))) => {
+                parse_fetch_text(&attributes).map(|text| text.to_vec())
+            }
+            _ => None,
+        })
+        .ok_or_else(|| std::io::Error::other("Email not found"))?;
 
-    let sender_host_bytes = email_message_raw
-        .envelope()
-        .unwrap()
-        .from
-        .as_ref()
-        .unwrap_or(&vec![])[0]
-        .host
-        .unwrap_or_default();
+    for line in summary_response.untagged.iter() {
+        if let Ok((_, Response::Fetch(_, attributes))) = imap_proto::parse_response(line) {
+            // Skips flag updates caused by other clients or marking the email read
+            if !attributes
+                .iter()
+                .any(|attribute| matches!(attribute, AttributeValue::Envelope(_)))
+            {
+                continue;
+            }
+            let response = parse_email_detail(&attributes, &text, &request.mailbox_name);
 
-    let subject_bytes = email_message_raw
-        .envelope()
-        .unwrap()
-        .subject
-        .unwrap_or_default();
+            store_summary(&cache, &account, &request.mailbox_name, line)?;
+            cache.store_part(
+                &account,
+                &request.mailbox_name,
+                response.uid,
+                CACHE_TEXT_SECTION,
+                &text,
+            )?;
+            index_email(&cache, &account, &request.mailbox_name, &response)?;
+            return Ok(response);
+        }
+    }
 
-    let sender = String::from_utf8(sender_bytes.to_vec()).unwrap_or_default()
-        + "@"
-        + &String::from_utf8(sender_host_bytes.to_vec()).unwrap_or_default();
-    let (_, subject) = encoded_word(subject_bytes).unwrap_or((
-        subject_bytes,
-        String::from_utf8(subject_bytes.to_vec()).unwrap_or("Cant parse subject".to_string()),
-    ));
+    Err(std::io::Error::other("Email not found"))
+}
 
+/// Builds the detail from a FETCH response of `CACHE_SUMMARY_ITEMS` and the text of the email.
+pub fn parse_email_detail(
+    attributes: &[AttributeValue],
+    text: &[u8],
+    mailbox_name: &str,
+) -> EmailDetailOutDTO {
     let mut response = EmailDetailOutDTO {
-        from_address: sender,
-        subject,
-        send_date,
+        uid: parse_fetch_uid(attributes).unwrap_or_default(),
+        from_address: String::new(),
+        subject: String::new(),
+        send_date: NaiveDateTime::default(),
         body_text: String::new(),
+        body_html: None,
+        attachments: vec![],
+        inline_parts: vec![],
+        messages: vec![],
+        invitation: None,
+    };
+
+    let mut description = EmailAnalysis {
         attachments: vec![],
+        messages: vec![],
     };
+    for attribute in attributes.iter() {
+        match attribute {
+            AttributeValue::BodyStructure(structure) => parse_body_structure(
+                structure,
+                text,
+                &mut description,
+                String::new(),
+                0,
+                String::new(),
+            ),
+            AttributeValue::Envelope(envelope) => {
+                (response.from_address, response.subject) = parse_sender_and_subject(envelope)
+            }
+            AttributeValue::InternalDate(date) => {
+                response.send_date = parse_internal_date(date).unwrap_or_default().naive_utc()
+            }
+            _ => {}
+        }
+    }
 
-    if let Some(text_body) = description
+    let inline_part_url = format!(
+        "{}?{}",
+        INLINE_PART_PATH,
+        url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("mailbox_name", mailbox_name)
+            .append_pair("uid", &response.uid.to_string())
+            .finish()
+    );
+    response.body_text =
+        decode_body_text(&description, text, |part| part.is_email_text).unwrap_or_default();
+    response.body_html = decode_body_text(&description, text, |part| part.is_email_html)
+        .map(|html| sanitize_html(&html, &inline_part_url));
+    response.invitation = description
         .attachments
         .iter()
-        .find(|attach| attach.is_email_text)
-    {
-        let text_bytes =
-            &email_message_raw.text().unwrap()[text_body.bytes_start..text_body.bytes_end];
-        response.body_text = match text_body.encoding {
-            EncodingType::SevenBit => String::from_utf8(text_bytes.to_vec()).unwrap_or_default(),
-            EncodingType::Base64 => String::from_utf8(
-                data_encoding::BASE64_MIME
-                    .decode(text_bytes)
-                    .unwrap_or_default(),
+        .find(|part| is_calendar_content_type(&part.content_type))
+        .and_then(|part| {
+            let bytes = text
+                .get(part.bytes_start..part.bytes_end)
+                .unwrap_or_default();
+            parse_invitation(
+                &String::from_utf8_lossy(&decode_part_bytes(bytes, &part.encoding)),
+                &part.section,
             )
+        });
+    response.inline_parts = inline_parts(&description.attachments);
+    response.attachments = file_attachments(description.attachments);
+    response.messages = description
+        .messages
+        .into_iter()
+        .map(|message| embedded_message_out(message, &inline_part_url))
+        .collect();
+    response
+}
+
+fn embedded_message_out(
+    message: EmbeddedMessageDescription,
+    inline_part_url: &str,
+) -> EmailDetailMessageOutDTO {
+    let analysis = message.analysis;
+    EmailDetailMessageOutDTO {
+        section: message.section,
+        from_address: message.from_address,
+        subject: message.subject,
+        send_date: message.send_date,
+        body_text: decode_body_text(&analysis, &message.text, |part| part.is_email_text)
             .unwrap_or_default(),
-            EncodingType::QuotedPrintable => String::from_utf8(quoted_printable::decode(text_bytes, ParseMode::Robust).unwrap_or_default().to_vec()).unwrap_or_default(),
-            EncodingType::Other => String::from_utf8_lossy(text_bytes).to_string(),
-        }
+        body_html: decode_body_text(&analysis, &message.text, |part| part.is_email_html)
+            .map(|html| sanitize_html(&html, inline_part_url)),
+        inline_parts: inline_parts(&analysis.attachments),
+        attachments: file_attachments(analysis.attachments),
+        messages: analysis
+            .messages
+            .into_iter()
+            .map(|message| embedded_message_out(message, inline_part_url))
+            .collect(),
     }
-    
+}
 
-    for attach_info in description.attachments {
-        if attach_info.is_file {
-            let attach = EmailDetailAttachmentOutDTO {
-                file_name: attach_info.file_name,
-                size_octets: attach_info.size_octets,
-                is_file: attach_info.is_file,
-            };
-            response.attachments.push(attach);
-        }
-    }
+/// Decodes the first part chosen by `is_body`, `None` when there is no such part.
+fn decode_body_text(
+    description: &EmailAnalysis,
+    text: &[u8],
+    is_body: fn(&EmailPartDescription) -> bool,
+) -> Option<String> {
+    let text_body = description
+        .attachments
+        .iter()
+        .find(|attach| is_body(attach))?;
+    let text_bytes = text
+        .get(text_body.bytes_start..text_body.bytes_end)
+        .unwrap_or_default();
+    Some(match text_body.encoding {
+        EncodingType::Other => String::from_utf8_lossy(text_bytes).to_string(),
+        _ => String::from_utf8(decode_part_bytes(text_bytes, &text_body.encoding))
+            .unwrap_or_default(),
+    })
+}
 
-    imap_session.logout().unwrap();
-    response
+/// Removes scripts, event handlers and everything else which could run in the client.
+/// `cid:` references are turned into links to the inline part endpoint.
+fn sanitize_html(html: &str, inline_part_url: &str) -> String {
+    let inline_part_url = inline_part_url.to_string();
+    ammonia::Builder::default()
+        .add_url_schemes(&["cid"])
+        .attribute_filter(move |_, _, value| match value.get(..4) {
+            Some(scheme) if scheme.eq_ignore_ascii_case("cid:") => Some(
+                format!(
+                    "{}&{}",
+                    inline_part_url,
+                    url::form_urlencoded::Serializer::new(String::new())
+                        .append_pair("cid", &value[4..])
+                        .finish()
+                )
+                .into(),
+            ),
+            _ => Some(value.into()),
+        })
+        .clean(html)
+        .to_string()
+}
+
+fn inline_parts(attachments: &[EmailPartDescription]) -> Vec<EmailDetailInlinePartOutDTO> {
+    attachments
+        .iter()
+        .filter(|part| part.is_inline)
+        .map(|part| EmailDetailInlinePartOutDTO {
+            content_id: part.content_id.clone(),
+            content_type: part.content_type.clone(),
+            section: part.section.clone(),
+            size_octets: part.size_octets,
+        })
+        .collect()
+}
+
+fn file_attachments(attachments: Vec<EmailPartDescription>) -> Vec<EmailDetailAttachmentOutDTO> {
+    attachments
+        .into_iter()
+        .filter(|attach_info| attach_info.is_file)
+        .map(|attach_info| EmailDetailAttachmentOutDTO {
+            file_name: attach_info.file_name,
+            section: attach_info.section,
+            content_type: attach_info.content_type,
+            size_octets: attach_info.size_octets,
+            is_file: attach_info.is_file,
+        })
+        .collect()
+}
+
+/// Undoes the Content-Transfer-Encoding of a part.
+pub fn decode_part_bytes(bytes: &[u8], encoding: &EncodingType) -> Vec<u8> {
+    match encoding {
+        EncodingType::SevenBit => bytes.to_vec(),
+        EncodingType::Base64 => match data_encoding::BASE64_MIME.decode(bytes) {
+            Ok(bytes) => bytes,
+            Err(error) => {
+                println!("Decoding error: {}", error);
+                vec![]
+            }
+        },
+        EncodingType::QuotedPrintable => quoted_printable::decode(bytes, ParseMode::Robust)
+            .unwrap_or_default()
+            .to_vec(),
+        EncodingType::Other => bytes.to_vec(),
+    }
 }
 
 async fn delete_email_from_inbox(
     session: Session,
+    cache: web::Data<MessageCache>,
     request: web::Query<EmailDeleteInDTO>,
 ) -> Result<HttpResponse, Error> {
-    let credentials = check_is_valid_session(&session).unwrap();
+    let credentials = check_is_valid_session(&session)?;
     let mut imap_session = create_imap_session(
         &credentials.email,
         &credentials.password,
         &credentials.get_imap_string(),
     )
-    .await
-    .unwrap();
+    .await?;
+    let imap_error =
+        |err: imap::Error| std::io::Error::other(format!("IMAP delete failed: {:?}", err));
 
     println!("Request: {:?}", request);
+    let mailbox = imap_session
+        .select(encode_utf7_imap(request.mailbox_name.clone()))
+        .map_err(imap_error)?;
+    let sequence_set = format!(
+        "{}:{}",
+        request.sequence_set_top, request.sequence_set_bottom
+    );
+    // The cache is keyed by UID, the emails are dropped from it after the expunge
+    let uids: Vec<u32> = imap_session
+        .fetch(&sequence_set, "UID")
+        .map_err(imap_error)?
+        .iter()
+        .filter_map(|fetch| fetch.uid)
+        .collect();
     imap_session
-        .select(&encode_utf7_imap(request.mailbox_name.clone()))
-        .unwrap();
-    imap_session
-        .store(
-            format!(
-                "{}:{}",
-                request.sequence_set_top, request.sequence_set_bottom
-            ),
-            "+FLAGS (\\Deleted)",
-        )
-        .unwrap();
-    imap_session.expunge().unwrap();
+        .store(&sequence_set, "+FLAGS (\\Deleted)")
+        .map_err(imap_error)?;
+    let expunged = imap_session.expunge().map_err(imap_error)?;
+    let _ = imap_session.logout();
 
-    imap_session.logout().unwrap();
+    if let Some(uid_validity) = mailbox.uid_validity {
+        cache.validate_mailbox(&credentials.email, &request.mailbox_name, uid_validity)?;
+    }
+    cache.remove_messages(&credentials.email, &request.mailbox_name, &uids)?;
+    // Emails flagged by another client went too, the cached sequence numbers are off until
+    // the next sync
+    if expunged.len() != uids.len() {
+        cache.mark_unsynced(&credentials.email, &request.mailbox_name)?;
+    }
     Ok(HttpResponse::Ok().body("Ok"))
 }
 
 async fn list_emails_from_inbox(
     session: Session,
+    cache: web::Data<MessageCache>,
     request: web::Query<EmailListInDTO>,
-) -> impl Responder {
-    let credentials = check_is_valid_session(&session).unwrap();
-    let mut imap_session = create_imap_session(
+) -> Result<EmailListOutDTO, std::io::Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let account = credentials.email.clone();
+    watch_cached_mailbox(&cache, &credentials, &request.mailbox_name);
+
+    let mut response = EmailListOutDTO {
+        mailbox_name: request.mailbox_name.clone(),
+        total_emails_count: 0,
+        requested_page_number: request.requested_page_number,
+        page_size: request.page_size,
+        emails: vec![],
+        next_cursor: None,
+        has_more: false,
+    };
+
+    let sort_by = request.sort_by.as_ref().unwrap_or(&EmailSortKey::Arrival);
+    let descending = request.order.as_ref() != Some(&SortOrder::Asc);
+    // Newest arrivals first is the order of sequence numbers, anything else needs sorting
+    let is_sorted = *sort_by != EmailSortKey::Arrival || !descending;
+    let cursor = match &request.cursor {
+        Some(cursor) => Some(parse_list_cursor(cursor)?),
+        None => None,
+    };
+
+    if !is_sorted
+        && !request.fresh.unwrap_or(false)
+        && cache.is_synced(&account, &request.mailbox_name)?
+    {
+        let uid_validity = cache
+            .uid_validity(&account, &request.mailbox_name)?
+            .unwrap_or_default();
+        response.total_emails_count = cache.message_count(&account, &request.mailbox_name)?;
+
+        if let Some(cursor) = cursor {
+            let uids = cache.uids(&account, &request.mailbox_name)?;
+            let ordered_uids: Vec<u32> = uids.iter().rev().cloned().collect();
+            let (page_uids, has_more) = page_after_cursor(
+                &ordered_uids,
+                cursor,
+                uid_validity,
+                sort_by,
+                descending,
+                request.page_size,
+            )?;
+            for uid in page_uids.iter() {
+                let message = match cache.message(&account, &request.mailbox_name, *uid)? {
+                    Some(message) => message,
+                    None => continue,
+                };
+                if let Ok((_, Response::Fetch(_, attributes))) =
+                    imap_proto::parse_response(&message.summary)
+                {
+                    let sequence_number = uids.binary_search(uid).unwrap_or_default() as u32 + 1;
+                    let mut message_out = parse_inspect_fetch(sequence_number, &attributes);
+                    message_out.was_read = message.flags.contains(&"\\Seen".to_string());
+                    message_out.preview =
+                        cached_preview(&cache, &account, &request.mailbox_name, *uid)?
+                            .unwrap_or_default();
+                    response.emails.push(message_out);
+                }
+            }
+            set_next_cursor(&mut response, uid_validity, page_uids.last(), has_more);
+        } else if let Some((first, last)) = page_bounds(
+            response.total_emails_count,
+            request.requested_page_number,
+            request.page_size,
+        ) {
+            let messages =
+                cache.messages(&account, &request.mailbox_name, first - 1, last - first + 1)?;
+            for (index, message) in messages.into_iter().enumerate() {
+                if let Ok((_, Response::Fetch(_, attributes))) =
+                    imap_proto::parse_response(&message.summary)
+                {
+                    let mut message_out = parse_inspect_fetch(first + index as u32, &attributes);
+                    message_out.was_read = message.flags.contains(&"\\Seen".to_string());
+                    message_out.preview =
+                        cached_preview(&cache, &account, &request.mailbox_name, message_out.uid)?
+                            .unwrap_or_default();
+                    response.emails.push(message_out);
+                }
+            }
+            // Sequence numbers count from the oldest email, pages list the newest first like
+            // cursors do
+            response.emails.reverse();
+            let oldest_uid = response.emails.last().map(|email| email.uid);
+            set_next_cursor(&mut response, uid_validity, oldest_uid.as_ref(), first > 1);
+        }
+        return Ok(response);
+    }
+
+    let mut imap_connection = create_imap_connection(
         &credentials.email,
         &credentials.password,
         &credentials.get_imap_string(),
     )
-    .await
-    .unwrap();
+    .await?;
 
-    let mailbox_info = imap_session
-        .select(&encode_utf7_imap(request.mailbox_name.clone()))
-        .unwrap();
-    println!("Mailbox info: {:?}", mailbox_info);
+    let select_response = imap_connection.command(&format!(
+        "SELECT {}",
+        quote_imap_string(&encode_utf7_imap(request.mailbox_name.clone()))
+    ))?;
+    let (exists, uid_validity) = parse_select_response(&select_response.untagged);
+    cache.validate_mailbox(&account, &request.mailbox_name, uid_validity)?;
+    response.total_emails_count = exists;
 
-    if mailbox_info.exists == 0 || mailbox_info.exists < request.requested_page_number * request.page_size
-    {
-        return EmailListOutDTO {
-            mailbox_name: request.mailbox_name.clone(),
-            total_emails_count: mailbox_info.exists,
-            requested_page_number: request.requested_page_number,
-            page_size: request.page_size,
-            emails: vec!(),
+    if (is_sorted || cursor.is_some()) && exists > 0 {
+        let ordered_uids = if is_sorted {
+            sorted_uids(&mut imap_connection, sort_by, descending)?
+        } else {
+            let mut uids = vec![];
+            for line in imap_connection.command("UID SEARCH ALL")?.untagged.iter() {
+                if let Ok((_, Response::IDs(ids))) = imap_proto::parse_response(line) {
+                    uids.extend(ids);
+                }
+            }
+            uids.sort_unstable_by(|first, second| second.cmp(first));
+            uids
+        };
+
+        let (page_uids, has_more) = match cursor {
+            Some(cursor) => page_after_cursor(
+                &ordered_uids,
+                cursor,
+                uid_validity,
+                sort_by,
+                descending,
+                request.page_size,
+            )?,
+            None => {
+                let skipped = (request.requested_page_number * request.page_size) as usize;
+                let page_uids: Vec<u32> = ordered_uids
+                    .iter()
+                    .skip(skipped)
+                    .take(request.page_size as usize)
+                    .cloned()
+                    .collect();
+                let has_more = skipped + page_uids.len() < ordered_uids.len();
+                (page_uids, has_more)
+            }
         };
+
+        if !page_uids.is_empty() {
+            let mut sorted_uid_set = page_uids.clone();
+            sorted_uid_set.sort_unstable();
+            let fetch_response = imap_connection.command(&format!(
+                "UID FETCH {} {}",
+                format_uid_set(&sorted_uid_set),
+                CACHE_SUMMARY_ITEMS
+            ))?;
+
+            let mut emails = HashMap::new();
+            let mut summary_lines = vec![];
+            for line in fetch_response.untagged.iter() {
+                if let Ok((_, Response::Fetch(sequence_number, attributes))) =
+                    imap_proto::parse_response(line)
+                {
+                    let email = parse_inspect_fetch(sequence_number, &attributes);
+                    store_summary(&cache, &account, &request.mailbox_name, line)?;
+                    emails.insert(email.uid, email);
+                    summary_lines.push(line.as_slice());
+                }
+            }
+            let previews = fetch_previews(
+                &mut imap_connection,
+                &cache,
+                &account,
+                &request.mailbox_name,
+                &summary_lines,
+            )?;
+            for (uid, preview) in previews.into_iter() {
+                if let Some(email) = emails.get_mut(&uid) {
+                    email.preview = preview;
+                }
+            }
+            // The server answers in its own order
+            response.emails = page_uids
+                .iter()
+                .filter_map(|uid| emails.remove(uid))
+                .collect();
+        }
+        set_next_cursor(&mut response, uid_validity, page_uids.last(), has_more);
+    } else if let Some((first, last)) =
+        page_bounds(exists, request.requested_page_number, request.page_size)
+    {
+        let fetch_response = imap_connection
+            .command(&format!("FETCH {}:{} {}", first, last, CACHE_SUMMARY_ITEMS))?;
+        let mut summary_lines = vec![];
+        for line in fetch_response.untagged.iter() {
+            if let Ok((_, Response::Fetch(sequence_number, attributes))) =
+                imap_proto::parse_response(line)
+            {
+                response
+                    .emails
+                    .push(parse_inspect_fetch(sequence_number, &attributes));
+                store_summary(&cache, &account, &request.mailbox_name, line)?;
+                summary_lines.push(line.as_slice());
+            }
+        }
+        let mut previews = fetch_previews(
+            &mut imap_connection,
+            &cache,
+            &account,
+            &request.mailbox_name,
+            &summary_lines,
+        )?;
+        for email in response.emails.iter_mut() {
+            email.preview = previews.remove(&email.uid).unwrap_or_default();
+        }
+        // The server answers in its own order, pages list the newest first like cursors do
+        response
+            .emails
+            .sort_unstable_by_key(|email| std::cmp::Reverse(email.sequence_number));
+        let oldest_uid = response.emails.last().map(|email| email.uid);
+        set_next_cursor(&mut response, uid_validity, oldest_uid.as_ref(), first > 1);
     }
 
-    let start_number = mailbox_info.exists - min(mailbox_info.exists, request.requested_page_number * request.page_size);
-    let end_number = start_number - min(start_number, request.page_size) + 1;
-    let messages_raw = imap_session
-        .fetch(
-            format!("{}:{}", end_number, start_number),
-            "(FLAGS BODYSTRUCTURE BODY[TEXT] RFC822.SIZE ENVELOPE INTERNALDATE)",
-        )
-        .unwrap();
+    imap_connection.logout()?;
+    Ok(response)
+}
 
-    let mut messages_out: Vec<EmailInspectOutDTO> = vec![];
+/// Returns the first and last sequence number of the requested page, pages are counted from
+/// the newest email. The oldest page may be shorter than the others.
+fn page_bounds(total: u32, page_number: u32, page_size: u32) -> Option<(u32, u32)> {
+    let skipped = page_number * page_size;
+    if total <= skipped || page_size == 0 {
+        return None;
+    }
 
-    for message in messages_raw.into_iter() {
-        let sender_bytes = message.envelope().unwrap().from.as_ref().unwrap()[0]
-            .mailbox
-            .unwrap_or_default();
+    let last = total - skipped;
+    let first = last - min(last, page_size) + 1;
+    Some((first, last))
+}
 
-        let sender_host_bytes = message.envelope().unwrap().from.as_ref().unwrap_or(&vec![])[0]
-            .host
-            .unwrap_or_default();
+/// Cursors are opaque to clients, inside they carry UIDVALIDITY and the UID of the last listed
+/// email. Unlike page numbers they are not shifted by emails arriving or being deleted.
+fn format_list_cursor(uid_validity: u32, last_uid: u32) -> String {
+    data_encoding::BASE64URL_NOPAD.encode(format!("{}:{}", uid_validity, last_uid).as_bytes())
+}
 
-        let subject_bytes = message.envelope().unwrap().subject.unwrap_or_default();
+fn parse_list_cursor(cursor: &str) -> Result<(u32, u32), std::io::Error> {
+    let invalid_cursor = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid cursor: {}", cursor),
+        )
+    };
 
-        let sender = String::from_utf8(sender_bytes.to_vec()).unwrap_or_default()
-            + "@"
-            + &String::from_utf8(sender_host_bytes.to_vec()).unwrap_or_default();
-        let (_, subject) = encoded_word(subject_bytes).unwrap_or((
-            subject_bytes,
-            String::from_utf8(subject_bytes.to_vec()).unwrap_or("Cant parse subject".to_string()),
+    let decoded = data_encoding::BASE64URL_NOPAD
+        .decode(cursor.as_bytes())
+        .map_err(|_| invalid_cursor())?;
+    let decoded = String::from_utf8(decoded).map_err(|_| invalid_cursor())?;
+    let (uid_validity, last_uid) = decoded.split_once(':').ok_or_else(invalid_cursor)?;
+    Ok((
+        uid_validity.parse().map_err(|_| invalid_cursor())?,
+        last_uid.parse().map_err(|_| invalid_cursor())?,
+    ))
+}
+
+fn set_next_cursor(
+    response: &mut EmailListOutDTO,
+    uid_validity: u32,
+    last_uid: Option<&u32>,
+    has_more: bool,
+) {
+    response.has_more = has_more;
+    if has_more {
+        response.next_cursor = last_uid.map(|uid| format_list_cursor(uid_validity, *uid));
+    }
+}
+
+/// Returns the page of `ordered_uids` following the email of the cursor and whether more emails
+/// come after it. In arrival order the page is found even when that email was deleted since.
+fn page_after_cursor(
+    ordered_uids: &[u32],
+    (cursor_uid_validity, last_uid): (u32, u32),
+    uid_validity: u32,
+    sort_by: &EmailSortKey,
+    descending: bool,
+    page_size: u32,
+) -> Result<(Vec<u32>, bool), std::io::Error> {
+    if cursor_uid_validity != uid_validity {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "The mailbox was rebuilt since the cursor was issued, start from the first page",
         ));
+    }
 
-        let was_read = message.flags().contains(&Flag::Seen);
-        let send_date = message.internal_date().unwrap_or_default().naive_utc();
+    let start = match ordered_uids.iter().position(|uid| *uid == last_uid) {
+        Some(position) => position + 1,
+        None if *sort_by == EmailSortKey::Arrival => ordered_uids.partition_point(|uid| {
+            if descending {
+                *uid > last_uid
+            } else {
+                *uid < last_uid
+            }
+        }),
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "The email of the cursor was deleted, start from the first page",
+            ))
+        }
+    };
 
-        let message_out = EmailInspectOutDTO {
-            from_address: sender,
-            subject,
-            was_read,
-            send_date,
-            sequence_number: message.message
+    let page_uids: Vec<u32> = ordered_uids
+        .iter()
+        .skip(start)
+        .take(page_size as usize)
+        .cloned()
+        .collect();
+    let has_more = start + page_uids.len() < ordered_uids.len();
+    Ok((page_uids, has_more))
+}
+
+pub fn parse_sender_and_subject(envelope: &Envelope) -> (String, String) {
+    let (sender_bytes, sender_host_bytes) =
+        match envelope.from.as_ref().and_then(|from| from.first()) {
+            Some(address) => (
+                address.mailbox.unwrap_or_default(),
+                address.host.unwrap_or_default(),
+            ),
+            None => (&b""[..], &b""[..]),
         };
 
-        messages_out.push(message_out);
-    }
+    let subject_bytes = envelope.subject.unwrap_or_default();
 
-    let response = EmailListOutDTO {
-        mailbox_name: request.mailbox_name.clone(),
-        total_emails_count: mailbox_info.exists,
-        requested_page_number: request.requested_page_number,
-        page_size: request.page_size,
-        emails: messages_out,
-    };
+    let sender = String::from_utf8(sender_bytes.to_vec()).unwrap_or_default()
+        + "@"
+        + &String::from_utf8(sender_host_bytes.to_vec()).unwrap_or_default();
+    let (_, subject) = encoded_word(subject_bytes).unwrap_or((
+        subject_bytes,
+        String::from_utf8(subject_bytes.to_vec()).unwrap_or("Cant parse subject".to_string()),
+    ));
 
-    imap_session.logout().unwrap();
-    response
+    (sender, subject)
+}
+
+pub fn parse_fetch_uid(attributes: &[AttributeValue]) -> Option<u32> {
+    attributes.iter().find_map(|attribute| match attribute {
+        AttributeValue::Uid(uid) => Some(*uid),
+        _ => None,
+    })
+}
+
+pub fn parse_fetch_size(attributes: &[AttributeValue]) -> u32 {
+    attributes
+        .iter()
+        .find_map(|attribute| match attribute {
+            AttributeValue::Rfc822Size(size) => Some(*size),
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
+/// Returns the data of the first fetched body section, like `BODY[TEXT]`.
+pub fn parse_fetch_text<'a>(attributes: &[AttributeValue<'a>]) -> Option<&'a [u8]> {
+    attributes.iter().find_map(|attribute| match attribute {
+        AttributeValue::BodySection { data, .. } => *data,
+        _ => None,
+    })
+}
+
+pub fn parse_fetch_mod_seq(attributes: &[AttributeValue]) -> Option<u64> {
+    attributes.iter().find_map(|attribute| match attribute {
+        AttributeValue::ModSeq(mod_seq) => Some(*mod_seq),
+        _ => None,
+    })
+}
+
+pub fn parse_fetch_flags(attributes: &[AttributeValue]) -> Vec<String> {
+    attributes
+        .iter()
+        .find_map(|attribute| match attribute {
+            AttributeValue::Flags(flags) => {
+                Some(flags.iter().map(|flag| flag.to_string()).collect())
+            }
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
+/// Builds the list item from a FETCH response received on an `ImapConnection`.
+pub fn parse_inspect_fetch(
+    sequence_number: u32,
+    attributes: &[AttributeValue],
+) -> EmailInspectOutDTO {
+    let mut sender_and_subject = (String::new(), String::new());
+    let mut send_date = None;
+    let mut has_attachments = false;
+    for attribute in attributes.iter() {
+        match attribute {
+            AttributeValue::Envelope(envelope) => {
+                sender_and_subject = parse_sender_and_subject(envelope)
+            }
+            AttributeValue::InternalDate(date) => send_date = parse_internal_date(date),
+            AttributeValue::BodyStructure(structure) => {
+                has_attachments = email_preview::has_attachments(structure)
+            }
+            _ => {}
+        }
+    }
+
+    let (from_address, subject) = sender_and_subject;
+    EmailInspectOutDTO {
+        from_address,
+        subject,
+        was_read: parse_fetch_flags(attributes).contains(&"\\Seen".to_string()),
+        send_date: send_date.unwrap_or_default().naive_utc(),
+        sequence_number,
+        uid: parse_fetch_uid(attributes).unwrap_or_default(),
+        size_octets: parse_fetch_size(attributes),
+        has_attachments,
+        preview: String::new(),
+    }
 }
 
 fn parse_body_structure(
     structure: &imap_proto::BodyStructure,
-    message: &Fetch,
+    text: &[u8],
     description: &mut EmailAnalysis,
     separator: String,
     match_index: usize,
+    section: String,
 ) {
+    // A message which is not multipart has its body at section 1
+    let part_section = if section.is_empty() {
+        "1".to_string()
+    } else {
+        section.clone()
+    };
+
     match structure {
         imap_proto::BodyStructure::Basic {
             common,
@@ -284,32 +840,14 @@ fn parse_body_structure(
                 common, other
             );
 
-            let regex_string = format!(
-                r"{}(\r\n|\n)[\S\s]*?(\r\n|\n)(\r\n|\n)([\S\s]*?)(\r\n|\n)--",
-                separator
-            );
-            let regex = Regex::new(&regex_string).unwrap();
-            let body_matches = regex.captures_iter(message.text().unwrap());
-
-            let mut attachment_description = EmailPartDescription {
-                file_name: "Unparsed attachment".to_string(),
-                size_octets: other.octets,
-                is_file: false,
-                bytes_start: 0,
-                bytes_end: 0,
-                is_email_text: false,
-                encoding: decide_encoding(other),
-            };
-
-            modify_part_description(
-                body_matches,
+            description.attachments.push(describe_single_part(
+                text,
+                &separator,
                 match_index,
-                &mut attachment_description,
+                part_section,
                 common,
-                other
-            );
-
-            description.attachments.push(attachment_description);
+                other,
+            ));
         }
         imap_proto::BodyStructure::Text {
             common,
@@ -324,42 +862,76 @@ fn parse_body_structure(
             );
             println!("Separator: {}", separator);
 
-            let regex_string = format!(
-                r"{}(\r\n|\n)[\S\s]*?(\r\n|\n)(\r\n|\n)([\S\s]*?)(\r\n|\n)--",
-                separator
-            );
-            let regex = Regex::new(&regex_string).unwrap();
-            let body_matches = regex.captures_iter(message.text().unwrap());
-
-            let mut attachment_description = EmailPartDescription {
-                file_name: "Unparsed attachment".to_string(),
-                size_octets: other.octets,
-                is_file: false,
-                bytes_start: 0,
-                bytes_end: 0,
-                is_email_text: false,
-                encoding: decide_encoding(other),
-            };
-
-            modify_part_description(
-                body_matches,
+            description.attachments.push(describe_single_part(
+                text,
+                &separator,
                 match_index,
-                &mut attachment_description,
+                part_section,
                 common,
-                other
-            );
-
-            description.attachments.push(attachment_description)
+                other,
+            ));
         }
         imap_proto::BodyStructure::Message {
-            common: _,
-            other: _,
-            envelope: _,
-            body: _,
+            common,
+            other,
+            envelope,
+            body,
             lines: _,
             extension: _,
         } => {
-            println!("Message body structure ignored");
+            let mut part_description = describe_single_part(
+                text,
+                &separator,
+                match_index,
+                part_section.clone(),
+                common,
+                other,
+            );
+            // The embedded message has its own boundaries, which would end the match early
+            (part_description.bytes_start, part_description.bytes_end) =
+                locate_embedded_message(text, &separator, match_index);
+
+            // The part is the whole embedded message, its parts are located in its body
+            let message = &text[part_description.bytes_start..part_description.bytes_end];
+            let body_start = header_end(message);
+            let (from_address, subject) = parse_sender_and_subject(envelope);
+            let mut embedded_message = EmbeddedMessageDescription {
+                section: part_section.clone(),
+                from_address,
+                subject,
+                send_date: envelope
+                    .date
+                    .and_then(|date| {
+                        DateTime::parse_from_rfc2822(String::from_utf8_lossy(date).trim()).ok()
+                    })
+                    .map(|date| date.naive_utc())
+                    .unwrap_or_default(),
+                text: message[body_start..].to_vec(),
+                analysis: EmailAnalysis {
+                    attachments: vec![],
+                    messages: vec![],
+                },
+            };
+
+            // Parts of a multipart body are numbered below the message, a single part is `.1`
+            let body_section = match body.as_ref() {
+                imap_proto::BodyStructure::Multipart { .. } => part_section,
+                _ => format!("{}.1", part_section),
+            };
+            parse_body_structure(
+                body,
+                &embedded_message.text.clone(),
+                &mut embedded_message.analysis,
+                String::new(),
+                0,
+                body_section,
+            );
+
+            // A forwarded email attached as a file can also be downloaded as a whole
+            if part_description.is_file {
+                description.attachments.push(part_description);
+            }
+            description.messages.push(embedded_message);
         }
         imap_proto::BodyStructure::Multipart {
             common,
@@ -375,13 +947,19 @@ fn parse_body_structure(
                     None
                 };
 
+                let child_section = if section.is_empty() {
+                    (part_index + 1).to_string()
+                } else {
+                    format!("{}.{}", section, part_index + 1)
+                };
                 if let Some(boundary_value) = boundary {
                     parse_body_structure(
                         body,
-                        message,
+                        text,
                         description,
                         boundary_value.1.to_string(),
                         part_index,
+                        child_section,
                     );
                 }
             }
@@ -389,7 +967,175 @@ fn parse_body_structure(
     }
 }
 
-fn decide_encoding(other: &imap_proto::BodyContentSinglePart) -> EncodingType {
+fn describe_single_part(
+    text: &[u8],
+    separator: &str,
+    match_index: usize,
+    section: String,
+    common: &imap_proto::BodyContentCommon,
+    other: &imap_proto::BodyContentSinglePart,
+) -> EmailPartDescription {
+    let regex_string = format!(
+        r"{}(\r\n|\n)[\S\s]*?(\r\n|\n)(\r\n|\n)([\S\s]*?)(\r\n|\n)--",
+        regex::escape(separator)
+    );
+    let regex = Regex::new(&regex_string).unwrap();
+    let body_matches = regex.captures_iter(text);
+
+    let mut attachment_description = EmailPartDescription {
+        file_name: "Unparsed attachment".to_string(),
+        section,
+        size_octets: other.octets,
+        is_file: false,
+        is_inline: false,
+        bytes_start: 0,
+        bytes_end: 0,
+        is_email_text: false,
+        is_email_html: false,
+        content_type: format!("{}/{}", common.ty.ty, common.ty.subtype).to_lowercase(),
+        content_id: other.id.map(|id| {
+            id.trim()
+                .trim_start_matches('<')
+                .trim_end_matches('>')
+                .to_string()
+        }),
+        encoding: decide_encoding(other),
+    };
+
+    modify_part_description(
+        body_matches,
+        match_index,
+        &mut attachment_description,
+        common,
+        other,
+    );
+
+    attachment_description
+}
+
+/// Returns where the content of the `match_index` part between the given boundaries starts
+/// and ends. Without a boundary the whole text is the part.
+fn locate_embedded_message(text: &[u8], separator: &str, match_index: usize) -> (usize, usize) {
+    if separator.is_empty() {
+        return (0, text.len());
+    }
+
+    let delimiter = format!("--{}", separator);
+    let delimiters: Vec<usize> = text
+        .windows(delimiter.len())
+        .enumerate()
+        .filter(|(position, window)| {
+            *window == delimiter.as_bytes() && (*position == 0 || text[position - 1] == b'\n')
+        })
+        .map(|(position, _)| position)
+        .collect();
+    let (start, end) = match (delimiters.get(match_index), delimiters.get(match_index + 1)) {
+        (Some(start), Some(end)) => (*start, *end),
+        _ => return (0, 0),
+    };
+
+    // Skips the rest of the delimiter line and the headers of the part
+    let part_start = text[start..end]
+        .iter()
+        .position(|byte| *byte == b'\n')
+        .map(|position| start + position + 1)
+        .unwrap_or(end);
+    let content_start = part_start + header_end(&text[part_start..end]);
+    // The line break before the next delimiter belongs to it
+    let mut content_end = end;
+    if content_end > content_start && text[content_end - 1] == b'\n' {
+        content_end -= 1;
+        if content_end > content_start && text[content_end - 1] == b'\r' {
+            content_end -= 1;
+        }
+    }
+    (content_start, content_end.max(content_start))
+}
+
+/// Position right after the empty line ending the header section.
+fn header_end(part: &[u8]) -> usize {
+    if part.starts_with(b"\r\n") {
+        return 2;
+    }
+    if part.starts_with(b"\n") {
+        return 1;
+    }
+    part.windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|position| position + 4)
+        .or_else(|| {
+            part.windows(2)
+                .position(|window| window == b"\n\n")
+                .map(|position| position + 2)
+        })
+        .unwrap_or(part.len())
+}
+
+/// Lists every part below the structure with its section path, embedded messages are listed
+/// before their own parts. Multipart containers are left out.
+pub fn body_parts<'a>(
+    structure: &'a imap_proto::BodyStructure<'a>,
+) -> Vec<(String, &'a imap_proto::BodyStructure<'a>)> {
+    let mut parts = vec![];
+    collect_body_parts(structure, String::new(), &mut parts);
+    parts
+}
+
+fn collect_body_parts<'a>(
+    structure: &'a imap_proto::BodyStructure<'a>,
+    section: String,
+    parts: &mut Vec<(String, &'a imap_proto::BodyStructure<'a>)>,
+) {
+    let part_section = if section.is_empty() {
+        "1".to_string()
+    } else {
+        section.clone()
+    };
+    match structure {
+        imap_proto::BodyStructure::Multipart { bodies, .. } => {
+            for (index, body) in bodies.iter().enumerate() {
+                let child_section = if section.is_empty() {
+                    (index + 1).to_string()
+                } else {
+                    format!("{}.{}", section, index + 1)
+                };
+                collect_body_parts(body, child_section, parts);
+            }
+        }
+        imap_proto::BodyStructure::Message { body, .. } => {
+            parts.push((part_section.clone(), structure));
+            let body_section = match body.as_ref() {
+                imap_proto::BodyStructure::Multipart { .. } => part_section,
+                _ => format!("{}.1", part_section),
+            };
+            collect_body_parts(body, body_section, parts);
+        }
+        _ => parts.push((part_section, structure)),
+    }
+}
+
+/// Finds the part at an IMAP section path like `2.1` in the structure of the whole message.
+pub fn find_body_part<'a>(
+    structure: &'a imap_proto::BodyStructure<'a>,
+    section: &str,
+) -> Option<&'a imap_proto::BodyStructure<'a>> {
+    let mut part = structure;
+    for (depth, number) in section.split('.').enumerate() {
+        let index = number.parse::<usize>().ok()?.checked_sub(1)?;
+        // Numbers below an embedded message count the parts of its body
+        if let (imap_proto::BodyStructure::Message { body, .. }, true) = (part, depth > 0) {
+            part = body;
+        }
+        part = match part {
+            imap_proto::BodyStructure::Multipart { bodies, .. } => bodies.get(index)?,
+            _ if index == 0 => part,
+            _ => return None,
+        };
+    }
+    Some(part)
+}
+
+pub fn decide_encoding(other: &imap_proto::BodyContentSinglePart) -> EncodingType {
     match other.transfer_encoding {
         imap_proto::ContentEncoding::SevenBit => EncodingType::SevenBit,
         imap_proto::ContentEncoding::Base64 => EncodingType::Base64,
@@ -403,140 +1149,337 @@ fn modify_part_description(
     match_index: usize,
     attachment_description: &mut EmailPartDescription,
     common: &imap_proto::BodyContentCommon,
-    single_part: &imap_proto::BodyContentSinglePart
+    single_part: &imap_proto::BodyContentSinglePart,
 ) {
     if let Some(capture_match) = body_matches.nth(match_index) {
         if let Some(result_match) = capture_match.get(4) {
             attachment_description.bytes_start = result_match.start();
             attachment_description.bytes_end = result_match.end();
         }
-    }
-    else {
+    } else {
         attachment_description.bytes_start = 0;
         attachment_description.bytes_end = single_part.octets as usize;
     }
 
+    let is_attachment = common
+        .disposition
+        .as_ref()
+        .map(|disposition| disposition.ty.eq_ignore_ascii_case("ATTACHMENT"))
+        .unwrap_or(false);
+    let is_text = common.ty.ty.eq_ignore_ascii_case("TEXT");
+    // Images of an HTML body come with a Content-ID, mostly in multipart/related
+    attachment_description.is_inline = !is_attachment
+        && !is_text
+        && (attachment_description.content_id.is_some()
+            || common
+                .disposition
+                .as_ref()
+                .map(|disposition| disposition.ty.eq_ignore_ascii_case("INLINE"))
+                .unwrap_or(false));
+
     if let Some(disposition) = &common.disposition {
         if let Some(parameters) = &disposition.params {
-            if let Some(file_name) = parameters.iter().find(|(desc, _)| desc == &"FILENAME") {
-                attachment_description.is_file = true;
+            if let Some(file_name) = parameters
+                .iter()
+                .find(|(desc, _)| desc.eq_ignore_ascii_case("FILENAME"))
+            {
+                attachment_description.is_file = !attachment_description.is_inline;
                 attachment_description.file_name = file_name.1.to_string();
             }
         }
-    } else {
-        attachment_description.is_email_text =
-            common.ty.ty == "TEXT" && common.ty.subtype == "PLAIN";
+    }
+    if !is_attachment && !attachment_description.is_file && is_text {
+        attachment_description.is_email_text = common.ty.subtype.eq_ignore_ascii_case("PLAIN");
+        attachment_description.is_email_html = common.ty.subtype.eq_ignore_ascii_case("HTML");
         attachment_description.file_name = "Email text".to_string();
     }
 }
 
+/// Downloads a single attachment by its part id with the content type from BODYSTRUCTURE.
+/// `inline` is only honored for safe image types, see `part_response`. Single byte ranges
+/// are supported, so big files can be resumed or streamed by the browser. Nothing is served
+/// before all attachment scanners let the part pass.
 async fn download_attachment_from_email(
     session: Session,
+    http_request: HttpRequest,
+    scanners: web::Data<AttachmentScanners>,
+    cache: web::Data<MessageCache>,
     request: web::Query<EmailAttachmentInDTO>,
-) -> impl Responder {
-    let credentials = check_is_valid_session(&session).unwrap();
-    let mut imap_session = create_imap_session(
+) -> Result<HttpResponse, std::io::Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let fetch_command = match (request.uid, request.sequence_number) {
+        (Some(uid), _) => format!("UID FETCH {}", uid),
+        (None, Some(sequence_number)) => format!("FETCH {}", sequence_number),
+        (None, None) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Either uid or sequence_number has to be set",
+            ))
+        }
+    };
+
+    let mut imap_connection = create_imap_connection(
         &credentials.email,
         &credentials.password,
         &credentials.get_imap_string(),
     )
-    .await
-    .unwrap();
+    .await?;
+    imap_connection.command(&format!(
+        "EXAMINE {}",
+        quote_imap_string(&encode_utf7_imap(request.mailbox_name.clone()))
+    ))?;
 
-    imap_session
-        .select(&encode_utf7_imap(request.mailbox_name.clone()))
-        .unwrap();
-    let email_message_raw = &imap_session
-        .fetch(
-            format!("{}", request.sequence_number),
-            "(FLAGS BODYSTRUCTURE BODY[TEXT] ENVELOPE INTERNALDATE)",
-        )
-        .unwrap()[0];
-    let structure = email_message_raw.bodystructure().unwrap();
-
-    let mut description = EmailAnalysis {
-        plain_text_octets: 0,
-        attachments: vec![],
+    let section = match (&request.part_id, &request.attachment_name) {
+        (Some(part_id), _) => validate_section(part_id)?.to_string(),
+        (None, Some(attachment_name)) => {
+            let structure_response =
+                imap_connection.command(&format!("{} (UID BODYSTRUCTURE)", fetch_command))?;
+            find_attachment_section(&structure_response.untagged, attachment_name)?
+        }
+        (None, None) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Either part_id or attachment_name has to be set",
+            ))
+        }
     };
-    parse_body_structure(
-        structure,
-        email_message_raw,
-        &mut description,
-        String::new(),
-        0,
-    );
+    let fetch_response = imap_connection.command(&format!(
+        "{} (UID BODYSTRUCTURE BODY.PEEK[{}])",
+        fetch_command, section
+    ))?;
+    imap_connection.logout()?;
 
-    let found_attachment = description
-        .attachments
-        .iter()
-        .find(|attachment| attachment.file_name == request.attachment_name);
-
-    let result_http_response = match found_attachment {
-        Some(description) => {
-            let result_bytes =
-                &email_message_raw.text().unwrap()[description.bytes_start..description.bytes_end];
-
-            let decoded_bytes = match description.encoding {
-                EncodingType::SevenBit => result_bytes.to_vec(),
-                EncodingType::Base64 => match data_encoding::BASE64_MIME.decode(result_bytes) {
-                    Ok(bytes) => bytes,
-                    Err(error) => {
-                        println!("Decoding error: {}", error);
-                        vec![]
-                    }
-                },
-                EncodingType::QuotedPrintable => quoted_printable::decode(result_bytes, ParseMode::Robust).unwrap_or_default().to_vec(),
-                EncodingType::Other => result_bytes.to_vec()
-            };
+    // part_response turns anything but a safe image into a download again
+    let disposition = match request.disposition {
+        Some(AttachmentDisposition::Inline) => DispositionType::Inline,
+        _ => DispositionType::Attachment,
+    };
+    for line in fetch_response.untagged.iter() {
+        if let Ok((_, Response::Fetch(_, attributes))) = imap_proto::parse_response(line) {
+            let part = attributes.iter().find_map(|attribute| match attribute {
+                AttributeValue::BodyStructure(structure) => find_body_part(structure, &section),
+                _ => None,
+            });
+            if let (Some(part), Some(data)) = (part, parse_fetch_text(&attributes)) {
+                let target = part_scan_target(
+                    part,
+                    &section,
+                    &request.mailbox_name,
+                    parse_fetch_uid(&attributes),
+                );
+                if let Some(refusal) =
+                    scan_part(&scanners, &cache, &credentials.email, part, data, target).await?
+                {
+                    return Ok(refusal);
+                }
+                return part_response(part, data, &section, disposition, &http_request);
+            }
+        }
+    }
 
-            let content_disposition = ContentDisposition {
-                disposition: DispositionType::Attachment,
-                parameters: vec![DispositionParam::Filename(description.file_name.clone())],
-            };
+    Err(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        "Attachment not found",
+    ))
+}
 
-            HttpResponse::Ok()
-                .insert_header(ContentEncoding::Identity)
-                .insert_header(content_disposition)
-                .content_type("application/octet-stream")
-                .body(decoded_bytes)
+/// Finds the only attachment with the given file name, text parts are never matched.
+fn find_attachment_section(
+    untagged: &[Vec<u8>],
+    attachment_name: &str,
+) -> Result<String, std::io::Error> {
+    let mut sections = vec![];
+    for line in untagged.iter() {
+        if let Ok((_, Response::Fetch(_, attributes))) = imap_proto::parse_response(line) {
+            for attribute in attributes.iter() {
+                if let AttributeValue::BodyStructure(structure) = attribute {
+                    sections.extend(
+                        body_parts(structure)
+                            .into_iter()
+                            .filter(|(_, part)| {
+                                attachment_file_name(part).as_deref() == Some(attachment_name)
+                            })
+                            .map(|(section, _)| section),
+                    );
+                }
+            }
         }
-        None => HttpResponse::NotFound().body("404 Not Found"),
-    };
+    }
 
-    imap_session.logout().unwrap();
-    result_http_response
+    match sections.len() {
+        0 => Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "Attachment not found",
+        )),
+        1 => Ok(sections.remove(0)),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Several attachments have this name, part_id has to be used",
+        )),
+    }
 }
 
-async fn get_mailboxes(session: Session) -> impl Responder {
-    let credentials = check_is_valid_session(&session).unwrap();
-    let mut imap_session = create_imap_session(
+/// File name of a part which is an attachment, from Content-Disposition or the older name
+/// parameter of Content-Type.
+pub fn attachment_file_name(part: &imap_proto::BodyStructure) -> Option<String> {
+    let common = match part {
+        imap_proto::BodyStructure::Basic { common, .. }
+        | imap_proto::BodyStructure::Text { common, .. }
+        | imap_proto::BodyStructure::Message { common, .. } => common,
+        imap_proto::BodyStructure::Multipart { .. } => return None,
+    };
+    let find_param = |params: &Option<Vec<(&str, &str)>>, key: &str| {
+        params
+            .iter()
+            .flatten()
+            .find(|(name, _)| name.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.to_string())
+    };
+    common
+        .disposition
+        .as_ref()
+        .and_then(|disposition| find_param(&disposition.params, "filename"))
+        .or_else(|| find_param(&common.ty.params, "name"))
+}
+
+async fn get_mailboxes(
+    session: Session,
+    request: web::Query<MailboxListInDTO>,
+) -> Result<MailboxListOutDTO, std::io::Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let mut imap_connection = create_imap_connection(
         &credentials.email,
         &credentials.password,
         &credentials.get_imap_string(),
     )
-    .await
-    .unwrap();
+    .await?;
+
+    let pattern = quote_imap_string(&encode_utf7_imap(
+        request
+            .name_pattern
+            .clone()
+            .unwrap_or_else(|| "*".to_string()),
+    ));
+
+    // LIST-STATUS returns the counts together with the names in a single round trip
+    let list_command = if imap_connection.has_capability("LIST-STATUS") {
+        format!(
+            "LIST \"\" {} RETURN (STATUS {})",
+            pattern, MAILBOX_STATUS_ITEMS
+        )
+    } else {
+        format!("LIST \"\" {}", pattern)
+    };
+    let list_response = imap_connection.command(&list_command)?;
+
+    let subscribed_names = if request.subscribed_only.unwrap_or(false) {
+        let lsub_response = imap_connection.command(&format!("LSUB \"\" {}", pattern))?;
+        let names: HashSet<String> = lsub_response
+            .untagged
+            .iter()
+            .filter_map(|line| match imap_proto::parse_response(line) {
+                Ok((_, Response::MailboxData(MailboxDatum::List { name, .. }))) => {
+                    Some(name.to_string())
+                }
+                _ => None,
+            })
+            .collect();
+        Some(names)
+    } else {
+        None
+    };
 
-    let mailboxes = imap_session.list(None, Some("*")).unwrap();
     let mut mailbox_names = vec![];
+    let mut mailbox_statuses = HashMap::new();
+    for line in list_response.untagged.iter() {
+        match imap_proto::parse_response(line) {
+            Ok((_, Response::MailboxData(MailboxDatum::List { flags, name, .. }))) => {
+                let selectable = !flags.iter().any(|flag| {
+                    flag.eq_ignore_ascii_case("\\Noselect")
+                        || flag.eq_ignore_ascii_case("\\NonExistent")
+                });
+                let subscribed = match &subscribed_names {
+                    Some(names) => names.contains(name),
+                    None => true,
+                };
+
+                if selectable && subscribed {
+                    mailbox_names.push(name.to_string());
+                }
+            }
+            Ok((_, Response::MailboxData(MailboxDatum::Status { mailbox, status }))) => {
+                mailbox_statuses.insert(mailbox.to_string(), status);
+            }
+            _ => {}
+        }
+    }
 
-    for mailbox in mailboxes.iter() {
-        if !mailbox.attributes().contains(&NameAttribute::NoSelect)
-        {
-            let mailbox_info = imap_session
-                .examine(mailbox.name())
-                .unwrap();
+    // Without LIST-STATUS ask for all the missing counts at once instead of one by one
+    let missing_names: Vec<&String> = mailbox_names
+        .iter()
+        .filter(|name| !mailbox_statuses.contains_key(*name))
+        .collect();
+    if !missing_names.is_empty() {
+        let status_commands: Vec<String> = missing_names
+            .iter()
+            .map(|name| {
+                format!(
+                    "STATUS {} {}",
+                    quote_imap_string(name),
+                    MAILBOX_STATUS_ITEMS
+                )
+            })
+            .collect();
+        let status_responses = imap_connection.pipeline(&status_commands)?;
 
-            let mailbox = MailboxOutInfoDTO {
-                name: decode_utf7_imap(mailbox.name().to_string()),
-                emails_count: mailbox_info.exists,
+        for (name, status_response) in missing_names.into_iter().zip(status_responses) {
+            let status_response = match status_response {
+                Ok(response) => response,
+                Err(err) => {
+                    println!("STATUS of mailbox {} failed: {}", name, err);
+                    continue;
+                }
             };
-            mailbox_names.push(mailbox);
+            for line in status_response.untagged.iter() {
+                if let Ok((_, Response::MailboxData(MailboxDatum::Status { status, .. }))) =
+                    imap_proto::parse_response(line)
+                {
+                    mailbox_statuses.insert(name.clone(), status);
+                }
+            }
+        }
+    }
+
+    let mut mailboxes = vec![];
+    for name in mailbox_names.into_iter() {
+        let mut mailbox = MailboxOutInfoDTO {
+            name: decode_utf7_imap(name.clone()),
+            emails_count: 0,
+            unread_count: 0,
+            recent_count: 0,
+            uid_next: None,
+            uid_validity: None,
+        };
+
+        if let Some(status) = mailbox_statuses.get(&name) {
+            for attribute in status.iter() {
+                match attribute {
+                    StatusAttribute::Messages(count) => mailbox.emails_count = *count,
+                    StatusAttribute::Unseen(count) => mailbox.unread_count = *count,
+                    StatusAttribute::Recent(count) => mailbox.recent_count = *count,
+                    StatusAttribute::UidNext(uid) => mailbox.uid_next = Some(*uid),
+                    StatusAttribute::UidValidity(validity) => {
+                        mailbox.uid_validity = Some(*validity)
+                    }
+                    StatusAttribute::HighestModSeq(_) => {}
+                }
+            }
         }
+        mailboxes.push(mailbox);
     }
 
-    imap_session.logout().unwrap();
-    MailboxListOutDTO { mailboxes: mailbox_names }
+    imap_connection.logout()?;
+    Ok(MailboxListOutDTO { mailboxes })
 }
 
 pub fn email_imap_config(cfg: &mut web::ServiceConfig) {
@@ -0,0 +1,252 @@
+use std::{
+    fs::{read, remove_file},
+    io::Error,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_multipart::Multipart;
+use actix_session::Session;
+use actix_web::web;
+use chrono::{DateTime, FixedOffset};
+use futures_util::{StreamExt, TryStreamExt};
+use utf7_imap::encode_utf7_imap;
+
+use crate::{
+    handlers::auth::models::SignInMessage,
+    storage::message_cache::MessageCache,
+    utils::{
+        utils_imap::quote_imap_string, utils_multipart::write_field_to_file,
+        utils_session::check_is_valid_session, utils_transports::create_imap_connection,
+    },
+};
+
+use super::{
+    email_source::parse_headers,
+    models::{EmailImportOutDTO, EmailImportResultOutDTO},
+};
+
+pub struct ImportUpload {
+    pub mailbox_name: Option<String>,
+    pub flags: String,
+    /// `mbox` or `maildir` for archive imports
+    pub format: Option<String>,
+    /// Original file names with the paths of their temporary copies
+    pub files: Vec<(String, String)>,
+}
+
+/// Uploads `.eml` files into a mailbox with APPEND. Every file keeps its Date header as the
+/// internal date, so imported emails are listed where they originally belonged.
+async fn import_emails(
+    mut payload: Multipart,
+    session: Session,
+    cache: web::Data<MessageCache>,
+) -> Result<EmailImportOutDTO, Error> {
+    let credentials = check_is_valid_session(&session)?;
+
+    let mut upload = ImportUpload {
+        mailbox_name: None,
+        flags: String::new(),
+        format: None,
+        files: vec![],
+    };
+    let upload_result = read_import_upload(&mut payload, &mut upload).await;
+    let import_result = match upload_result {
+        Ok(()) => append_files(&credentials, &upload).await,
+        Err(err) => Err(err),
+    };
+
+    for (_, path) in upload.files.into_iter() {
+        let _ = web::block(move || remove_file(path)).await;
+    }
+    // The cache lists the new emails after the next sync
+    if let (Ok(_), Some(mailbox_name)) = (&import_result, upload.mailbox_name.as_deref()) {
+        cache.mark_unsynced(&credentials.email, mailbox_name)?;
+    }
+    import_result
+}
+
+pub async fn read_import_upload(
+    payload: &mut Multipart,
+    upload: &mut ImportUpload,
+) -> Result<(), Error> {
+    web::block(|| std::fs::create_dir_all("./tmp"))
+        .await
+        .map_err(|err| Error::other(format!("Creating directory Blocking Error {:?}", err)))??;
+    let upload_id = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+
+    while let Some(mut field) = payload
+        .try_next()
+        .await
+        .map_err(|err| Error::other(format!("Reading upload Error {:?}", err)))?
+    {
+        match field.content_disposition().get_filename() {
+            Some(file_name) => {
+                // The user given name is only reported back, never used as a path
+                let file_path = format!("./tmp/import-{}-{}.eml", upload_id, upload.files.len());
+                upload
+                    .files
+                    .push((file_name.to_string(), file_path.clone()));
+                write_field_to_file(&mut field, file_path).await?;
+            }
+            None => {
+                let name = field.content_disposition().get_name().map(str::to_string);
+                let mut value = vec![];
+                while let Some(Ok(chunk)) = field.next().await {
+                    value.extend_from_slice(&chunk);
+                }
+                let value = String::from_utf8_lossy(&value).trim().to_string();
+                match name.as_deref() {
+                    Some("mailbox_name") => upload.mailbox_name = Some(value),
+                    Some("flags") => upload.flags = value,
+                    Some("format") => upload.format = Some(value),
+                    // Fields of newer clients are ignored
+                    _ => {}
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn append_files(
+    credentials: &SignInMessage,
+    upload: &ImportUpload,
+) -> Result<EmailImportOutDTO, Error> {
+    let mailbox_name = upload.mailbox_name.clone().ok_or_else(|| {
+        Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "mailbox_name has to be set",
+        )
+    })?;
+    let flags = parse_import_flags(&upload.flags);
+
+    let mut imap_connection = create_imap_connection(
+        &credentials.email,
+        &credentials.password,
+        &credentials.get_imap_string(),
+    )
+    .await?;
+    let mailbox = quote_imap_string(&encode_utf7_imap(mailbox_name.clone()));
+    let has_uid_plus = imap_connection.has_capability("UIDPLUS");
+
+    let mut results = vec![];
+    for (file_name, path) in upload.files.iter() {
+        let path = path.clone();
+        let message = web::block(move || read(path)).await.map_err(|err| {
+            Error::other(format!("Error reading file Blocking Error {:?}", err))
+        })??;
+        let message = normalize_line_endings(&message);
+
+        let mut result = EmailImportResultOutDTO {
+            file_name: file_name.clone(),
+            imported: false,
+            uid: None,
+            error: None,
+        };
+        if message.is_empty() {
+            result.error = Some("The file is empty".to_string());
+            results.push(result);
+            continue;
+        }
+
+        let arguments = append_arguments(&flags, parse_internal_date_of(&message));
+        match imap_connection.append(&mailbox, &arguments, &message) {
+            Ok(response) => {
+                result.imported = true;
+                if has_uid_plus {
+                    result.uid = parse_append_uid(&response.status);
+                }
+            }
+            // A refused file does not stop the others, a broken connection does
+            Err(err) if err.kind() == std::io::ErrorKind::Other => {
+                result.error = Some(err.to_string())
+            }
+            Err(err) => return Err(err),
+        }
+        results.push(result);
+    }
+    imap_connection.logout()?;
+
+    Ok(EmailImportOutDTO {
+        mailbox_name,
+        results,
+    })
+}
+
+/// Keeps system flags and keywords, anything else could break the command. \Recent can only
+/// be set by the server.
+pub fn parse_import_flags(flags: &str) -> Vec<String> {
+    flags
+        .split(|char: char| char.is_whitespace() || char == ',')
+        .filter(|flag| {
+            let keyword = flag.strip_prefix('\\').unwrap_or(flag);
+            !keyword.is_empty()
+                && keyword
+                    .chars()
+                    .all(|char| char.is_ascii_alphanumeric() || matches!(char, '$' | '_' | '-'))
+                && !flag.eq_ignore_ascii_case("\\Recent")
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+/// Builds the optional flag list and internal date of APPEND.
+pub fn append_arguments(flags: &[String], internal_date: Option<String>) -> String {
+    let mut arguments = String::new();
+    if !flags.is_empty() {
+        arguments.push_str(&format!(" ({})", flags.join(" ")));
+    }
+    if let Some(internal_date) = internal_date {
+        arguments.push_str(&format!(" \"{}\"", internal_date));
+    }
+    arguments
+}
+
+pub fn format_append_date(date: DateTime<FixedOffset>) -> String {
+    date.format("%d-%b-%Y %H:%M:%S %z").to_string()
+}
+
+/// Returns the Date header in the date-time format of APPEND.
+pub fn parse_internal_date_of(message: &[u8]) -> Option<String> {
+    let header_end = message
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|position| position + 2)
+        .unwrap_or(message.len());
+    parse_headers(&message[..header_end])
+        .into_iter()
+        .find(|header| header.name.eq_ignore_ascii_case("Date"))
+        .and_then(|header| DateTime::parse_from_rfc2822(header.value.trim()).ok())
+        .map(format_append_date)
+}
+
+/// Files saved on Unix often use bare LF, IMAP servers expect CRLF.
+pub fn normalize_line_endings(message: &[u8]) -> Vec<u8> {
+    let mut normalized = Vec::with_capacity(message.len());
+    let mut previous = 0;
+    for byte in message.iter() {
+        if *byte == b'\n' && previous != b'\r' {
+            normalized.push(b'\r');
+        }
+        normalized.push(*byte);
+        previous = *byte;
+    }
+    normalized
+}
+
+/// Reads the UID from `[APPENDUID <uidvalidity> <uid>] APPEND completed`.
+pub fn parse_append_uid(status: &str) -> Option<u32> {
+    let code = status.strip_prefix('[')?.split(']').next()?;
+    let mut parts = code.split_whitespace();
+    if !parts.next()?.eq_ignore_ascii_case("APPENDUID") {
+        return None;
+    }
+    parts.nth(1)?.parse().ok()
+}
+
+pub fn email_import_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/email/import").route(web::post().to(import_emails)));
+}
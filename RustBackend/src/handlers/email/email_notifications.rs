@@ -0,0 +1,297 @@
+use std::{
+    collections::HashMap,
+    io::Error,
+    thread,
+    time::{Duration, Instant},
+};
+
+use actix_session::Session;
+use actix_web::{
+    http::header::{CacheControl, CacheDirective},
+    web::{self, Bytes},
+    HttpResponse,
+};
+use imap_proto::Response;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use utf7_imap::encode_utf7_imap;
+
+use crate::{
+    constants::{
+        IDLE_READ_TIMEOUT_SECS, IDLE_RENEW_INTERVAL_SECS, NOOP_POLL_INTERVAL_SECS,
+        NOTIFICATION_CHANNEL_SIZE, NOTIFICATION_MAX_MAILBOXES,
+    },
+    utils::{
+        utils_imap::{format_uid_set, is_timeout, quote_imap_string, ImapConnection},
+        utils_session::check_is_valid_session,
+        utils_transports::create_imap_connection,
+    },
+};
+
+use super::{
+    email_imap::{parse_fetch_flags, parse_fetch_uid, parse_inspect_fetch},
+    models::{MailboxEventOutDTO, MailboxEventType, NotificationsInDTO},
+};
+
+// SSE comment line, ignored by EventSource but lets us notice a closed connection
+const KEEP_ALIVE_COMMENT: &str = ": keep-alive\n\n";
+
+/// Streams changes of the watched mailboxes as Server-Sent Events. Every mailbox gets its own
+/// IMAP connection which waits in IDLE (or polls with NOOP) on a separate thread.
+async fn watch_mailboxes(
+    session: Session,
+    request: web::Query<NotificationsInDTO>,
+) -> Result<HttpResponse, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let mailbox_names = request
+        .mailbox_names
+        .clone()
+        .unwrap_or_else(|| "INBOX".to_string());
+    let mut watched_names: Vec<&str> = vec![];
+    for name in mailbox_names
+        .split(',')
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())
+    {
+        if !watched_names.contains(&name) {
+            watched_names.push(name);
+        }
+    }
+    if watched_names.len() > NOTIFICATION_MAX_MAILBOXES {
+        return Ok(HttpResponse::BadRequest().body(format!(
+            "At most {} mailboxes can be watched at once",
+            NOTIFICATION_MAX_MAILBOXES
+        )));
+    }
+
+    let (sender, receiver) = channel::<String>(NOTIFICATION_CHANNEL_SIZE);
+
+    for mailbox_name in watched_names.into_iter() {
+        let mut imap_connection = create_imap_connection(
+            &credentials.email,
+            &credentials.password,
+            &credentials.get_imap_string(),
+        )
+        .await?;
+        imap_connection.command(&format!(
+            "SELECT {}",
+            quote_imap_string(&encode_utf7_imap(mailbox_name.to_string()))
+        ))?;
+
+        let mailbox_name = mailbox_name.to_string();
+        let sender = sender.clone();
+        thread::spawn(move || {
+            if let Err(err) = watch_mailbox(&mut imap_connection, &mailbox_name, &sender) {
+                println!("Watching mailbox {} failed: {}", mailbox_name, err);
+                let event = MailboxEventOutDTO {
+                    event_type: MailboxEventType::Error,
+                    mailbox_name: mailbox_name.clone(),
+                    uid: None,
+                    flags: vec![],
+                    email: None,
+                    message: Some(err.to_string()),
+                };
+                let _ = sender.blocking_send(format_event(&event));
+            }
+            let _ = imap_connection.logout();
+        });
+    }
+
+    let stream = futures_util::stream::unfold(receiver, |mut receiver: Receiver<String>| async {
+        receiver
+            .recv()
+            .await
+            .map(|chunk| (Ok::<Bytes, Error>(Bytes::from(chunk)), receiver))
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        .streaming(stream))
+}
+
+fn watch_mailbox(
+    imap_connection: &mut ImapConnection,
+    mailbox_name: &str,
+    sender: &Sender<String>,
+) -> Result<(), Error> {
+    let supports_idle = imap_connection.has_capability("IDLE");
+    let mut known_flags = fetch_flags_snapshot(imap_connection)?;
+
+    while !sender.is_closed() {
+        let untagged = if supports_idle {
+            wait_in_idle(imap_connection, sender)?
+        } else {
+            thread::sleep(Duration::from_secs(NOOP_POLL_INTERVAL_SECS));
+            if sender
+                .blocking_send(KEEP_ALIVE_COMMENT.to_string())
+                .is_err()
+            {
+                break;
+            }
+            imap_connection.command("NOOP")?.untagged
+        };
+
+        // EXISTS, EXPUNGE and FETCH responses only carry sequence numbers, comparing
+        // UIDs and flags with the last snapshot tells what actually changed
+        if !untagged.iter().any(|line| !line.starts_with(b"* OK")) {
+            continue;
+        }
+
+        let current_flags = fetch_flags_snapshot(imap_connection)?;
+        let mut events = vec![];
+
+        let mut new_uids: Vec<u32> = current_flags
+            .keys()
+            .filter(|uid| !known_flags.contains_key(uid))
+            .cloned()
+            .collect();
+        new_uids.sort_unstable();
+        if !new_uids.is_empty() {
+            events.extend(fetch_new_message_events(
+                imap_connection,
+                mailbox_name,
+                &new_uids,
+            )?);
+        }
+
+        for (uid, flags) in known_flags.iter() {
+            match current_flags.get(uid) {
+                None => events.push(MailboxEventOutDTO {
+                    event_type: MailboxEventType::Expunged,
+                    mailbox_name: mailbox_name.to_string(),
+                    uid: Some(*uid),
+                    flags: vec![],
+                    email: None,
+                    message: None,
+                }),
+                Some(current) if current != flags => events.push(MailboxEventOutDTO {
+                    event_type: MailboxEventType::FlagsChanged,
+                    mailbox_name: mailbox_name.to_string(),
+                    uid: Some(*uid),
+                    flags: current.clone(),
+                    email: None,
+                    message: None,
+                }),
+                _ => {}
+            }
+        }
+
+        for event in events.iter() {
+            if sender.blocking_send(format_event(event)).is_err() {
+                return Ok(());
+            }
+        }
+        known_flags = current_flags;
+    }
+
+    Ok(())
+}
+
+/// Waits in IDLE until the server reports something, the client disconnects or it is time
+/// to re-issue the command.
+fn wait_in_idle(
+    imap_connection: &mut ImapConnection,
+    sender: &Sender<String>,
+) -> Result<Vec<Vec<u8>>, Error> {
+    let (tag, mut untagged) = imap_connection.start_idle()?;
+    let idle_start = Instant::now();
+
+    imap_connection.set_read_timeout(Some(Duration::from_secs(IDLE_READ_TIMEOUT_SECS)))?;
+    while untagged.is_empty() && idle_start.elapsed().as_secs() < IDLE_RENEW_INTERVAL_SECS {
+        match imap_connection.read_response_unit() {
+            Ok(unit) => {
+                // Dovecot reminds idling clients it is still alive
+                if !unit.starts_with(b"* OK") {
+                    untagged.push(unit);
+                }
+            }
+            Err(err) if is_timeout(&err) => {
+                if sender
+                    .blocking_send(KEEP_ALIVE_COMMENT.to_string())
+                    .is_err()
+                {
+                    break;
+                }
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    imap_connection.set_read_timeout(None)?;
+
+    let finished = imap_connection.finish_idle(&tag)?;
+    untagged.extend(finished.untagged);
+    Ok(untagged)
+}
+
+fn fetch_flags_snapshot(
+    imap_connection: &mut ImapConnection,
+) -> Result<HashMap<u32, Vec<String>>, Error> {
+    let mut snapshot = HashMap::new();
+
+    // Servers answer with NO or BAD for an empty mailbox
+    let response = match imap_connection.command("UID FETCH 1:* (UID FLAGS)") {
+        Ok(response) => response,
+        Err(err) if err.kind() == std::io::ErrorKind::Other => return Ok(snapshot),
+        Err(err) => return Err(err),
+    };
+
+    for line in response.untagged.iter() {
+        if let Ok((_, Response::Fetch(_, attributes))) = imap_proto::parse_response(line) {
+            if let Some(uid) = parse_fetch_uid(&attributes) {
+                let mut flags = parse_fetch_flags(&attributes);
+                flags.sort();
+                snapshot.insert(uid, flags);
+            }
+        }
+    }
+
+    Ok(snapshot)
+}
+
+fn fetch_new_message_events(
+    imap_connection: &mut ImapConnection,
+    mailbox_name: &str,
+    uids: &[u32],
+) -> Result<Vec<MailboxEventOutDTO>, Error> {
+    let response = imap_connection.command(&format!(
+        "UID FETCH {} (UID FLAGS ENVELOPE INTERNALDATE)",
+        format_uid_set(uids)
+    ))?;
+
+    let mut events = vec![];
+    for line in response.untagged.iter() {
+        if let Ok((_, Response::Fetch(sequence_number, attributes))) =
+            imap_proto::parse_response(line)
+        {
+            let email = parse_inspect_fetch(sequence_number, &attributes);
+            events.push(MailboxEventOutDTO {
+                event_type: MailboxEventType::NewMessage,
+                mailbox_name: mailbox_name.to_string(),
+                uid: Some(email.uid),
+                flags: parse_fetch_flags(&attributes),
+                email: Some(email),
+                message: None,
+            });
+        }
+    }
+
+    Ok(events)
+}
+
+fn format_event(event: &MailboxEventOutDTO) -> String {
+    let event_name = match event.event_type {
+        MailboxEventType::NewMessage => "new_message",
+        MailboxEventType::Expunged => "expunged",
+        MailboxEventType::FlagsChanged => "flags_changed",
+        MailboxEventType::Error => "error",
+    };
+    format!(
+        "event: {}\ndata: {}\n\n",
+        event_name,
+        serde_json::to_string(event).unwrap_or_default()
+    )
+}
+
+pub fn email_notifications_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/notifications").route(web::get().to(watch_mailboxes)));
+}
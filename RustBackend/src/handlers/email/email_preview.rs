@@ -0,0 +1,274 @@
+use std::{collections::HashMap, io::Error};
+
+use imap_proto::{AttributeValue, BodyContentCommon, BodyStructure, ContentEncoding, Response};
+use quoted_printable::ParseMode;
+
+use crate::{
+    constants::{PREVIEW_FETCH_OCTETS, PREVIEW_LENGTH},
+    storage::message_cache::MessageCache,
+    utils::utils_imap::{format_uid_set, ImapConnection},
+};
+
+use super::email_imap::{parse_fetch_text, parse_fetch_uid};
+
+// Previews are cached next to the texts, an empty one marks an email without a text part
+pub const CACHE_PREVIEW_SECTION: &str = "PREVIEW";
+
+#[derive(Clone)]
+struct PreviewPart {
+    section: String,
+    is_html: bool,
+    is_base64: bool,
+    is_quoted_printable: bool,
+}
+
+/// Whether any part of the email is an attachment or at least carries a file name.
+pub fn has_attachments(structure: &BodyStructure) -> bool {
+    match structure {
+        BodyStructure::Multipart { bodies, .. } => bodies.iter().any(has_attachments),
+        BodyStructure::Basic { common, .. } | BodyStructure::Message { common, .. } => {
+            is_attachment(common) || common.ty.ty.eq_ignore_ascii_case("application")
+        }
+        BodyStructure::Text { common, .. } => is_attachment(common),
+    }
+}
+
+fn is_attachment(common: &BodyContentCommon) -> bool {
+    let has_file_name = |params: &Option<Vec<(&str, &str)>>, key: &str| {
+        params
+            .iter()
+            .flatten()
+            .any(|(name, _)| name.eq_ignore_ascii_case(key))
+    };
+    match &common.disposition {
+        Some(disposition) => {
+            disposition.ty.eq_ignore_ascii_case("attachment")
+                || has_file_name(&disposition.params, "filename")
+        }
+        None => has_file_name(&common.ty.params, "name"),
+    }
+}
+
+/// Finds the part the preview is made of, plain text is preferred over HTML.
+fn find_preview_part(structure: &BodyStructure) -> Option<PreviewPart> {
+    let mut html_part = None;
+    let plain_part = find_text_part(structure, String::new(), &mut html_part);
+    plain_part.or(html_part)
+}
+
+fn find_text_part(
+    structure: &BodyStructure,
+    section: String,
+    html_part: &mut Option<PreviewPart>,
+) -> Option<PreviewPart> {
+    match structure {
+        BodyStructure::Multipart { bodies, .. } => {
+            bodies.iter().enumerate().find_map(|(index, body)| {
+                let child_section = if section.is_empty() {
+                    (index + 1).to_string()
+                } else {
+                    format!("{}.{}", section, index + 1)
+                };
+                find_text_part(body, child_section, html_part)
+            })
+        }
+        BodyStructure::Text { common, other, .. } if !is_attachment(common) => {
+            let part = PreviewPart {
+                // A message which is not multipart has its body at section 1
+                section: if section.is_empty() {
+                    "1".to_string()
+                } else {
+                    section
+                },
+                is_html: common.ty.subtype.eq_ignore_ascii_case("html"),
+                is_base64: other.transfer_encoding == ContentEncoding::Base64,
+                is_quoted_printable: other.transfer_encoding == ContentEncoding::QuotedPrintable,
+            };
+            if !part.is_html {
+                return Some(part);
+            }
+            if html_part.is_none() {
+                *html_part = Some(part);
+            }
+            None
+        }
+        _ => None,
+    }
+}
+
+/// Downloads the beginning of the text part of every given email and makes a preview of it.
+/// `summary_lines` are FETCH responses with UID and BODYSTRUCTURE, the previews are cached and
+/// returned by UID.
+pub fn fetch_previews(
+    imap_connection: &mut ImapConnection,
+    cache: &MessageCache,
+    account: &str,
+    mailbox_name: &str,
+    summary_lines: &[&[u8]],
+) -> Result<HashMap<u32, String>, Error> {
+    let mut previews = HashMap::new();
+    let mut parts_by_section: HashMap<String, Vec<(u32, PreviewPart)>> = HashMap::new();
+    for line in summary_lines.iter() {
+        if let Ok((_, Response::Fetch(_, attributes))) = imap_proto::parse_response(line) {
+            let uid = match parse_fetch_uid(&attributes) {
+                Some(uid) => uid,
+                None => continue,
+            };
+            let part = attributes.iter().find_map(|attribute| match attribute {
+                AttributeValue::BodyStructure(structure) => find_preview_part(structure),
+                _ => None,
+            });
+            match part {
+                Some(part) => parts_by_section
+                    .entry(part.section.clone())
+                    .or_default()
+                    .push((uid, part)),
+                None => {
+                    previews.insert(uid, String::new());
+                }
+            }
+        }
+    }
+
+    // Most emails share a handful of layouts, so one command per section is enough
+    let sections: Vec<(String, Vec<(u32, PreviewPart)>)> = parts_by_section.into_iter().collect();
+    let commands: Vec<String> = sections
+        .iter()
+        .map(|(section, parts)| {
+            let mut uids: Vec<u32> = parts.iter().map(|(uid, _)| *uid).collect();
+            uids.sort_unstable();
+            format!(
+                "UID FETCH {} (UID BODY.PEEK[{}]<0.{}>)",
+                format_uid_set(&uids),
+                section,
+                PREVIEW_FETCH_OCTETS
+            )
+        })
+        .collect();
+    let responses = if commands.is_empty() {
+        vec![]
+    } else {
+        imap_connection.pipeline(&commands)?
+    };
+
+    for ((_, parts), response) in sections.iter().zip(responses) {
+        let parts: HashMap<u32, &PreviewPart> =
+            parts.iter().map(|(uid, part)| (*uid, part)).collect();
+        for line in response?.untagged.iter() {
+            if let Ok((_, Response::Fetch(_, attributes))) = imap_proto::parse_response(line) {
+                let uid = match parse_fetch_uid(&attributes) {
+                    Some(uid) => uid,
+                    None => continue,
+                };
+                if let Some(part) = parts.get(&uid) {
+                    let data = parse_fetch_text(&attributes).unwrap_or_default();
+                    previews.insert(uid, build_preview(data, part));
+                }
+            }
+        }
+    }
+
+    for (uid, preview) in previews.iter() {
+        cache.store_part(
+            account,
+            mailbox_name,
+            *uid,
+            CACHE_PREVIEW_SECTION,
+            preview.as_bytes(),
+        )?;
+    }
+    Ok(previews)
+}
+
+/// Returns the cached preview, `None` when it was not downloaded yet.
+pub fn cached_preview(
+    cache: &MessageCache,
+    account: &str,
+    mailbox_name: &str,
+    uid: u32,
+) -> Result<Option<String>, Error> {
+    Ok(cache
+        .part(account, mailbox_name, uid, CACHE_PREVIEW_SECTION)?
+        .map(|preview| String::from_utf8_lossy(&preview).to_string()))
+}
+
+/// Decodes the truncated part and returns its first words as a single line.
+fn build_preview(data: &[u8], part: &PreviewPart) -> String {
+    let decoded = if part.is_base64 {
+        let mut encoded: Vec<u8> = data
+            .iter()
+            .filter(|byte| !byte.is_ascii_whitespace())
+            .cloned()
+            .collect();
+        // The part is cut off, only whole groups of four characters can be decoded
+        encoded.truncate(encoded.len() / 4 * 4);
+        data_encoding::BASE64
+            .decode(&encoded)
+            .unwrap_or_else(|_| data.to_vec())
+    } else if part.is_quoted_printable {
+        quoted_printable::decode(data, ParseMode::Robust).unwrap_or_else(|_| data.to_vec())
+    } else {
+        data.to_vec()
+    };
+
+    let mut text = String::from_utf8_lossy(&decoded).to_string();
+    if part.is_html {
+        text = strip_html(&text);
+    }
+
+    let mut preview = String::new();
+    for word in text.split_whitespace() {
+        if !preview.is_empty() {
+            preview.push(' ');
+        }
+        preview.push_str(word);
+        if preview.chars().count() >= PREVIEW_LENGTH {
+            break;
+        }
+    }
+    match preview.char_indices().nth(PREVIEW_LENGTH) {
+        Some((end, _)) => format!("{}…", &preview[..end]),
+        None => preview,
+    }
+}
+
+/// Drops tags, styles and scripts and resolves the most common entities. Good enough for a
+/// preview, not for displaying HTML.
+fn strip_html(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        text.push(' ');
+        rest = &rest[start..];
+
+        let lowercase_start: String = rest.chars().take(7).collect::<String>().to_lowercase();
+        let closing_tag = if lowercase_start.starts_with("<style") {
+            Some("</style>")
+        } else if lowercase_start.starts_with("<script") {
+            Some("</script>")
+        } else {
+            None
+        };
+        let end = match closing_tag {
+            Some(closing_tag) => rest
+                .to_ascii_lowercase()
+                .find(closing_tag)
+                .map(|end| end + closing_tag.len()),
+            None => rest.find('>').map(|end| end + 1),
+        };
+        match end {
+            Some(end) => rest = &rest[end..],
+            // Cut off inside of a tag
+            None => rest = "",
+        }
+    }
+    text.push_str(rest);
+
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
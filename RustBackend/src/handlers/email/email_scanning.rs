@@ -0,0 +1,191 @@
+use std::io::Error;
+
+use actix_session::Session;
+use actix_web::{http::header::ContentType, web, HttpResponse};
+use chrono::Utc;
+use imap_proto::BodyStructure;
+
+use crate::{
+    constants::{SCAN_REFUSALS_DEFAULT_LIMIT, SCAN_REFUSALS_MAX_LIMIT},
+    scanning::{AttachmentScanners, ScanRefusalReason, ScannedAttachment},
+    storage::{
+        message_cache::MessageCache,
+        scan_log::{ScanDirection, ScanRefusal},
+    },
+    utils::utils_session::check_is_valid_session,
+};
+
+use super::{
+    email_imap::{attachment_file_name, decide_encoding, decode_part_bytes},
+    email_source::decode_header_value,
+    models::{AttachmentRefusedOutDTO, ScanRefusalListInDTO, ScanRefusalListOutDTO},
+};
+
+/// Where the scanned attachment comes from, kept with a refusal.
+pub struct ScanTarget {
+    pub direction: ScanDirection,
+    pub mailbox_name: Option<String>,
+    pub uid: Option<u32>,
+    pub part_id: Option<String>,
+    pub file_name: String,
+    pub content_type: String,
+}
+
+/// Runs all scanners over the decoded attachment. A refusal is recorded for the account and
+/// returned as a 403 response with the reason, `None` means the attachment may pass.
+pub async fn scan_attachment(
+    scanners: &web::Data<AttachmentScanners>,
+    cache: &MessageCache,
+    account: &str,
+    target: ScanTarget,
+    data: Vec<u8>,
+) -> Result<Option<HttpResponse>, Error> {
+    let scanners = scanners.clone();
+    let file_name = target.file_name.clone();
+    let content_type = target.content_type.clone();
+    // Scanners may read the whole attachment or wait for a daemon
+    let refusal = web::block(move || {
+        scanners.check(&ScannedAttachment {
+            file_name: &file_name,
+            content_type: &content_type,
+            data: &data,
+        })
+    })
+    .await
+    .map_err(Error::other)?;
+    let refusal = match refusal {
+        Some(refusal) => record_refusal(cache, account, target, refusal)?,
+        None => return Ok(None),
+    };
+
+    let body = serde_json::to_string(&refusal)?;
+    Ok(Some(
+        HttpResponse::Forbidden()
+            .content_type(ContentType::json())
+            .body(body),
+    ))
+}
+
+/// Scans the decoded content of a part fetched from the server. Multipart sections have no
+/// content of their own and pass.
+pub async fn scan_part(
+    scanners: &web::Data<AttachmentScanners>,
+    cache: &MessageCache,
+    account: &str,
+    part: &BodyStructure<'_>,
+    data: &[u8],
+    target: ScanTarget,
+) -> Result<Option<HttpResponse>, Error> {
+    let encoding = match part {
+        BodyStructure::Basic { other, .. }
+        | BodyStructure::Text { other, .. }
+        | BodyStructure::Message { other, .. } => decide_encoding(other),
+        BodyStructure::Multipart { .. } => return Ok(None),
+    };
+    scan_attachment(
+        scanners,
+        cache,
+        account,
+        target,
+        decode_part_bytes(data, &encoding),
+    )
+    .await
+}
+
+/// Same as `scan_attachment` for code which already runs on a blocking thread, like the
+/// writer of a streamed archive.
+pub fn scan_attachment_blocking(
+    scanners: &AttachmentScanners,
+    cache: &MessageCache,
+    account: &str,
+    target: ScanTarget,
+    data: &[u8],
+) -> Result<Option<AttachmentRefusedOutDTO>, Error> {
+    let refusal = scanners.check(&ScannedAttachment {
+        file_name: &target.file_name,
+        content_type: &target.content_type,
+        data,
+    });
+    match refusal {
+        Some(refusal) => record_refusal(cache, account, target, refusal).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Scan target for a part of a downloaded email, named like `part_response` names it.
+pub fn part_scan_target(
+    part: &BodyStructure,
+    section: &str,
+    mailbox_name: &str,
+    uid: Option<u32>,
+) -> ScanTarget {
+    let content_type = match part {
+        BodyStructure::Basic { common, .. }
+        | BodyStructure::Text { common, .. }
+        | BodyStructure::Message { common, .. }
+        | BodyStructure::Multipart { common, .. } => {
+            format!("{}/{}", common.ty.ty, common.ty.subtype).to_lowercase()
+        }
+    };
+    ScanTarget {
+        direction: ScanDirection::Download,
+        mailbox_name: Some(mailbox_name.to_string()),
+        uid,
+        part_id: Some(section.to_string()),
+        file_name: attachment_file_name(part)
+            .map(|file_name| decode_header_value(file_name.as_bytes()))
+            .unwrap_or_else(|| format!("part-{}", section)),
+        content_type,
+    }
+}
+
+fn record_refusal(
+    cache: &MessageCache,
+    account: &str,
+    target: ScanTarget,
+    refusal: ScanRefusalReason,
+) -> Result<AttachmentRefusedOutDTO, Error> {
+    println!(
+        "Refused {:?} attachment {} by {}: {}",
+        target.direction, target.file_name, refusal.scanner, refusal.reason
+    );
+    cache.record_scan_refusal(
+        account,
+        &ScanRefusal {
+            direction: target.direction,
+            mailbox_name: target.mailbox_name,
+            uid: target.uid,
+            part_id: target.part_id,
+            file_name: target.file_name.clone(),
+            content_type: target.content_type,
+            scanner: refusal.scanner.clone(),
+            reason: refusal.reason.clone(),
+            refused_at: Utc::now().naive_utc(),
+        },
+    )?;
+    Ok(AttachmentRefusedOutDTO {
+        file_name: target.file_name,
+        scanner: refusal.scanner,
+        reason: refusal.reason,
+    })
+}
+
+/// Attachments refused for the signed in account, latest first.
+async fn list_scan_refusals(
+    session: Session,
+    cache: web::Data<MessageCache>,
+    request: web::Query<ScanRefusalListInDTO>,
+) -> Result<ScanRefusalListOutDTO, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let limit = request
+        .limit
+        .unwrap_or(SCAN_REFUSALS_DEFAULT_LIMIT)
+        .clamp(1, SCAN_REFUSALS_MAX_LIMIT);
+    Ok(ScanRefusalListOutDTO {
+        refusals: cache.scan_refusals(&credentials.email, limit)?,
+    })
+}
+
+pub fn email_scanning_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/attachments/refusals").route(web::get().to(list_scan_refusals)));
+}
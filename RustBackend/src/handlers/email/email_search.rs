@@ -0,0 +1,202 @@
+use std::{cmp::min, io::Error, iter::Peekable, str::Chars};
+
+use actix_session::Session;
+use actix_web::web;
+
+use crate::{
+    constants::SEARCH_SNIPPET_WORDS,
+    storage::{message_cache::MessageCache, search_index::search_words},
+    utils::utils_session::check_is_valid_session,
+};
+
+use super::models::{FullTextSearchInDTO, FullTextSearchOutDTO, SearchHitOutDTO};
+
+struct SearchTerm {
+    column: Option<&'static str>,
+    text: String,
+}
+
+/// Searches the locally indexed emails of all cached mailboxes, or of a single one, and
+/// returns the best matches first.
+async fn search_full_text(
+    session: Session,
+    cache: web::Data<MessageCache>,
+    request: web::Query<FullTextSearchInDTO>,
+) -> Result<FullTextSearchOutDTO, Error> {
+    let credentials = check_is_valid_session(&session)?;
+
+    let mut response = FullTextSearchOutDTO {
+        query: request.query.clone(),
+        total_results_count: 0,
+        requested_page_number: request.requested_page_number,
+        page_size: request.page_size,
+        results: vec![],
+    };
+
+    let terms = parse_search_query(&request.query);
+    if terms.is_empty() || request.page_size == 0 {
+        return Ok(response);
+    }
+
+    let (total, results) = cache.search(
+        &credentials.email,
+        request.mailbox_name.as_deref(),
+        &build_match_query(&cache, &terms),
+        request.requested_page_number * request.page_size,
+        request.page_size,
+    )?;
+    response.total_results_count = total;
+
+    // Sender and subject are returned whole, the snippet only needs the body words
+    let highlighted_terms: Vec<&SearchTerm> = terms
+        .iter()
+        .filter(|term| term.column.is_none() || term.column == Some("body"))
+        .collect();
+
+    for result in results.into_iter() {
+        let document = result.document;
+        let snippet_source = if document.body.trim().is_empty() {
+            &document.subject
+        } else {
+            &document.body
+        };
+        response.results.push(SearchHitOutDTO {
+            snippet: build_snippet(snippet_source, &highlighted_terms),
+            mailbox_name: document.mailbox_name,
+            uid: document.uid,
+            from_address: document.from_address,
+            subject: document.subject,
+            send_date: document.send_date,
+            score: result.score,
+        });
+    }
+
+    Ok(response)
+}
+
+fn search_column(field: &str) -> Option<&'static str> {
+    match field.to_ascii_lowercase().as_str() {
+        "from" => Some("from_address"),
+        "subject" => Some("subject"),
+        "body" => Some("body"),
+        "attachment" => Some("attachments"),
+        _ => None,
+    }
+}
+
+/// Splits the user query into words and "quoted phrases", each optionally limited to a field
+/// with a prefix like `from:` or `subject:"weekly report"`.
+fn parse_search_query(query: &str) -> Vec<SearchTerm> {
+    let mut terms = vec![];
+    let mut chars = query.chars().peekable();
+
+    loop {
+        while chars.next_if(|char| char.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let mut column = None;
+        let mut text = String::new();
+        let mut is_phrase = false;
+        while let Some(char) = chars.next_if(|char| !char.is_whitespace()) {
+            if char == '"' && text.is_empty() {
+                text = read_phrase(&mut chars);
+                is_phrase = true;
+                break;
+            }
+            if char == ':' && column.is_none() {
+                if let Some(field_column) = search_column(&text) {
+                    column = Some(field_column);
+                    text.clear();
+                    continue;
+                }
+            }
+            text.push(char);
+        }
+
+        // The index only knows whole words, `report*` is searched as `report`
+        let text = if is_phrase {
+            text
+        } else {
+            text.trim_end_matches('*').to_string()
+        };
+        if text.chars().any(|char| char.is_alphanumeric()) {
+            terms.push(SearchTerm { column, text });
+        }
+    }
+
+    terms
+}
+
+fn read_phrase(chars: &mut Peekable<Chars>) -> String {
+    let mut phrase = String::new();
+    for char in chars.by_ref() {
+        if char == '"' {
+            break;
+        }
+        phrase.push(char);
+    }
+    phrase
+}
+
+/// Turns the terms into an FTS5 query over the hashed words of the index. Every term becomes a
+/// quoted phrase of hex tokens, so nothing the user types can be interpreted as FTS5 syntax.
+fn build_match_query(cache: &MessageCache, terms: &[SearchTerm]) -> String {
+    terms
+        .iter()
+        .map(|term| {
+            let query = format!("\"{}\"", cache.search_tokens(&term.text));
+            match term.column {
+                Some(column) => format!("{} : {}", column, query),
+                None => query,
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" AND ")
+}
+
+/// Cuts the part of the text around the first match and marks all matched words in it.
+fn build_snippet(text: &str, terms: &[&SearchTerm]) -> String {
+    let searched_words: Vec<String> = terms
+        .iter()
+        .flat_map(|term| search_words(&term.text))
+        .collect();
+    let is_match = |word: &str| search_words(word).any(|part| searched_words.contains(&part));
+
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let first_match = words.iter().position(|word| is_match(word)).unwrap_or(0);
+    let start = first_match.saturating_sub(SEARCH_SNIPPET_WORDS / 4);
+    let end = min(words.len(), start + SEARCH_SNIPPET_WORDS);
+
+    let mut snippet = words[start..end]
+        .iter()
+        .map(|word| {
+            let escaped = escape_html(word);
+            if is_match(word) {
+                format!("<mark>{}</mark>", escaped)
+            } else {
+                escaped
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ");
+    if start > 0 {
+        snippet = format!("… {}", snippet);
+    }
+    if end < words.len() {
+        snippet.push_str(" …");
+    }
+    snippet
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub fn email_search_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/search/fulltext").route(web::get().to(search_full_text)));
+}
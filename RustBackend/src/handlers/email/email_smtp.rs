@@ -1,6 +1,7 @@
 use std::{
+    collections::HashMap,
     fs::{read, remove_file},
-    io::{Error, ErrorKind, Write},
+    io::Error,
 };
 
 use actix_multipart::Multipart;
@@ -9,31 +10,81 @@ use actix_web::{
     web::{self, Bytes},
     HttpResponse,
 };
+use chrono::Utc;
 use futures_util::{StreamExt, TryStreamExt};
+use imap_proto::{AttributeValue, Response};
 use lettre::{
     message::{header::ContentType, Attachment, MultiPart, SinglePart},
     AsyncTransport, Message,
 };
+use utf7_imap::encode_utf7_imap;
 
-use crate::utils::{
-    utils_session::check_is_valid_session, utils_transports::create_smtp_transport,
+use crate::{
+    handlers::auth::models::SignInMessage,
+    scanning::AttachmentScanners,
+    storage::{
+        contacts::ContactedAddress, message_cache::MessageCache, scan_log::ScanDirection,
+        templates::TemplateAttachmentFile,
+    },
+    utils::{
+        utils_imap::quote_imap_string,
+        utils_multipart::write_field_to_file,
+        utils_session::check_is_valid_session,
+        utils_transports::{connect_imap, create_smtp_transport},
+    },
 };
 
-use super::models::EmailInDTO;
+use super::{
+    email_contacts::resolve_recipients,
+    email_scanning::{scan_attachment, ScanTarget},
+    email_source::decode_header_value,
+    email_templates::{load_template_message, parse_variables},
+    models::EmailInDTO,
+};
+
+async fn send_email(
+    payload: Multipart,
+    session: Session,
+    scanners: web::Data<AttachmentScanners>,
+    cache: web::Data<MessageCache>,
+) -> Result<HttpResponse, Error> {
+    let mut file_complete_path = Vec::new();
+    let result = send_email_with_uploads(
+        payload,
+        &session,
+        &scanners,
+        &cache,
+        &mut file_complete_path,
+    )
+    .await;
+    // Uploads are in the message, refused or abandoned by now, whichever way it ended
+    remove_uploads(&file_complete_path);
+    result
+}
 
-async fn send_email(mut payload: Multipart, session: Session) -> Result<HttpResponse, Error> {
+/// Uploaded files are written to `./tmp` and recorded in `file_complete_path`, the caller
+/// removes them.
+async fn send_email_with_uploads(
+    mut payload: Multipart,
+    session: &Session,
+    scanners: &web::Data<AttachmentScanners>,
+    cache: &web::Data<MessageCache>,
+    file_complete_path: &mut Vec<(String, String)>,
+) -> Result<HttpResponse, Error> {
     // Check the session
-    let sess_values = check_is_valid_session(&session)?;
+    let sess_values = check_is_valid_session(session)?;
 
     // Create initial email struct
     let mut email_struct = EmailInDTO {
         to_address: String::new(),
         subject: String::new(),
         body: String::new(),
+        reply_mailbox_name: None,
+        reply_uid: None,
+        template_id: None,
+        variables: HashMap::new(),
     };
 
-    let mut file_complete_path = Vec::new();
-
     // Iterate over multipart stream
     while let Some(mut field) = payload.try_next().await.unwrap() {
         match field.content_disposition().get_filename() {
@@ -43,49 +94,7 @@ async fn send_email(mut payload: Multipart, session: Session) -> Result<HttpResp
                 let filepath = format!("./tmp/{}", file_name);
                 file_complete_path.push((filepath.clone(), file_name.to_string()));
 
-                let mut file_created;
-
-                // File::create is blocking operation, use threadpool
-                match web::block(|| std::fs::File::create(filepath)).await {
-                    Ok(res) => match res {
-                        Ok(file) => file_created = file,
-                        Err(err) => {
-                            return Err(Error::new(
-                                ErrorKind::Other,
-                                format!("Creating file Error {:?}", err),
-                            ))
-                        }
-                    },
-                    Err(err) => {
-                        return Err(Error::new(
-                            ErrorKind::Other,
-                            format!("Creating file Blocking Error {:?}", err),
-                        ))
-                    }
-                };
-                // Field in turn is stream of *Bytes* object
-                while let Some(Ok(chunk)) = field.next().await {
-                    // Filesystem operations are blocking, we have to use threadpool
-                    match web::block(move || file_created.write_all(&chunk).map(|_| file_created))
-                        .await
-                    {
-                        Ok(res) => match res {
-                            Ok(file) => file_created = file,
-                            Err(err) => {
-                                return Err(Error::new(
-                                    ErrorKind::Other,
-                                    format!("Creating file Error {:?}", err),
-                                ))
-                            }
-                        },
-                        Err(err) => {
-                            return Err(Error::new(
-                                ErrorKind::Other,
-                                format!("Creating file Blocking Error {:?}", err),
-                            ))
-                        }
-                    }
-                }
+                write_field_to_file(&mut field, filepath).await?;
             }
             _ => {
                 let field_value = match field.next().await {
@@ -100,8 +109,7 @@ async fn send_email(mut payload: Multipart, session: Session) -> Result<HttpResp
                 let field_content_disposion_name = field.content_disposition().get_name();
 
                 if field_content_disposion_name.is_none() {
-                    return Err(Error::new(
-                        ErrorKind::Other,
+                    return Err(Error::other(
                         "couldnt parse name from field content disposion",
                     ));
                 }
@@ -122,6 +130,19 @@ async fn send_email(mut payload: Multipart, session: Session) -> Result<HttpResp
                         email_struct.body = String::from_utf8(field_value.to_vec())
                             .expect("coudlnt parse body from field");
                     }
+                    "reply_mailbox_name" => {
+                        email_struct.reply_mailbox_name =
+                            Some(String::from_utf8_lossy(&field_value).to_string());
+                    }
+                    "reply_uid" => {
+                        email_struct.reply_uid =
+                            String::from_utf8_lossy(&field_value).trim().parse().ok();
+                    }
+                    "template_id" => {
+                        email_struct.template_id =
+                            String::from_utf8_lossy(&field_value).trim().parse().ok();
+                    }
+                    "variables" => email_struct.variables = parse_variables(&field_value)?,
                     other => {
                         print!("Other name {}", other);
                     }
@@ -130,34 +151,73 @@ async fn send_email(mut payload: Multipart, session: Session) -> Result<HttpResp
         };
     }
 
-    let mut body_total = MultiPart::mixed().singlepart(
-        SinglePart::builder()
-            .content_type(ContentType::TEXT_PLAIN)
-            .body(email_struct.body.to_string()),
-    );
+    // Contact and group names are replaced by their addresses, a template by its texts
+    let recipients = resolve_recipients(cache, &sess_values.email, &email_struct.to_address)?;
+    let template = match email_struct.template_id {
+        Some(template_id) => Some(load_template_message(
+            cache,
+            &sess_values.email,
+            template_id,
+            &email_struct.variables,
+        )?),
+        None => None,
+    };
+    let (subject, text_body, html_body, template_attachments) = match template {
+        Some((rendered, attachments)) => (
+            rendered.subject,
+            rendered.text_body,
+            rendered.html_body,
+            attachments,
+        ),
+        None => (
+            email_struct.subject.clone(),
+            email_struct.body.clone(),
+            None,
+            vec![],
+        ),
+    };
+
+    // The reply is sent even when the answered email is gone
+    let replied_sender = match (
+        email_struct.reply_mailbox_name.clone(),
+        email_struct.reply_uid,
+    ) {
+        (Some(mailbox_name), Some(uid)) => {
+            let credentials = sess_values.clone();
+            web::block(move || fetch_replied_sender(&credentials, &mailbox_name, uid))
+                .await
+                .map_err(Error::other)
+                .and_then(|result| result)
+                .unwrap_or_else(|err| {
+                    println!("Couldnt fetch the answered email {:?}", err);
+                    None
+                })
+        }
+        _ => None,
+    };
+
+    let mut body_total = message_body(text_body, html_body);
 
     if !file_complete_path.is_empty() {
-        for (path, name) in file_complete_path.into_iter() {
+        for (path, name) in file_complete_path.iter().cloned() {
             let file_content;
-            let path_copy = path.clone();
             match web::block(move || read(path)).await {
                 Ok(res) => match res {
                     Ok(content) => {
                         file_content = content;
-                        remove_file(path_copy);
                     }
                     Err(err) => {
-                        return Err(Error::new(
-                            ErrorKind::Other,
-                            format!("Error reading file content {:?}", err),
-                        ))
+                        return Err(Error::other(format!(
+                            "Error reading file content {:?}",
+                            err
+                        )))
                     }
                 },
                 Err(err) => {
-                    return Err(Error::new(
-                        ErrorKind::Other,
-                        format!("Error reading file Blocking Error {:?}", err),
-                    ))
+                    return Err(Error::other(format!(
+                        "Error reading file Blocking Error {:?}",
+                        err
+                    )))
                 }
             };
 
@@ -170,24 +230,51 @@ async fn send_email(mut payload: Multipart, session: Session) -> Result<HttpResp
             {
                 Ok(con_type) => content_type = con_type,
                 Err(err) => {
-                    return Err(Error::new(
-                        ErrorKind::Other,
-                        format!("Error parsing content_type (CotnentTypeErr) {:?}", err),
-                    ))
+                    return Err(Error::other(format!(
+                        "Error parsing content_type (CotnentTypeErr) {:?}",
+                        err
+                    )))
                 }
             }
-            
+
+            let target = ScanTarget {
+                direction: ScanDirection::Upload,
+                mailbox_name: None,
+                uid: None,
+                part_id: None,
+                file_name: name.clone(),
+                content_type: mime_guess::from_path(&name)
+                    .first_or_octet_stream()
+                    .essence_str()
+                    .to_string(),
+            };
+            if let Some(refusal) = scan_attachment(
+                scanners,
+                cache,
+                &sess_values.email,
+                target,
+                file_content.clone(),
+            )
+            .await?
+            {
+                return Ok(refusal);
+            }
+
             body_total =
                 body_total.singlepart(Attachment::new(name).body(file_content, content_type));
         }
     }
 
-    match Message::builder()
-        .to(email_struct.to_address.parse().unwrap())
+    body_total = attach_template_files(body_total, template_attachments)?;
+
+    let mut message_builder = Message::builder()
         .from(sess_values.email.parse().unwrap())
-        .subject(email_struct.subject.to_string())
-        .multipart(body_total)
-    {
+        .subject(subject);
+    for recipient in recipients.iter() {
+        message_builder = message_builder.to(recipient.clone());
+    }
+
+    match message_builder.multipart(body_total) {
         Ok(message) => {
             let session = create_smtp_transport(
                 &sess_values.email,
@@ -200,23 +287,123 @@ async fn send_email(mut payload: Multipart, session: Session) -> Result<HttpResp
             let send_result = session.send(message).await;
 
             if send_result.is_err() {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    format!("Couldnt build message {:?}", send_result.err()),
-                ));
+                return Err(Error::other(format!(
+                    "Couldnt build message {:?}",
+                    send_result.err()
+                )));
             }
         }
-        Err(err) => {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("Couldnt build message {:?}", err),
-            ))
-        }
+        Err(err) => return Err(Error::other(format!("Couldnt build message {:?}", err))),
     };
 
+    let mut contacted: Vec<ContactedAddress> = recipients
+        .into_iter()
+        .map(|recipient| ContactedAddress {
+            name: recipient.name,
+            email: recipient.email.to_string(),
+        })
+        .collect();
+    if let Some(sender) = replied_sender {
+        match contacted
+            .iter_mut()
+            .find(|address| address.email.eq_ignore_ascii_case(&sender.email))
+        {
+            Some(address) if address.name.is_none() => address.name = sender.name,
+            Some(_) => {}
+            None => contacted.push(sender),
+        }
+    }
+    // The email is out, a failing address book must not report it as unsent
+    if let Err(err) = cache.record_contacted(&sess_values.email, &contacted, Utc::now().naive_utc())
+    {
+        println!("Couldnt update contacts {:?}", err);
+    }
+
     Ok(HttpResponse::Ok().body("Ok"))
 }
 
+/// Mixed multipart with the text body, or with the text and HTML bodies as alternatives.
+pub fn message_body(text_body: String, html_body: Option<String>) -> MultiPart {
+    match html_body {
+        Some(html_body) => {
+            MultiPart::mixed().multipart(MultiPart::alternative_plain_html(text_body, html_body))
+        }
+        None => MultiPart::mixed().singlepart(
+            SinglePart::builder()
+                .content_type(ContentType::TEXT_PLAIN)
+                .body(text_body),
+        ),
+    }
+}
+
+/// Default attachments of the template were scanned when they were added to it.
+pub fn attach_template_files(
+    mut body: MultiPart,
+    attachments: Vec<TemplateAttachmentFile>,
+) -> Result<MultiPart, Error> {
+    for (attachment, data) in attachments.into_iter() {
+        let content_type = ContentType::parse(&attachment.content_type)
+            .map_err(|err| Error::other(format!("Invalid content type {:?}", err)))?;
+        body = body.singlepart(Attachment::new(attachment.file_name).body(data, content_type));
+    }
+    Ok(body)
+}
+
+fn remove_uploads(uploads: &[(String, String)]) {
+    for (path, _) in uploads.iter() {
+        let _ = remove_file(path);
+    }
+}
+
+/// Sender of the answered email. Replies go to Reply-To, which the server reports as the
+/// sender when the email has none. Blocks until the server answered.
+fn fetch_replied_sender(
+    credentials: &SignInMessage,
+    mailbox_name: &str,
+    uid: u32,
+) -> Result<Option<ContactedAddress>, Error> {
+    let mut imap_connection = connect_imap(
+        &credentials.email,
+        &credentials.password,
+        &credentials.get_imap_string(),
+    )?;
+    imap_connection.command(&format!(
+        "EXAMINE {}",
+        quote_imap_string(&encode_utf7_imap(mailbox_name.to_string()))
+    ))?;
+    let response = imap_connection.command(&format!("UID FETCH {} (UID ENVELOPE)", uid))?;
+    imap_connection.logout()?;
+
+    Ok(response
+        .untagged
+        .iter()
+        .find_map(|line| match imap_proto::parse_response(line) {
+            Ok((_, Response::Fetch(_, attributes))) => {
+                let envelope = attributes.iter().find_map(|attribute| match attribute {
+                    AttributeValue::Envelope(envelope) => Some(envelope),
+                    _ => None,
+                })?;
+                envelope
+                    .reply_to
+                    .as_ref()
+                    .or(envelope.from.as_ref())
+                    .and_then(|addresses| addresses.first())
+                    .and_then(|address| match (address.mailbox, address.host) {
+                        (Some(mailbox), Some(host)) => Some(ContactedAddress {
+                            name: address.name.map(decode_header_value),
+                            email: format!(
+                                "{}@{}",
+                                String::from_utf8_lossy(mailbox),
+                                String::from_utf8_lossy(host)
+                            ),
+                        }),
+                        _ => None,
+                    })
+            }
+            _ => None,
+        }))
+}
+
 pub fn email_smtp_config(cfg: &mut web::ServiceConfig) {
     cfg.service(web::resource("/email/send").route(web::post().to(send_email)));
 }
@@ -0,0 +1,138 @@
+use std::io::Error;
+
+use chrono::DateTime;
+use imap_proto::{AttributeValue, Response};
+
+use crate::utils::{
+    utils_imap::{parse_internal_date, ImapConnection},
+    utils_threading::normalize_subject,
+};
+
+use super::{email_imap::parse_fetch_uid, email_source::decode_header_value, models::EmailSortKey};
+
+struct SortKeys {
+    uid: u32,
+    arrival: i64,
+    date: i64,
+    from: String,
+    subject: String,
+    size: u32,
+}
+
+/// Returns UIDs of all messages in the selected mailbox in the requested order. Uses SORT
+/// (RFC 5256) when the server has it, otherwise fetches the keys and sorts them here. Equal
+/// keys are ordered by UID, so pages stay stable between requests.
+pub fn sorted_uids(
+    imap_connection: &mut ImapConnection,
+    sort_by: &EmailSortKey,
+    descending: bool,
+) -> Result<Vec<u32>, Error> {
+    if imap_connection.has_capability("SORT") {
+        let sort_criterion = match sort_by {
+            EmailSortKey::Date => "DATE",
+            EmailSortKey::Arrival => "ARRIVAL",
+            EmailSortKey::From => "FROM",
+            EmailSortKey::Subject => "SUBJECT",
+            EmailSortKey::Size => "SIZE",
+        };
+        let reverse = if descending { "REVERSE " } else { "" };
+        let sort_response = imap_connection.command(&format!(
+            "UID SORT ({}{}) UTF-8 ALL",
+            reverse, sort_criterion
+        ))?;
+
+        // `* SORT 2 84 882`, not known to `imap_proto`
+        let mut uids = vec![];
+        for line in sort_response.untagged.iter() {
+            let line = String::from_utf8_lossy(line);
+            if let Some(numbers) = line.trim_end().strip_prefix("* SORT") {
+                uids.extend(
+                    numbers
+                        .split_whitespace()
+                        .filter_map(|number| number.parse::<u32>().ok()),
+                );
+            }
+        }
+        return Ok(uids);
+    }
+
+    // The whole mailbox is fetched, so only with the items the key needs. Emails without a
+    // valid Date header sort by the time they arrived, like SORT does.
+    let fetch_items = match sort_by {
+        EmailSortKey::Date => "(UID INTERNALDATE ENVELOPE)",
+        EmailSortKey::Arrival => "(UID INTERNALDATE)",
+        EmailSortKey::From | EmailSortKey::Subject => "(UID ENVELOPE)",
+        EmailSortKey::Size => "(UID RFC822.SIZE)",
+    };
+    let mut messages = vec![];
+    let fetch_response = imap_connection.command(&format!("UID FETCH 1:* {}", fetch_items))?;
+    for line in fetch_response.untagged.iter() {
+        if let Ok((_, Response::Fetch(_, attributes))) = imap_proto::parse_response(line) {
+            if let Some(uid) = parse_fetch_uid(&attributes) {
+                messages.push(parse_sort_keys(uid, &attributes));
+            }
+        }
+    }
+
+    messages.sort_by(|first, second| {
+        let ordering = match sort_by {
+            EmailSortKey::Date => first.date.cmp(&second.date),
+            EmailSortKey::Arrival => first.arrival.cmp(&second.arrival),
+            EmailSortKey::From => first.from.cmp(&second.from),
+            EmailSortKey::Subject => first.subject.cmp(&second.subject),
+            EmailSortKey::Size => first.size.cmp(&second.size),
+        };
+        // Copied or imported emails keep their internal date, so UIDs only break ties
+        ordering.then(first.uid.cmp(&second.uid))
+    });
+    if descending {
+        messages.reverse();
+    }
+
+    Ok(messages.into_iter().map(|message| message.uid).collect())
+}
+
+fn parse_sort_keys(uid: u32, attributes: &[AttributeValue]) -> SortKeys {
+    let mut keys = SortKeys {
+        uid,
+        arrival: 0,
+        date: 0,
+        from: String::new(),
+        subject: String::new(),
+        size: 0,
+    };
+
+    let mut sent_date = None;
+    for attribute in attributes.iter() {
+        match attribute {
+            AttributeValue::InternalDate(date) => {
+                keys.arrival = parse_internal_date(date)
+                    .map(|date| date.timestamp())
+                    .unwrap_or_default()
+            }
+            AttributeValue::Rfc822Size(size) => keys.size = *size,
+            AttributeValue::Envelope(envelope) => {
+                sent_date = envelope.date.and_then(|date| {
+                    DateTime::parse_from_rfc2822(String::from_utf8_lossy(date).trim())
+                        .ok()
+                        .map(|date| date.timestamp())
+                });
+                keys.from = envelope
+                    .from
+                    .as_ref()
+                    .and_then(|from| from.first())
+                    .and_then(|address| address.mailbox)
+                    .map(|mailbox| String::from_utf8_lossy(mailbox).to_lowercase())
+                    .unwrap_or_default();
+                keys.subject = envelope
+                    .subject
+                    .map(|subject| normalize_subject(&decode_header_value(subject)))
+                    .unwrap_or_default();
+            }
+            _ => {}
+        }
+    }
+    // Same as SORT, emails without a valid Date header use the time they arrived
+    keys.date = sent_date.unwrap_or(keys.arrival);
+    keys
+}
@@ -0,0 +1,521 @@
+use std::io::Error;
+
+use actix_session::Session;
+use actix_web::{
+    http::header::{
+        self, CacheControl, CacheDirective, ContentDisposition, ContentRange, ContentRangeSpec,
+        DispositionParam, DispositionType, Header, HeaderValue, Range, TryIntoHeaderValue,
+    },
+    web, HttpRequest, HttpResponse,
+};
+use imap_proto::{AttributeValue, BodyStructure, Response};
+use rustyknife::{behaviour::Intl, rfc5322::unstructured};
+use utf7_imap::encode_utf7_imap;
+
+use crate::{
+    constants::{INLINE_PART_CONTENT_TYPES, INLINE_PART_MAX_AGE_SECS},
+    scanning::AttachmentScanners,
+    storage::{message_cache::MessageCache, scan_log::ScanDirection},
+    utils::{
+        utils_imap::{quote_imap_string, ImapConnection},
+        utils_session::check_is_valid_session,
+        utils_transports::create_imap_connection,
+    },
+};
+
+use super::{
+    email_imap::{
+        attachment_file_name, body_parts, decide_encoding, decode_part_bytes, find_body_part,
+        parse_fetch_text, parse_fetch_uid, parse_sender_and_subject,
+    },
+    email_scanning::{part_scan_target, scan_attachment, scan_part, ScanTarget},
+    models::{EmailHeaderOutDTO, EmailHeadersOutDTO, EmailInlinePartInDTO, EmailSourceInDTO},
+};
+
+// Longest file name built from the subject, without the extension
+const MAX_FILE_NAME_LENGTH: usize = 80;
+
+/// Returns the complete message as the server stores it, for saving it or opening it in
+/// another client. With a section an embedded message is returned instead. The message is
+/// scanned as a whole, so attachments can not be fetched past the scanners this way.
+async fn download_raw_email(
+    session: Session,
+    scanners: web::Data<AttachmentScanners>,
+    cache: web::Data<MessageCache>,
+    request: web::Query<EmailSourceInDTO>,
+) -> Result<HttpResponse, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let section = parse_section(&request)?;
+    let mut imap_connection = connect_and_examine(&session, &request.mailbox_name).await?;
+    let fetch_response = imap_connection.command(&format!(
+        "{} (UID ENVELOPE BODY.PEEK[{}])",
+        fetch_command(request.uid, request.sequence_number)?,
+        section.unwrap_or_default()
+    ))?;
+    imap_connection.logout()?;
+
+    for line in fetch_response.untagged.iter() {
+        if let Ok((_, Response::Fetch(_, attributes))) = imap_proto::parse_response(line) {
+            let source = match parse_fetch_text(&attributes) {
+                Some(source) => source,
+                None => continue,
+            };
+            let uid = parse_fetch_uid(&attributes).unwrap_or_default();
+            let subject = attributes
+                .iter()
+                .find_map(|attribute| match attribute {
+                    AttributeValue::Envelope(envelope) => {
+                        Some(parse_sender_and_subject(envelope).1)
+                    }
+                    _ => None,
+                })
+                .unwrap_or_default();
+
+            let file_name = eml_file_name(&subject, uid);
+            let target = ScanTarget {
+                direction: ScanDirection::Download,
+                mailbox_name: Some(request.mailbox_name.clone()),
+                uid: Some(uid),
+                part_id: section.map(str::to_string),
+                file_name: file_name.clone(),
+                content_type: "message/rfc822".to_string(),
+            };
+            if let Some(refusal) = scan_attachment(
+                &scanners,
+                &cache,
+                &credentials.email,
+                target,
+                source.to_vec(),
+            )
+            .await?
+            {
+                return Ok(refusal);
+            }
+
+            let content_disposition = ContentDisposition {
+                disposition: DispositionType::Attachment,
+                parameters: vec![DispositionParam::Filename(file_name)],
+            };
+            return Ok(HttpResponse::Ok()
+                .insert_header(content_disposition)
+                .content_type("message/rfc822")
+                .body(source.to_vec()));
+        }
+    }
+
+    Err(Error::new(std::io::ErrorKind::NotFound, "Email not found"))
+}
+
+/// Returns all headers of the message in their original order with encoded words decoded,
+/// trace headers like Received and Authentication-Results included. With a section the headers
+/// of an embedded message are returned.
+async fn get_email_headers(
+    session: Session,
+    request: web::Query<EmailSourceInDTO>,
+) -> Result<EmailHeadersOutDTO, Error> {
+    let section = parse_section(&request)?;
+    let mut imap_connection = connect_and_examine(&session, &request.mailbox_name).await?;
+    let fetch_response = imap_connection.command(&format!(
+        "{} (UID BODY.PEEK[{}HEADER])",
+        fetch_command(request.uid, request.sequence_number)?,
+        section
+            .map(|section| format!("{}.", section))
+            .unwrap_or_default()
+    ))?;
+    imap_connection.logout()?;
+
+    for line in fetch_response.untagged.iter() {
+        if let Ok((_, Response::Fetch(_, attributes))) = imap_proto::parse_response(line) {
+            if let Some(header) = parse_fetch_text(&attributes) {
+                return Ok(EmailHeadersOutDTO {
+                    uid: parse_fetch_uid(&attributes).unwrap_or_default(),
+                    headers: parse_headers(header),
+                });
+            }
+        }
+    }
+
+    Err(Error::new(std::io::ErrorKind::NotFound, "Email not found"))
+}
+
+/// Downloads a single decoded part by its section path, which also reaches attachments of
+/// forwarded emails. Nothing is served before all attachment scanners let the part pass.
+async fn download_email_part(
+    session: Session,
+    http_request: HttpRequest,
+    scanners: web::Data<AttachmentScanners>,
+    cache: web::Data<MessageCache>,
+    request: web::Query<EmailSourceInDTO>,
+) -> Result<HttpResponse, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let section = parse_section(&request)?
+        .ok_or_else(|| Error::new(std::io::ErrorKind::InvalidInput, "section has to be set"))?;
+    let mut imap_connection = connect_and_examine(&session, &request.mailbox_name).await?;
+    let fetch_response = imap_connection.command(&format!(
+        "{} (UID BODYSTRUCTURE BODY.PEEK[{}])",
+        fetch_command(request.uid, request.sequence_number)?,
+        section
+    ))?;
+    imap_connection.logout()?;
+
+    for line in fetch_response.untagged.iter() {
+        if let Ok((_, Response::Fetch(_, attributes))) = imap_proto::parse_response(line) {
+            let part = attributes.iter().find_map(|attribute| match attribute {
+                AttributeValue::BodyStructure(structure) => find_body_part(structure, section),
+                _ => None,
+            });
+            let (part, data) = match (part, parse_fetch_text(&attributes)) {
+                (Some(part), Some(data)) => (part, data),
+                _ => continue,
+            };
+            let target = part_scan_target(
+                part,
+                section,
+                &request.mailbox_name,
+                parse_fetch_uid(&attributes),
+            );
+            if let Some(refusal) =
+                scan_part(&scanners, &cache, &credentials.email, part, data, target).await?
+            {
+                return Ok(refusal);
+            }
+
+            return part_response(
+                part,
+                data,
+                section,
+                DispositionType::Attachment,
+                &http_request,
+            );
+        }
+    }
+
+    Err(Error::new(
+        std::io::ErrorKind::NotFound,
+        "Email part not found",
+    ))
+}
+
+/// Serves an inline part, like an image of the HTML body, by its Content-ID. The HTML body
+/// returned with the email detail links here instead of `cid:`. Inline parts are scanned like
+/// attachments.
+async fn get_inline_part(
+    session: Session,
+    http_request: HttpRequest,
+    scanners: web::Data<AttachmentScanners>,
+    cache: web::Data<MessageCache>,
+    request: web::Query<EmailInlinePartInDTO>,
+) -> Result<HttpResponse, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let content_id = request
+        .cid
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>');
+    let mut imap_connection = connect_and_examine(&session, &request.mailbox_name).await?;
+    let structure_response =
+        imap_connection.command(&format!("UID FETCH {} (UID BODYSTRUCTURE)", request.uid))?;
+
+    let mut found_part = None;
+    for line in structure_response.untagged.iter() {
+        if let Ok((_, Response::Fetch(_, attributes))) = imap_proto::parse_response(line) {
+            for attribute in attributes.into_iter() {
+                if let AttributeValue::BodyStructure(structure) = attribute {
+                    found_part = body_parts(&structure)
+                        .into_iter()
+                        .find(|(_, part)| part_content_id(part) == Some(content_id))
+                        .map(|(section, _)| section);
+                }
+            }
+        }
+    }
+    let section = match found_part {
+        Some(section) => section,
+        None => {
+            imap_connection.logout()?;
+            return Err(Error::new(
+                std::io::ErrorKind::NotFound,
+                "Inline part not found",
+            ));
+        }
+    };
+
+    let fetch_response = imap_connection.command(&format!(
+        "UID FETCH {} (UID BODYSTRUCTURE BODY.PEEK[{}])",
+        request.uid, section
+    ))?;
+    imap_connection.logout()?;
+
+    for line in fetch_response.untagged.iter() {
+        if let Ok((_, Response::Fetch(_, attributes))) = imap_proto::parse_response(line) {
+            let part = attributes.iter().find_map(|attribute| match attribute {
+                AttributeValue::BodyStructure(structure) => find_body_part(structure, &section),
+                _ => None,
+            });
+            if let (Some(part), Some(data)) = (part, parse_fetch_text(&attributes)) {
+                let target =
+                    part_scan_target(part, &section, &request.mailbox_name, Some(request.uid));
+                if let Some(refusal) =
+                    scan_part(&scanners, &cache, &credentials.email, part, data, target).await?
+                {
+                    return Ok(refusal);
+                }
+                let mut response =
+                    part_response(part, data, &section, DispositionType::Inline, &http_request)?;
+                // A message never changes under its UID
+                let cache_control = CacheControl(vec![
+                    CacheDirective::Private,
+                    CacheDirective::MaxAge(INLINE_PART_MAX_AGE_SECS),
+                ]);
+                response.headers_mut().insert(
+                    header::CACHE_CONTROL,
+                    cache_control.try_into_value().map_err(Error::other)?,
+                );
+                return Ok(response);
+            }
+        }
+    }
+
+    Err(Error::new(
+        std::io::ErrorKind::NotFound,
+        "Inline part not found",
+    ))
+}
+
+/// Builds the response for a single part with its decoded content and the content type of its
+/// body structure. Only the image types of `INLINE_PART_CONTENT_TYPES` keep the requested
+/// disposition, any other part is an attachment, as it could run script with the session of
+/// the user. A single byte range is answered with 206, several ranges get the whole part. The
+/// range is cut from the decoded part, which is fetched and scanned whole either way, since
+/// offsets in a base64 or quoted-printable section do not match the decoded bytes.
+pub fn part_response(
+    part: &BodyStructure,
+    data: &[u8],
+    section: &str,
+    disposition: DispositionType,
+    http_request: &HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let (common, encoding) = match part {
+        BodyStructure::Basic { common, other, .. }
+        | BodyStructure::Text { common, other, .. }
+        | BodyStructure::Message { common, other, .. } => (common, decide_encoding(other)),
+        BodyStructure::Multipart { .. } => {
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "A multipart section has no content of its own",
+            ))
+        }
+    };
+    let content_disposition = ContentDisposition {
+        disposition,
+        parameters: vec![DispositionParam::Filename(
+            attachment_file_name(part).unwrap_or_else(|| format!("part-{}", section)),
+        )],
+    };
+    let mime_type = format!(
+        "{}/{}",
+        common.ty.ty.to_lowercase(),
+        common.ty.subtype.to_lowercase()
+    );
+    let content_disposition = if INLINE_PART_CONTENT_TYPES.contains(&mime_type.as_str()) {
+        content_disposition
+    } else {
+        ContentDisposition {
+            disposition: DispositionType::Attachment,
+            ..content_disposition
+        }
+    };
+    let content_type = part_content_type(&mime_type, common.ty.params.as_deref());
+    let content = decode_part_bytes(data, &encoding);
+    let length = content.len() as u64;
+
+    let range = match Range::parse(http_request) {
+        Ok(Range::Bytes(ranges)) if ranges.len() == 1 => {
+            Some(ranges[0].to_satisfiable_range(length))
+        }
+        _ => None,
+    };
+    let mut response = match range {
+        Some(Some((start, end))) => HttpResponse::PartialContent()
+            .insert_header(content_disposition)
+            .insert_header((header::ACCEPT_RANGES, "bytes"))
+            .insert_header(ContentRange(ContentRangeSpec::Bytes {
+                range: Some((start, end)),
+                instance_length: Some(length),
+            }))
+            .content_type(content_type)
+            .body(content[start as usize..=end as usize].to_vec()),
+        Some(None) => HttpResponse::RangeNotSatisfiable()
+            .insert_header(ContentRange(ContentRangeSpec::Bytes {
+                range: None,
+                instance_length: Some(length),
+            }))
+            .finish(),
+        None => HttpResponse::Ok()
+            .insert_header(content_disposition)
+            .insert_header((header::ACCEPT_RANGES, "bytes"))
+            .content_type(content_type)
+            .body(content),
+    };
+    // Opened directly, the part gets neither a sniffed type nor the origin of the API
+    response.headers_mut().insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    response.headers_mut().insert(
+        header::CONTENT_SECURITY_POLICY,
+        HeaderValue::from_static("sandbox"),
+    );
+    Ok(response)
+}
+
+/// The part's own type with its charset. Whatever a sender put there ends up in a header, so
+/// anything that is not a plain MIME token is served as `application/octet-stream`.
+fn part_content_type(mime_type: &str, params: Option<&[(&str, &str)]>) -> String {
+    let is_token = |value: &str| {
+        !value.is_empty()
+            && value
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"!#$&-^_.+".contains(&b))
+    };
+    match mime_type.split_once('/') {
+        Some((ty, subtype)) if is_token(ty) && is_token(subtype) => {}
+        _ => return "application/octet-stream".to_string(),
+    }
+
+    let charset = params
+        .unwrap_or_default()
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("charset"))
+        .map(|(_, value)| *value)
+        .filter(|value| is_token(value));
+    match charset {
+        Some(charset) => format!("{}; charset={}", mime_type, charset),
+        None => mime_type.to_string(),
+    }
+}
+
+fn part_content_id<'a>(part: &BodyStructure<'a>) -> Option<&'a str> {
+    match part {
+        BodyStructure::Basic { other, .. }
+        | BodyStructure::Text { other, .. }
+        | BodyStructure::Message { other, .. } => other
+            .id
+            .map(|id| id.trim().trim_start_matches('<').trim_end_matches('>')),
+        BodyStructure::Multipart { .. } => None,
+    }
+}
+
+pub async fn connect_and_examine(
+    session: &Session,
+    mailbox_name: &str,
+) -> Result<ImapConnection, Error> {
+    let credentials = check_is_valid_session(session)?;
+    let mut imap_connection = create_imap_connection(
+        &credentials.email,
+        &credentials.password,
+        &credentials.get_imap_string(),
+    )
+    .await?;
+    // Read-only, downloading must not mark anything as seen
+    imap_connection.command(&format!(
+        "EXAMINE {}",
+        quote_imap_string(&encode_utf7_imap(mailbox_name.to_string()))
+    ))?;
+    Ok(imap_connection)
+}
+
+/// `UID FETCH` when the UID is known, `FETCH` of the sequence number otherwise.
+pub fn fetch_command(uid: Option<u32>, sequence_number: Option<u32>) -> Result<String, Error> {
+    match (uid, sequence_number) {
+        (Some(uid), _) => Ok(format!("UID FETCH {}", uid)),
+        (None, Some(sequence_number)) => Ok(format!("FETCH {}", sequence_number)),
+        (None, None) => Err(Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Either uid or sequence_number has to be set",
+        )),
+    }
+}
+
+fn parse_section(request: &EmailSourceInDTO) -> Result<Option<&str>, Error> {
+    request.section.as_deref().map(validate_section).transpose()
+}
+
+/// Accepts only section paths made of part numbers, anything else would end up in the command.
+pub fn validate_section(section: &str) -> Result<&str, Error> {
+    if section
+        .split('.')
+        .any(|number| number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()))
+    {
+        return Err(Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid section {}", section),
+        ));
+    }
+    Ok(section)
+}
+
+fn eml_file_name(subject: &str, uid: u32) -> String {
+    let name: String = subject
+        .chars()
+        .filter(|char| char.is_alphanumeric() || matches!(char, ' ' | '-' | '_' | '.'))
+        .take(MAX_FILE_NAME_LENGTH)
+        .collect();
+    let name = name.trim().trim_matches('.');
+    if name.is_empty() {
+        format!("email-{}.eml", uid)
+    } else {
+        format!("{}.eml", name)
+    }
+}
+
+/// Splits the header section into fields, unfolding continuation lines. Both CRLF and bare LF
+/// line endings are accepted.
+pub fn parse_headers(header: &[u8]) -> Vec<EmailHeaderOutDTO> {
+    let mut fields: Vec<(String, Vec<u8>)> = vec![];
+    for line in header.split(|byte| *byte == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            continue;
+        }
+
+        if line[0] == b' ' || line[0] == b'\t' {
+            if let Some((_, value)) = fields.last_mut() {
+                value.push(b' ');
+                value.extend_from_slice(line.trim_ascii_start());
+            }
+            continue;
+        }
+        if let Some(colon) = line.iter().position(|byte| *byte == b':') {
+            fields.push((
+                String::from_utf8_lossy(&line[..colon]).trim().to_string(),
+                line[colon + 1..].trim_ascii_start().to_vec(),
+            ));
+        }
+    }
+
+    fields
+        .into_iter()
+        .map(|(name, value)| EmailHeaderOutDTO {
+            name,
+            value: decode_header_value(&value),
+        })
+        .collect()
+}
+
+pub fn decode_header_value(value: &[u8]) -> String {
+    // The parser needs the line ending to know the value is complete
+    let mut line = value.to_vec();
+    line.extend_from_slice(b"\r\n");
+    match unstructured::<Intl>(&line) {
+        Ok((_, decoded)) => decoded.trim_end().to_string(),
+        Err(_) => String::from_utf8_lossy(value).to_string(),
+    }
+}
+
+pub fn email_source_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/email/raw").route(web::get().to(download_raw_email)))
+        .service(web::resource("/email/headers").route(web::get().to(get_email_headers)))
+        .service(web::resource("/email/part").route(web::get().to(download_email_part)))
+        .service(web::resource("/email/cid").route(web::get().to(get_inline_part)));
+}
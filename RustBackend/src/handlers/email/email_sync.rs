@@ -0,0 +1,278 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::Error,
+};
+
+use actix_session::Session;
+use actix_web::web;
+use imap_proto::{MailboxDatum, Response, ResponseCode};
+use utf7_imap::encode_utf7_imap;
+
+use crate::{
+    constants::MAX_SYNC_UIDS,
+    utils::{
+        utils_imap::{
+            format_uid_set, parse_uid_ranges, parse_uid_set, quote_imap_string, ImapConnection,
+        },
+        utils_session::check_is_valid_session,
+        utils_transports::create_imap_connection,
+    },
+};
+
+use super::{
+    email_imap::{parse_fetch_flags, parse_fetch_mod_seq, parse_fetch_uid, parse_inspect_fetch},
+    models::{EmailFlagsOutDTO, SyncInDTO, SyncMode, SyncOutDTO},
+};
+
+/// Returns what changed in a mailbox since the state the client already knows. Uses QRESYNC or
+/// CONDSTORE when the server has them, otherwise compares the flags of all known messages with
+/// the ones the client sent.
+async fn sync_mailbox(
+    session: Session,
+    request: web::Query<SyncInDTO>,
+) -> Result<SyncOutDTO, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let mut imap_connection = create_imap_connection(
+        &credentials.email,
+        &credentials.password,
+        &credentials.get_imap_string(),
+    )
+    .await?;
+
+    let known_uids = match &request.known_uids {
+        Some(uid_set) => parse_uid_set(uid_set, MAX_SYNC_UIDS)?,
+        None => vec![],
+    };
+    let last_known_uid = known_uids.last().cloned().unwrap_or(0);
+
+    // Without known UIDs QRESYNC would report every UID expunged since the mod sequence
+    let sync_mode = match (request.uid_validity, request.highest_mod_seq) {
+        (Some(_), Some(_))
+            if !known_uids.is_empty() && imap_connection.has_capability("QRESYNC") =>
+        {
+            SyncMode::Qresync
+        }
+        (_, Some(_)) if imap_connection.has_capability("CONDSTORE") => SyncMode::Condstore,
+        _ => SyncMode::Full,
+    };
+
+    let mailbox = quote_imap_string(&encode_utf7_imap(request.mailbox_name.clone()));
+    let select_command = match (&sync_mode, request.uid_validity, request.highest_mod_seq) {
+        (SyncMode::Qresync, Some(uid_validity), Some(highest_mod_seq)) => {
+            imap_connection.command("ENABLE QRESYNC")?;
+            format!(
+                "SELECT {} (QRESYNC ({} {} {}))",
+                mailbox,
+                uid_validity,
+                highest_mod_seq,
+                format_uid_set(&known_uids)
+            )
+        }
+        // Selecting with CONDSTORE returns HIGHESTMODSEQ the client can use next time
+        _ if imap_connection.has_capability("CONDSTORE") => {
+            format!("SELECT {} (CONDSTORE)", mailbox)
+        }
+        _ => format!("SELECT {}", mailbox),
+    };
+    let select_response = imap_connection.command(&select_command)?;
+
+    let mut response = SyncOutDTO {
+        mailbox_name: request.mailbox_name.clone(),
+        uid_validity: 0,
+        highest_mod_seq: None,
+        uid_validity_changed: false,
+        sync_mode,
+        new_messages: vec![],
+        flag_changes: vec![],
+        vanished_uids: vec![],
+    };
+
+    let mut exists = 0;
+    for line in select_response.untagged.iter() {
+        if let Some(vanished) = parse_vanished(line)? {
+            // The server's set is not capped, only the known UIDs inside it are of interest
+            for (start, end) in vanished {
+                let first = known_uids.partition_point(|uid| *uid < start);
+                let last = known_uids.partition_point(|uid| *uid <= end);
+                response
+                    .vanished_uids
+                    .extend_from_slice(&known_uids[first..last]);
+            }
+            continue;
+        }
+        match imap_proto::parse_response(line) {
+            Ok((_, Response::Data { code, .. })) => match code {
+                Some(ResponseCode::UidValidity(uid_validity)) => {
+                    response.uid_validity = uid_validity
+                }
+                Some(ResponseCode::HighestModSeq(mod_seq)) => {
+                    response.highest_mod_seq = Some(mod_seq)
+                }
+                _ => {}
+            },
+            Ok((_, Response::MailboxData(MailboxDatum::Exists(count)))) => exists = count,
+            Ok((_, Response::Fetch(_, attributes))) => {
+                // QRESYNC reports changed flags of known messages right in the SELECT response
+                if let Some(uid) = parse_fetch_uid(&attributes) {
+                    if uid <= last_known_uid {
+                        response.flag_changes.push(EmailFlagsOutDTO {
+                            uid,
+                            flags: parse_fetch_flags(&attributes),
+                            mod_seq: parse_fetch_mod_seq(&attributes),
+                        });
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(uid_validity) = request.uid_validity {
+        if uid_validity != response.uid_validity {
+            // Known UIDs mean nothing anymore, the client has to start over
+            response.uid_validity_changed = true;
+            response.flag_changes.clear();
+            response.vanished_uids.clear();
+            imap_connection.logout()?;
+            return Ok(response);
+        }
+    }
+
+    if !known_uids.is_empty() && exists > 0 {
+        match (&response.sync_mode, request.highest_mod_seq) {
+            (SyncMode::Qresync, _) => {}
+            (SyncMode::Condstore, Some(highest_mod_seq)) => {
+                response.flag_changes = fetch_flags(
+                    &mut imap_connection,
+                    &format!(
+                        "UID FETCH {} (UID FLAGS) (CHANGEDSINCE {})",
+                        format_uid_set(&known_uids),
+                        highest_mod_seq
+                    ),
+                )?;
+                let search_response = imap_connection
+                    .command(&format!("UID SEARCH UID {}", format_uid_set(&known_uids)))?;
+                let mut existing_uids = HashSet::new();
+                for line in search_response.untagged.iter() {
+                    if let Ok((_, Response::IDs(uids))) = imap_proto::parse_response(line) {
+                        existing_uids.extend(uids);
+                    }
+                }
+                response.vanished_uids = known_uids
+                    .iter()
+                    .filter(|uid| !existing_uids.contains(*uid))
+                    .cloned()
+                    .collect();
+            }
+            _ => {
+                let known_flags = match &request.known_flags {
+                    Some(known_flags) => parse_known_flags(known_flags)?,
+                    None => HashMap::new(),
+                };
+                let current_flags = fetch_flags(
+                    &mut imap_connection,
+                    &format!("UID FETCH {} (UID FLAGS)", format_uid_set(&known_uids)),
+                )?;
+                let existing_uids: HashSet<u32> =
+                    current_flags.iter().map(|flags| flags.uid).collect();
+                response.vanished_uids = known_uids
+                    .iter()
+                    .filter(|uid| !existing_uids.contains(*uid))
+                    .cloned()
+                    .collect();
+                response.flag_changes = current_flags
+                    .into_iter()
+                    .filter(|flags| {
+                        let known = known_flags.get(&flags.uid).cloned().unwrap_or_default();
+                        normalize_flags(flags.flags.iter()) != known
+                    })
+                    .collect();
+            }
+        }
+    } else if exists == 0 {
+        response.vanished_uids = known_uids;
+    }
+
+    if exists > 0 {
+        // `n:*` always matches at least the last message, even when its UID is lower than n
+        let new_response = imap_connection.command(&format!(
+            "UID FETCH {}:* (UID FLAGS ENVELOPE INTERNALDATE)",
+            last_known_uid + 1
+        ))?;
+        for line in new_response.untagged.iter() {
+            if let Ok((_, Response::Fetch(sequence_number, attributes))) =
+                imap_proto::parse_response(line)
+            {
+                let email = parse_inspect_fetch(sequence_number, &attributes);
+                if email.uid > last_known_uid {
+                    response.new_messages.push(email);
+                }
+            }
+        }
+    }
+
+    response.vanished_uids.sort_unstable();
+    response.vanished_uids.dedup();
+
+    imap_connection.logout()?;
+    Ok(response)
+}
+
+fn fetch_flags(
+    imap_connection: &mut ImapConnection,
+    command: &str,
+) -> Result<Vec<EmailFlagsOutDTO>, Error> {
+    let mut flag_changes = vec![];
+    for line in imap_connection.command(command)?.untagged.iter() {
+        if let Ok((_, Response::Fetch(_, attributes))) = imap_proto::parse_response(line) {
+            if let Some(uid) = parse_fetch_uid(&attributes) {
+                flag_changes.push(EmailFlagsOutDTO {
+                    uid,
+                    flags: parse_fetch_flags(&attributes),
+                    mod_seq: parse_fetch_mod_seq(&attributes),
+                });
+            }
+        }
+    }
+    Ok(flag_changes)
+}
+
+/// Parses `* VANISHED (EARLIER) 41,43:116`, which `imap_proto` does not know, into UID ranges.
+fn parse_vanished(line: &[u8]) -> Result<Option<Vec<(u32, u32)>>, Error> {
+    let line = String::from_utf8_lossy(line);
+    let uid_set = match line.trim_end().strip_prefix("* VANISHED ") {
+        Some(rest) => rest.trim_start_matches("(EARLIER) "),
+        None => return Ok(None),
+    };
+    parse_uid_ranges(uid_set).map(Some)
+}
+
+/// Parses `\Seen 1:100 \Flagged 7,9` into the flags of every UID.
+fn parse_known_flags(known_flags: &str) -> Result<HashMap<u32, HashSet<String>>, Error> {
+    let mut flags_by_uid: HashMap<u32, HashSet<String>> = HashMap::new();
+    let mut words = known_flags.split_whitespace();
+    while let Some(flag) = words.next() {
+        let uid_set = words
+            .next()
+            .ok_or_else(|| Error::other(format!("Missing UID set of flag {}", flag)))?;
+        for uid in parse_uid_set(uid_set, MAX_SYNC_UIDS)? {
+            flags_by_uid
+                .entry(uid)
+                .or_default()
+                .extend(normalize_flags([flag].into_iter()));
+        }
+    }
+    Ok(flags_by_uid)
+}
+
+/// Flags are case-insensitive, `\Recent` only applies to the session that sees it.
+fn normalize_flags<S: AsRef<str>>(flags: impl Iterator<Item = S>) -> HashSet<String> {
+    flags
+        .map(|flag| flag.as_ref().to_ascii_lowercase())
+        .filter(|flag| flag != "\\recent")
+        .collect()
+}
+
+pub fn email_sync_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/sync").route(web::get().to(sync_mailbox)));
+}
@@ -0,0 +1,351 @@
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind},
+};
+
+use actix_multipart::Multipart;
+use actix_session::Session;
+use actix_web::{
+    web::{self, Json},
+    HttpRequest, HttpResponse, Responder,
+};
+use chrono::Utc;
+use futures_util::TryStreamExt;
+use serde_json::Value;
+
+use crate::{
+    constants::TEMPLATE_ATTACHMENT_MAX_BYTES,
+    scanning::AttachmentScanners,
+    storage::{
+        message_cache::MessageCache,
+        scan_log::ScanDirection,
+        templates::{MessageTemplate, TemplateAttachment, TemplateAttachmentFile},
+    },
+    utils::{
+        utils_multipart::read_field_bytes,
+        utils_session::check_is_valid_session,
+        utils_template::{missing_variables, parse_placeholders, render_template, TemplateEscape},
+    },
+};
+
+use super::{
+    email_scanning::{scan_attachment, ScanTarget},
+    models::{
+        TemplateAttachmentIdInDTO, TemplateAttachmentOutDTO, TemplateIdInDTO, TemplateInDTO,
+        TemplateListOutDTO, TemplateOutDTO, TemplateVariableOutDTO,
+    },
+};
+
+/// Subject and bodies of a template with the variables filled in.
+pub struct RenderedMessage {
+    pub subject: String,
+    pub text_body: String,
+    pub html_body: Option<String>,
+}
+
+/// Templates of the signed in account ordered by name.
+async fn list_templates(
+    session: Session,
+    cache: web::Data<MessageCache>,
+) -> Result<TemplateListOutDTO, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let mut templates = cache.templates(&credentials.email)?;
+    templates.sort_by_key(|(_, template)| template.name.to_lowercase());
+    Ok(TemplateListOutDTO {
+        templates: templates
+            .into_iter()
+            .map(|(id, template)| template_out(&cache, &credentials.email, id, template))
+            .collect::<Result<Vec<TemplateOutDTO>, Error>>()?,
+    })
+}
+
+async fn create_template(
+    session: Session,
+    cache: web::Data<MessageCache>,
+    request: Json<TemplateInDTO>,
+) -> Result<TemplateOutDTO, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let template = read_template_input(request.into_inner())?;
+    let id = cache.insert_template(&credentials.email, &template)?;
+    template_out(&cache, &credentials.email, id, template)
+}
+
+/// Replaces the texts, the attachments are kept.
+async fn update_template(
+    session: Session,
+    cache: web::Data<MessageCache>,
+    query: web::Query<TemplateIdInDTO>,
+    request: Json<TemplateInDTO>,
+) -> Result<TemplateOutDTO, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let template = read_template_input(request.into_inner())?;
+    if !cache.update_template(&credentials.email, query.id, &template)? {
+        return Err(template_not_found());
+    }
+    template_out(&cache, &credentials.email, query.id, template)
+}
+
+async fn delete_template(
+    session: Session,
+    cache: web::Data<MessageCache>,
+    query: web::Query<TemplateIdInDTO>,
+) -> Result<HttpResponse, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    if !cache.delete_template(&credentials.email, query.id)? {
+        return Err(template_not_found());
+    }
+    Ok(HttpResponse::Ok().body("Ok"))
+}
+
+/// Adds the uploaded files as default attachments. They are scanned like attachments of a
+/// sent email, a refused file rejects the whole upload.
+async fn add_template_attachments(
+    mut payload: Multipart,
+    http_request: HttpRequest,
+    session: Session,
+    scanners: web::Data<AttachmentScanners>,
+    cache: web::Data<MessageCache>,
+    query: web::Query<TemplateIdInDTO>,
+) -> Result<HttpResponse, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let template = cache
+        .template(&credentials.email, query.id)?
+        .ok_or_else(template_not_found)?;
+
+    let mut files = vec![];
+    while let Some(mut field) = payload
+        .try_next()
+        .await
+        .map_err(|err| Error::other(format!("Reading upload Error {:?}", err)))?
+    {
+        let file_name = match field.content_disposition().get_filename() {
+            Some(file_name) if !file_name.trim().is_empty() => file_name.to_string(),
+            _ => continue,
+        };
+        let data = read_field_bytes(&mut field, TEMPLATE_ATTACHMENT_MAX_BYTES).await?;
+        files.push((file_name, data));
+    }
+    if files.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "No file uploaded"));
+    }
+
+    let mut attachments = vec![];
+    for (file_name, data) in files.into_iter() {
+        let content_type = mime_guess::from_path(&file_name)
+            .first_or_octet_stream()
+            .essence_str()
+            .to_string();
+        let target = ScanTarget {
+            direction: ScanDirection::Upload,
+            mailbox_name: None,
+            uid: None,
+            part_id: None,
+            file_name: file_name.clone(),
+            content_type: content_type.clone(),
+        };
+        if let Some(refusal) =
+            scan_attachment(&scanners, &cache, &credentials.email, target, data.clone()).await?
+        {
+            return Ok(refusal);
+        }
+        attachments.push((
+            TemplateAttachment {
+                file_name,
+                content_type,
+                size: data.len(),
+            },
+            data,
+        ));
+    }
+    for (attachment, data) in attachments.iter() {
+        cache.insert_template_attachment(&credentials.email, query.id, attachment, data)?;
+    }
+
+    Ok(template_out(&cache, &credentials.email, query.id, template)?.respond_to(&http_request))
+}
+
+async fn delete_template_attachment(
+    session: Session,
+    cache: web::Data<MessageCache>,
+    query: web::Query<TemplateAttachmentIdInDTO>,
+) -> Result<TemplateOutDTO, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let template = cache
+        .template(&credentials.email, query.template_id)?
+        .ok_or_else(template_not_found)?;
+    if !cache.delete_template_attachment(
+        &credentials.email,
+        query.template_id,
+        query.attachment_id,
+    )? {
+        return Err(Error::new(ErrorKind::NotFound, "Attachment not found"));
+    }
+    template_out(&cache, &credentials.email, query.template_id, template)
+}
+
+/// Fills in the subject and the bodies. Every missing required variable is named in the
+/// error, values are HTML escaped in the HTML body and kept on one line in the subject.
+pub fn render_message(
+    template: &MessageTemplate,
+    variables: &HashMap<String, String>,
+) -> Result<RenderedMessage, Error> {
+    let missing = missing_variables(&template.texts(), variables)?;
+    if !missing.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Missing template variables: {}", missing.join(", ")),
+        ));
+    }
+
+    let subject_variables: HashMap<String, String> = variables
+        .iter()
+        .map(|(name, value)| {
+            let value = value.split_whitespace().collect::<Vec<&str>>().join(" ");
+            (name.clone(), value)
+        })
+        .collect();
+    Ok(RenderedMessage {
+        subject: render_template(&template.subject, &subject_variables, TemplateEscape::None)?,
+        text_body: render_template(&template.text_body, variables, TemplateEscape::None)?,
+        html_body: match template.html_body.as_deref() {
+            Some(html_body) => Some(render_template(html_body, variables, TemplateEscape::Html)?),
+            None => None,
+        },
+    })
+}
+
+/// The saved template filled in with the variables, with its default attachments.
+pub fn load_template_message(
+    cache: &MessageCache,
+    account: &str,
+    template_id: i64,
+    variables: &HashMap<String, String>,
+) -> Result<(RenderedMessage, Vec<TemplateAttachmentFile>), Error> {
+    let template = cache
+        .template(account, template_id)?
+        .ok_or_else(template_not_found)?;
+    let rendered = render_message(&template, variables)?;
+    Ok((
+        rendered,
+        cache.template_attachment_files(account, template_id)?,
+    ))
+}
+
+/// Variables sent as a JSON object. Numbers and booleans are used as text, null counts as a
+/// missing value.
+pub fn parse_variables(data: &[u8]) -> Result<HashMap<String, String>, Error> {
+    let object: serde_json::Map<String, Value> = serde_json::from_slice(data).map_err(|err| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid template variables: {}", err),
+        )
+    })?;
+    json_variables(object)
+}
+
+pub fn json_variables(
+    object: serde_json::Map<String, Value>,
+) -> Result<HashMap<String, String>, Error> {
+    object
+        .into_iter()
+        .filter_map(|(name, value)| match value {
+            Value::Null => None,
+            Value::String(value) => Some(Ok((name, value))),
+            Value::Number(value) => Some(Ok((name, value.to_string()))),
+            Value::Bool(value) => Some(Ok((name, value.to_string()))),
+            _ => Some(Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Template variable {} is no text", name),
+            ))),
+        })
+        .collect()
+}
+
+/// Checks the placeholders, so a broken template is refused when it is saved and not when
+/// it is used.
+fn read_template_input(input: TemplateInDTO) -> Result<MessageTemplate, Error> {
+    let name = input.name.trim().to_string();
+    if name.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "The template needs a name",
+        ));
+    }
+    let template = MessageTemplate {
+        name,
+        subject: input.subject,
+        text_body: input.text_body,
+        html_body: input
+            .html_body
+            .filter(|html_body| !html_body.trim().is_empty()),
+        updated_at: Utc::now().naive_utc(),
+    };
+    for text in template.texts() {
+        parse_placeholders(text)?;
+    }
+    Ok(template)
+}
+
+fn template_out(
+    cache: &MessageCache,
+    account: &str,
+    id: i64,
+    template: MessageTemplate,
+) -> Result<TemplateOutDTO, Error> {
+    let mut variables: Vec<TemplateVariableOutDTO> = vec![];
+    for text in template.texts() {
+        for placeholder in parse_placeholders(text)?.into_iter() {
+            match variables
+                .iter_mut()
+                .find(|variable| variable.name == placeholder.name)
+            {
+                // Required as soon as one use has no default
+                Some(variable) => variable.required |= placeholder.default.is_none(),
+                None => variables.push(TemplateVariableOutDTO {
+                    name: placeholder.name,
+                    required: placeholder.default.is_none(),
+                    default: placeholder.default,
+                }),
+            }
+        }
+    }
+
+    Ok(TemplateOutDTO {
+        id,
+        variables,
+        attachments: cache
+            .template_attachments(account, id)?
+            .into_iter()
+            .map(|(attachment_id, attachment)| TemplateAttachmentOutDTO {
+                id: attachment_id,
+                file_name: attachment.file_name,
+                content_type: attachment.content_type,
+                size: attachment.size,
+            })
+            .collect(),
+        name: template.name,
+        subject: template.subject,
+        text_body: template.text_body,
+        html_body: template.html_body,
+        updated_at: template.updated_at,
+    })
+}
+
+pub fn template_not_found() -> Error {
+    Error::new(ErrorKind::NotFound, "Template not found")
+}
+
+pub fn email_templates_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/templates")
+            .route(web::get().to(list_templates))
+            .route(web::post().to(create_template))
+            .route(web::put().to(update_template))
+            .route(web::delete().to(delete_template)),
+    )
+    .service(
+        web::resource("/templates/attachments")
+            .route(web::post().to(add_template_attachments))
+            .route(web::delete().to(delete_template_attachment)),
+    );
+}
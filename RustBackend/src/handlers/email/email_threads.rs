@@ -0,0 +1,257 @@
+use std::{cmp::Reverse, collections::HashMap, io::Error};
+
+use actix_session::Session;
+use actix_web::web;
+use chrono::NaiveDateTime;
+use imap_proto::{AttributeValue, Envelope, Response};
+use utf7_imap::encode_utf7_imap;
+
+use crate::{
+    constants::THREAD_MAX_EMAILS,
+    utils::{
+        utils_imap::quote_imap_string,
+        utils_session::check_is_valid_session,
+        utils_threading::{
+            parse_message_ids, parse_thread_response, thread_by_references, ThreadInput, ThreadTree,
+        },
+        utils_transports::create_imap_connection,
+    },
+};
+
+use super::{
+    email_cache::parse_select_response,
+    email_imap::{parse_fetch_text, parse_inspect_fetch},
+    models::{
+        EmailInspectOutDTO, EmailThreadOutDTO, ThreadListInDTO, ThreadListOutDTO, ThreadNodeOutDTO,
+        ThreadingAlgorithm,
+    },
+};
+
+// References is not part of the envelope, Message-ID and In-Reply-To are
+const THREAD_FETCH_ITEMS: &str =
+    "(UID FLAGS ENVELOPE INTERNALDATE RFC822.SIZE BODYSTRUCTURE BODY.PEEK[HEADER.FIELDS (REFERENCES)])";
+
+struct ThreadedEmail {
+    mailbox_name: String,
+    email: EmailInspectOutDTO,
+    participants: Vec<String>,
+}
+
+#[derive(Default)]
+struct ThreadSummary {
+    subject: Option<String>,
+    emails_count: u32,
+    unread_count: u32,
+    participants: Vec<String>,
+    latest_date: Option<NaiveDateTime>,
+}
+
+/// Returns conversations of the given mailboxes, newest activity first. A single mailbox is
+/// threaded by the server when it supports THREAD=REFERENCES, anything else locally.
+async fn list_threads(
+    session: Session,
+    request: web::Query<ThreadListInDTO>,
+) -> Result<ThreadListOutDTO, Error> {
+    let credentials = check_is_valid_session(&session)?;
+    let mailbox_names: Vec<String> = request
+        .mailbox_names
+        .clone()
+        .unwrap_or_else(|| "INBOX".to_string())
+        .split(',')
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect();
+
+    let mut imap_connection = create_imap_connection(
+        &credentials.email,
+        &credentials.password,
+        &credentials.get_imap_string(),
+    )
+    .await?;
+    // Server threading can not join conversations spread over more mailboxes
+    let use_server_threading =
+        mailbox_names.len() == 1 && imap_connection.has_capability("THREAD=REFERENCES");
+
+    let mut emails = vec![];
+    let mut thread_inputs = vec![];
+    let mut server_threads = None;
+    for mailbox_name in mailbox_names.iter() {
+        let examine_response = imap_connection.command(&format!(
+            "EXAMINE {}",
+            quote_imap_string(&encode_utf7_imap(mailbox_name.clone()))
+        ))?;
+        let (exists, _) = parse_select_response(&examine_response.untagged);
+        if exists == 0 {
+            continue;
+        }
+
+        let first = exists.saturating_sub(THREAD_MAX_EMAILS) + 1;
+        let mut commands = vec![format!("FETCH {}:* {}", first, THREAD_FETCH_ITEMS)];
+        if use_server_threading {
+            commands.push(format!("UID THREAD REFERENCES UTF-8 {}:*", first));
+        }
+        let mut responses = imap_connection.pipeline(&commands)?.into_iter();
+
+        let mut positions = HashMap::new();
+        for line in responses.next().unwrap()?.untagged.iter() {
+            if let Ok((_, Response::Fetch(sequence_number, attributes))) =
+                imap_proto::parse_response(line)
+            {
+                let email = parse_inspect_fetch(sequence_number, &attributes);
+                let envelope = attributes.iter().find_map(|attribute| match attribute {
+                    AttributeValue::Envelope(envelope) => Some(envelope),
+                    _ => None,
+                });
+                let references = parse_fetch_text(&attributes)
+                    .map(|header| parse_message_ids(&String::from_utf8_lossy(header)))
+                    .unwrap_or_default();
+
+                let mut thread_input = ThreadInput {
+                    message_id: None,
+                    references,
+                    subject: email.subject.clone(),
+                };
+                let mut participants = vec![];
+                if let Some(envelope) = envelope {
+                    thread_input.message_id = envelope
+                        .message_id
+                        .and_then(|id| parse_message_ids(&String::from_utf8_lossy(id)).pop());
+                    if thread_input.references.is_empty() {
+                        thread_input.references = envelope
+                            .in_reply_to
+                            .map(|ids| parse_message_ids(&String::from_utf8_lossy(ids)))
+                            .unwrap_or_default();
+                    }
+                    participants = parse_participants(envelope);
+                }
+
+                positions.insert(email.uid, emails.len());
+                thread_inputs.push(thread_input);
+                emails.push(Some(ThreadedEmail {
+                    mailbox_name: mailbox_name.clone(),
+                    email,
+                    participants,
+                }));
+            }
+        }
+
+        match responses.next() {
+            Some(Ok(thread_response)) => {
+                server_threads = thread_response
+                    .untagged
+                    .iter()
+                    .map(|line| String::from_utf8_lossy(line).to_string())
+                    .find(|line| line.starts_with("* THREAD"))
+                    .map(|line| parse_thread_response(&line, &positions));
+            }
+            Some(Err(err)) => println!("Server threading failed, threading locally: {}", err),
+            None => {}
+        }
+    }
+    imap_connection.logout()?;
+
+    let (threading_algorithm, trees) = match server_threads {
+        Some(trees) => (ThreadingAlgorithm::Server, trees),
+        None => (
+            ThreadingAlgorithm::Local,
+            thread_by_references(&thread_inputs),
+        ),
+    };
+
+    let mut threads = vec![];
+    for tree in trees.into_iter() {
+        let mut summary = ThreadSummary::default();
+        let root = build_thread_node(tree, &mut emails, &mut summary);
+        if let (Some(latest_date), true) = (summary.latest_date, summary.emails_count > 0) {
+            threads.push(EmailThreadOutDTO {
+                subject: summary.subject.unwrap_or_default(),
+                emails_count: summary.emails_count,
+                unread_count: summary.unread_count,
+                participants: summary.participants,
+                latest_date,
+                root,
+            });
+        }
+    }
+    threads.sort_by_key(|thread| Reverse(thread.latest_date));
+
+    Ok(ThreadListOutDTO {
+        mailbox_names,
+        threading_algorithm,
+        total_threads_count: threads.len() as u32,
+        requested_page_number: request.requested_page_number,
+        page_size: request.page_size,
+        threads: threads
+            .into_iter()
+            .skip((request.requested_page_number * request.page_size) as usize)
+            .take(request.page_size as usize)
+            .collect(),
+    })
+}
+
+fn parse_participants(envelope: &Envelope) -> Vec<String> {
+    [&envelope.from, &envelope.to, &envelope.cc]
+        .iter()
+        .filter_map(|addresses| addresses.as_ref())
+        .flatten()
+        .filter_map(|address| match (address.mailbox, address.host) {
+            (Some(mailbox), Some(host)) => Some(format!(
+                "{}@{}",
+                String::from_utf8_lossy(mailbox),
+                String::from_utf8_lossy(host)
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
+fn build_thread_node(
+    tree: ThreadTree,
+    emails: &mut [Option<ThreadedEmail>],
+    summary: &mut ThreadSummary,
+) -> ThreadNodeOutDTO {
+    let mut node = ThreadNodeOutDTO {
+        mailbox_name: None,
+        email: None,
+        children: vec![],
+    };
+
+    if let Some(threaded) = tree.message.and_then(|index| emails[index].take()) {
+        summary.emails_count += 1;
+        if !threaded.email.was_read {
+            summary.unread_count += 1;
+        }
+        for participant in threaded.participants.into_iter() {
+            if !summary.participants.contains(&participant) {
+                summary.participants.push(participant);
+            }
+        }
+        if summary.latest_date < Some(threaded.email.send_date) {
+            summary.latest_date = Some(threaded.email.send_date);
+        }
+        if summary.subject.is_none() {
+            summary.subject = Some(threaded.email.subject.clone());
+        }
+        node.mailbox_name = Some(threaded.mailbox_name);
+        node.email = Some(threaded.email);
+    }
+
+    node.children = tree
+        .children
+        .into_iter()
+        .map(|child| build_thread_node(child, emails, summary))
+        .collect();
+    node.children.sort_by_key(earliest_date);
+    node
+}
+
+fn earliest_date(node: &ThreadNodeOutDTO) -> Option<NaiveDateTime> {
+    match &node.email {
+        Some(email) => Some(email.send_date),
+        None => node.children.iter().filter_map(earliest_date).min(),
+    }
+}
+
+pub fn email_threads_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/threads").route(web::get().to(list_threads)));
+}
@@ -1,15 +1,37 @@
+use chrono::NaiveDateTime;
+
 pub struct EmailAnalysis {
-    pub plain_text_octets: u32,
     pub attachments: Vec<EmailPartDescription>,
+    pub messages: Vec<EmbeddedMessageDescription>,
+}
+
+/// A message/rfc822 part, like a forwarded email or the original message of a bounce.
+pub struct EmbeddedMessageDescription {
+    pub section: String,
+    pub from_address: String,
+    pub subject: String,
+    pub send_date: NaiveDateTime,
+    /// Body of the embedded message, the positions of its parts point into it
+    pub text: Vec<u8>,
+    pub analysis: EmailAnalysis,
 }
 
 pub struct EmailPartDescription {
     pub file_name: String,
+    /// IMAP section path of the part, e.g. `2.1`
+    pub section: String,
     pub size_octets: u32,
     pub bytes_start: usize,
     pub bytes_end: usize,
     pub is_file: bool,
+    /// Shown inside the HTML body, like a logo referenced by `cid:`
+    pub is_inline: bool,
     pub is_email_text: bool,
+    pub is_email_html: bool,
+    /// Lowercase `type/subtype`
+    pub content_type: String,
+    /// Content-ID without the angle brackets
+    pub content_id: Option<String>,
     pub encoding: EncodingType,
 }
 
@@ -1,5 +1,23 @@
+pub mod email_archive;
+pub mod email_attachment_preview;
+pub mod email_attachments;
+pub mod email_bulk_send;
+pub mod email_cache;
+pub mod email_calendar;
+pub mod email_contacts;
+pub mod email_dav;
 pub mod email_imap;
+pub mod email_import;
+pub mod email_notifications;
+pub mod email_preview;
+pub mod email_scanning;
+pub mod email_search;
 pub mod email_smtp;
+pub mod email_sort;
+pub mod email_source;
+pub mod email_sync;
+pub mod email_templates;
+pub mod email_threads;
 pub mod helper_models;
 pub mod models;
 pub mod models_responders;
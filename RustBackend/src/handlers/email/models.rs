@@ -1,18 +1,58 @@
-use chrono::NaiveDateTime;
+use std::collections::HashMap;
+
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime};
 use serde::{Deserialize, Serialize};
 
+use crate::storage::{bulk_sends::BulkRecipientStatus, scan_log::ScanRefusal};
+
 #[derive(Deserialize, Debug)]
 pub struct EmailInDTO {
+    /// Addresses, contact names or group names separated by commas
     pub to_address: String,
     pub subject: String,
     pub body: String,
+    /// Email being answered, its sender is added to the contacts
+    pub reply_mailbox_name: Option<String>,
+    pub reply_uid: Option<u32>,
+    /// Saved template replacing subject and body, its attachments are added to the uploads
+    pub template_id: Option<i64>,
+    /// Values of the template placeholders
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct EmailListInDTO {
+    /// Ignored when a cursor is given
+    #[serde(default)]
     pub requested_page_number: u32,
     pub page_size: u32,
     pub mailbox_name: String,
+    /// Skips the local cache and asks the server
+    pub fresh: Option<bool>,
+    /// Arrival when missing
+    pub sort_by: Option<EmailSortKey>,
+    /// Descending when missing
+    pub order: Option<SortOrder>,
+    /// `next_cursor` of the previous page, continues right after its last email
+    pub cursor: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EmailSortKey {
+    Date,
+    Arrival,
+    From,
+    Subject,
+    Size,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    Asc,
+    Desc,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -22,6 +62,8 @@ pub struct EmailListOutDTO {
     pub requested_page_number: u32,
     pub page_size: u32,
     pub emails: Vec<EmailInspectOutDTO>,
+    pub next_cursor: Option<String>,
+    pub has_more: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -30,7 +72,12 @@ pub struct EmailInspectOutDTO {
     pub subject: String,
     pub was_read: bool,
     pub send_date: NaiveDateTime,
-    pub sequence_number: u32
+    pub sequence_number: u32,
+    pub uid: u32,
+    pub size_octets: u32,
+    pub has_attachments: bool,
+    /// Beginning of the text, empty when it was not downloaded yet
+    pub preview: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -43,21 +90,60 @@ pub struct EmailDeleteInDTO {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct EmailDetailInDTO {
     pub mailbox_name: String,
-    pub sequence_number: u32,
+    pub sequence_number: Option<u32>,
+    /// Preferred over `sequence_number`, only emails requested by UID are served from cache
+    pub uid: Option<u32>,
+    pub fresh: Option<bool>,
+    /// Sets \Seen, reading the email alone leaves its flags untouched
+    pub mark_read: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct EmailDetailOutDTO {
+    pub uid: u32,
+    pub from_address: String,
+    pub subject: String,
+    pub send_date: NaiveDateTime,
+    pub body_text: String,
+    /// Sanitised HTML part, `cid:` references point to `/email/cid`
+    pub body_html: Option<String>,
+    pub attachments: Vec<EmailDetailAttachmentOutDTO>,
+    pub inline_parts: Vec<EmailDetailInlinePartOutDTO>,
+    /// Forwarded emails and other message/rfc822 parts
+    pub messages: Vec<EmailDetailMessageOutDTO>,
+    /// Meeting invitation or other event of the first text/calendar part
+    pub invitation: Option<EmailInvitationOutDTO>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EmailDetailMessageOutDTO {
+    /// Section path of the message/rfc822 part, e.g. `2`
+    pub section: String,
     pub from_address: String,
     pub subject: String,
     pub send_date: NaiveDateTime,
     pub body_text: String,
+    pub body_html: Option<String>,
     pub attachments: Vec<EmailDetailAttachmentOutDTO>,
+    pub inline_parts: Vec<EmailDetailInlinePartOutDTO>,
+    pub messages: Vec<EmailDetailMessageOutDTO>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EmailDetailInlinePartOutDTO {
+    pub content_id: Option<String>,
+    pub content_type: String,
+    pub section: String,
+    pub size_octets: u32,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct EmailDetailAttachmentOutDTO {
     pub file_name: String,
+    /// Stable part id for `/attachment` and `/email/part`, also inside of embedded messages
+    pub section: String,
+    /// MIME type from BODYSTRUCTURE, e.g. `application/pdf`
+    pub content_type: String,
     pub size_octets: u32,
     pub is_file: bool,
 }
@@ -67,16 +153,622 @@ pub struct MailboxListOutDTO {
     pub mailboxes: Vec<MailboxOutInfoDTO>,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MailboxListInDTO {
+    pub subscribed_only: Option<bool>,
+    pub name_pattern: Option<String>,
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MailboxOutInfoDTO {
     pub name: String,
-    pub emails_count: u32
+    pub emails_count: u32,
+    pub unread_count: u32,
+    pub recent_count: u32,
+    pub uid_next: Option<u32>,
+    pub uid_validity: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct EmailAttachmentInDTO {
     pub mailbox_name: String,
-    pub sequence_number: u32,
-    pub attachment_name: String,
+    pub sequence_number: Option<u32>,
+    /// Preferred over `sequence_number`
+    pub uid: Option<u32>,
+    /// Section path of the attachment, see `EmailDetailAttachmentOutDTO::section`
+    pub part_id: Option<String>,
+    /// Only used without `part_id`, fails when several attachments share the name
+    pub attachment_name: Option<String>,
+    /// `attachment` by default, `inline` lets the browser show the file. Only images of
+    /// `INLINE_PART_CONTENT_TYPES` are shown, other parts are always downloaded
+    pub disposition: Option<AttachmentDisposition>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AttachmentDisposition {
+    Inline,
+    Attachment,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NotificationsInDTO {
+    /// Comma separated names of the watched mailboxes, at most `NOTIFICATION_MAX_MAILBOXES`
+    pub mailbox_names: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MailboxEventType {
+    NewMessage,
+    Expunged,
+    FlagsChanged,
+    Error,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MailboxEventOutDTO {
+    pub event_type: MailboxEventType,
+    pub mailbox_name: String,
+    pub uid: Option<u32>,
+    pub flags: Vec<String>,
+    pub email: Option<EmailInspectOutDTO>,
+    pub message: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SyncInDTO {
+    pub mailbox_name: String,
+    pub uid_validity: Option<u32>,
+    pub highest_mod_seq: Option<u64>,
+    /// UID set of messages the client has, e.g. `1:120,125`
+    pub known_uids: Option<String>,
+    /// Flags the client has for its known UIDs, each followed by a UID set, e.g.
+    /// `\Seen 1:100 \Flagged 7,9`. Only used to diff flags when the server has no CONDSTORE.
+    pub known_flags: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncMode {
+    Qresync,
+    Condstore,
+    Full,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SyncOutDTO {
+    pub mailbox_name: String,
+    pub uid_validity: u32,
+    pub highest_mod_seq: Option<u64>,
+    pub uid_validity_changed: bool,
+    pub sync_mode: SyncMode,
+    pub new_messages: Vec<EmailInspectOutDTO>,
+    pub flag_changes: Vec<EmailFlagsOutDTO>,
+    pub vanished_uids: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EmailFlagsOutDTO {
+    pub uid: u32,
+    pub flags: Vec<String>,
+    pub mod_seq: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FullTextSearchInDTO {
+    /// Whole words and "quoted phrases" with optional `from:`, `subject:`, `body:` and
+    /// `attachment:` fields. Word prefixes are not supported, a trailing `*` is ignored.
+    pub query: String,
+    pub mailbox_name: Option<String>,
+    pub requested_page_number: u32,
+    pub page_size: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FullTextSearchOutDTO {
+    pub query: String,
+    pub total_results_count: u32,
+    pub requested_page_number: u32,
+    pub page_size: u32,
+    pub results: Vec<SearchHitOutDTO>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SearchHitOutDTO {
+    pub mailbox_name: String,
+    pub uid: u32,
+    pub from_address: String,
+    pub subject: String,
+    pub send_date: NaiveDateTime,
+    pub score: f64,
+    /// HTML escaped part of the text with matched words wrapped in `<mark>`
+    pub snippet: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ThreadListInDTO {
+    /// Comma separated names of the threaded mailboxes, e.g. `INBOX,Sent`
+    pub mailbox_names: Option<String>,
+    pub requested_page_number: u32,
+    pub page_size: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ThreadingAlgorithm {
+    Server,
+    Local,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ThreadListOutDTO {
+    pub mailbox_names: Vec<String>,
+    pub threading_algorithm: ThreadingAlgorithm,
+    pub total_threads_count: u32,
+    pub requested_page_number: u32,
+    pub page_size: u32,
+    pub threads: Vec<EmailThreadOutDTO>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EmailThreadOutDTO {
+    pub subject: String,
+    pub emails_count: u32,
+    pub unread_count: u32,
+    pub participants: Vec<String>,
+    pub latest_date: NaiveDateTime,
+    pub root: ThreadNodeOutDTO,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ThreadNodeOutDTO {
+    /// Missing for emails which are replied to but not present in the mailboxes
+    pub mailbox_name: Option<String>,
+    pub email: Option<EmailInspectOutDTO>,
+    pub children: Vec<ThreadNodeOutDTO>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EmailSourceInDTO {
+    pub mailbox_name: String,
+    /// Preferred over `sequence_number`
+    pub uid: Option<u32>,
+    pub sequence_number: Option<u32>,
+    /// Section path of a part, e.g. `2` for an embedded message or `2.1` for a file in it
+    pub section: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EmailInlinePartInDTO {
+    pub mailbox_name: String,
+    pub uid: u32,
+    /// Content-ID of the part, with or without the angle brackets
+    pub cid: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EmailHeadersOutDTO {
+    pub uid: u32,
+    pub headers: Vec<EmailHeaderOutDTO>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EmailHeaderOutDTO {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EmailImportOutDTO {
+    pub mailbox_name: String,
+    pub results: Vec<EmailImportResultOutDTO>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EmailImportResultOutDTO {
+    pub file_name: String,
+    pub imported: bool,
+    /// Only known when the server supports UIDPLUS
+    pub uid: Option<u32>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveFormat {
+    Mbox,
+    Maildir,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ArchiveExportInDTO {
+    pub mailbox_name: String,
+    pub format: ArchiveFormat,
+    /// UID set of the exported messages, e.g. `1:120,125`
+    pub uids: Option<String>,
+    /// Exports only messages containing the text, ignored when `uids` is set
+    pub search_text: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveImportEventType {
+    Progress,
+    Done,
+    Error,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ArchiveImportEventOutDTO {
+    pub event_type: ArchiveImportEventType,
+    pub mailbox_name: String,
+    pub processed: usize,
+    pub imported: usize,
+    pub failed: usize,
+    pub message: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AttachmentsZipInDTO {
+    pub mailbox_name: String,
+    /// Preferred over `sequence_number`
+    pub uid: Option<u32>,
+    pub sequence_number: Option<u32>,
+    /// Comma separated part ids of the wanted attachments, all of them when missing
+    pub part_ids: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AttachmentSearchInDTO {
+    /// Comma separated names of the searched mailboxes, INBOX when missing, at most
+    /// `ATTACHMENT_SEARCH_MAX_MAILBOXES`
+    pub mailbox_names: Option<String>,
+    /// First day of the range, e.g. `2024-01-31`
+    pub since: Option<NaiveDate>,
+    /// Day after the range
+    pub before: Option<NaiveDate>,
+    /// Part of the sender address or name
+    pub from: Option<String>,
+    /// `application/pdf` or a whole type like `image/*`
+    pub content_type: Option<String>,
+    /// File name pattern with `*` and `?`, without them any file name containing the text
+    pub file_name: Option<String>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AttachmentSearchOutDTO {
+    pub attachments: Vec<AttachmentSearchResultOutDTO>,
+    /// More attachments matched than `limit`, or the search stopped before the oldest emails
+    pub truncated: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AttachmentSearchResultOutDTO {
+    pub mailbox_name: String,
+    pub uid: u32,
+    pub part_id: String,
+    pub file_name: String,
+    pub size_octets: u32,
+    pub content_type: String,
+    pub from_address: String,
+    pub subject: String,
+    pub send_date: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AttachmentPreviewInDTO {
+    pub mailbox_name: String,
+    pub uid: u32,
+    pub part_id: String,
+    /// Longest edge of a thumbnail in pixels
+    pub size: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AttachmentTextPreviewOutDTO {
+    pub content_type: String,
+    pub text: String,
+    /// The text was cut after `ATTACHMENT_PREVIEW_TEXT_LENGTH` characters
+    pub truncated: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AttachmentRefusedOutDTO {
+    pub file_name: String,
+    pub scanner: String,
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ScanRefusalListInDTO {
+    pub limit: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ScanRefusalListOutDTO {
+    pub refusals: Vec<ScanRefusal>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EmailInvitationOutDTO {
+    /// Section of the text/calendar part, for `/calendar/rsvp`
+    pub section: String,
+    /// `REQUEST`, `CANCEL`, `REPLY` and so on, `None` for a plain event file
+    pub method: Option<String>,
+    pub uid: String,
+    pub sequence: u32,
+    pub summary: String,
+    pub description: Option<String>,
+    pub location: Option<String>,
+    pub status: Option<String>,
+    pub organizer: Option<InvitationParticipantOutDTO>,
+    pub attendees: Vec<InvitationParticipantOutDTO>,
+    /// With the offset of the event time zone, floating times as UTC
+    pub start: Option<DateTime<FixedOffset>>,
+    pub end: Option<DateTime<FixedOffset>>,
+    pub all_day: bool,
+    /// TZID as sent, which may be a Windows zone name
+    pub time_zone: Option<String>,
+    /// RRULE as sent, e.g. `FREQ=WEEKLY;BYDAY=MO`
+    pub recurrence: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InvitationParticipantOutDTO {
+    pub email: String,
+    pub name: Option<String>,
+    /// PARTSTAT, e.g. `NEEDS-ACTION` or `ACCEPTED`
+    pub participation_status: Option<String>,
+    pub role: Option<String>,
+    pub rsvp: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RsvpResponse {
+    Accepted,
+    Tentative,
+    Declined,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CalendarRsvpInDTO {
+    pub mailbox_name: String,
+    pub uid: u32,
+    /// Calendar part, the first one of the email when missing
+    pub section: Option<String>,
+    pub response: RsvpResponse,
+    pub comment: Option<String>,
+    /// Writes accepted events to the CalDAV calendar and removes declined ones, defaults to
+    /// true when a CalDAV server is configured
+    pub save_to_calendar: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ContactInDTO {
+    pub name: String,
+    #[serde(default)]
+    pub emails: Vec<String>,
+    #[serde(default)]
+    pub phones: Vec<String>,
+    pub organization: Option<String>,
+    pub note: Option<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ContactIdInDTO {
+    pub id: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ContactListInDTO {
+    /// Only contacts of this group
+    pub group: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ContactOutDTO {
+    pub id: i64,
+    pub uid: Option<String>,
+    pub name: String,
+    pub emails: Vec<String>,
+    pub phones: Vec<String>,
+    pub organization: Option<String>,
+    pub note: Option<String>,
+    pub groups: Vec<String>,
+    pub times_contacted: u32,
+    pub last_contacted: Option<NaiveDateTime>,
+    /// Synced with a card on the CardDAV server
+    pub synced: bool,
+    /// Edited since the last sync
+    pub pending_push: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ContactListOutDTO {
+    pub contacts: Vec<ContactOutDTO>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ContactAutocompleteInDTO {
+    pub query: String,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ContactSuggestionKind {
+    Contact,
+    Group,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ContactSuggestionOutDTO {
+    pub kind: ContactSuggestionKind,
+    /// Not set for groups
+    pub contact_id: Option<i64>,
+    pub name: String,
+    /// Not set for groups
+    pub email: Option<String>,
+    /// What to put into `to_address`, the group name expands into its members when sending
+    pub recipient: String,
+    pub times_contacted: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ContactAutocompleteOutDTO {
+    pub suggestions: Vec<ContactSuggestionOutDTO>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum VCardVersion {
+    #[serde(rename = "3.0")]
+    V3,
+    #[serde(rename = "4.0")]
+    V4,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ContactExportInDTO {
+    /// 4.0 when missing
+    pub version: Option<VCardVersion>,
+    pub group: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ContactImportOutDTO {
+    pub created: u32,
+    pub updated: u32,
+    /// vCards without a name or an address
+    pub skipped: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ContactSyncOutDTO {
+    pub address_books: usize,
+    /// Local contacts changed by the server
+    pub created: u32,
+    pub updated: u32,
+    pub deleted: u32,
+    /// Local edits and deletions written to the server
+    pub pushed: u32,
+    /// Local edits dropped as the card changed on the server too
+    pub conflicts: u32,
+    /// Local edits the server refused, they are tried again on the next sync
+    pub failed: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TemplateInDTO {
+    pub name: String,
+    /// `{{name}}` is replaced by the variable, `{{name|default}}` falls back to the default
+    pub subject: String,
+    pub text_body: String,
+    pub html_body: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TemplateIdInDTO {
+    pub id: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TemplateAttachmentIdInDTO {
+    pub template_id: i64,
+    pub attachment_id: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TemplateVariableOutDTO {
+    pub name: String,
+    pub required: bool,
+    pub default: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TemplateAttachmentOutDTO {
+    pub id: i64,
+    pub file_name: String,
+    pub content_type: String,
+    pub size: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TemplateOutDTO {
+    pub id: i64,
+    pub name: String,
+    pub subject: String,
+    pub text_body: String,
+    pub html_body: Option<String>,
+    /// Placeholders of subject and bodies, each once
+    pub variables: Vec<TemplateVariableOutDTO>,
+    pub attachments: Vec<TemplateAttachmentOutDTO>,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TemplateListOutDTO {
+    pub templates: Vec<TemplateOutDTO>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BulkSendInDTO {
+    pub template_id: i64,
+    /// Lower the limits of the server for this send
+    pub rate_per_minute: Option<u32>,
+    pub concurrency: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BulkSendIdInDTO {
+    pub id: i64,
+}
+
+/// Row of a JSON recipient list.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BulkRecipientInDTO {
+    pub to_address: String,
+    #[serde(default)]
+    pub variables: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BulkSendOutDTO {
+    pub id: i64,
+    pub template_id: i64,
+    pub template_name: String,
+    pub rate_per_minute: u32,
+    pub concurrency: usize,
+    pub created_at: NaiveDateTime,
+    /// Pending recipients of a send which is not running are sent by a retry
+    pub running: bool,
+    pub pending: usize,
+    pub sent: usize,
+    pub failed: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BulkRecipientOutDTO {
+    pub id: i64,
+    pub to_address: String,
+    pub variables: HashMap<String, String>,
+    pub status: BulkRecipientStatus,
+    pub error: Option<String>,
+    pub attempts: u32,
+    pub sent_at: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BulkSendListOutDTO {
+    pub bulk_sends: Vec<BulkSendOutDTO>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BulkSendRecipientListOutDTO {
+    pub bulk_send: BulkSendOutDTO,
+    pub recipients: Vec<BulkRecipientOutDTO>,
 }
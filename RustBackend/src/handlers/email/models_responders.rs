@@ -1,6 +1,12 @@
 use actix_web::{body::BoxBody, http::header::ContentType, HttpRequest, HttpResponse, Responder};
 
-use super::models::{EmailDetailOutDTO, EmailListOutDTO, MailboxListOutDTO};
+use super::models::{
+    AttachmentSearchOutDTO, BulkSendListOutDTO, BulkSendOutDTO, BulkSendRecipientListOutDTO,
+    ContactAutocompleteOutDTO, ContactImportOutDTO, ContactListOutDTO, ContactOutDTO,
+    ContactSyncOutDTO, EmailDetailOutDTO, EmailHeadersOutDTO, EmailImportOutDTO, EmailListOutDTO,
+    FullTextSearchOutDTO, MailboxListOutDTO, ScanRefusalListOutDTO, SyncOutDTO, TemplateListOutDTO,
+    TemplateOutDTO, ThreadListOutDTO,
+};
 
 impl Responder for EmailDetailOutDTO {
     type Body = BoxBody;
@@ -58,3 +64,326 @@ impl Responder for MailboxListOutDTO {
             .body(body)
     }
 }
+
+impl Responder for SyncOutDTO {
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &HttpRequest) -> HttpResponse<Self::Body> {
+        let body = match serde_json::to_string(&self) {
+            Ok(val) => val,
+            Err(err) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Error serializing response: {}", err))
+            }
+        };
+
+        // Create response and set content type
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(body)
+    }
+}
+
+impl Responder for FullTextSearchOutDTO {
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &HttpRequest) -> HttpResponse<Self::Body> {
+        let body = match serde_json::to_string(&self) {
+            Ok(val) => val,
+            Err(err) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Error serializing response: {}", err))
+            }
+        };
+
+        // Create response and set content type
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(body)
+    }
+}
+
+impl Responder for ThreadListOutDTO {
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &HttpRequest) -> HttpResponse<Self::Body> {
+        let body = match serde_json::to_string(&self) {
+            Ok(val) => val,
+            Err(err) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Error serializing response: {}", err))
+            }
+        };
+
+        // Create response and set content type
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(body)
+    }
+}
+
+impl Responder for EmailHeadersOutDTO {
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &HttpRequest) -> HttpResponse<Self::Body> {
+        let body = match serde_json::to_string(&self) {
+            Ok(val) => val,
+            Err(err) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Error serializing response: {}", err))
+            }
+        };
+
+        // Create response and set content type
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(body)
+    }
+}
+
+impl Responder for EmailImportOutDTO {
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &HttpRequest) -> HttpResponse<Self::Body> {
+        let body = match serde_json::to_string(&self) {
+            Ok(val) => val,
+            Err(err) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Error serializing response: {}", err))
+            }
+        };
+
+        // Create response and set content type
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(body)
+    }
+}
+
+impl Responder for AttachmentSearchOutDTO {
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &HttpRequest) -> HttpResponse<Self::Body> {
+        let body = match serde_json::to_string(&self) {
+            Ok(val) => val,
+            Err(err) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Error serializing response: {}", err))
+            }
+        };
+
+        // Create response and set content type
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(body)
+    }
+}
+
+impl Responder for ScanRefusalListOutDTO {
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &HttpRequest) -> HttpResponse<Self::Body> {
+        let body = match serde_json::to_string(&self) {
+            Ok(val) => val,
+            Err(err) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Error serializing response: {}", err))
+            }
+        };
+
+        // Create response and set content type
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(body)
+    }
+}
+
+impl Responder for ContactOutDTO {
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &HttpRequest) -> HttpResponse<Self::Body> {
+        let body = match serde_json::to_string(&self) {
+            Ok(val) => val,
+            Err(err) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Error serializing response: {}", err))
+            }
+        };
+
+        // Create response and set content type
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(body)
+    }
+}
+
+impl Responder for ContactListOutDTO {
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &HttpRequest) -> HttpResponse<Self::Body> {
+        let body = match serde_json::to_string(&self) {
+            Ok(val) => val,
+            Err(err) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Error serializing response: {}", err))
+            }
+        };
+
+        // Create response and set content type
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(body)
+    }
+}
+
+impl Responder for ContactAutocompleteOutDTO {
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &HttpRequest) -> HttpResponse<Self::Body> {
+        let body = match serde_json::to_string(&self) {
+            Ok(val) => val,
+            Err(err) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Error serializing response: {}", err))
+            }
+        };
+
+        // Create response and set content type
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(body)
+    }
+}
+
+impl Responder for ContactImportOutDTO {
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &HttpRequest) -> HttpResponse<Self::Body> {
+        let body = match serde_json::to_string(&self) {
+            Ok(val) => val,
+            Err(err) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Error serializing response: {}", err))
+            }
+        };
+
+        // Create response and set content type
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(body)
+    }
+}
+
+impl Responder for ContactSyncOutDTO {
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &HttpRequest) -> HttpResponse<Self::Body> {
+        let body = match serde_json::to_string(&self) {
+            Ok(val) => val,
+            Err(err) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Error serializing response: {}", err))
+            }
+        };
+
+        // Create response and set content type
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(body)
+    }
+}
+
+impl Responder for TemplateOutDTO {
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &HttpRequest) -> HttpResponse<Self::Body> {
+        let body = match serde_json::to_string(&self) {
+            Ok(val) => val,
+            Err(err) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Error serializing response: {}", err))
+            }
+        };
+
+        // Create response and set content type
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(body)
+    }
+}
+
+impl Responder for TemplateListOutDTO {
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &HttpRequest) -> HttpResponse<Self::Body> {
+        let body = match serde_json::to_string(&self) {
+            Ok(val) => val,
+            Err(err) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Error serializing response: {}", err))
+            }
+        };
+
+        // Create response and set content type
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(body)
+    }
+}
+
+impl Responder for BulkSendOutDTO {
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &HttpRequest) -> HttpResponse<Self::Body> {
+        let body = match serde_json::to_string(&self) {
+            Ok(val) => val,
+            Err(err) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Error serializing response: {}", err))
+            }
+        };
+
+        // Create response and set content type
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(body)
+    }
+}
+
+impl Responder for BulkSendListOutDTO {
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &HttpRequest) -> HttpResponse<Self::Body> {
+        let body = match serde_json::to_string(&self) {
+            Ok(val) => val,
+            Err(err) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Error serializing response: {}", err))
+            }
+        };
+
+        // Create response and set content type
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(body)
+    }
+}
+
+impl Responder for BulkSendRecipientListOutDTO {
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &HttpRequest) -> HttpResponse<Self::Body> {
+        let body = match serde_json::to_string(&self) {
+            Ok(val) => val,
+            Err(err) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Error serializing response: {}", err))
+            }
+        };
+
+        // Create response and set content type
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(body)
+    }
+}
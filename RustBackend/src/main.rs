@@ -8,27 +8,60 @@ use actix_web::{
     cookie::{time::Duration, Key, SameSite},
     web, App, HttpResponse, HttpServer,
 };
-use constants::AUTH_COOKIE_NAME;
+use constants::{AUTH_COOKIE_NAME, MESSAGE_CACHE_FILE_NAME};
+use dav::DavConfig;
 use dotenv::dotenv;
 use handlers::{
     auth::auth::auth_config,
-    email::{email_imap::email_imap_config, email_smtp::email_smtp_config},
+    email::{
+        email_archive::email_archive_config,
+        email_attachment_preview::email_attachment_preview_config,
+        email_attachments::email_attachments_config,
+        email_bulk_send::{email_bulk_send_config, BulkSender},
+        email_cache::run_cache_sync,
+        email_calendar::email_calendar_config,
+        email_contacts::email_contacts_config,
+        email_dav::email_dav_config,
+        email_imap::email_imap_config,
+        email_import::email_import_config,
+        email_notifications::email_notifications_config,
+        email_scanning::email_scanning_config,
+        email_search::email_search_config,
+        email_smtp::email_smtp_config,
+        email_source::email_source_config,
+        email_sync::email_sync_config,
+        email_templates::email_templates_config,
+        email_threads::email_threads_config,
+    },
 };
-use std::env;
+use scanning::AttachmentScanners;
+use std::{env, path::Path, sync::Arc};
+use storage::{encryption::StorageCipher, message_cache::MessageCache};
 use utils::auth_guards::AuthGuardFactory;
 
 mod constants;
+mod dav;
 mod handlers;
+mod scanning;
+mod storage;
 mod utils;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenv().ok();
-    let secret_key: Key = Key::derive_from(
-        env::var("ENCRYPTION_KEY")
-            .expect("ENCRYPTION_KEY must be set")
-            .as_bytes(),
-    );
+    let encryption_key = env::var("ENCRYPTION_KEY").expect("ENCRYPTION_KEY must be set");
+    let secret_key: Key = Key::derive_from(encryption_key.as_bytes());
+    let storage_cipher = Arc::new(StorageCipher::from_server_key(encryption_key.as_bytes()));
+
+    let data_dir = env::var("DATA_DIR").unwrap_or_else(|_| "./data".to_string());
+    let message_cache = web::Data::new(MessageCache::open(
+        &Path::new(&data_dir).join(MESSAGE_CACHE_FILE_NAME),
+        storage_cipher,
+    )?);
+    tokio::spawn(run_cache_sync(message_cache.clone()));
+    let attachment_scanners = web::Data::new(AttachmentScanners::from_env());
+    let dav_config = web::Data::new(DavConfig::from_env());
+    let bulk_sender = web::Data::new(BulkSender::from_env());
 
     let port = match env::var("PORT") {
         Ok(number) => number.parse::<u16>()?,
@@ -42,7 +75,7 @@ async fn main() -> anyhow::Result<()> {
                     .allow_any_origin()
                     .allow_any_header()
                     // .allowed_origin("http://localhost:5173/")
-                    .allowed_methods(vec!["GET", "POST", "DELETE"])
+                    .allowed_methods(vec!["GET", "POST", "PUT", "DELETE"])
                     // .allowed_headers(vec![http::header::AUTHORIZATION, http::header::ACCEPT, http::header::ACCESS_CONTROL_ALLOW_ORIGIN])
                     // .allowed_header(http::header::CONTENT_TYPE)
                     .supports_credentials()
@@ -58,12 +91,31 @@ async fn main() -> anyhow::Result<()> {
                     .cookie_name(AUTH_COOKIE_NAME.to_string())
                     .build(),
             )
+            .app_data(message_cache.clone())
+            .app_data(attachment_scanners.clone())
+            .app_data(dav_config.clone())
+            .app_data(bulk_sender.clone())
             .configure(app_config)
             .service(web::scope("/auth").configure(auth_config))
             .service(
                 web::scope("/api")
                     .configure(email_smtp_config)
                     .configure(email_imap_config)
+                    .configure(email_notifications_config)
+                    .configure(email_sync_config)
+                    .configure(email_search_config)
+                    .configure(email_threads_config)
+                    .configure(email_source_config)
+                    .configure(email_import_config)
+                    .configure(email_archive_config)
+                    .configure(email_attachments_config)
+                    .configure(email_attachment_preview_config)
+                    .configure(email_scanning_config)
+                    .configure(email_calendar_config)
+                    .configure(email_contacts_config)
+                    .configure(email_dav_config)
+                    .configure(email_templates_config)
+                    .configure(email_bulk_send_config)
                     .wrap(AuthGuardFactory),
             )
     })
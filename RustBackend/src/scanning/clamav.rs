@@ -0,0 +1,210 @@
+use std::{
+    io::{Error, ErrorKind, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    os::unix::net::UnixStream,
+    time::Duration,
+};
+
+use crate::constants::{CLAMD_CHUNK_SIZE, CLAMD_TIMEOUT_SECS};
+
+use super::{AttachmentScanner, ScanVerdict, ScannedAttachment};
+
+enum ClamdAddress {
+    Tcp(String),
+    Unix(String),
+}
+
+/// Sends attachments to a clamd daemon with the INSTREAM command. Anything speaking the
+/// protocol on the address works, the content is never written to disk here.
+pub struct ClamdScanner {
+    address: ClamdAddress,
+    timeout: Duration,
+}
+
+impl ClamdScanner {
+    /// `host:port`, or the path of a Unix socket with or without a `unix:` prefix.
+    pub fn new(address: &str) -> ClamdScanner {
+        let address = match address.strip_prefix("unix:") {
+            Some(path) => ClamdAddress::Unix(path.to_string()),
+            None if address.starts_with('/') => ClamdAddress::Unix(address.to_string()),
+            None => ClamdAddress::Tcp(address.to_string()),
+        };
+        ClamdScanner {
+            address,
+            timeout: Duration::from_secs(CLAMD_TIMEOUT_SECS),
+        }
+    }
+
+    /// Tries every resolved address, each within the timeout.
+    fn connect_tcp(&self, address: &str) -> Result<TcpStream, Error> {
+        let mut last_error = None;
+        for socket_address in address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&socket_address, self.timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_error = Some(err),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("clamd address {} did not resolve", address),
+            )
+        }))
+    }
+
+    fn scan_stream<S: Read + Write>(&self, mut stream: S, data: &[u8]) -> Result<String, Error> {
+        // The `z` prefix makes clamd answer with a null terminated line
+        stream.write_all(b"zINSTREAM\0")?;
+        for chunk in data.chunks(CLAMD_CHUNK_SIZE) {
+            stream.write_all(&(chunk.len() as u32).to_be_bytes())?;
+            stream.write_all(chunk)?;
+        }
+        stream.write_all(&0u32.to_be_bytes())?;
+        stream.flush()?;
+
+        let mut reply = vec![];
+        let mut buffer = [0; 512];
+        loop {
+            let read = stream.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            reply.extend_from_slice(&buffer[..read]);
+            if reply.contains(&0) {
+                break;
+            }
+        }
+        let reply = String::from_utf8_lossy(&reply);
+        Ok(reply.trim_end_matches(['\0', '\n']).to_string())
+    }
+}
+
+impl AttachmentScanner for ClamdScanner {
+    fn name(&self) -> &str {
+        "clamav"
+    }
+
+    fn scan(&self, attachment: &ScannedAttachment) -> Result<ScanVerdict, Error> {
+        let reply = match &self.address {
+            ClamdAddress::Tcp(address) => {
+                let stream = self.connect_tcp(address)?;
+                stream.set_read_timeout(Some(self.timeout))?;
+                stream.set_write_timeout(Some(self.timeout))?;
+                self.scan_stream(stream, attachment.data)?
+            }
+            ClamdAddress::Unix(path) => {
+                let stream = UnixStream::connect(path)?;
+                stream.set_read_timeout(Some(self.timeout))?;
+                stream.set_write_timeout(Some(self.timeout))?;
+                self.scan_stream(stream, attachment.data)?
+            }
+        };
+
+        // `stream: OK`, `stream: Eicar-Signature FOUND` or `... ERROR`, e.g. when the
+        // attachment is bigger than StreamMaxLength
+        let result = reply.strip_prefix("stream:").unwrap_or(&reply).trim();
+        if result == "OK" {
+            Ok(ScanVerdict::Clean)
+        } else if let Some(signature) = result.strip_suffix("FOUND") {
+            Ok(ScanVerdict::Refused(format!(
+                "Malware found: {}",
+                signature.trim()
+            )))
+        } else {
+            Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unexpected clamd reply: {}", reply),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Error, ErrorKind, Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use super::ClamdScanner;
+    use crate::scanning::{AttachmentScanner, ScanVerdict, ScannedAttachment};
+
+    /// Stand-in clamd answering one INSTREAM request, after checking its framing.
+    fn serve_once(reply: &'static str) -> (String, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut command = [0u8; 10];
+            stream.read_exact(&mut command).unwrap();
+            assert_eq!(&command, b"zINSTREAM\0");
+            let mut data = vec![];
+            loop {
+                let mut length = [0u8; 4];
+                stream.read_exact(&mut length).unwrap();
+                let length = u32::from_be_bytes(length) as usize;
+                if length == 0 {
+                    break;
+                }
+                let mut chunk = vec![0u8; length];
+                stream.read_exact(&mut chunk).unwrap();
+                data.extend_from_slice(&chunk);
+            }
+            stream.write_all(reply.as_bytes()).unwrap();
+            data
+        });
+        (address, handle)
+    }
+
+    fn scan(reply: &'static str, data: &[u8]) -> (Result<ScanVerdict, Error>, Vec<u8>) {
+        let (address, handle) = serve_once(reply);
+        let verdict = ClamdScanner::new(&address).scan(&ScannedAttachment {
+            file_name: "file.bin",
+            content_type: "application/octet-stream",
+            data,
+        });
+        (verdict, handle.join().unwrap())
+    }
+
+    #[test]
+    fn streams_the_attachment_and_accepts_ok() {
+        // Bigger than one chunk
+        let data: Vec<u8> = (0..150_000u32).map(|index| index as u8).collect();
+        let (verdict, received) = scan("stream: OK\0", &data);
+        assert!(matches!(verdict, Ok(ScanVerdict::Clean)));
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn refuses_found_signatures() {
+        let (verdict, _) = scan("stream: Eicar-Signature FOUND\0", b"eicar");
+        match verdict {
+            Ok(ScanVerdict::Refused(reason)) => {
+                assert_eq!(reason, "Malware found: Eicar-Signature")
+            }
+            _ => panic!("Expected a refusal"),
+        }
+    }
+
+    #[test]
+    fn reports_error_replies() {
+        let (verdict, _) = scan("INSTREAM size limit exceeded. ERROR\0", b"data");
+        assert_eq!(verdict.err().unwrap().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn fails_when_nothing_listens() {
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let verdict = ClamdScanner::new(&address).scan(&ScannedAttachment {
+            file_name: "file.bin",
+            content_type: "application/octet-stream",
+            data: b"data",
+        });
+        assert!(verdict.is_err());
+    }
+}
@@ -0,0 +1,89 @@
+pub mod clamav;
+pub mod policy;
+
+use std::{env, io::Error};
+
+use self::{clamav::ClamdScanner, policy::PolicyScanner};
+
+pub struct ScannedAttachment<'a> {
+    pub file_name: &'a str,
+    /// Lowercase `type/subtype`
+    pub content_type: &'a str,
+    /// Decoded content
+    pub data: &'a [u8],
+}
+
+pub enum ScanVerdict {
+    Clean,
+    /// The attachment must not be served or sent, with the reason shown to the user
+    Refused(String),
+}
+
+/// Checks attachments before they are downloaded or sent. Scanners are called from a
+/// blocking thread, so they can talk to daemons over plain sockets.
+pub trait AttachmentScanner: Send + Sync {
+    /// Short name stored with refusals, e.g. `clamav`
+    fn name(&self) -> &str;
+
+    fn scan(&self, attachment: &ScannedAttachment) -> Result<ScanVerdict, Error>;
+}
+
+pub struct ScanRefusalReason {
+    pub scanner: String,
+    pub reason: String,
+}
+
+/// All configured scanners, cheap ones first.
+pub struct AttachmentScanners {
+    scanners: Vec<Box<dyn AttachmentScanner>>,
+}
+
+impl AttachmentScanners {
+    pub fn new(scanners: Vec<Box<dyn AttachmentScanner>>) -> AttachmentScanners {
+        AttachmentScanners { scanners }
+    }
+
+    /// The policy scanner always runs, clamd only when `CLAMD_ADDRESS` is set, either as
+    /// `host:port` or as the path of its Unix socket.
+    pub fn from_env() -> AttachmentScanners {
+        let list = |name: &str| {
+            env::var(name).ok().map(|value| {
+                value
+                    .split(',')
+                    .map(|item| item.trim().trim_start_matches('.').to_lowercase())
+                    .filter(|item| !item.is_empty())
+                    .collect::<Vec<String>>()
+            })
+        };
+        let mut scanners: Vec<Box<dyn AttachmentScanner>> = vec![Box::new(PolicyScanner::new(
+            list("ATTACHMENT_BLOCKED_EXTENSIONS"),
+            list("ATTACHMENT_BLOCKED_CONTENT_TYPES"),
+        ))];
+        if let Ok(address) = env::var("CLAMD_ADDRESS") {
+            if !address.trim().is_empty() {
+                scanners.push(Box::new(ClamdScanner::new(address.trim())));
+            }
+        }
+        AttachmentScanners::new(scanners)
+    }
+
+    /// Returns why the attachment is refused by the first scanner which does. A scanner that
+    /// fails refuses as well, an attachment is never let through unscanned.
+    pub fn check(&self, attachment: &ScannedAttachment) -> Option<ScanRefusalReason> {
+        for scanner in self.scanners.iter() {
+            let reason = match scanner.scan(attachment) {
+                Ok(ScanVerdict::Clean) => continue,
+                Ok(ScanVerdict::Refused(reason)) => reason,
+                Err(err) => {
+                    println!("Scanner {} failed: {}", scanner.name(), err);
+                    format!("The attachment could not be scanned: {}", err)
+                }
+            };
+            return Some(ScanRefusalReason {
+                scanner: scanner.name().to_string(),
+                reason,
+            });
+        }
+        None
+    }
+}
@@ -0,0 +1,59 @@
+use std::{collections::HashSet, io::Error};
+
+use crate::constants::{BLOCKED_ATTACHMENT_CONTENT_TYPES, BLOCKED_ATTACHMENT_EXTENSIONS};
+
+use super::{AttachmentScanner, ScanVerdict, ScannedAttachment};
+
+/// Refuses executables and scripts by the extension of their name or their content type,
+/// without looking at the content.
+pub struct PolicyScanner {
+    blocked_extensions: HashSet<String>,
+    blocked_content_types: HashSet<String>,
+}
+
+impl PolicyScanner {
+    /// Lists replace the defaults, lowercase and extensions without the dot.
+    pub fn new(
+        blocked_extensions: Option<Vec<String>>,
+        blocked_content_types: Option<Vec<String>>,
+    ) -> PolicyScanner {
+        let defaults = |items: &[&str]| items.iter().map(|item| item.to_string()).collect();
+        PolicyScanner {
+            blocked_extensions: blocked_extensions
+                .map(|items| items.into_iter().collect())
+                .unwrap_or_else(|| defaults(&BLOCKED_ATTACHMENT_EXTENSIONS)),
+            blocked_content_types: blocked_content_types
+                .map(|items| items.into_iter().collect())
+                .unwrap_or_else(|| defaults(&BLOCKED_ATTACHMENT_CONTENT_TYPES)),
+        }
+    }
+}
+
+impl AttachmentScanner for PolicyScanner {
+    fn name(&self) -> &str {
+        "policy"
+    }
+
+    fn scan(&self, attachment: &ScannedAttachment) -> Result<ScanVerdict, Error> {
+        // Trailing dots and spaces are dropped by Windows, `invoice.exe.` still runs
+        let file_name = attachment
+            .file_name
+            .trim_end_matches(['.', ' '])
+            .to_lowercase();
+        if let Some((_, extension)) = file_name.rsplit_once('.') {
+            if self.blocked_extensions.contains(extension) {
+                return Ok(ScanVerdict::Refused(format!(
+                    "Files of type .{} are not allowed",
+                    extension
+                )));
+            }
+        }
+        if self.blocked_content_types.contains(attachment.content_type) {
+            return Ok(ScanVerdict::Refused(format!(
+                "Attachments of type {} are not allowed",
+                attachment.content_type
+            )));
+        }
+        Ok(ScanVerdict::Clean)
+    }
+}
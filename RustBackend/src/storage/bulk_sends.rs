@@ -0,0 +1,305 @@
+use std::{collections::HashMap, io::Error};
+
+use chrono::NaiveDateTime;
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use super::message_cache::{storage_error, MessageCache};
+
+pub const BULK_SENDS_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS bulk_sends (
+        id INTEGER PRIMARY KEY,
+        account TEXT NOT NULL,
+        bulk_send BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS bulk_sends_account ON bulk_sends (account);
+    CREATE TABLE IF NOT EXISTS bulk_send_recipients (
+        id INTEGER PRIMARY KEY,
+        bulk_send_id INTEGER NOT NULL,
+        account TEXT NOT NULL,
+        status TEXT NOT NULL,
+        recipient BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS bulk_send_recipients_bulk_send
+        ON bulk_send_recipients (account, bulk_send_id);
+";
+
+/// One template sent to a list of recipients.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BulkSend {
+    pub template_id: i64,
+    /// Kept for the listing, the template may be renamed or deleted meanwhile
+    pub template_name: String,
+    pub rate_per_minute: u32,
+    pub concurrency: usize,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BulkRecipientStatus {
+    Pending,
+    Sent,
+    Failed,
+}
+
+impl BulkRecipientStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            BulkRecipientStatus::Pending => "pending",
+            BulkRecipientStatus::Sent => "sent",
+            BulkRecipientStatus::Failed => "failed",
+        }
+    }
+
+    fn parse(value: &str) -> BulkRecipientStatus {
+        match value {
+            "sent" => BulkRecipientStatus::Sent,
+            "failed" => BulkRecipientStatus::Failed,
+            _ => BulkRecipientStatus::Pending,
+        }
+    }
+}
+
+/// One row of the recipient list.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BulkRecipient {
+    pub to_address: String,
+    pub variables: HashMap<String, String>,
+    /// Reason of the last failed attempt
+    pub error: Option<String>,
+    pub attempts: u32,
+    pub sent_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Default)]
+pub struct BulkSendCounts {
+    pub pending: usize,
+    pub sent: usize,
+    pub failed: usize,
+}
+
+impl MessageCache {
+    /// Stores the bulk send with all its recipients as pending.
+    pub fn insert_bulk_send(
+        &self,
+        account: &str,
+        bulk_send: &BulkSend,
+        recipients: &[BulkRecipient],
+    ) -> Result<i64, Error> {
+        let account = self.cipher.hash_id(account);
+        let encrypted_bulk_send = self.cipher.encrypt(&serde_json::to_vec(bulk_send)?)?;
+        let encrypted_recipients = recipients
+            .iter()
+            .map(|recipient| self.cipher.encrypt(&serde_json::to_vec(recipient)?))
+            .collect::<Result<Vec<Vec<u8>>, Error>>()?;
+
+        let mut database = self.database();
+        let transaction = database.transaction().map_err(storage_error)?;
+        transaction
+            .execute(
+                "INSERT INTO bulk_sends (account, bulk_send) VALUES (?1, ?2)",
+                params![account, encrypted_bulk_send],
+            )
+            .map_err(storage_error)?;
+        let id = transaction.last_insert_rowid();
+        {
+            let mut statement = transaction
+                .prepare(
+                    "INSERT INTO bulk_send_recipients (bulk_send_id, account, status, recipient) \
+                     VALUES (?1, ?2, ?3, ?4)",
+                )
+                .map_err(storage_error)?;
+            for recipient in encrypted_recipients.iter() {
+                statement
+                    .execute(params![
+                        id,
+                        account,
+                        BulkRecipientStatus::Pending.as_str(),
+                        recipient
+                    ])
+                    .map_err(storage_error)?;
+            }
+        }
+        transaction.commit().map_err(storage_error)?;
+        Ok(id)
+    }
+
+    /// Bulk sends of the account, newest first.
+    pub fn bulk_sends(&self, account: &str) -> Result<Vec<(i64, BulkSend)>, Error> {
+        let account = self.cipher.hash_id(account);
+        let rows = {
+            let database = self.database();
+            let mut statement = database
+                .prepare("SELECT id, bulk_send FROM bulk_sends WHERE account = ?1 ORDER BY id DESC")
+                .map_err(storage_error)?;
+            let rows = statement
+                .query_map(params![account], |row| Ok((row.get(0)?, row.get(1)?)))
+                .map_err(storage_error)?
+                .collect::<Result<Vec<(i64, Vec<u8>)>, rusqlite::Error>>()
+                .map_err(storage_error)?;
+            rows
+        };
+
+        rows.into_iter()
+            .map(|(id, bulk_send)| {
+                Ok((
+                    id,
+                    serde_json::from_slice(&self.cipher.decrypt(&bulk_send)?)?,
+                ))
+            })
+            .collect()
+    }
+
+    pub fn bulk_send(&self, account: &str, id: i64) -> Result<Option<BulkSend>, Error> {
+        let account = self.cipher.hash_id(account);
+        let bulk_send: Option<Vec<u8>> = self
+            .database()
+            .query_row(
+                "SELECT bulk_send FROM bulk_sends WHERE id = ?1 AND account = ?2",
+                params![id, account],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(storage_error)?;
+        match bulk_send {
+            Some(bulk_send) => Ok(Some(serde_json::from_slice(
+                &self.cipher.decrypt(&bulk_send)?,
+            )?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes the bulk send with its recipients.
+    pub fn delete_bulk_send(&self, account: &str, id: i64) -> Result<bool, Error> {
+        let account = self.cipher.hash_id(account);
+        let mut database = self.database();
+        let transaction = database.transaction().map_err(storage_error)?;
+        transaction
+            .execute(
+                "DELETE FROM bulk_send_recipients WHERE bulk_send_id = ?1 AND account = ?2",
+                params![id, account],
+            )
+            .map_err(storage_error)?;
+        let deleted = transaction
+            .execute(
+                "DELETE FROM bulk_sends WHERE id = ?1 AND account = ?2",
+                params![id, account],
+            )
+            .map_err(storage_error)?;
+        transaction.commit().map_err(storage_error)?;
+        Ok(deleted > 0)
+    }
+
+    /// Recipients in the order of the list, optionally only those with the status.
+    pub fn bulk_send_recipients(
+        &self,
+        account: &str,
+        bulk_send_id: i64,
+        status: Option<BulkRecipientStatus>,
+    ) -> Result<Vec<(i64, BulkRecipientStatus, BulkRecipient)>, Error> {
+        let account = self.cipher.hash_id(account);
+        let rows = {
+            let database = self.database();
+            let mut statement = database
+                .prepare(
+                    "SELECT id, status, recipient FROM bulk_send_recipients \
+                     WHERE account = ?1 AND bulk_send_id = ?2 AND (?3 IS NULL OR status = ?3) \
+                     ORDER BY id",
+                )
+                .map_err(storage_error)?;
+            let rows = statement
+                .query_map(
+                    params![account, bulk_send_id, status.map(|status| status.as_str())],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                )
+                .map_err(storage_error)?
+                .collect::<Result<Vec<(i64, String, Vec<u8>)>, rusqlite::Error>>()
+                .map_err(storage_error)?;
+            rows
+        };
+
+        rows.into_iter()
+            .map(|(id, status, recipient)| {
+                Ok((
+                    id,
+                    BulkRecipientStatus::parse(&status),
+                    serde_json::from_slice(&self.cipher.decrypt(&recipient)?)?,
+                ))
+            })
+            .collect()
+    }
+
+    pub fn bulk_send_counts(
+        &self,
+        account: &str,
+        bulk_send_id: i64,
+    ) -> Result<BulkSendCounts, Error> {
+        let account = self.cipher.hash_id(account);
+        let database = self.database();
+        let mut statement = database
+            .prepare(
+                "SELECT status, COUNT(*) FROM bulk_send_recipients \
+                 WHERE account = ?1 AND bulk_send_id = ?2 GROUP BY status",
+            )
+            .map_err(storage_error)?;
+        let rows = statement
+            .query_map(params![account, bulk_send_id], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .map_err(storage_error)?
+            .collect::<Result<Vec<(String, i64)>, rusqlite::Error>>()
+            .map_err(storage_error)?;
+
+        let mut counts = BulkSendCounts::default();
+        for (status, count) in rows.into_iter() {
+            let count = count as usize;
+            match BulkRecipientStatus::parse(&status) {
+                BulkRecipientStatus::Pending => counts.pending += count,
+                BulkRecipientStatus::Sent => counts.sent += count,
+                BulkRecipientStatus::Failed => counts.failed += count,
+            }
+        }
+        Ok(counts)
+    }
+
+    pub fn update_bulk_recipient(
+        &self,
+        account: &str,
+        id: i64,
+        status: BulkRecipientStatus,
+        recipient: &BulkRecipient,
+    ) -> Result<(), Error> {
+        let account = self.cipher.hash_id(account);
+        let encrypted_recipient = self.cipher.encrypt(&serde_json::to_vec(recipient)?)?;
+        self.database()
+            .execute(
+                "UPDATE bulk_send_recipients SET status = ?1, recipient = ?2 \
+                 WHERE id = ?3 AND account = ?4",
+                params![status.as_str(), encrypted_recipient, id, account],
+            )
+            .map(|_| ())
+            .map_err(storage_error)
+    }
+
+    /// Marks the failed recipients as pending again, returns how many there were.
+    pub fn reset_failed_bulk_recipients(
+        &self,
+        account: &str,
+        bulk_send_id: i64,
+    ) -> Result<usize, Error> {
+        let account = self.cipher.hash_id(account);
+        self.database()
+            .execute(
+                "UPDATE bulk_send_recipients SET status = ?1 \
+                 WHERE account = ?2 AND bulk_send_id = ?3 AND status = ?4",
+                params![
+                    BulkRecipientStatus::Pending.as_str(),
+                    account,
+                    bulk_send_id,
+                    BulkRecipientStatus::Failed.as_str()
+                ],
+            )
+            .map_err(storage_error)
+    }
+}
@@ -0,0 +1,307 @@
+use std::io::Error;
+
+use chrono::NaiveDateTime;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    encryption::StorageCipher,
+    message_cache::{storage_error, MessageCache},
+};
+
+// Address books are small, contacts are matched and ranked after decrypting all of them
+pub const CONTACTS_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS contacts (
+        id INTEGER PRIMARY KEY,
+        account TEXT NOT NULL,
+        contact BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS contacts_account ON contacts (account);
+";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Contact {
+    /// UID of an imported vCard, a repeated import updates the contact instead of adding it
+    pub uid: Option<String>,
+    pub name: String,
+    /// Preferred address first
+    pub emails: Vec<String>,
+    pub phones: Vec<String>,
+    pub organization: Option<String>,
+    pub note: Option<String>,
+    pub groups: Vec<String>,
+    /// How often the user wrote to the contact, ranks autocomplete suggestions
+    pub times_contacted: u32,
+    pub last_contacted: Option<NaiveDateTime>,
+    /// Card on the CardDAV server the contact is synced with
+    #[serde(default)]
+    pub remote: Option<RemoteContact>,
+    /// Edited locally since the last sync, the next sync writes it to the server
+    #[serde(default)]
+    pub pending_push: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RemoteContact {
+    pub collection: String,
+    pub href: String,
+    pub etag: Option<String>,
+    /// Card as last seen on the server, properties the contact does not model are kept from it
+    pub vcard: String,
+}
+
+impl Contact {
+    pub fn has_email(&self, email: &str) -> bool {
+        self.emails
+            .iter()
+            .any(|address| address.eq_ignore_ascii_case(email))
+    }
+
+    pub fn in_group(&self, group: &str) -> bool {
+        self.groups
+            .iter()
+            .any(|name| name.to_lowercase() == group.to_lowercase())
+    }
+}
+
+/// Address the user wrote to, with the display name it was written with.
+pub struct ContactedAddress {
+    pub name: Option<String>,
+    pub email: String,
+}
+
+pub struct ContactImportCounts {
+    pub created: u32,
+    pub updated: u32,
+}
+
+impl MessageCache {
+    /// All contacts of the account with their ids, in no particular order.
+    pub fn contacts(&self, account: &str) -> Result<Vec<(i64, Contact)>, Error> {
+        let account = self.cipher.hash_id(account);
+        load_contacts(&self.database(), &self.cipher, &account)
+    }
+
+    pub fn contact(&self, account: &str, id: i64) -> Result<Option<Contact>, Error> {
+        let account = self.cipher.hash_id(account);
+        let contact: Option<Vec<u8>> = self
+            .database()
+            .query_row(
+                "SELECT contact FROM contacts WHERE id = ?1 AND account = ?2",
+                params![id, account],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(storage_error)?;
+        match contact {
+            Some(contact) => Ok(Some(serde_json::from_slice(
+                &self.cipher.decrypt(&contact)?,
+            )?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn insert_contact(&self, account: &str, contact: &Contact) -> Result<i64, Error> {
+        let account = self.cipher.hash_id(account);
+        let database = self.database();
+        insert_contact(&database, &self.cipher, &account, contact)?;
+        Ok(database.last_insert_rowid())
+    }
+
+    /// Replaces the contact, returns false when the account has no contact with the id.
+    pub fn update_contact(&self, account: &str, id: i64, contact: &Contact) -> Result<bool, Error> {
+        let account = self.cipher.hash_id(account);
+        update_contact(&self.database(), &self.cipher, &account, id, contact)
+    }
+
+    pub fn delete_contact(&self, account: &str, id: i64) -> Result<bool, Error> {
+        let account = self.cipher.hash_id(account);
+        self.database()
+            .execute(
+                "DELETE FROM contacts WHERE id = ?1 AND account = ?2",
+                params![id, account],
+            )
+            .map(|deleted| deleted > 0)
+            .map_err(storage_error)
+    }
+
+    /// Counts a sent email for every address. Unknown addresses become new contacts, known
+    /// ones without a name take the display name they were written with. New contacts and new
+    /// names are written to the CardDAV server by its next sync, the counts stay local.
+    pub fn record_contacted(
+        &self,
+        account: &str,
+        addresses: &[ContactedAddress],
+        contacted_at: NaiveDateTime,
+    ) -> Result<(), Error> {
+        let account = self.cipher.hash_id(account);
+        let mut database = self.database();
+        let transaction = database.transaction().map_err(storage_error)?;
+        let mut contacts = load_contacts(&transaction, &self.cipher, &account)?;
+
+        for address in addresses.iter() {
+            let name = address
+                .name
+                .as_deref()
+                .map(str::trim)
+                .filter(|name| !name.is_empty());
+            match contacts
+                .iter_mut()
+                .find(|(_, contact)| contact.has_email(&address.email))
+            {
+                Some((id, contact)) => {
+                    contact.times_contacted += 1;
+                    contact.last_contacted = Some(contacted_at);
+                    if let (true, Some(name)) = (contact.name.is_empty(), name) {
+                        contact.name = name.to_string();
+                        contact.pending_push = true;
+                    }
+                    update_contact(&transaction, &self.cipher, &account, *id, contact)?;
+                }
+                None => {
+                    let contact = Contact {
+                        name: name.unwrap_or_default().to_string(),
+                        emails: vec![address.email.clone()],
+                        times_contacted: 1,
+                        last_contacted: Some(contacted_at),
+                        pending_push: true,
+                        ..Contact::default()
+                    };
+                    insert_contact(&transaction, &self.cipher, &account, &contact)?;
+                    contacts.push((transaction.last_insert_rowid(), contact));
+                }
+            }
+        }
+        transaction.commit().map_err(storage_error)
+    }
+
+    /// Adds imported contacts, merging each into the contact with the same vCard UID or, without
+    /// one, into a contact sharing an address. Usage counts of merged contacts are kept. Both
+    /// are written to the CardDAV server by its next sync.
+    pub fn import_contacts(
+        &self,
+        account: &str,
+        imported: Vec<Contact>,
+    ) -> Result<ContactImportCounts, Error> {
+        let account = self.cipher.hash_id(account);
+        let mut database = self.database();
+        let transaction = database.transaction().map_err(storage_error)?;
+        let mut contacts = load_contacts(&transaction, &self.cipher, &account)?;
+        let mut counts = ContactImportCounts {
+            created: 0,
+            updated: 0,
+        };
+
+        for mut contact in imported.into_iter() {
+            contact.pending_push = true;
+            let existing =
+                contacts
+                    .iter_mut()
+                    .find(|(_, existing)| match (&existing.uid, &contact.uid) {
+                        (Some(existing_uid), Some(uid)) => existing_uid == uid,
+                        _ => contact.emails.iter().any(|email| existing.has_email(email)),
+                    });
+            match existing {
+                Some((id, existing)) => {
+                    merge_contact(existing, contact);
+                    update_contact(&transaction, &self.cipher, &account, *id, existing)?;
+                    counts.updated += 1;
+                }
+                None => {
+                    insert_contact(&transaction, &self.cipher, &account, &contact)?;
+                    contacts.push((transaction.last_insert_rowid(), contact));
+                    counts.created += 1;
+                }
+            }
+        }
+        transaction.commit().map_err(storage_error)?;
+        Ok(counts)
+    }
+}
+
+fn load_contacts(
+    connection: &Connection,
+    cipher: &StorageCipher,
+    account: &str,
+) -> Result<Vec<(i64, Contact)>, Error> {
+    let rows = {
+        let mut statement = connection
+            .prepare("SELECT id, contact FROM contacts WHERE account = ?1")
+            .map_err(storage_error)?;
+        let rows = statement
+            .query_map(params![account], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(storage_error)?
+            .collect::<Result<Vec<(i64, Vec<u8>)>, rusqlite::Error>>()
+            .map_err(storage_error)?;
+        rows
+    };
+
+    rows.into_iter()
+        .map(|(id, contact)| Ok((id, serde_json::from_slice(&cipher.decrypt(&contact)?)?)))
+        .collect()
+}
+
+fn insert_contact(
+    connection: &Connection,
+    cipher: &StorageCipher,
+    account: &str,
+    contact: &Contact,
+) -> Result<(), Error> {
+    let encrypted_contact = cipher.encrypt(&serde_json::to_vec(contact)?)?;
+    connection
+        .execute(
+            "INSERT INTO contacts (account, contact) VALUES (?1, ?2)",
+            params![account, encrypted_contact],
+        )
+        .map(|_| ())
+        .map_err(storage_error)
+}
+
+fn update_contact(
+    connection: &Connection,
+    cipher: &StorageCipher,
+    account: &str,
+    id: i64,
+    contact: &Contact,
+) -> Result<bool, Error> {
+    let encrypted_contact = cipher.encrypt(&serde_json::to_vec(contact)?)?;
+    connection
+        .execute(
+            "UPDATE contacts SET contact = ?1 WHERE id = ?2 AND account = ?3",
+            params![encrypted_contact, id, account],
+        )
+        .map(|updated| updated > 0)
+        .map_err(storage_error)
+}
+
+/// Imported values win, lists are joined without duplicates.
+fn merge_contact(existing: &mut Contact, imported: Contact) {
+    existing.pending_push = true;
+    if imported.uid.is_some() {
+        existing.uid = imported.uid;
+    }
+    if !imported.name.is_empty() {
+        existing.name = imported.name;
+    }
+    for email in imported.emails.into_iter() {
+        if !existing.has_email(&email) {
+            existing.emails.push(email);
+        }
+    }
+    for phone in imported.phones.into_iter() {
+        if !existing.phones.contains(&phone) {
+            existing.phones.push(phone);
+        }
+    }
+    for group in imported.groups.into_iter() {
+        if !existing.in_group(&group) {
+            existing.groups.push(group);
+        }
+    }
+    if imported.organization.is_some() {
+        existing.organization = imported.organization;
+    }
+    if imported.note.is_some() {
+        existing.note = imported.note;
+    }
+}
@@ -0,0 +1,74 @@
+use std::io::Error;
+
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    contacts::RemoteContact,
+    message_cache::{storage_error, MessageCache},
+};
+
+pub const DAV_STATE_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS dav_collections (
+        account TEXT NOT NULL,
+        collection TEXT NOT NULL,
+        state BLOB NOT NULL,
+        PRIMARY KEY (account, collection)
+    );
+";
+
+/// Sync state of one CardDAV address book.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct DavCollectionState {
+    pub sync_token: Option<String>,
+    /// Synced contacts deleted locally, the next sync removes their cards
+    pub pending_deletions: Vec<RemoteContact>,
+}
+
+impl MessageCache {
+    pub fn dav_collection_state(
+        &self,
+        account: &str,
+        collection: &str,
+    ) -> Result<DavCollectionState, Error> {
+        let (account, collection) = self.keys(account, collection);
+        let state: Option<Vec<u8>> = self
+            .database()
+            .query_row(
+                "SELECT state FROM dav_collections WHERE account = ?1 AND collection = ?2",
+                params![account, collection],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(storage_error)?;
+        match state {
+            Some(state) => Ok(serde_json::from_slice(&self.cipher.decrypt(&state)?)?),
+            None => Ok(DavCollectionState::default()),
+        }
+    }
+
+    pub fn set_dav_collection_state(
+        &self,
+        account: &str,
+        collection: &str,
+        state: &DavCollectionState,
+    ) -> Result<(), Error> {
+        let (account, collection) = self.keys(account, collection);
+        let encrypted_state = self.cipher.encrypt(&serde_json::to_vec(state)?)?;
+        self.database()
+            .execute(
+                "INSERT OR REPLACE INTO dav_collections (account, collection, state) \
+                 VALUES (?1, ?2, ?3)",
+                params![account, collection, encrypted_state],
+            )
+            .map(|_| ())
+            .map_err(storage_error)
+    }
+
+    /// Remembers the card of a deleted contact for removal on the server.
+    pub fn queue_dav_deletion(&self, account: &str, remote: &RemoteContact) -> Result<(), Error> {
+        let mut state = self.dav_collection_state(account, &remote.collection)?;
+        state.pending_deletions.push(remote.clone());
+        self.set_dav_collection_state(account, &remote.collection, &state)
+    }
+}
@@ -0,0 +1,65 @@
+use std::io::Error;
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
+};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+
+const NONCE_LENGTH: usize = 12;
+
+/// Encrypts everything the server keeps on disk with keys derived from `ENCRYPTION_KEY`.
+pub struct StorageCipher {
+    cipher: Aes256Gcm,
+    id_key: [u8; 32],
+}
+
+impl StorageCipher {
+    pub fn from_server_key(server_key: &[u8]) -> StorageCipher {
+        let hkdf = Hkdf::<Sha256>::new(None, server_key);
+
+        let mut encryption_key = [0u8; 32];
+        let mut id_key = [0u8; 32];
+        // Both lengths are valid for SHA-256, expand can not fail
+        hkdf.expand(b"storage-encryption", &mut encryption_key)
+            .unwrap();
+        hkdf.expand(b"storage-identifiers", &mut id_key).unwrap();
+
+        StorageCipher {
+            cipher: Aes256Gcm::new(&encryption_key.into()),
+            id_key,
+        }
+    }
+
+    /// Returns the random nonce followed by the ciphertext.
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, data)
+            .map_err(|err| Error::other(format!("Encryption failed: {}", err)))?;
+
+        let mut result = nonce.to_vec();
+        result.extend(ciphertext);
+        Ok(result)
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        if data.len() < NONCE_LENGTH {
+            return Err(Error::other("Encrypted data too short"));
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LENGTH);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|err| Error::other(format!("Decryption failed: {}", err)))
+    }
+
+    /// Keyed hash used instead of plain email addresses and mailbox names in storage keys.
+    pub fn hash_id(&self, value: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.id_key);
+        hasher.update(value.as_bytes());
+        data_encoding::HEXLOWER.encode(&hasher.finalize())
+    }
+}
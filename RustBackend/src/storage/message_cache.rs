@@ -0,0 +1,487 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::Error,
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::handlers::auth::models::SignInMessage;
+
+use super::{
+    bulk_sends::BULK_SENDS_SCHEMA,
+    contacts::CONTACTS_SCHEMA,
+    dav_state::DAV_STATE_SCHEMA,
+    encryption::StorageCipher,
+    scan_log::SCAN_LOG_SCHEMA,
+    search_index::{drop_plaintext_search_index, unindex_documents, SEARCH_SCHEMA},
+    templates::TEMPLATES_SCHEMA,
+};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS mailboxes (
+        account TEXT NOT NULL,
+        mailbox TEXT NOT NULL,
+        uid_validity INTEGER NOT NULL,
+        synced_at INTEGER,
+        PRIMARY KEY (account, mailbox)
+    );
+    CREATE TABLE IF NOT EXISTS messages (
+        account TEXT NOT NULL,
+        mailbox TEXT NOT NULL,
+        uid INTEGER NOT NULL,
+        flags BLOB NOT NULL,
+        summary BLOB NOT NULL,
+        PRIMARY KEY (account, mailbox, uid)
+    );
+    CREATE TABLE IF NOT EXISTS message_parts (
+        account TEXT NOT NULL,
+        mailbox TEXT NOT NULL,
+        uid INTEGER NOT NULL,
+        section TEXT NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (account, mailbox, uid, section)
+    );
+";
+
+/// Local copy of envelopes, flags, body structures and fetched parts, keyed by account,
+/// mailbox and UID. Account and mailbox names are stored as keyed hashes and everything
+/// else is encrypted with the server key.
+pub struct MessageCache {
+    connection: Mutex<Connection>,
+    pub(super) cipher: Arc<StorageCipher>,
+    accounts: Mutex<HashMap<String, CachedAccount>>,
+}
+
+/// Account the background sync keeps fresh. Credentials only ever live in memory.
+struct CachedAccount {
+    credentials: SignInMessage,
+    mailbox_names: HashSet<String>,
+    last_used: Instant,
+}
+
+pub struct CachedMessage {
+    pub flags: Vec<String>,
+    /// Raw FETCH response with the summary items, parse with `imap_proto::parse_response`
+    pub summary: Vec<u8>,
+}
+
+impl MessageCache {
+    pub fn open(path: &Path, cipher: Arc<StorageCipher>) -> Result<MessageCache, Error> {
+        if let Some(directory) = path.parent() {
+            std::fs::create_dir_all(directory)?;
+        }
+        let connection = Connection::open(path).map_err(storage_error)?;
+        connection.execute_batch(SCHEMA).map_err(storage_error)?;
+        connection
+            .execute_batch(SEARCH_SCHEMA)
+            .map_err(storage_error)?;
+        drop_plaintext_search_index(&connection)?;
+        connection
+            .execute_batch(SCAN_LOG_SCHEMA)
+            .map_err(storage_error)?;
+        connection
+            .execute_batch(CONTACTS_SCHEMA)
+            .map_err(storage_error)?;
+        connection
+            .execute_batch(DAV_STATE_SCHEMA)
+            .map_err(storage_error)?;
+        connection
+            .execute_batch(TEMPLATES_SCHEMA)
+            .map_err(storage_error)?;
+        connection
+            .execute_batch(BULK_SENDS_SCHEMA)
+            .map_err(storage_error)?;
+
+        Ok(MessageCache {
+            connection: Mutex::new(connection),
+            cipher,
+            accounts: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub(super) fn database(&self) -> MutexGuard<'_, Connection> {
+        self.connection.lock().unwrap()
+    }
+
+    pub(super) fn keys(&self, account: &str, mailbox_name: &str) -> (String, String) {
+        (
+            self.cipher.hash_id(account),
+            self.cipher
+                .hash_id(&format!("{}/{}", account, mailbox_name)),
+        )
+    }
+
+    pub fn uid_validity(&self, account: &str, mailbox_name: &str) -> Result<Option<u32>, Error> {
+        let (account, mailbox) = self.keys(account, mailbox_name);
+        self.database()
+            .query_row(
+                "SELECT uid_validity FROM mailboxes WHERE account = ?1 AND mailbox = ?2",
+                params![account, mailbox],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(storage_error)
+    }
+
+    /// Whether the mailbox went through a complete sync, only then the cache can answer
+    /// listings on its own.
+    pub fn is_synced(&self, account: &str, mailbox_name: &str) -> Result<bool, Error> {
+        let (account, mailbox) = self.keys(account, mailbox_name);
+        let synced_at: Option<Option<i64>> = self
+            .database()
+            .query_row(
+                "SELECT synced_at FROM mailboxes WHERE account = ?1 AND mailbox = ?2",
+                params![account, mailbox],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(storage_error)?;
+        Ok(matches!(synced_at, Some(Some(_))))
+    }
+
+    /// Compares UIDVALIDITY reported by the server with the cached one and drops all cached
+    /// messages of the mailbox when it changed, as their UIDs now point to different messages.
+    pub fn validate_mailbox(
+        &self,
+        account: &str,
+        mailbox_name: &str,
+        uid_validity: u32,
+    ) -> Result<(), Error> {
+        if self.uid_validity(account, mailbox_name)? == Some(uid_validity) {
+            return Ok(());
+        }
+
+        let (account, mailbox) = self.keys(account, mailbox_name);
+        let mut database = self.database();
+        let transaction = database.transaction().map_err(storage_error)?;
+        transaction
+            .execute(
+                "DELETE FROM messages WHERE account = ?1 AND mailbox = ?2",
+                params![account, mailbox],
+            )
+            .map_err(storage_error)?;
+        transaction
+            .execute(
+                "DELETE FROM message_parts WHERE account = ?1 AND mailbox = ?2",
+                params![account, mailbox],
+            )
+            .map_err(storage_error)?;
+        unindex_documents(&transaction, &self.cipher, &account, &mailbox, None)?;
+        transaction
+            .execute(
+                "INSERT OR REPLACE INTO mailboxes (account, mailbox, uid_validity, synced_at) \
+                 VALUES (?1, ?2, ?3, NULL)",
+                params![account, mailbox, uid_validity],
+            )
+            .map_err(storage_error)?;
+        transaction.commit().map_err(storage_error)
+    }
+
+    pub fn mark_synced(&self, account: &str, mailbox_name: &str) -> Result<(), Error> {
+        let (account, mailbox) = self.keys(account, mailbox_name);
+        self.database()
+            .execute(
+                "UPDATE mailboxes SET synced_at = strftime('%s', 'now') \
+                 WHERE account = ?1 AND mailbox = ?2",
+                params![account, mailbox],
+            )
+            .map(|_| ())
+            .map_err(storage_error)
+    }
+
+    /// Sends listings to the server again until the next sync, for changes of the mailbox
+    /// the cache cannot follow on its own.
+    pub fn mark_unsynced(&self, account: &str, mailbox_name: &str) -> Result<(), Error> {
+        let (account, mailbox) = self.keys(account, mailbox_name);
+        self.database()
+            .execute(
+                "UPDATE mailboxes SET synced_at = NULL WHERE account = ?1 AND mailbox = ?2",
+                params![account, mailbox],
+            )
+            .map(|_| ())
+            .map_err(storage_error)
+    }
+
+    pub fn uids(&self, account: &str, mailbox_name: &str) -> Result<Vec<u32>, Error> {
+        let (account, mailbox) = self.keys(account, mailbox_name);
+        let database = self.database();
+        let mut statement = database
+            .prepare("SELECT uid FROM messages WHERE account = ?1 AND mailbox = ?2 ORDER BY uid")
+            .map_err(storage_error)?;
+        let uids = statement
+            .query_map(params![account, mailbox], |row| row.get(0))
+            .map_err(storage_error)?
+            .collect::<Result<Vec<u32>, rusqlite::Error>>()
+            .map_err(storage_error)?;
+        Ok(uids)
+    }
+
+    /// Returns UIDs of cached messages which do not have given part stored yet.
+    pub fn uids_without_part(
+        &self,
+        account: &str,
+        mailbox_name: &str,
+        section: &str,
+    ) -> Result<Vec<u32>, Error> {
+        let (account, mailbox) = self.keys(account, mailbox_name);
+        let database = self.database();
+        let mut statement = database
+            .prepare(
+                "SELECT uid FROM messages WHERE account = ?1 AND mailbox = ?2 AND NOT EXISTS ( \
+                     SELECT 1 FROM message_parts WHERE message_parts.account = messages.account \
+                     AND message_parts.mailbox = messages.mailbox \
+                     AND message_parts.uid = messages.uid AND section = ?3 \
+                 ) ORDER BY uid",
+            )
+            .map_err(storage_error)?;
+        let uids = statement
+            .query_map(params![account, mailbox, section], |row| row.get(0))
+            .map_err(storage_error)?
+            .collect::<Result<Vec<u32>, rusqlite::Error>>()
+            .map_err(storage_error)?;
+        Ok(uids)
+    }
+
+    pub fn remove_messages(
+        &self,
+        account: &str,
+        mailbox_name: &str,
+        uids: &[u32],
+    ) -> Result<(), Error> {
+        let (account, mailbox) = self.keys(account, mailbox_name);
+        let mut database = self.database();
+        let transaction = database.transaction().map_err(storage_error)?;
+        for uid in uids.iter() {
+            transaction
+                .execute(
+                    "DELETE FROM messages WHERE account = ?1 AND mailbox = ?2 AND uid = ?3",
+                    params![account, mailbox, uid],
+                )
+                .map_err(storage_error)?;
+            transaction
+                .execute(
+                    "DELETE FROM message_parts WHERE account = ?1 AND mailbox = ?2 AND uid = ?3",
+                    params![account, mailbox, uid],
+                )
+                .map_err(storage_error)?;
+            unindex_documents(&transaction, &self.cipher, &account, &mailbox, Some(*uid))?;
+        }
+        transaction.commit().map_err(storage_error)
+    }
+
+    pub fn store_message(
+        &self,
+        account: &str,
+        mailbox_name: &str,
+        uid: u32,
+        flags: &[String],
+        summary: &[u8],
+    ) -> Result<(), Error> {
+        let (account, mailbox) = self.keys(account, mailbox_name);
+        let flags = self.encrypt_flags(flags)?;
+        let summary = self.cipher.encrypt(summary)?;
+        self.database()
+            .execute(
+                "INSERT OR REPLACE INTO messages (account, mailbox, uid, flags, summary) \
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![account, mailbox, uid, flags, summary],
+            )
+            .map(|_| ())
+            .map_err(storage_error)
+    }
+
+    /// Updates flags of already cached messages, unknown UIDs are ignored.
+    pub fn update_flags(
+        &self,
+        account: &str,
+        mailbox_name: &str,
+        flags: &[(u32, Vec<String>)],
+    ) -> Result<(), Error> {
+        let (account, mailbox) = self.keys(account, mailbox_name);
+        let mut encrypted_flags = vec![];
+        for (uid, message_flags) in flags.iter() {
+            encrypted_flags.push((*uid, self.encrypt_flags(message_flags)?));
+        }
+
+        let mut database = self.database();
+        let transaction = database.transaction().map_err(storage_error)?;
+        for (uid, message_flags) in encrypted_flags.iter() {
+            transaction
+                .execute(
+                    "UPDATE messages SET flags = ?4 \
+                     WHERE account = ?1 AND mailbox = ?2 AND uid = ?3",
+                    params![account, mailbox, uid, message_flags],
+                )
+                .map_err(storage_error)?;
+        }
+        transaction.commit().map_err(storage_error)
+    }
+
+    pub fn message(
+        &self,
+        account: &str,
+        mailbox_name: &str,
+        uid: u32,
+    ) -> Result<Option<CachedMessage>, Error> {
+        let (account, mailbox) = self.keys(account, mailbox_name);
+        let row: Option<(Vec<u8>, Vec<u8>)> = self
+            .database()
+            .query_row(
+                "SELECT flags, summary FROM messages \
+                 WHERE account = ?1 AND mailbox = ?2 AND uid = ?3",
+                params![account, mailbox, uid],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .map_err(storage_error)?;
+
+        row.map(|row| self.decrypt_message(row)).transpose()
+    }
+
+    pub fn message_count(&self, account: &str, mailbox_name: &str) -> Result<u32, Error> {
+        let (account, mailbox) = self.keys(account, mailbox_name);
+        self.database()
+            .query_row(
+                "SELECT COUNT(*) FROM messages WHERE account = ?1 AND mailbox = ?2",
+                params![account, mailbox],
+                |row| row.get(0),
+            )
+            .map_err(storage_error)
+    }
+
+    /// Returns cached messages ordered by UID, which matches the order of sequence numbers.
+    pub fn messages(
+        &self,
+        account: &str,
+        mailbox_name: &str,
+        offset: u32,
+        limit: u32,
+    ) -> Result<Vec<CachedMessage>, Error> {
+        let (account, mailbox) = self.keys(account, mailbox_name);
+        let rows = {
+            let database = self.database();
+            let mut statement = database
+                .prepare(
+                    "SELECT flags, summary FROM messages WHERE account = ?1 AND mailbox = ?2 \
+                     ORDER BY uid LIMIT ?3 OFFSET ?4",
+                )
+                .map_err(storage_error)?;
+            let rows = statement
+                .query_map(params![account, mailbox, limit, offset], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })
+                .map_err(storage_error)?
+                .collect::<Result<Vec<(Vec<u8>, Vec<u8>)>, rusqlite::Error>>()
+                .map_err(storage_error)?;
+            rows
+        };
+
+        rows.into_iter()
+            .map(|row| self.decrypt_message(row))
+            .collect()
+    }
+
+    pub fn store_part(
+        &self,
+        account: &str,
+        mailbox_name: &str,
+        uid: u32,
+        section: &str,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let (account, mailbox) = self.keys(account, mailbox_name);
+        let data = self.cipher.encrypt(data)?;
+        self.database()
+            .execute(
+                "INSERT OR REPLACE INTO message_parts (account, mailbox, uid, section, data) \
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![account, mailbox, uid, section, data],
+            )
+            .map(|_| ())
+            .map_err(storage_error)
+    }
+
+    pub fn part(
+        &self,
+        account: &str,
+        mailbox_name: &str,
+        uid: u32,
+        section: &str,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let (account, mailbox) = self.keys(account, mailbox_name);
+        let data: Option<Vec<u8>> = self
+            .database()
+            .query_row(
+                "SELECT data FROM message_parts \
+                 WHERE account = ?1 AND mailbox = ?2 AND uid = ?3 AND section = ?4",
+                params![account, mailbox, uid, section],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(storage_error)?;
+
+        data.map(|data| self.cipher.decrypt(&data)).transpose()
+    }
+
+    /// Adds the mailbox to the ones kept fresh by the background sync. Returns true when it
+    /// was not watched before, so the caller can start the first sync right away.
+    pub fn register_mailbox(&self, credentials: &SignInMessage, mailbox_name: &str) -> bool {
+        let mut accounts = self.accounts.lock().unwrap();
+        let account = accounts
+            .entry(credentials.email.clone())
+            .or_insert_with(|| CachedAccount {
+                credentials: credentials.clone(),
+                mailbox_names: HashSet::new(),
+                last_used: Instant::now(),
+            });
+        account.credentials = credentials.clone();
+        account.last_used = Instant::now();
+        account.mailbox_names.insert(mailbox_name.to_string())
+    }
+
+    /// Whether the background sync keeps the mailbox fresh, and so clears its parts when the
+    /// UIDVALIDITY changes.
+    pub fn is_watched(&self, account: &str, mailbox_name: &str) -> bool {
+        self.accounts
+            .lock()
+            .unwrap()
+            .get(account)
+            .is_some_and(|account| account.mailbox_names.contains(mailbox_name))
+    }
+
+    /// Returns the accounts to sync together with their mailboxes and forgets the ones
+    /// nobody used for longer than `max_idle`.
+    pub fn accounts_to_sync(&self, max_idle: Duration) -> Vec<(SignInMessage, Vec<String>)> {
+        let mut accounts = self.accounts.lock().unwrap();
+        accounts.retain(|_, account| account.last_used.elapsed() < max_idle);
+        accounts
+            .values()
+            .map(|account| {
+                (
+                    account.credentials.clone(),
+                    account.mailbox_names.iter().cloned().collect(),
+                )
+            })
+            .collect()
+    }
+
+    fn encrypt_flags(&self, flags: &[String]) -> Result<Vec<u8>, Error> {
+        self.cipher
+            .encrypt(serde_json::to_string(flags)?.as_bytes())
+    }
+
+    fn decrypt_message(&self, row: (Vec<u8>, Vec<u8>)) -> Result<CachedMessage, Error> {
+        let (flags, summary) = row;
+        Ok(CachedMessage {
+            flags: serde_json::from_slice(&self.cipher.decrypt(&flags)?)?,
+            summary: self.cipher.decrypt(&summary)?,
+        })
+    }
+}
+
+pub(super) fn storage_error(err: rusqlite::Error) -> Error {
+    Error::other(format!("Message cache failed: {}", err))
+}
@@ -0,0 +1,8 @@
+pub mod bulk_sends;
+pub mod contacts;
+pub mod dav_state;
+pub mod encryption;
+pub mod message_cache;
+pub mod scan_log;
+pub mod search_index;
+pub mod templates;
@@ -0,0 +1,76 @@
+use std::io::Error;
+
+use chrono::NaiveDateTime;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+
+use super::message_cache::{storage_error, MessageCache};
+
+pub const SCAN_LOG_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS scan_refusals (
+        id INTEGER PRIMARY KEY,
+        account TEXT NOT NULL,
+        refused_at INTEGER NOT NULL,
+        refusal BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS scan_refusals_account ON scan_refusals (account, refused_at);
+";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanDirection {
+    Download,
+    Upload,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ScanRefusal {
+    pub direction: ScanDirection,
+    /// Set for downloads only
+    pub mailbox_name: Option<String>,
+    pub uid: Option<u32>,
+    pub part_id: Option<String>,
+    pub file_name: String,
+    pub content_type: String,
+    pub scanner: String,
+    pub reason: String,
+    pub refused_at: NaiveDateTime,
+}
+
+impl MessageCache {
+    pub fn record_scan_refusal(&self, account: &str, refusal: &ScanRefusal) -> Result<(), Error> {
+        let account = self.cipher.hash_id(account);
+        let encrypted_refusal = self.cipher.encrypt(&serde_json::to_vec(refusal)?)?;
+        self.database()
+            .execute(
+                "INSERT INTO scan_refusals (account, refused_at, refusal) VALUES (?1, ?2, ?3)",
+                params![account, refusal.refused_at.timestamp(), encrypted_refusal],
+            )
+            .map(|_| ())
+            .map_err(storage_error)
+    }
+
+    /// Latest refusals of the account first.
+    pub fn scan_refusals(&self, account: &str, limit: u32) -> Result<Vec<ScanRefusal>, Error> {
+        let account = self.cipher.hash_id(account);
+        let rows = {
+            let database = self.database();
+            let mut statement = database
+                .prepare(
+                    "SELECT refusal FROM scan_refusals WHERE account = ?1 \
+                     ORDER BY refused_at DESC, id DESC LIMIT ?2",
+                )
+                .map_err(storage_error)?;
+            let rows = statement
+                .query_map(params![account, limit], |row| row.get(0))
+                .map_err(storage_error)?
+                .collect::<Result<Vec<Vec<u8>>, rusqlite::Error>>()
+                .map_err(storage_error)?;
+            rows
+        };
+
+        rows.into_iter()
+            .map(|refusal| Ok(serde_json::from_slice(&self.cipher.decrypt(&refusal)?)?))
+            .collect()
+    }
+}
@@ -0,0 +1,312 @@
+use std::io::Error;
+
+use chrono::NaiveDateTime;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use serde::{Deserialize, Serialize};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+use super::{
+    encryption::StorageCipher,
+    message_cache::{storage_error, MessageCache},
+};
+
+// The index is contentless and holds keyed hashes of the words instead of the words, the
+// documents themselves are kept encrypted next to it. Words and phrases can be searched, the
+// beginning of a word can not. Removing a document from such an index needs its original values.
+pub const SEARCH_SCHEMA: &str = "
+    CREATE VIRTUAL TABLE IF NOT EXISTS search_tokens USING fts5(
+        from_address,
+        subject,
+        body,
+        attachments,
+        content = '',
+        tokenize = 'ascii'
+    );
+    CREATE TABLE IF NOT EXISTS search_documents (
+        id INTEGER PRIMARY KEY,
+        account TEXT NOT NULL,
+        mailbox TEXT NOT NULL,
+        uid INTEGER NOT NULL,
+        document BLOB NOT NULL,
+        UNIQUE (account, mailbox, uid)
+    );
+";
+
+// Hex characters kept of the hash of a word, 64 bits
+const TOKEN_LENGTH: usize = 16;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SearchDocument {
+    pub mailbox_name: String,
+    pub uid: u32,
+    pub from_address: String,
+    pub subject: String,
+    pub send_date: NaiveDateTime,
+    pub body: String,
+    pub attachments: Vec<String>,
+}
+
+pub struct SearchResult {
+    pub document: SearchDocument,
+    pub score: f64,
+}
+
+impl MessageCache {
+    pub fn is_indexed(&self, account: &str, mailbox_name: &str, uid: u32) -> Result<bool, Error> {
+        let (account, mailbox) = self.keys(account, mailbox_name);
+        let id: Option<i64> = self
+            .database()
+            .query_row(
+                "SELECT id FROM search_documents WHERE account = ?1 AND mailbox = ?2 AND uid = ?3",
+                params![account, mailbox, uid],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(storage_error)?;
+        Ok(id.is_some())
+    }
+
+    /// Adds the document to the full-text index, replacing the previous version of the email.
+    pub fn index_document(&self, account: &str, document: &SearchDocument) -> Result<(), Error> {
+        let (account, mailbox) = self.keys(account, &document.mailbox_name);
+        let encrypted_document = self.cipher.encrypt(&serde_json::to_vec(document)?)?;
+
+        let mut database = self.database();
+        let transaction = database.transaction().map_err(storage_error)?;
+        unindex_documents(
+            &transaction,
+            &self.cipher,
+            &account,
+            &mailbox,
+            Some(document.uid),
+        )?;
+        transaction
+            .execute(
+                "INSERT INTO search_documents (account, mailbox, uid, document) \
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![account, mailbox, document.uid, encrypted_document],
+            )
+            .map_err(storage_error)?;
+        transaction
+            .execute(
+                "INSERT INTO search_tokens (rowid, from_address, subject, body, attachments) \
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    transaction.last_insert_rowid(),
+                    search_tokens(&self.cipher, &document.from_address),
+                    search_tokens(&self.cipher, &document.subject),
+                    search_tokens(&self.cipher, &document.body),
+                    search_tokens(&self.cipher, &document.attachments.join(" "))
+                ],
+            )
+            .map_err(storage_error)?;
+        transaction.commit().map_err(storage_error)
+    }
+
+    /// Tokens the words of the text are indexed as, to build match queries from.
+    pub fn search_tokens(&self, text: &str) -> String {
+        search_tokens(&self.cipher, text)
+    }
+
+    /// Runs an FTS5 match query over the emails of the account, best matches first. Returns
+    /// the total count of matches together with the requested slice.
+    pub fn search(
+        &self,
+        account: &str,
+        mailbox_name: Option<&str>,
+        match_query: &str,
+        offset: u32,
+        limit: u32,
+    ) -> Result<(u32, Vec<SearchResult>), Error> {
+        let account_key = self.cipher.hash_id(account);
+        let mailbox_key = mailbox_name
+            .map(|name| self.keys(account, name).1)
+            .unwrap_or_default();
+
+        let database = self.database();
+        let total: u32 = database
+            .query_row(
+                "SELECT COUNT(*) FROM search_tokens \
+                 JOIN search_documents ON search_documents.id = search_tokens.rowid \
+                 WHERE search_tokens MATCH ?1 AND account = ?2 AND (?3 = '' OR mailbox = ?3)",
+                params![match_query, account_key, mailbox_key],
+                |row| row.get(0),
+            )
+            .map_err(storage_error)?;
+
+        // Matches in the sender and subject weigh more than the ones in the body
+        let mut statement = database
+            .prepare(
+                "SELECT document, bm25(search_tokens, 3.0, 2.0, 1.0, 1.0) AS rank \
+                 FROM search_tokens \
+                 JOIN search_documents ON search_documents.id = search_tokens.rowid \
+                 WHERE search_tokens MATCH ?1 AND account = ?2 AND (?3 = '' OR mailbox = ?3) \
+                 ORDER BY rank LIMIT ?4 OFFSET ?5",
+            )
+            .map_err(storage_error)?;
+        let rows = statement
+            .query_map(
+                params![match_query, account_key, mailbox_key, limit, offset],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .map_err(storage_error)?
+            .collect::<Result<Vec<(Vec<u8>, f64)>, rusqlite::Error>>()
+            .map_err(storage_error)?;
+
+        let mut results = vec![];
+        for (document, rank) in rows.into_iter() {
+            results.push(SearchResult {
+                document: serde_json::from_slice(&self.cipher.decrypt(&document)?)?,
+                // bm25 is negative, the lower the better
+                score: -rank,
+            });
+        }
+        Ok((total, results))
+    }
+}
+
+/// Removes documents of the mailbox, or of a single email when `uid` is set, from the index.
+pub fn unindex_documents(
+    transaction: &Transaction,
+    cipher: &StorageCipher,
+    account: &str,
+    mailbox: &str,
+    uid: Option<u32>,
+) -> Result<(), Error> {
+    let rows = {
+        let mut statement = transaction
+            .prepare(
+                "SELECT id, document FROM search_documents \
+                 WHERE account = ?1 AND mailbox = ?2 AND (?3 IS NULL OR uid = ?3)",
+            )
+            .map_err(storage_error)?;
+        let rows = statement
+            .query_map(params![account, mailbox, uid], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .map_err(storage_error)?
+            .collect::<Result<Vec<(i64, Vec<u8>)>, rusqlite::Error>>()
+            .map_err(storage_error)?;
+        rows
+    };
+
+    for (id, document) in rows.into_iter() {
+        let document: SearchDocument = serde_json::from_slice(&cipher.decrypt(&document)?)?;
+        transaction
+            .execute(
+                "INSERT INTO search_tokens \
+                 (search_tokens, rowid, from_address, subject, body, attachments) \
+                 VALUES ('delete', ?1, ?2, ?3, ?4, ?5)",
+                params![
+                    id,
+                    search_tokens(cipher, &document.from_address),
+                    search_tokens(cipher, &document.subject),
+                    search_tokens(cipher, &document.body),
+                    search_tokens(cipher, &document.attachments.join(" "))
+                ],
+            )
+            .map_err(storage_error)?;
+        transaction
+            .execute("DELETE FROM search_documents WHERE id = ?1", params![id])
+            .map_err(storage_error)?;
+    }
+    Ok(())
+}
+
+/// Drops the index of earlier versions, which kept the words readable. Its documents are
+/// removed too, so the emails are indexed again by the next sync.
+pub fn drop_plaintext_search_index(connection: &Connection) -> Result<(), Error> {
+    let exists: Option<String> = connection
+        .query_row(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'search_index'",
+            [],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(storage_error)?;
+    if exists.is_some() {
+        connection
+            .execute_batch("DROP TABLE search_index; DELETE FROM search_documents;")
+            .map_err(storage_error)?;
+    }
+    Ok(())
+}
+
+/// Lowercase words without diacritics, split at everything but letters and digits.
+pub fn search_words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|char: char| !char.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            word.nfd()
+                .filter(|char| !is_combining_mark(*char))
+                .collect::<String>()
+                .to_lowercase()
+        })
+}
+
+/// The words of the text as keyed hashes, in their order so phrases still match.
+fn search_tokens(cipher: &StorageCipher, text: &str) -> String {
+    search_words(text)
+        .map(|word| cipher.hash_id(&format!("search:{}", word))[..TOKEN_LENGTH].to_string())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process, sync::Arc};
+
+    use chrono::NaiveDate;
+
+    use super::SearchDocument;
+    use crate::storage::{encryption::StorageCipher, message_cache::MessageCache};
+
+    #[test]
+    fn indexes_hashed_words_only() {
+        let path = env::temp_dir().join(format!("search-index-{}.sqlite", process::id()));
+        let cipher = Arc::new(StorageCipher::from_server_key(b"test server key"));
+        let cache = MessageCache::open(&path, cipher).unwrap();
+        let account = "user@example.com";
+        cache
+            .index_document(
+                account,
+                &SearchDocument {
+                    mailbox_name: "INBOX".to_string(),
+                    uid: 7,
+                    from_address: "carol@example.com".to_string(),
+                    subject: "Quarterly réport".to_string(),
+                    send_date: NaiveDate::from_ymd_opt(2023, 5, 17)
+                        .unwrap()
+                        .and_hms_opt(10, 30, 0)
+                        .unwrap(),
+                    body: "The budget for the confidential merger is attached.".to_string(),
+                    attachments: vec!["numbers.xlsx".to_string()],
+                },
+            )
+            .unwrap();
+
+        let search = |query: &str| {
+            let (total, _) = cache.search(account, None, query, 0, 10).unwrap();
+            total
+        };
+        let phrase = |text: &str| format!("\"{}\"", cache.search_tokens(text));
+        assert_eq!(search(&phrase("CONFIDENTIAL merger")), 1);
+        assert_eq!(search(&phrase("merger confidential")), 0);
+        assert_eq!(search(&format!("subject : {}", phrase("report"))), 1);
+        assert_eq!(search(&format!("subject : {}", phrase("budget"))), 0);
+        assert_eq!(search(&phrase("numbers")), 1);
+
+        // Neither the index nor the encrypted documents contain the words
+        let database = fs::read(&path).unwrap();
+        for word in ["confidential", "merger", "budget", "Quarterly"] {
+            assert!(!database
+                .windows(word.len())
+                .any(|window| window == word.as_bytes()));
+        }
+
+        cache.remove_messages(account, "INBOX", &[7]).unwrap();
+        assert_eq!(search(&phrase("merger")), 0);
+        drop(cache);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,263 @@
+use std::io::Error;
+
+use chrono::NaiveDateTime;
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use super::message_cache::{storage_error, MessageCache};
+
+pub const TEMPLATES_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS templates (
+        id INTEGER PRIMARY KEY,
+        account TEXT NOT NULL,
+        template BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS templates_account ON templates (account);
+    CREATE TABLE IF NOT EXISTS template_attachments (
+        id INTEGER PRIMARY KEY,
+        template_id INTEGER NOT NULL,
+        account TEXT NOT NULL,
+        attachment BLOB NOT NULL,
+        data BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS template_attachments_template
+        ON template_attachments (account, template_id);
+";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageTemplate {
+    pub name: String,
+    /// Texts with `{{placeholder}}` variables
+    pub subject: String,
+    pub text_body: String,
+    /// Sent as the alternative of the text body when set
+    pub html_body: Option<String>,
+    pub updated_at: NaiveDateTime,
+}
+
+/// File sent with every email written from the template.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TemplateAttachment {
+    pub file_name: String,
+    pub content_type: String,
+    pub size: usize,
+}
+
+/// Attachment with its content.
+pub type TemplateAttachmentFile = (TemplateAttachment, Vec<u8>);
+
+impl MessageTemplate {
+    pub fn texts(&self) -> Vec<&str> {
+        let mut texts = vec![self.subject.as_str(), self.text_body.as_str()];
+        if let Some(html_body) = self.html_body.as_deref() {
+            texts.push(html_body);
+        }
+        texts
+    }
+}
+
+impl MessageCache {
+    pub fn templates(&self, account: &str) -> Result<Vec<(i64, MessageTemplate)>, Error> {
+        let account = self.cipher.hash_id(account);
+        let rows = {
+            let database = self.database();
+            let mut statement = database
+                .prepare("SELECT id, template FROM templates WHERE account = ?1")
+                .map_err(storage_error)?;
+            let rows = statement
+                .query_map(params![account], |row| Ok((row.get(0)?, row.get(1)?)))
+                .map_err(storage_error)?
+                .collect::<Result<Vec<(i64, Vec<u8>)>, rusqlite::Error>>()
+                .map_err(storage_error)?;
+            rows
+        };
+
+        rows.into_iter()
+            .map(|(id, template)| {
+                Ok((
+                    id,
+                    serde_json::from_slice(&self.cipher.decrypt(&template)?)?,
+                ))
+            })
+            .collect()
+    }
+
+    pub fn template(&self, account: &str, id: i64) -> Result<Option<MessageTemplate>, Error> {
+        let account = self.cipher.hash_id(account);
+        let template: Option<Vec<u8>> = self
+            .database()
+            .query_row(
+                "SELECT template FROM templates WHERE id = ?1 AND account = ?2",
+                params![id, account],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(storage_error)?;
+        match template {
+            Some(template) => Ok(Some(serde_json::from_slice(
+                &self.cipher.decrypt(&template)?,
+            )?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn insert_template(&self, account: &str, template: &MessageTemplate) -> Result<i64, Error> {
+        let account = self.cipher.hash_id(account);
+        let encrypted_template = self.cipher.encrypt(&serde_json::to_vec(template)?)?;
+        let database = self.database();
+        database
+            .execute(
+                "INSERT INTO templates (account, template) VALUES (?1, ?2)",
+                params![account, encrypted_template],
+            )
+            .map_err(storage_error)?;
+        Ok(database.last_insert_rowid())
+    }
+
+    /// Replaces the template, returns false when the account has no template with the id.
+    pub fn update_template(
+        &self,
+        account: &str,
+        id: i64,
+        template: &MessageTemplate,
+    ) -> Result<bool, Error> {
+        let account = self.cipher.hash_id(account);
+        let encrypted_template = self.cipher.encrypt(&serde_json::to_vec(template)?)?;
+        self.database()
+            .execute(
+                "UPDATE templates SET template = ?1 WHERE id = ?2 AND account = ?3",
+                params![encrypted_template, id, account],
+            )
+            .map(|updated| updated > 0)
+            .map_err(storage_error)
+    }
+
+    /// Removes the template with its attachments.
+    pub fn delete_template(&self, account: &str, id: i64) -> Result<bool, Error> {
+        let account = self.cipher.hash_id(account);
+        let mut database = self.database();
+        let transaction = database.transaction().map_err(storage_error)?;
+        transaction
+            .execute(
+                "DELETE FROM template_attachments WHERE template_id = ?1 AND account = ?2",
+                params![id, account],
+            )
+            .map_err(storage_error)?;
+        let deleted = transaction
+            .execute(
+                "DELETE FROM templates WHERE id = ?1 AND account = ?2",
+                params![id, account],
+            )
+            .map_err(storage_error)?;
+        transaction.commit().map_err(storage_error)?;
+        Ok(deleted > 0)
+    }
+
+    /// Attachments of the template without their content, in the order they were added.
+    pub fn template_attachments(
+        &self,
+        account: &str,
+        template_id: i64,
+    ) -> Result<Vec<(i64, TemplateAttachment)>, Error> {
+        let account = self.cipher.hash_id(account);
+        let rows = {
+            let database = self.database();
+            let mut statement = database
+                .prepare(
+                    "SELECT id, attachment FROM template_attachments \
+                     WHERE account = ?1 AND template_id = ?2 ORDER BY id",
+                )
+                .map_err(storage_error)?;
+            let rows = statement
+                .query_map(params![account, template_id], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })
+                .map_err(storage_error)?
+                .collect::<Result<Vec<(i64, Vec<u8>)>, rusqlite::Error>>()
+                .map_err(storage_error)?;
+            rows
+        };
+
+        rows.into_iter()
+            .map(|(id, attachment)| {
+                Ok((
+                    id,
+                    serde_json::from_slice(&self.cipher.decrypt(&attachment)?)?,
+                ))
+            })
+            .collect()
+    }
+
+    /// Attachments of the template with their content, for sending.
+    pub fn template_attachment_files(
+        &self,
+        account: &str,
+        template_id: i64,
+    ) -> Result<Vec<TemplateAttachmentFile>, Error> {
+        let account = self.cipher.hash_id(account);
+        let rows = {
+            let database = self.database();
+            let mut statement = database
+                .prepare(
+                    "SELECT attachment, data FROM template_attachments \
+                     WHERE account = ?1 AND template_id = ?2 ORDER BY id",
+                )
+                .map_err(storage_error)?;
+            let rows = statement
+                .query_map(params![account, template_id], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })
+                .map_err(storage_error)?
+                .collect::<Result<Vec<(Vec<u8>, Vec<u8>)>, rusqlite::Error>>()
+                .map_err(storage_error)?;
+            rows
+        };
+
+        rows.into_iter()
+            .map(|(attachment, data)| {
+                Ok((
+                    serde_json::from_slice(&self.cipher.decrypt(&attachment)?)?,
+                    self.cipher.decrypt(&data)?,
+                ))
+            })
+            .collect()
+    }
+
+    pub fn insert_template_attachment(
+        &self,
+        account: &str,
+        template_id: i64,
+        attachment: &TemplateAttachment,
+        data: &[u8],
+    ) -> Result<i64, Error> {
+        let account = self.cipher.hash_id(account);
+        let encrypted_attachment = self.cipher.encrypt(&serde_json::to_vec(attachment)?)?;
+        let encrypted_data = self.cipher.encrypt(data)?;
+        let database = self.database();
+        database
+            .execute(
+                "INSERT INTO template_attachments (template_id, account, attachment, data) \
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![template_id, account, encrypted_attachment, encrypted_data],
+            )
+            .map_err(storage_error)?;
+        Ok(database.last_insert_rowid())
+    }
+
+    pub fn delete_template_attachment(
+        &self,
+        account: &str,
+        template_id: i64,
+        id: i64,
+    ) -> Result<bool, Error> {
+        let account = self.cipher.hash_id(account);
+        self.database()
+            .execute(
+                "DELETE FROM template_attachments \
+                 WHERE id = ?1 AND template_id = ?2 AND account = ?3",
+                params![id, template_id, account],
+            )
+            .map(|deleted| deleted > 0)
+            .map_err(storage_error)
+    }
+}
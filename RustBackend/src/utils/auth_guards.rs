@@ -1,7 +1,4 @@
-use std::{
-    future::{ready, Ready},
-    io::ErrorKind,
-};
+use std::future::{ready, Ready};
 
 use actix_web::{
     dev::{self, Service, ServiceRequest, ServiceResponse, Transform},
@@ -57,15 +54,10 @@ where
         if req.cookie(AUTH_COOKIE_NAME).is_some() {
             println!("Got cookie");
             let fut = self.service.call(req);
-            Box::pin(async move { fut.await })
+            Box::pin(fut)
         } else {
             println!("Aint got cookie");
-            Box::pin(async move {
-                Err(Error::from(std::io::Error::new(
-                    ErrorKind::Other,
-                    "Unauthenticated",
-                )))
-            })
+            Box::pin(async move { Err(Error::from(std::io::Error::other("Unauthenticated"))) })
         }
     }
 }
@@ -1,3 +1,14 @@
 pub mod auth_guards;
+pub mod utils_archive;
+pub mod utils_csv;
+pub mod utils_icalendar;
+pub mod utils_imap;
+pub mod utils_multipart;
+pub mod utils_preview;
 pub mod utils_session;
+pub mod utils_template;
+pub mod utils_threading;
 pub mod utils_transports;
+pub mod utils_vcard;
+pub mod utils_xml;
+pub mod utils_zip;
@@ -0,0 +1,276 @@
+use std::io::{BufRead, Error};
+
+use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeZone, Utc};
+
+// Maildir info flags and the IMAP flags they stand for, in the required alphabetical order
+const MAILDIR_FLAGS: [(char, &str); 6] = [
+    ('D', "\\Draft"),
+    ('F', "\\Flagged"),
+    ('P', "$Forwarded"),
+    ('R', "\\Answered"),
+    ('S', "\\Seen"),
+    ('T', "\\Deleted"),
+];
+// Letters of the X-Status header written by mutt and Thunderbird
+const X_STATUS_FLAGS: [(char, &str); 4] = [
+    ('A', "\\Answered"),
+    ('F', "\\Flagged"),
+    ('T', "\\Draft"),
+    ('D', "\\Deleted"),
+];
+
+pub struct ArchivedMessage {
+    /// Message with LF line endings, as both formats store it
+    pub message: Vec<u8>,
+    pub flags: Vec<String>,
+    /// Arrival date from the format itself, used when the message has no Date header
+    pub received: Option<DateTime<FixedOffset>>,
+}
+
+/// Formats the message as one entry of an mboxrd file. Flags are kept in Status and X-Status
+/// headers and every line starting with `From ` behind any number of `>` gets one more.
+pub fn to_mboxrd_entry(
+    message: &[u8],
+    flags: &[String],
+    internal_date: DateTime<FixedOffset>,
+) -> Vec<u8> {
+    let message = to_lf_line_endings(message);
+    let mut output = format!(
+        "From MAILER-DAEMON {}\n",
+        internal_date
+            .with_timezone(&Utc)
+            .format("%a %b %e %H:%M:%S %Y")
+    )
+    .into_bytes();
+
+    let mut in_header = true;
+    let mut skipping_header = false;
+    for line in message.split_inclusive(|byte| *byte == b'\n') {
+        if in_header {
+            if line == b"\n" {
+                in_header = false;
+                output.extend_from_slice(&status_headers(flags));
+            } else if line[0] == b' ' || line[0] == b'\t' {
+                if skipping_header {
+                    continue;
+                }
+            } else {
+                // Old status headers would contradict the current flags
+                skipping_header = starts_with_ignore_case(line, b"Status:")
+                    || starts_with_ignore_case(line, b"X-Status:");
+                if skipping_header {
+                    continue;
+                }
+            }
+        }
+
+        let quotes = line.iter().take_while(|byte| **byte == b'>').count();
+        if line[quotes..].starts_with(b"From ") {
+            output.push(b'>');
+        }
+        output.extend_from_slice(line);
+    }
+    if in_header {
+        output.extend_from_slice(b"\n");
+        output.extend_from_slice(&status_headers(flags));
+    }
+
+    if !output.ends_with(b"\n") {
+        output.push(b'\n');
+    }
+    output.push(b'\n');
+    output
+}
+
+fn status_headers(flags: &[String]) -> Vec<u8> {
+    let has_flag = |name: &str| flags.iter().any(|flag| flag.eq_ignore_ascii_case(name));
+    let mut headers = String::new();
+    if has_flag("\\Seen") {
+        headers.push_str("Status: RO\n");
+    } else {
+        headers.push_str("Status: O\n");
+    }
+    let x_status: String = X_STATUS_FLAGS
+        .iter()
+        .filter(|(_, flag)| has_flag(flag))
+        .map(|(letter, _)| *letter)
+        .collect();
+    if !x_status.is_empty() {
+        headers.push_str(&format!("X-Status: {}\n", x_status));
+    }
+    headers.into_bytes()
+}
+
+/// Reads messages of an mbox file one at a time, so big files are never loaded whole. Files
+/// are read as mboxrd, mboxo only differs in lines which originally started with `>From `.
+pub struct MboxReader<R: BufRead> {
+    reader: R,
+    next_from_line: Option<Vec<u8>>,
+}
+
+impl<R: BufRead> MboxReader<R> {
+    pub fn new(reader: R) -> MboxReader<R> {
+        MboxReader {
+            reader,
+            next_from_line: None,
+        }
+    }
+
+    pub fn next_message(&mut self) -> Result<Option<ArchivedMessage>, Error> {
+        let from_line = match self.next_from_line.take() {
+            Some(line) => line,
+            None => loop {
+                let mut line = vec![];
+                if self.reader.read_until(b'\n', &mut line)? == 0 {
+                    return Ok(None);
+                }
+                if line.starts_with(b"From ") {
+                    break line;
+                }
+            },
+        };
+
+        let mut lines: Vec<Vec<u8>> = vec![];
+        loop {
+            let mut line = vec![];
+            if self.reader.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+            let line = to_lf_line_endings(&line);
+            // A new message starts with a From line after an empty line
+            if line.starts_with(b"From ") && lines.last().is_none_or(|last| last == b"\n") {
+                self.next_from_line = Some(line);
+                break;
+            }
+            lines.push(line);
+        }
+        if lines.last().is_some_and(|last| last == b"\n") {
+            lines.pop();
+        }
+
+        let mut message = ArchivedMessage {
+            message: vec![],
+            flags: vec![],
+            received: parse_from_line_date(&from_line),
+        };
+        let mut in_header = true;
+        let mut skipping_header = false;
+        for mut line in lines.into_iter() {
+            if in_header {
+                if line == b"\n" {
+                    in_header = false;
+                } else if line[0] == b' ' || line[0] == b'\t' {
+                    if skipping_header {
+                        continue;
+                    }
+                } else if starts_with_ignore_case(&line, b"Status:") {
+                    if line[7..].contains(&b'R') {
+                        message.flags.push("\\Seen".to_string());
+                    }
+                    skipping_header = true;
+                    continue;
+                } else if starts_with_ignore_case(&line, b"X-Status:") {
+                    for (letter, flag) in X_STATUS_FLAGS.iter() {
+                        if line[9..].contains(&(*letter as u8)) {
+                            message.flags.push(flag.to_string());
+                        }
+                    }
+                    skipping_header = true;
+                    continue;
+                } else {
+                    skipping_header = false;
+                }
+            }
+
+            let quotes = line.iter().take_while(|byte| **byte == b'>').count();
+            if quotes > 0 && line[quotes..].starts_with(b"From ") {
+                line.remove(0);
+            }
+            message.message.extend_from_slice(&line);
+        }
+        Ok(Some(message))
+    }
+}
+
+/// `From sender Mon Jan  1 10:00:00 2024`, the date is always the last five words.
+fn parse_from_line_date(line: &[u8]) -> Option<DateTime<FixedOffset>> {
+    let line = String::from_utf8_lossy(line);
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let date = words.get(words.len().checked_sub(5)?..)?.join(" ");
+    NaiveDateTime::parse_from_str(&date, "%a %b %e %H:%M:%S %Y")
+        .ok()
+        .map(|date| FixedOffset::east_opt(0).unwrap().from_utc_datetime(&date))
+}
+
+/// File name of a message in a Maildir `cur` directory, `<time>.<unique>.<host>:2,<flags>`.
+pub fn maildir_file_name(
+    internal_date: DateTime<FixedOffset>,
+    uid: u32,
+    uid_validity: u32,
+    flags: &[String],
+) -> String {
+    let info: String = MAILDIR_FLAGS
+        .iter()
+        .filter(|(_, flag)| flags.iter().any(|name| name.eq_ignore_ascii_case(flag)))
+        .map(|(letter, _)| *letter)
+        .collect();
+    format!(
+        "{}.U{}V{}.rust-email-client:2,{}",
+        internal_date.timestamp(),
+        uid,
+        uid_validity,
+        info
+    )
+}
+
+/// Parses a path inside a Maildir tree. Returns `None` for anything which is not a message in
+/// `cur` or `new`, messages in `new` were not seen by any client yet.
+pub fn parse_maildir_path(path: &str) -> Option<ArchivedMessage> {
+    let mut components = path.rsplit('/');
+    let file_name = components.next().filter(|name| !name.is_empty())?;
+    let directory = components.next()?;
+    if directory != "cur" && directory != "new" {
+        return None;
+    }
+
+    let mut message = ArchivedMessage {
+        message: vec![],
+        flags: vec![],
+        received: file_name
+            .split('.')
+            .next()
+            .and_then(|time| time.parse::<i64>().ok())
+            .and_then(|time| {
+                FixedOffset::east_opt(0)
+                    .unwrap()
+                    .timestamp_opt(time, 0)
+                    .single()
+            }),
+    };
+    // Windows tools use `;` or `!` instead of `:`, which is not allowed in file names there
+    if let Some(position) = file_name.rfind([':', ';', '!']) {
+        if let Some(info) = file_name[position + 1..].strip_prefix("2,") {
+            for (letter, flag) in MAILDIR_FLAGS.iter() {
+                if info.contains(*letter) {
+                    message.flags.push(flag.to_string());
+                }
+            }
+        }
+    }
+    Some(message)
+}
+
+pub fn to_lf_line_endings(message: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(message.len());
+    for (index, byte) in message.iter().enumerate() {
+        if *byte == b'\r' && message.get(index + 1) == Some(&b'\n') {
+            continue;
+        }
+        output.push(*byte);
+    }
+    output
+}
+
+fn starts_with_ignore_case(line: &[u8], prefix: &[u8]) -> bool {
+    line.len() >= prefix.len() && line[..prefix.len()].eq_ignore_ascii_case(prefix)
+}
@@ -0,0 +1,84 @@
+use std::io::{Error, ErrorKind};
+
+/// Reads CSV records (RFC 4180). Fields may be quoted, with `""` for a quote inside, and
+/// span lines. The delimiter is a comma, or a semicolon when the first line has semicolons
+/// but no commas, as spreadsheets write with some locales. Blank lines are skipped.
+pub fn parse_csv(text: &str) -> Result<Vec<Vec<String>>, Error> {
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    let delimiter = detect_delimiter(text);
+
+    let mut records: Vec<Vec<String>> = vec![];
+    let mut record: Vec<String> = vec![];
+    let mut field = String::new();
+    let mut in_quotes = false;
+    // A quoted field ends at its closing quote, text after it is an error
+    let mut field_closed = false;
+    let mut line = 1;
+    let mut chars = text.chars().peekable();
+    while let Some(char) = chars.next() {
+        if in_quotes {
+            match char {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => {
+                    in_quotes = false;
+                    field_closed = true;
+                }
+                '\n' => {
+                    line += 1;
+                    field.push(char);
+                }
+                _ => field.push(char),
+            }
+            continue;
+        }
+        match char {
+            '"' if field.is_empty() && !field_closed => in_quotes = true,
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' | '\r' => {
+                record.push(std::mem::take(&mut field));
+                field_closed = false;
+                if record.len() > 1 || !record[0].trim().is_empty() {
+                    records.push(std::mem::take(&mut record));
+                } else {
+                    record.clear();
+                }
+                line += 1;
+            }
+            _ if char == delimiter => {
+                record.push(std::mem::take(&mut field));
+                field_closed = false;
+            }
+            _ if field_closed && !char.is_whitespace() => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Unexpected text after a quoted field on line {}", line),
+                ));
+            }
+            _ if field_closed => {}
+            _ => field.push(char),
+        }
+    }
+    if in_quotes {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Unclosed quote on line {}", line),
+        ));
+    }
+    record.push(field);
+    if record.len() > 1 || !record[0].trim().is_empty() {
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn detect_delimiter(text: &str) -> char {
+    let first_line = text.lines().next().unwrap_or_default();
+    if first_line.contains(';') && !first_line.contains(',') {
+        ';'
+    } else {
+        ','
+    }
+}
@@ -0,0 +1,541 @@
+use chrono::{
+    DateTime, Datelike, Duration, FixedOffset, LocalResult, NaiveDate, NaiveDateTime, Offset,
+    TimeZone, Weekday,
+};
+use chrono_tz::Tz;
+
+#[derive(Clone)]
+pub struct CalendarProperty {
+    /// Uppercase name, e.g. `DTSTART`
+    pub name: String,
+    /// Uppercase parameter names with unquoted values
+    pub params: Vec<(String, String)>,
+    pub value: String,
+}
+
+impl CalendarProperty {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Content line folded after 75 octets, without the line break at the end.
+    pub fn to_line(&self) -> String {
+        let mut line = self.name.clone();
+        for (param, value) in self.params.iter() {
+            if value.contains([':', ';', ',']) {
+                line.push_str(&format!(";{}=\"{}\"", param, value.replace('"', "")));
+            } else {
+                line.push_str(&format!(";{}={}", param, value));
+            }
+        }
+        line.push(':');
+        line.push_str(&self.value);
+        fold_line(&line)
+    }
+}
+
+/// `BEGIN:NAME` ... `END:NAME` with its properties and nested components.
+#[derive(Clone)]
+pub struct CalendarComponent {
+    pub name: String,
+    pub properties: Vec<CalendarProperty>,
+    pub components: Vec<CalendarComponent>,
+}
+
+impl CalendarComponent {
+    pub fn property(&self, name: &str) -> Option<&CalendarProperty> {
+        self.properties
+            .iter()
+            .find(|property| property.name == name)
+    }
+
+    pub fn properties<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a CalendarProperty> {
+        self.properties
+            .iter()
+            .filter(move |property| property.name == name)
+    }
+
+    /// Value of a TEXT property with escapes removed.
+    pub fn text(&self, name: &str) -> Option<String> {
+        self.property(name)
+            .map(|property| unescape_text(&property.value))
+    }
+
+    pub fn components<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a CalendarComponent> {
+        self.components
+            .iter()
+            .filter(move |component| component.name == name)
+    }
+}
+
+/// Parses the first VCALENDAR of an iCalendar text (RFC 5545). Unknown properties are kept,
+/// lines which are not content lines are skipped.
+pub fn parse_calendar(text: &str) -> Option<CalendarComponent> {
+    parse_components(text)
+        .into_iter()
+        .find(|component| component.name == "VCALENDAR")
+}
+
+/// Top level components of a text made of content lines, which vCard (RFC 6350) shares
+/// with iCalendar. Components left open at the end are dropped.
+pub fn parse_components(text: &str) -> Vec<CalendarComponent> {
+    let mut components = vec![];
+    let mut stack: Vec<CalendarComponent> = vec![];
+    for line in unfold_lines(text).iter() {
+        let property = match parse_content_line(line) {
+            Some(property) => property,
+            None => continue,
+        };
+        match property.name.as_str() {
+            "BEGIN" => stack.push(CalendarComponent {
+                name: property.value.trim().to_uppercase(),
+                properties: vec![],
+                components: vec![],
+            }),
+            "END" => {
+                let component = match stack.pop() {
+                    Some(component) => component,
+                    None => continue,
+                };
+                match stack.last_mut() {
+                    Some(parent) => parent.components.push(component),
+                    None => components.push(component),
+                }
+            }
+            _ => {
+                if let Some(component) = stack.last_mut() {
+                    component.properties.push(property);
+                }
+            }
+        }
+    }
+    components
+}
+
+/// Joins lines folded with a leading space or tab.
+fn unfold_lines(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    for line in text.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(continuation), Some(last)) => last.push_str(continuation),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+/// `NAME;PARAM=value;PARAM="quoted:value":value`
+fn parse_content_line(line: &str) -> Option<CalendarProperty> {
+    let mut in_quotes = false;
+    let mut value_start = None;
+    let mut separators = vec![];
+    for (index, char) in line.char_indices() {
+        match char {
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => separators.push(index),
+            ':' if !in_quotes => {
+                value_start = Some(index);
+                break;
+            }
+            _ => {}
+        }
+    }
+    let value_start = value_start?;
+
+    let mut segments = vec![];
+    let mut start = 0;
+    for separator in separators.into_iter() {
+        segments.push(&line[start..separator]);
+        start = separator + 1;
+    }
+    segments.push(&line[start..value_start]);
+
+    let name = segments[0].trim().to_uppercase();
+    if name.is_empty() {
+        return None;
+    }
+    let params = segments[1..]
+        .iter()
+        .filter_map(|segment| segment.split_once('='))
+        .map(|(param, value)| {
+            (
+                param.trim().to_uppercase(),
+                value.trim().trim_matches('"').to_string(),
+            )
+        })
+        .collect();
+    Some(CalendarProperty {
+        name,
+        params,
+        value: line[value_start + 1..].to_string(),
+    })
+}
+
+/// Content lines of the component and its nested components, each ending with CRLF.
+pub fn write_component(component: &CalendarComponent) -> String {
+    let mut output = format!("BEGIN:{}\r\n", component.name);
+    for property in component.properties.iter() {
+        output.push_str(&property.to_line());
+        output.push_str("\r\n");
+    }
+    for nested in component.components.iter() {
+        output.push_str(&write_component(nested));
+    }
+    output.push_str(&format!("END:{}\r\n", component.name));
+    output
+}
+
+pub fn unescape_text(value: &str) -> String {
+    let mut output = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(char) = chars.next() {
+        if char != '\\' {
+            output.push(char);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => output.push('\n'),
+            Some(escaped) => output.push(escaped),
+            None => output.push('\\'),
+        }
+    }
+    output
+}
+
+pub fn escape_text(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// A DATE or DATE-TIME value resolved to a fixed offset, with whether it is a whole day.
+/// `TZID` is looked up in the IANA database first and then in the VTIMEZONE components of
+/// the calendar, as Outlook sends Windows zone names. Floating times are taken as UTC.
+/// A time skipped by a forward transition uses the offset in force before it (RFC 5545 3.3.5),
+/// a repeated time the first of its two offsets.
+pub fn resolve_date_time(
+    property: &CalendarProperty,
+    calendar: &CalendarComponent,
+) -> Option<(DateTime<FixedOffset>, bool)> {
+    let value = property.value.trim();
+    let utc = FixedOffset::east_opt(0).unwrap();
+    if property.param("VALUE") == Some("DATE") || value.len() == 8 {
+        let date = NaiveDate::parse_from_str(value, "%Y%m%d").ok()?;
+        return Some((utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0)?), true));
+    }
+
+    if let Some(value) = value.strip_suffix('Z') {
+        let date_time = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok()?;
+        return Some((utc.from_utc_datetime(&date_time), false));
+    }
+    let local = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok()?;
+    let offset = match property.param("TZID") {
+        Some(tz_id) => zone_offset(tz_id, local, calendar).unwrap_or(utc),
+        None => utc,
+    };
+    Some((offset.from_local_datetime(&local).single()?, false))
+}
+
+fn zone_offset(
+    tz_id: &str,
+    local: NaiveDateTime,
+    calendar: &CalendarComponent,
+) -> Option<FixedOffset> {
+    // Some producers prefix global ids with a slash
+    if let Ok(zone) = tz_id.trim_start_matches('/').parse::<Tz>() {
+        return match zone.offset_from_local_datetime(&local) {
+            // Transitions are at least a day apart, so a day earlier the old offset applies
+            LocalResult::None => Some(
+                zone.offset_from_utc_datetime(&(local - Duration::days(1)))
+                    .fix(),
+            ),
+            result => result.earliest().map(|offset| offset.fix()),
+        };
+    }
+
+    let zone = calendar.components("VTIMEZONE").find(|zone| {
+        zone.property("TZID")
+            .is_some_and(|property| property.value.trim() == tz_id)
+    })?;
+    // The observance with the latest onset before the time is in effect
+    let mut latest: Option<(NaiveDateTime, FixedOffset, Option<FixedOffset>)> = None;
+    for observance in zone.components.iter() {
+        let offset = match observance
+            .property("TZOFFSETTO")
+            .and_then(|property| parse_utc_offset(&property.value))
+        {
+            Some(offset) => offset,
+            None => continue,
+        };
+        let offset_from = observance
+            .property("TZOFFSETFROM")
+            .and_then(|property| parse_utc_offset(&property.value));
+        for year in [local.year() - 1, local.year()] {
+            let onset = match observance_onset(observance, year) {
+                Some(onset) if onset <= local => onset,
+                _ => continue,
+            };
+            if latest.is_none_or(|(latest_onset, _, _)| onset > latest_onset) {
+                latest = Some((onset, offset, offset_from));
+            }
+        }
+    }
+    // The onset is a local time of the previous offset, times in the gap after it keep that
+    let (onset, offset, offset_from) = latest?;
+    match offset_from {
+        Some(offset_from)
+            if local
+                < onset
+                    + Duration::seconds(
+                        (offset.local_minus_utc() - offset_from.local_minus_utc()) as i64,
+                    ) =>
+        {
+            Some(offset_from)
+        }
+        _ => Some(offset),
+    }
+}
+
+/// Start of a STANDARD or DAYLIGHT observance in the given year. Only yearly rules with a
+/// month and a weekday like `-1SU` are understood, which is what zones are described with.
+fn observance_onset(observance: &CalendarComponent, year: i32) -> Option<NaiveDateTime> {
+    let start = NaiveDateTime::parse_from_str(
+        observance.property("DTSTART")?.value.trim(),
+        "%Y%m%dT%H%M%S",
+    )
+    .ok()?;
+    let rule = match observance.property("RRULE") {
+        Some(rule) => rule,
+        None => return (start.year() == year).then_some(start),
+    };
+    if start.year() > year {
+        return None;
+    }
+
+    let part = |name: &str| {
+        rule.value.split(';').find_map(|part| {
+            part.split_once('=')
+                .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.trim().to_uppercase())
+        })
+    };
+    let month: u32 = part("BYMONTH")?.parse().ok()?;
+    let by_day = part("BYDAY")?;
+    // The weekday is the last two characters, split on a character boundary
+    let (count, weekday) = by_day.split_at(by_day.char_indices().rev().nth(1)?.0);
+    let count: i32 = if count.is_empty() {
+        1
+    } else {
+        count.parse().ok()?
+    };
+    // A month has at most five of every weekday
+    if !(1..=5).contains(&count.unsigned_abs()) {
+        return None;
+    }
+    let weekday = match weekday {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        _ => return None,
+    };
+
+    let date = nth_weekday_of_month(year, month, weekday, count)?;
+    Some(date.and_time(start.time()))
+}
+
+fn nth_weekday_of_month(year: i32, month: u32, weekday: Weekday, count: i32) -> Option<NaiveDate> {
+    if count > 0 {
+        let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+        let shift =
+            (7 + weekday.num_days_from_monday() - first.weekday().num_days_from_monday()) % 7;
+        let date =
+            first.checked_add_signed(Duration::days(shift as i64 + 7 * (count as i64 - 1)))?;
+        (date.month() == month).then_some(date)
+    } else {
+        let next_month = match month {
+            12 => NaiveDate::from_ymd_opt(year + 1, 1, 1)?,
+            _ => NaiveDate::from_ymd_opt(year, month + 1, 1)?,
+        };
+        let last = next_month.pred_opt()?;
+        let shift =
+            (7 + last.weekday().num_days_from_monday() - weekday.num_days_from_monday()) % 7;
+        let date =
+            last.checked_sub_signed(Duration::days(shift as i64 + 7 * (-count as i64 - 1)))?;
+        (date.month() == month).then_some(date)
+    }
+}
+
+/// `+0100`, `-0530` or `+013000`
+fn parse_utc_offset(value: &str) -> Option<FixedOffset> {
+    let value = value.trim();
+    let sign = match value.get(..1)? {
+        "+" => 1,
+        "-" => -1,
+        _ => return None,
+    };
+    let digits = &value[1..];
+    let hours: i32 = digits.get(0..2)?.parse().ok()?;
+    let minutes: i32 = digits.get(2..4)?.parse().ok()?;
+    let seconds: i32 = digits
+        .get(4..6)
+        .and_then(|seconds| seconds.parse().ok())
+        .unwrap_or(0);
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60 + seconds))
+}
+
+/// `P1D`, `PT1H30M`, `-PT15M` or `P2W`
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (sign, value) = match value.strip_prefix('-') {
+        Some(value) => (-1, value),
+        None => (1, value.trim_start_matches('+')),
+    };
+    let value = value.strip_prefix('P')?;
+
+    let mut seconds: i64 = 0;
+    let mut number = String::new();
+    let mut in_time = false;
+    for char in value.chars() {
+        match char {
+            'T' => in_time = true,
+            '0'..='9' => number.push(char),
+            unit => {
+                let amount: i64 = number.parse().ok()?;
+                number.clear();
+                let unit_seconds = match (unit, in_time) {
+                    ('W', false) => 7 * 24 * 3600,
+                    ('D', false) => 24 * 3600,
+                    ('H', true) => 3600,
+                    ('M', true) => 60,
+                    ('S', true) => 1,
+                    _ => return None,
+                };
+                seconds = seconds.checked_add(amount.checked_mul(unit_seconds)?)?;
+            }
+        }
+    }
+    // Duration::seconds panics beyond the range of a Duration
+    if !number.is_empty() || seconds > Duration::max_value().num_seconds() {
+        return None;
+    }
+    Some(Duration::seconds(sign * seconds))
+}
+
+/// DATE-TIME in UTC as used in DTSTAMP.
+pub fn format_utc_date_time(date_time: NaiveDateTime) -> String {
+    date_time.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+fn fold_line(line: &str) -> String {
+    let mut output = String::with_capacity(line.len() + line.len() / 74 * 3);
+    let mut length = 0;
+    for char in line.chars() {
+        // Continuation lines start with a space, which counts against their 75 octets
+        if length + char.len_utf8() > 75 {
+            output.push_str("\r\n ");
+            length = 1;
+        }
+        output.push(char);
+        length += char.len_utf8();
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_calendar, resolve_date_time, CalendarComponent};
+
+    const CALENDAR: &str = "BEGIN:VCALENDAR\r
+BEGIN:VTIMEZONE\r
+TZID:Eastern Standard Time\r
+BEGIN:STANDARD\r
+DTSTART:16010101T020000\r
+TZOFFSETFROM:-0400\r
+TZOFFSETTO:-0500\r
+RRULE:FREQ=YEARLY;BYDAY=1SU;BYMONTH=11\r
+END:STANDARD\r
+BEGIN:DAYLIGHT\r
+DTSTART:16010101T020000\r
+TZOFFSETFROM:-0500\r
+TZOFFSETTO:-0400\r
+RRULE:FREQ=YEARLY;BYDAY=2SU;BYMONTH=3\r
+END:DAYLIGHT\r
+END:VTIMEZONE\r
+END:VCALENDAR\r
+";
+
+    /// Resolves a DTSTART with the TZID to RFC 3339.
+    fn resolve(tz_id: &str, value: &str, calendar: &CalendarComponent) -> String {
+        let event = parse_calendar(&format!(
+            "BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nDTSTART;TZID={}:{}\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n",
+            tz_id, value
+        ))
+        .unwrap();
+        let property = event.components[0].property("DTSTART").unwrap();
+        let (date_time, all_day) = resolve_date_time(property, calendar).unwrap();
+        assert!(!all_day);
+        date_time.to_rfc3339()
+    }
+
+    #[test]
+    fn resolves_iana_zones() {
+        let calendar = parse_calendar(CALENDAR).unwrap();
+        assert_eq!(
+            resolve("Europe/Berlin", "20230615T120000", &calendar),
+            "2023-06-15T12:00:00+02:00"
+        );
+        assert_eq!(
+            resolve("/America/New_York", "20230115T120000", &calendar),
+            "2023-01-15T12:00:00-05:00"
+        );
+    }
+
+    #[test]
+    fn gap_uses_offset_before_transition() {
+        let calendar = parse_calendar(CALENDAR).unwrap();
+        // 02:30 does not exist on 2023-03-12 in New York, it is 03:30 EDT
+        assert_eq!(
+            resolve("America/New_York", "20230312T023000", &calendar),
+            "2023-03-12T02:30:00-05:00"
+        );
+        assert_eq!(
+            resolve("Eastern Standard Time", "20230312T023000", &calendar),
+            "2023-03-12T02:30:00-05:00"
+        );
+        assert_eq!(
+            resolve("Eastern Standard Time", "20230312T030000", &calendar),
+            "2023-03-12T03:00:00-04:00"
+        );
+    }
+
+    #[test]
+    fn overlap_uses_first_offset() {
+        let calendar = parse_calendar(CALENDAR).unwrap();
+        assert_eq!(
+            resolve("America/New_York", "20231105T013000", &calendar),
+            "2023-11-05T01:30:00-04:00"
+        );
+        assert_eq!(
+            resolve("Eastern Standard Time", "20231105T013000", &calendar),
+            "2023-11-05T01:30:00-04:00"
+        );
+    }
+
+    #[test]
+    fn unknown_zone_is_utc() {
+        let calendar = parse_calendar(CALENDAR).unwrap();
+        assert_eq!(
+            resolve("Nowhere/Atlantis", "20230615T120000", &calendar),
+            "2023-06-15T12:00:00+00:00"
+        );
+    }
+}
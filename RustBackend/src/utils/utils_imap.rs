@@ -0,0 +1,399 @@
+use std::{
+    cmp::min,
+    io::{BufRead, BufReader, Error, ErrorKind, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use chrono::{DateTime, FixedOffset};
+use native_tls::TlsStream;
+
+// The `imap` crate can not parse responses of extensions like SORT, THREAD or QRESYNC and it
+// does not allow pipelining, so commands that need those go through this thin connection instead.
+// It only deals with tags, literals and the final status, parsing is left to the caller
+// (usually `imap_proto::parse_response` on the returned untagged lines).
+pub struct ImapConnection {
+    stream: BufReader<TlsStream<TcpStream>>,
+    partial: Vec<u8>,
+    line_start: usize,
+    literal_remaining: usize,
+    tag: u32,
+    capabilities: Vec<String>,
+}
+
+/// Piece of a command sent with `command_parts`.
+#[derive(Clone)]
+pub enum CommandPart<'a> {
+    /// Sent as it is
+    Text(String),
+    /// Sent as a literal, so it may hold line breaks and 8-bit text
+    Literal(&'a [u8]),
+}
+
+pub struct ImapResponse {
+    pub untagged: Vec<Vec<u8>>,
+    /// Text of the tagged OK, including response codes like `[APPENDUID 38505 3955]`
+    pub status: String,
+}
+
+impl ImapConnection {
+    pub fn new(stream: TlsStream<TcpStream>) -> ImapConnection {
+        ImapConnection {
+            stream: BufReader::new(stream),
+            partial: vec![],
+            line_start: 0,
+            literal_remaining: 0,
+            tag: 0,
+            capabilities: vec![],
+        }
+    }
+
+    pub fn read_greeting(&mut self) -> Result<(), Error> {
+        let greeting = self.read_response_unit()?;
+        if greeting.starts_with(b"* OK") || greeting.starts_with(b"* PREAUTH") {
+            Ok(())
+        } else {
+            Err(Error::other(format!(
+                "Unexpected IMAP greeting: {}",
+                String::from_utf8_lossy(&greeting)
+            )))
+        }
+    }
+
+    pub fn login(&mut self, username: &str, password: &str) -> Result<(), Error> {
+        self.command(&format!(
+            "LOGIN {} {}",
+            quote_imap_string(username),
+            quote_imap_string(password)
+        ))?;
+        self.refresh_capabilities()
+    }
+
+    pub fn refresh_capabilities(&mut self) -> Result<(), Error> {
+        let response = self.command("CAPABILITY")?;
+        self.capabilities = response
+            .untagged
+            .iter()
+            .filter_map(|line| {
+                let line = String::from_utf8_lossy(line);
+                line.trim_end()
+                    .strip_prefix("* CAPABILITY ")
+                    .map(|caps| caps.to_string())
+            })
+            .flat_map(|caps| {
+                caps.split(' ')
+                    .map(|cap| cap.to_ascii_uppercase())
+                    .collect::<Vec<String>>()
+            })
+            .collect();
+        Ok(())
+    }
+
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities
+            .iter()
+            .any(|cap| cap.eq_ignore_ascii_case(capability))
+    }
+
+    /// Sends a tagged command without waiting for its completion, returns the used tag.
+    pub fn send_command(&mut self, command: &str) -> Result<String, Error> {
+        self.tag += 1;
+        let tag = format!("r{}", self.tag);
+        self.write_line(format!("{} {}", tag, command).as_bytes())?;
+        Ok(tag)
+    }
+
+    /// Reads responses until the completion of the command with given tag.
+    pub fn read_tagged_response(&mut self, tag: &str) -> Result<ImapResponse, Error> {
+        let tag_prefix = format!("{} ", tag);
+        let mut untagged = vec![];
+        loop {
+            let unit = self.read_response_unit()?;
+            if unit.starts_with(tag_prefix.as_bytes()) {
+                let status_line = String::from_utf8_lossy(&unit[tag_prefix.len()..])
+                    .trim_end()
+                    .to_string();
+                if status_line.to_ascii_uppercase().starts_with("OK") {
+                    return Ok(ImapResponse {
+                        untagged,
+                        status: status_line[2..].trim_start().to_string(),
+                    });
+                }
+                return Err(Error::other(format!(
+                    "IMAP command failed: {}",
+                    status_line
+                )));
+            }
+            if unit.starts_with(b"* BYE") {
+                return Err(Error::new(
+                    ErrorKind::ConnectionAborted,
+                    String::from_utf8_lossy(&unit).trim_end().to_string(),
+                ));
+            }
+            untagged.push(unit);
+        }
+    }
+
+    pub fn command(&mut self, command: &str) -> Result<ImapResponse, Error> {
+        let tag = self.send_command(command)?;
+        self.read_tagged_response(&tag)
+    }
+
+    /// Sends all commands at once and only then reads the responses, which saves a round trip
+    /// per command. Results are returned in the same order as the commands.
+    pub fn pipeline(
+        &mut self,
+        commands: &[String],
+    ) -> Result<Vec<Result<ImapResponse, Error>>, Error> {
+        let mut tags = vec![];
+        for command in commands.iter() {
+            self.tag += 1;
+            let tag = format!("r{}", self.tag);
+            self.stream
+                .get_mut()
+                .write_all(format!("{} {}\r\n", tag, command).as_bytes())?;
+            tags.push(tag);
+        }
+        self.stream.get_mut().flush()?;
+
+        let mut results = vec![];
+        for tag in tags.iter() {
+            match self.read_tagged_response(tag) {
+                Err(err) if err.kind() != ErrorKind::Other => return Err(err),
+                result => results.push(result),
+            }
+        }
+        Ok(results)
+    }
+
+    /// Starts IDLE and returns its tag together with anything the server sent before the
+    /// continuation request.
+    pub fn start_idle(&mut self) -> Result<(String, Vec<Vec<u8>>), Error> {
+        let tag = self.send_command("IDLE")?;
+        let mut untagged = vec![];
+        loop {
+            let unit = self.read_response_unit()?;
+            if unit.starts_with(b"+") {
+                return Ok((tag, untagged));
+            }
+            if unit.starts_with(format!("{} ", tag).as_bytes()) {
+                return Err(Error::other(format!(
+                    "IDLE refused: {}",
+                    String::from_utf8_lossy(&unit).trim_end()
+                )));
+            }
+            untagged.push(unit);
+        }
+    }
+
+    pub fn finish_idle(&mut self, tag: &str) -> Result<ImapResponse, Error> {
+        self.write_line(b"DONE")?;
+        self.read_tagged_response(tag)
+    }
+
+    /// Uploads a message to the mailbox. `arguments` are the optional flag list and internal
+    /// date, the message literal is appended after them.
+    pub fn append(
+        &mut self,
+        mailbox: &str,
+        arguments: &str,
+        message: &[u8],
+    ) -> Result<ImapResponse, Error> {
+        self.command_parts(&[
+            CommandPart::Text(format!("APPEND {}{} ", mailbox, arguments)),
+            CommandPart::Literal(message),
+        ])
+    }
+
+    /// Sends a command with literals, for values a quoted string can not carry.
+    pub fn command_parts(&mut self, parts: &[CommandPart]) -> Result<ImapResponse, Error> {
+        // LITERAL+ lets a literal follow right away, without waiting for the server
+        let non_synchronizing = self.has_capability("LITERAL+");
+        self.tag += 1;
+        let tag = format!("r{}", self.tag);
+
+        let mut untagged = vec![];
+        let mut line = format!("{} ", tag).into_bytes();
+        for part in parts.iter() {
+            match part {
+                CommandPart::Text(text) => line.extend_from_slice(text.as_bytes()),
+                CommandPart::Literal(data) => {
+                    line.extend_from_slice(
+                        format!(
+                            "{{{}{}}}",
+                            data.len(),
+                            if non_synchronizing { "+" } else { "" }
+                        )
+                        .as_bytes(),
+                    );
+                    self.write_line(&line)?;
+                    if !non_synchronizing {
+                        self.read_continuation(&tag, &mut untagged)?;
+                    }
+                    line = data.to_vec();
+                }
+            }
+        }
+
+        self.write_line(&line)?;
+        let mut response = self.read_tagged_response(&tag)?;
+        untagged.append(&mut response.untagged);
+        response.untagged = untagged;
+        Ok(response)
+    }
+
+    /// Waits for the server to ask for a literal, keeping untagged responses sent meanwhile.
+    fn read_continuation(&mut self, tag: &str, untagged: &mut Vec<Vec<u8>>) -> Result<(), Error> {
+        loop {
+            let unit = self.read_response_unit()?;
+            if unit.starts_with(b"+") {
+                return Ok(());
+            }
+            if unit.starts_with(format!("{} ", tag).as_bytes()) {
+                return Err(Error::other(format!(
+                    "IMAP command refused: {}",
+                    String::from_utf8_lossy(&unit).trim_end()
+                )));
+            }
+            untagged.push(unit);
+        }
+    }
+
+    pub fn logout(&mut self) -> Result<(), Error> {
+        self.command("LOGOUT").map(|_| ())
+    }
+
+    pub fn write_line(&mut self, line: &[u8]) -> Result<(), Error> {
+        let stream = self.stream.get_mut();
+        stream.write_all(line)?;
+        stream.write_all(b"\r\n")?;
+        stream.flush()
+    }
+
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.stream.get_ref().get_ref().set_read_timeout(timeout)
+    }
+
+    /// Reads a single response line including all of its literals. When reading times out,
+    /// already received data is kept and the next call continues where this one stopped.
+    pub fn read_response_unit(&mut self) -> Result<Vec<u8>, Error> {
+        loop {
+            if self.literal_remaining > 0 {
+                let buffer = self.stream.fill_buf()?;
+                if buffer.is_empty() {
+                    return Err(connection_closed());
+                }
+                let read = min(buffer.len(), self.literal_remaining);
+                self.partial.extend_from_slice(&buffer[..read]);
+                self.stream.consume(read);
+                self.literal_remaining -= read;
+                if self.literal_remaining == 0 {
+                    self.line_start = self.partial.len();
+                }
+                continue;
+            }
+
+            if self.stream.read_until(b'\n', &mut self.partial)? == 0 {
+                return Err(connection_closed());
+            }
+            if !self.partial.ends_with(b"\n") {
+                continue;
+            }
+
+            match literal_length(&self.partial[self.line_start..]) {
+                Some(length) => {
+                    self.literal_remaining = length;
+                    self.line_start = self.partial.len();
+                }
+                None => {
+                    self.line_start = 0;
+                    return Ok(std::mem::take(&mut self.partial));
+                }
+            }
+        }
+    }
+}
+
+fn connection_closed() -> Error {
+    Error::new(ErrorKind::ConnectionAborted, "IMAP connection closed")
+}
+
+fn literal_length(line: &[u8]) -> Option<usize> {
+    let line = line
+        .strip_suffix(b"\r\n")
+        .or_else(|| line.strip_suffix(b"\n"))?;
+    let line = line.strip_suffix(b"}")?;
+    let open = line.iter().rposition(|byte| *byte == b'{')?;
+    let digits = &line[open + 1..];
+    let digits = digits.strip_suffix(b"+").unwrap_or(digits);
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+pub fn quote_imap_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+pub fn is_timeout(error: &Error) -> bool {
+    error.kind() == ErrorKind::WouldBlock || error.kind() == ErrorKind::TimedOut
+}
+
+/// Parses the date-time format used by INTERNALDATE.
+pub fn parse_internal_date(date: &str) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_str(date, "%d-%b-%Y %H:%M:%S %z").ok()
+}
+
+/// Splits a UID set like `1:5,8` into inclusive ranges without expanding them, `*` is not
+/// supported.
+pub fn parse_uid_ranges(uid_set: &str) -> Result<Vec<(u32, u32)>, Error> {
+    let mut ranges = vec![];
+    for range in uid_set.split(',').filter(|range| !range.is_empty()) {
+        let (start, end) = match range.split_once(':') {
+            Some((start, end)) => (start, end),
+            None => (range, range),
+        };
+        match (start.trim().parse::<u32>(), end.trim().parse::<u32>()) {
+            (Ok(start), Ok(end)) => ranges.push((min(start, end), start.max(end))),
+            _ => return Err(Error::other(format!("Invalid UID set: {}", uid_set))),
+        }
+    }
+    Ok(ranges)
+}
+
+/// Expands a UID set like `1:5,8` into single UIDs, `*` is not supported.
+pub fn parse_uid_set(uid_set: &str, max_count: usize) -> Result<Vec<u32>, Error> {
+    let mut uids = vec![];
+    for (start, end) in parse_uid_ranges(uid_set)? {
+        if uids.len() + (end - start) as usize >= max_count {
+            return Err(Error::other(format!(
+                "UID set contains more than {} UIDs",
+                max_count
+            )));
+        }
+        uids.extend(start..=end);
+    }
+    uids.sort_unstable();
+    uids.dedup();
+    Ok(uids)
+}
+
+/// Builds the shortest UID set covering given sorted UIDs.
+pub fn format_uid_set(uids: &[u32]) -> String {
+    let mut ranges: Vec<(u32, u32)> = vec![];
+    for uid in uids.iter() {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == *uid => *end = *uid,
+            _ => ranges.push((*uid, *uid)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{}:{}", start, end)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
@@ -0,0 +1,58 @@
+use std::io::{Error, ErrorKind, Write};
+
+use actix_multipart::Field;
+use actix_web::web;
+use futures_util::StreamExt;
+
+/// Streams an uploaded file field to disk chunk by chunk, so big uploads are never held in
+/// memory as a whole.
+pub async fn write_field_to_file(field: &mut Field, file_path: String) -> Result<(), Error> {
+    let mut file_created;
+
+    // File::create is blocking operation, use threadpool
+    match web::block(|| std::fs::File::create(file_path)).await {
+        Ok(res) => match res {
+            Ok(file) => file_created = file,
+            Err(err) => return Err(Error::other(format!("Creating file Error {:?}", err))),
+        },
+        Err(err) => {
+            return Err(Error::other(format!(
+                "Creating file Blocking Error {:?}",
+                err
+            )))
+        }
+    };
+    // Field in turn is stream of *Bytes* object
+    while let Some(Ok(chunk)) = field.next().await {
+        // Filesystem operations are blocking, we have to use threadpool
+        match web::block(move || file_created.write_all(&chunk).map(|_| file_created)).await {
+            Ok(res) => match res {
+                Ok(file) => file_created = file,
+                Err(err) => return Err(Error::other(format!("Creating file Error {:?}", err))),
+            },
+            Err(err) => {
+                return Err(Error::other(format!(
+                    "Creating file Blocking Error {:?}",
+                    err
+                )))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Collects a small uploaded field in memory, refusing it when it grows beyond `max_bytes`.
+pub async fn read_field_bytes(field: &mut Field, max_bytes: usize) -> Result<Vec<u8>, Error> {
+    let mut data = vec![];
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk.map_err(|err| Error::other(format!("Reading upload Error {:?}", err)))?;
+        if data.len() + chunk.len() > max_bytes {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Upload is bigger than {} bytes", max_bytes),
+            ));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
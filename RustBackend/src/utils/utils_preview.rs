@@ -0,0 +1,101 @@
+use std::{
+    io::{Cursor, Error, ErrorKind},
+    panic::{catch_unwind, AssertUnwindSafe},
+};
+
+use image::{io::Limits, DynamicImage, ImageFormat, ImageOutputFormat};
+
+use crate::constants::{ATTACHMENT_PREVIEW_MAX_ALLOC, ATTACHMENT_PREVIEW_MAX_DIMENSION};
+
+const THUMBNAIL_JPEG_QUALITY: u8 = 80;
+
+/// Scales the image down so its longest edge is at most `size` pixels. Only the first frame
+/// of an animation is used. Images with transparency become PNG, everything else JPEG.
+pub fn make_thumbnail(data: &[u8], format: ImageFormat, size: u32) -> Result<Vec<u8>, Error> {
+    let mut reader = image::io::Reader::with_format(Cursor::new(data), format);
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(ATTACHMENT_PREVIEW_MAX_DIMENSION);
+    limits.max_image_height = Some(ATTACHMENT_PREVIEW_MAX_DIMENSION);
+    limits.max_alloc = Some(ATTACHMENT_PREVIEW_MAX_ALLOC);
+    reader.limits(limits);
+    let image = reader
+        .decode()
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+
+    // Small images are only re-encoded, never scaled up
+    let image = if image.width() > size || image.height() > size {
+        image.thumbnail(size, size)
+    } else {
+        image
+    };
+
+    let mut output = Cursor::new(vec![]);
+    let result = if image.color().has_alpha() {
+        image.write_to(&mut output, ImageOutputFormat::Png)
+    } else {
+        DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_to(&mut output, ImageOutputFormat::Jpeg(THUMBNAIL_JPEG_QUALITY))
+    };
+    result.map_err(Error::other)?;
+    Ok(output.into_inner())
+}
+
+/// Content type of a thumbnail made by `make_thumbnail`.
+pub fn thumbnail_content_type(thumbnail: &[u8]) -> &'static str {
+    if thumbnail.starts_with(b"\x89PNG") {
+        "image/png"
+    } else {
+        "image/jpeg"
+    }
+}
+
+/// Formats thumbnails can be made of, by content type.
+pub fn thumbnail_format(content_type: &str) -> Option<ImageFormat> {
+    match content_type {
+        "image/jpeg" | "image/jpg" | "image/pjpeg" => Some(ImageFormat::Jpeg),
+        "image/png" => Some(ImageFormat::Png),
+        "image/gif" => Some(ImageFormat::Gif),
+        "image/webp" => Some(ImageFormat::WebP),
+        _ => None,
+    }
+}
+
+/// Text of all pages. The parser panics on some malformed files, which is turned into an error.
+pub fn extract_pdf_text(data: &[u8]) -> Result<String, Error> {
+    match catch_unwind(AssertUnwindSafe(|| {
+        pdf_extract::extract_text_from_mem(data)
+    })) {
+        Ok(Ok(text)) => Ok(text),
+        Ok(Err(err)) => Err(Error::new(ErrorKind::InvalidData, err.to_string())),
+        Err(_) => Err(Error::new(
+            ErrorKind::InvalidData,
+            "The PDF could not be read",
+        )),
+    }
+}
+
+/// Collapses runs of blank lines and cuts the text after `max_length` characters. Returns
+/// whether anything was cut.
+pub fn shorten_preview_text(text: &str, max_length: usize) -> (String, bool) {
+    let mut lines = vec![];
+    let mut previous_blank = true;
+    for line in text.lines() {
+        let line = line.trim_end();
+        if line.trim().is_empty() {
+            if !previous_blank {
+                lines.push("");
+            }
+            previous_blank = true;
+        } else {
+            lines.push(line);
+            previous_blank = false;
+        }
+    }
+    let text = lines.join("\n");
+    let text = text.trim_end();
+
+    match text.char_indices().nth(max_length) {
+        Some((end, _)) => (text[..end].trim_end().to_string(), true),
+        None => (text.to_string(), false),
+    }
+}
@@ -1,4 +1,4 @@
-use std::io::{Error, ErrorKind};
+use std::io::Error;
 
 use crate::{
     constants::{AUTH_DOMAIN_STRING, AUTH_EMAIL_STRING, AUTH_PASSWORD_STRING},
@@ -20,6 +20,6 @@ pub fn check_is_valid_session(session: &Session) -> Result<SignInMessage, Error>
             domain: domain_value,
         })
     } else {
-        Err(Error::new(ErrorKind::Other, "Unauthenticated"))
+        Err(Error::other("Unauthenticated"))
     }
 }
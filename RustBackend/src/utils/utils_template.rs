@@ -0,0 +1,146 @@
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind},
+};
+
+/// `{{name}}` or `{{name|default}}` in a template text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Placeholder {
+    pub name: String,
+    /// Used when the variable is missing or blank, placeholders without one are required
+    pub default: Option<String>,
+}
+
+enum Segment<'a> {
+    Text(&'a str),
+    Placeholder(Placeholder),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TemplateEscape {
+    None,
+    Html,
+}
+
+/// Placeholders of the text in order of appearance. Fails for an unclosed `{{` or a name
+/// with other characters than letters, digits, `_`, `-` and `.`.
+pub fn parse_placeholders(text: &str) -> Result<Vec<Placeholder>, Error> {
+    Ok(parse_segments(text)?
+        .into_iter()
+        .filter_map(|segment| match segment {
+            Segment::Placeholder(placeholder) => Some(placeholder),
+            Segment::Text(_) => None,
+        })
+        .collect())
+}
+
+/// Names of required placeholders without a value, each once.
+pub fn missing_variables(
+    texts: &[&str],
+    variables: &HashMap<String, String>,
+) -> Result<Vec<String>, Error> {
+    let mut missing: Vec<String> = vec![];
+    for text in texts.iter() {
+        for placeholder in parse_placeholders(text)?.into_iter() {
+            if placeholder.default.is_none()
+                && lookup(variables, &placeholder.name).is_none()
+                && !missing.contains(&placeholder.name)
+            {
+                missing.push(placeholder.name);
+            }
+        }
+    }
+    Ok(missing)
+}
+
+/// Replaces the placeholders with their variables. Values are HTML escaped for HTML bodies,
+/// defaults are written by the template author and inserted as they are.
+pub fn render_template(
+    text: &str,
+    variables: &HashMap<String, String>,
+    escape: TemplateEscape,
+) -> Result<String, Error> {
+    let mut output = String::with_capacity(text.len());
+    for segment in parse_segments(text)?.into_iter() {
+        match segment {
+            Segment::Text(text) => output.push_str(text),
+            Segment::Placeholder(placeholder) => {
+                match (lookup(variables, &placeholder.name), placeholder.default) {
+                    (Some(value), _) if escape == TemplateEscape::Html => {
+                        output.push_str(&escape_html(value))
+                    }
+                    (Some(value), _) => output.push_str(value),
+                    (None, Some(default)) => output.push_str(&default),
+                    (None, None) => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("Missing template variable {}", placeholder.name),
+                        ))
+                    }
+                }
+            }
+        }
+    }
+    Ok(output)
+}
+
+/// Blank values count as missing, so an empty cell falls back to the default.
+fn lookup<'a>(variables: &'a HashMap<String, String>, name: &str) -> Option<&'a str> {
+    variables
+        .get(name)
+        .map(String::as_str)
+        .filter(|value| !value.trim().is_empty())
+}
+
+fn parse_segments(text: &str) -> Result<Vec<Segment<'_>>, Error> {
+    let mut segments = vec![];
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        if start > 0 {
+            segments.push(Segment::Text(&rest[..start]));
+        }
+        let inner_start = start + 2;
+        let end = rest[inner_start..].find("}}").ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unclosed placeholder {}", preview(&rest[start..])),
+            )
+        })?;
+        let inner = &rest[inner_start..inner_start + end];
+        let (name, default) = match inner.split_once('|') {
+            Some((name, default)) => (name.trim(), Some(default.trim().to_string())),
+            None => (inner.trim(), None),
+        };
+        let valid_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|char| char.is_alphanumeric() || matches!(char, '_' | '-' | '.'));
+        if !valid_name {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid placeholder {{{{{}}}}}", inner),
+            ));
+        }
+        segments.push(Segment::Placeholder(Placeholder {
+            name: name.to_string(),
+            default,
+        }));
+        rest = &rest[inner_start + end + 2..];
+    }
+    if !rest.is_empty() {
+        segments.push(Segment::Text(rest));
+    }
+    Ok(segments)
+}
+
+fn preview(text: &str) -> String {
+    text.chars().take(30).collect()
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+
+// Reply and forward prefixes stripped before comparing subjects, including common translations
+const SUBJECT_PREFIXES: [&str; 6] = ["re:", "fw:", "fwd:", "aw:", "wg:", "sv:"];
+
+pub struct ThreadInput {
+    pub message_id: Option<String>,
+    /// Message-IDs from References, or from In-Reply-To when References is missing
+    pub references: Vec<String>,
+    pub subject: String,
+}
+
+/// Node of a conversation tree, `message` is an index into the threaded messages. Nodes
+/// without a message stand for emails which are referenced but not available.
+pub struct ThreadTree {
+    pub message: Option<usize>,
+    pub children: Vec<ThreadTree>,
+}
+
+struct Container {
+    message: Option<usize>,
+    parent: Option<usize>,
+    children: Vec<usize>,
+}
+
+/// Threads messages by their Message-ID, References and subjects using the algorithm of
+/// Jamie Zawinski (https://www.jwz.org/doc/threading.html).
+pub fn thread_by_references(messages: &[ThreadInput]) -> Vec<ThreadTree> {
+    let mut containers: Vec<Container> = vec![];
+    let mut id_table: HashMap<String, usize> = HashMap::new();
+
+    for (index, message) in messages.iter().enumerate() {
+        let own = match &message.message_id {
+            Some(message_id) => {
+                let container = get_container(message_id, &mut containers, &mut id_table);
+                if containers[container].message.is_none() {
+                    container
+                } else {
+                    // Duplicate Message-ID, e.g. the same email in Inbox and Sent
+                    new_container(&mut containers)
+                }
+            }
+            None => new_container(&mut containers),
+        };
+        containers[own].message = Some(index);
+
+        let mut previous: Option<usize> = None;
+        for reference in message.references.iter() {
+            let current = get_container(reference, &mut containers, &mut id_table);
+            if let Some(parent) = previous {
+                if containers[current].parent.is_none()
+                    && !is_descendant(&containers, parent, current)
+                {
+                    link(&mut containers, parent, current);
+                }
+            }
+            previous = Some(current);
+        }
+
+        // The last reference is the parent, even when an earlier message claimed otherwise
+        if let Some(parent) = previous {
+            if !is_descendant(&containers, parent, own) {
+                unlink(&mut containers, own);
+                link(&mut containers, parent, own);
+            }
+        }
+    }
+
+    let mut roots = vec![];
+    for index in 0..containers.len() {
+        if containers[index].parent.is_some() {
+            continue;
+        }
+        let children: Vec<ThreadTree> = containers[index]
+            .children
+            .iter()
+            .flat_map(|child| prune(&containers, *child))
+            .collect();
+        match (containers[index].message, children.len()) {
+            (None, 0) => {}
+            // Promoting more children to the root level would split the conversation
+            (None, 1) => roots.extend(children),
+            (message, _) => roots.push(ThreadTree { message, children }),
+        }
+    }
+
+    group_by_subject(roots, messages)
+}
+
+fn new_container(containers: &mut Vec<Container>) -> usize {
+    containers.push(Container {
+        message: None,
+        parent: None,
+        children: vec![],
+    });
+    containers.len() - 1
+}
+
+fn get_container(
+    message_id: &str,
+    containers: &mut Vec<Container>,
+    id_table: &mut HashMap<String, usize>,
+) -> usize {
+    match id_table.get(message_id) {
+        Some(container) => *container,
+        None => {
+            let container = new_container(containers);
+            id_table.insert(message_id.to_string(), container);
+            container
+        }
+    }
+}
+
+/// Whether `node` is `ancestor` itself or lies somewhere below it.
+fn is_descendant(containers: &[Container], node: usize, ancestor: usize) -> bool {
+    let mut current = Some(node);
+    while let Some(index) = current {
+        if index == ancestor {
+            return true;
+        }
+        current = containers[index].parent;
+    }
+    false
+}
+
+fn link(containers: &mut [Container], parent: usize, child: usize) {
+    containers[child].parent = Some(parent);
+    containers[parent].children.push(child);
+}
+
+fn unlink(containers: &mut [Container], child: usize) {
+    if let Some(parent) = containers[child].parent.take() {
+        containers[parent].children.retain(|index| *index != child);
+    }
+}
+
+/// Drops containers without a message, their children take their place.
+fn prune(containers: &[Container], index: usize) -> Vec<ThreadTree> {
+    let children: Vec<ThreadTree> = containers[index]
+        .children
+        .iter()
+        .flat_map(|child| prune(containers, *child))
+        .collect();
+    match containers[index].message {
+        Some(message) => vec![ThreadTree {
+            message: Some(message),
+            children,
+        }],
+        None => children,
+    }
+}
+
+/// Joins root threads with the same subject, so replies of clients that drop References
+/// still end up in their conversation.
+fn group_by_subject(roots: Vec<ThreadTree>, messages: &[ThreadInput]) -> Vec<ThreadTree> {
+    let mut grouped: Vec<ThreadTree> = vec![];
+    let mut subject_table: HashMap<String, usize> = HashMap::new();
+
+    for root in roots.into_iter() {
+        let subject = match thread_subject(&root, messages) {
+            Some(subject) => normalize_subject(subject),
+            None => String::new(),
+        };
+        if subject.is_empty() {
+            grouped.push(root);
+            continue;
+        }
+
+        let index = match subject_table.get(&subject) {
+            Some(index) => *index,
+            None => {
+                subject_table.insert(subject, grouped.len());
+                grouped.push(root);
+                continue;
+            }
+        };
+
+        let existing = std::mem::replace(
+            &mut grouped[index],
+            ThreadTree {
+                message: None,
+                children: vec![],
+            },
+        );
+        grouped[index] = merge_threads(existing, root, messages);
+    }
+
+    grouped
+}
+
+fn merge_threads(
+    mut existing: ThreadTree,
+    mut other: ThreadTree,
+    messages: &[ThreadInput],
+) -> ThreadTree {
+    let is_reply = |tree: &ThreadTree| {
+        tree.message
+            .map(|message| is_reply_subject(&messages[message].subject))
+            .unwrap_or(false)
+    };
+
+    match (existing.message, other.message) {
+        (None, None) => {
+            existing.children.extend(other.children);
+            existing
+        }
+        (None, Some(_)) => {
+            existing.children.push(other);
+            existing
+        }
+        (Some(_), None) => {
+            other.children.push(existing);
+            other
+        }
+        _ if !is_reply(&existing) && is_reply(&other) => {
+            existing.children.push(other);
+            existing
+        }
+        _ if is_reply(&existing) && !is_reply(&other) => {
+            other.children.push(existing);
+            other
+        }
+        _ => ThreadTree {
+            message: None,
+            children: vec![existing, other],
+        },
+    }
+}
+
+fn thread_subject<'a>(tree: &ThreadTree, messages: &'a [ThreadInput]) -> Option<&'a str> {
+    match tree.message {
+        Some(message) => Some(&messages[message].subject),
+        None => tree
+            .children
+            .iter()
+            .find_map(|child| thread_subject(child, messages)),
+    }
+}
+
+fn strip_subject_prefix(subject: &str) -> Option<&str> {
+    SUBJECT_PREFIXES
+        .iter()
+        .find(|prefix| {
+            subject
+                .get(..prefix.len())
+                .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+        })
+        .map(|prefix| subject[prefix.len()..].trim_start())
+}
+
+fn is_reply_subject(subject: &str) -> bool {
+    strip_subject_prefix(subject.trim()).is_some()
+}
+
+pub fn normalize_subject(subject: &str) -> String {
+    let mut subject = subject.trim();
+    while let Some(stripped) = strip_subject_prefix(subject) {
+        subject = stripped;
+    }
+    subject.to_lowercase()
+}
+
+/// Returns all `<...>` message identifiers in a header value.
+pub fn parse_message_ids(value: &str) -> Vec<String> {
+    let mut message_ids = vec![];
+    let mut rest = value;
+    while let Some(start) = rest.find('<') {
+        match rest[start..].find('>') {
+            Some(end) => {
+                message_ids.push(rest[start..start + end + 1].to_string());
+                rest = &rest[start + end + 1..];
+            }
+            None => break,
+        }
+    }
+    message_ids
+}
+
+/// Parses the untagged response of `UID THREAD`, e.g. `* THREAD (2)(3 6 (4 23)(44 7 96))`.
+/// UIDs are mapped to message indexes through `positions`.
+pub fn parse_thread_response(line: &str, positions: &HashMap<u32, usize>) -> Vec<ThreadTree> {
+    let mut tokens = tokenize_thread_response(line).into_iter().peekable();
+    let mut threads = vec![];
+    while let Some(token) = tokens.next() {
+        if token == "(" {
+            threads.extend(parse_thread_group(&mut tokens, positions));
+        }
+    }
+    threads
+}
+
+fn tokenize_thread_response(line: &str) -> Vec<String> {
+    let line = line.trim_end().trim_start_matches("* THREAD");
+    let mut tokens = vec![];
+    let mut number = String::new();
+    for char in line.chars() {
+        if char.is_ascii_digit() {
+            number.push(char);
+            continue;
+        }
+        if !number.is_empty() {
+            tokens.push(std::mem::take(&mut number));
+        }
+        if char == '(' || char == ')' {
+            tokens.push(char.to_string());
+        }
+    }
+    if !number.is_empty() {
+        tokens.push(number);
+    }
+    tokens
+}
+
+/// Parses one parenthesized group after its opening parenthesis. A group is a chain of UIDs,
+/// each the parent of the next, optionally followed by nested groups replying to the last one.
+fn parse_thread_group(
+    tokens: &mut std::iter::Peekable<std::vec::IntoIter<String>>,
+    positions: &HashMap<u32, usize>,
+) -> Option<ThreadTree> {
+    let mut chain: Vec<Option<usize>> = vec![];
+    let mut branches = vec![];
+    while let Some(token) = tokens.next() {
+        match token.as_str() {
+            "(" => branches.extend(parse_thread_group(tokens, positions)),
+            ")" => break,
+            uid => chain.push(
+                uid.parse::<u32>()
+                    .ok()
+                    .and_then(|uid| positions.get(&uid).cloned()),
+            ),
+        }
+    }
+
+    let mut children = branches;
+    for message in chain.into_iter().rev() {
+        // UIDs outside of the fetched range leave nothing to show
+        if message.is_none() && children.is_empty() {
+            continue;
+        }
+        children = vec![ThreadTree { message, children }];
+    }
+    match children.len() {
+        0 => None,
+        1 => children.pop(),
+        _ => Some(ThreadTree {
+            message: None,
+            children,
+        }),
+    }
+}
@@ -1,9 +1,11 @@
-use std::{io::Error, io::ErrorKind, net::TcpStream};
+use std::{io::Error, net::TcpStream};
 
 use imap::Session;
 use lettre::{transport::smtp::authentication::Credentials, AsyncSmtpTransport, Tokio1Executor};
 use native_tls::TlsStream;
 
+use super::utils_imap::ImapConnection;
+
 pub async fn create_smtp_transport(
     username: &str,
     password: &str,
@@ -14,10 +16,10 @@ pub async fn create_smtp_transport(
     let smtp_session = match AsyncSmtpTransport::<Tokio1Executor>::relay(domain) {
         Ok(session) => session,
         Err(err) => {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("SMTP transport relay failed: {}", err),
-            ))
+            return Err(Error::other(format!(
+                "SMTP transport relay failed: {}",
+                err
+            )))
         }
     }
     .credentials(creds)
@@ -25,10 +27,7 @@ pub async fn create_smtp_transport(
 
     match smtp_session.test_connection().await {
         Ok(_) => Ok(smtp_session),
-        Err(e) => Err(Error::new(
-            ErrorKind::Other,
-            format!("SMTP test connection failed: {}", e),
-        )),
+        Err(e) => Err(Error::other(format!("SMTP test connection failed: {}", e))),
     }
 }
 
@@ -40,24 +39,52 @@ pub async fn create_imap_session(
     let tls = match native_tls::TlsConnector::builder().build() {
         Ok(val) => val,
         Err(err) => {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("TlsConnector build failed: {:?}", err),
-            ))
+            return Err(Error::other(format!(
+                "TlsConnector build failed: {:?}",
+                err
+            )))
         }
     };
 
     match imap::connect((domain.to_owned(), 993), domain, &tls) {
         Ok(client) => match client.login(username, password) {
             Ok(session) => Ok(session),
-            Err(err) => Err(Error::new(
-                ErrorKind::Other,
-                format!("IMAP login failed: {:?}", err),
-            )),
+            Err(err) => Err(Error::other(format!("IMAP login failed: {:?}", err))),
         },
-        Err(err) => Err(Error::new(
-            ErrorKind::Other,
-            format!("IMAP connect failed: {:?}", err),
-        )),
+        Err(err) => Err(Error::other(format!("IMAP connect failed: {:?}", err))),
+    }
+}
+
+pub async fn create_imap_connection(
+    username: &str,
+    password: &str,
+    domain: &str,
+) -> Result<ImapConnection, Error> {
+    connect_imap(username, password, domain)
+}
+
+/// Connects and logs in without yielding, for code already running on a blocking thread.
+pub fn connect_imap(username: &str, password: &str, domain: &str) -> Result<ImapConnection, Error> {
+    let tls = match native_tls::TlsConnector::builder().build() {
+        Ok(val) => val,
+        Err(err) => {
+            return Err(Error::other(format!(
+                "TlsConnector build failed: {:?}",
+                err
+            )))
+        }
+    };
+
+    let tcp_stream = TcpStream::connect((domain, 993))?;
+    let tls_stream = match tls.connect(domain, tcp_stream) {
+        Ok(stream) => stream,
+        Err(err) => return Err(Error::other(format!("IMAP connect failed: {:?}", err))),
+    };
+
+    let mut connection = ImapConnection::new(tls_stream);
+    connection.read_greeting()?;
+    match connection.login(username, password) {
+        Ok(_) => Ok(connection),
+        Err(err) => Err(Error::other(format!("IMAP login failed: {:?}", err))),
     }
 }
@@ -0,0 +1,367 @@
+use crate::{handlers::email::models::VCardVersion, storage::contacts::Contact};
+
+use super::utils_icalendar::{
+    escape_text, parse_components, unescape_text, CalendarComponent, CalendarProperty,
+};
+
+/// Reads vCard 3.0 and 4.0 contacts (RFC 2426, RFC 6350). Groups come from CATEGORIES and
+/// from 4.0 group cards, whose members are looked up by address or UID among the other cards.
+/// Returns the contacts with the count of cards skipped for having neither name nor address.
+pub fn parse_vcards(text: &str) -> (Vec<Contact>, u32) {
+    let cards: Vec<CalendarComponent> = parse_components(text)
+        .into_iter()
+        .filter(|component| component.name == "VCARD")
+        .collect();
+
+    let mut contacts = vec![];
+    let mut group_cards = vec![];
+    let mut skipped = 0;
+    for card in cards.iter() {
+        let is_group = card_property(card, "KIND")
+            .is_some_and(|kind| kind.value.trim().eq_ignore_ascii_case("group"));
+        if is_group {
+            group_cards.push(card);
+            continue;
+        }
+        let contact = read_contact(card);
+        if contact.name.is_empty() && contact.emails.is_empty() {
+            skipped += 1;
+        } else {
+            contacts.push(contact);
+        }
+    }
+
+    for card in group_cards.into_iter() {
+        let group = match card_text(card, "FN") {
+            Some(name) if !name.is_empty() => name,
+            _ => {
+                skipped += 1;
+                continue;
+            }
+        };
+        for member in card_properties(card, "MEMBER") {
+            let member = member.value.trim();
+            let email = member
+                .get(..7)
+                .filter(|scheme| scheme.eq_ignore_ascii_case("mailto:"))
+                .map(|_| &member[7..]);
+            for contact in contacts.iter_mut() {
+                let is_member = match email {
+                    Some(email) => contact.has_email(email),
+                    None => contact.uid.as_deref() == Some(member),
+                };
+                if is_member && !contact.in_group(&group) {
+                    contact.groups.push(group.clone());
+                }
+            }
+        }
+    }
+    (contacts, skipped)
+}
+
+fn read_contact(card: &CalendarComponent) -> Contact {
+    let name = card_text(card, "FN")
+        .filter(|name| !name.is_empty())
+        .or_else(|| {
+            // N is family;given;additional;prefixes;suffixes
+            card_property(card, "N").map(|property| {
+                let parts = split_escaped(&property.value, ';');
+                [
+                    parts.get(3),
+                    parts.get(1),
+                    parts.get(2),
+                    parts.first(),
+                    parts.get(4),
+                ]
+                .into_iter()
+                .flatten()
+                .filter(|part| !part.is_empty())
+                .cloned()
+                .collect::<Vec<String>>()
+                .join(" ")
+            })
+        })
+        .unwrap_or_default();
+
+    let mut emails: Vec<(bool, String)> = card_properties(card, "EMAIL")
+        .map(|property| (is_preferred(property), unescape_text(property.value.trim())))
+        .filter(|(_, email)| !email.is_empty())
+        .collect();
+    // Stable, so the order of the card is kept apart from the preferred address
+    emails.sort_by_key(|(preferred, _)| !preferred);
+    let phones = card_properties(card, "TEL")
+        .map(property_value)
+        .filter(|phone| !phone.is_empty())
+        .collect();
+    let mut groups: Vec<String> = vec![];
+    for property in card_properties(card, "CATEGORIES") {
+        for group in split_escaped(&property.value, ',') {
+            let group = group.trim().to_string();
+            if !group.is_empty() && !groups.contains(&group) {
+                groups.push(group);
+            }
+        }
+    }
+
+    Contact {
+        uid: card_property(card, "UID")
+            .map(|property| property.value.trim().to_string())
+            .filter(|uid| !uid.is_empty()),
+        name,
+        emails: emails.into_iter().map(|(_, email)| email).collect(),
+        phones,
+        // ORG is the organization name followed by its units
+        organization: card_property(card, "ORG")
+            .and_then(|property| split_escaped(&property.value, ';').into_iter().next())
+            .filter(|organization| !organization.is_empty()),
+        note: card_text(card, "NOTE").filter(|note| !note.is_empty()),
+        groups,
+        ..Contact::default()
+    }
+}
+
+/// vCard 3.0 marks the preferred value with `TYPE=pref`, 4.0 with `PREF=1`.
+fn is_preferred(property: &CalendarProperty) -> bool {
+    property
+        .params
+        .iter()
+        .any(|(param, value)| match param.as_str() {
+            "PREF" => value.trim() == "1",
+            "TYPE" => value
+                .split(',')
+                .any(|kind| kind.trim().eq_ignore_ascii_case("pref")),
+            _ => false,
+        })
+}
+
+/// Properties may be grouped as in `item1.EMAIL`, the group is ignored.
+fn card_properties<'a>(
+    card: &'a CalendarComponent,
+    name: &'a str,
+) -> impl Iterator<Item = &'a CalendarProperty> {
+    card.properties
+        .iter()
+        .filter(move |property| property.name.rsplit('.').next() == Some(name))
+}
+
+fn card_property<'a>(card: &'a CalendarComponent, name: &'a str) -> Option<&'a CalendarProperty> {
+    card_properties(card, name).next()
+}
+
+fn card_text(card: &CalendarComponent, name: &str) -> Option<String> {
+    card_property(card, name).map(|property| unescape_text(property.value.trim()))
+}
+
+/// Splits a structured or list value at separators which are not escaped and unescapes the
+/// parts.
+fn split_escaped(value: &str, separator: char) -> Vec<String> {
+    let mut parts = vec![];
+    let mut part = String::new();
+    let mut escaped = false;
+    for char in value.chars() {
+        if escaped {
+            part.push('\\');
+            part.push(char);
+            escaped = false;
+        } else if char == '\\' {
+            escaped = true;
+        } else if char == separator {
+            parts.push(unescape_text(&part));
+            part.clear();
+        } else {
+            part.push(char);
+        }
+    }
+    parts.push(unescape_text(&part));
+    parts
+}
+
+/// Writes the contacts as one vCard file with CRLF line endings. Groups are written as
+/// CATEGORIES, which both versions and most address books understand.
+pub fn write_vcards(contacts: &[Contact], version: VCardVersion) -> String {
+    contacts
+        .iter()
+        .map(|contact| write_card(&contact_properties(contact, version), version))
+        .collect()
+}
+
+/// Writes the contact over the card it was synced from. Properties the contact does not model,
+/// such as postal addresses, photos and custom fields, are kept as they are, and so are the
+/// parameters of addresses and numbers which did not change.
+pub fn update_vcard(original: &str, contact: &Contact) -> String {
+    let card = match parse_components(original)
+        .into_iter()
+        .find(|component| component.name == "VCARD")
+    {
+        Some(card) => card,
+        None => return write_vcards(std::slice::from_ref(contact), VCardVersion::V3),
+    };
+    let version = match card_property(&card, "VERSION") {
+        Some(version) if version.value.trim() == "4.0" => VCardVersion::V4,
+        _ => VCardVersion::V3,
+    };
+    let original_contact = read_contact(&card);
+
+    let mut properties = vec![];
+    for generated in contact_properties(contact, version).into_iter() {
+        let kept = match generated.name.as_str() {
+            // N carries the split of the name, only rebuilt when the name changed
+            "N" if original_contact.name == contact.name => card_property(&card, "N").cloned(),
+            "ORG" if original_contact.organization == contact.organization => {
+                card_property(&card, "ORG").cloned()
+            }
+            "EMAIL" | "TEL" => card_properties(&card, &generated.name)
+                .find(|property| {
+                    property_value(property).eq_ignore_ascii_case(&property_value(&generated))
+                })
+                .map(|property| with_preference(property, &generated)),
+            _ => None,
+        };
+        properties.push(kept.unwrap_or(generated));
+    }
+    properties.extend(
+        card.properties
+            .iter()
+            .filter(|property| {
+                let name = property.name.rsplit('.').next().unwrap_or_default();
+                !MODELED_PROPERTIES.contains(&name)
+            })
+            .cloned(),
+    );
+    write_card(&properties, version)
+}
+
+/// Properties written from the fields of a contact, everything else on a card is kept.
+const MODELED_PROPERTIES: [&str; 9] = [
+    "VERSION",
+    "UID",
+    "FN",
+    "N",
+    "EMAIL",
+    "TEL",
+    "ORG",
+    "NOTE",
+    "CATEGORIES",
+];
+
+fn contact_properties(contact: &Contact, version: VCardVersion) -> Vec<CalendarProperty> {
+    let mut properties = vec![];
+    if let Some(uid) = contact.uid.as_ref() {
+        properties.push(property("UID", vec![], uid));
+    }
+    let name = if contact.name.is_empty() {
+        contact.emails.first().cloned().unwrap_or_default()
+    } else {
+        contact.name.clone()
+    };
+    properties.push(property("FN", vec![], &escape_text(&name)));
+    if version == VCardVersion::V3 {
+        // Required in 3.0, the last word is taken as the family name
+        let (given, family) = match name.trim().rsplit_once(' ') {
+            Some((given, family)) => (given.trim(), family),
+            None => ("", name.trim()),
+        };
+        properties.push(property(
+            "N",
+            vec![],
+            &format!("{};{};;;", escape_text(family), escape_text(given)),
+        ));
+    }
+    for (index, email) in contact.emails.iter().enumerate() {
+        let params = match (version, index) {
+            (VCardVersion::V3, 0) => vec![("TYPE", "INTERNET"), ("TYPE", "pref")],
+            (VCardVersion::V3, _) => vec![("TYPE", "INTERNET")],
+            (VCardVersion::V4, 0) => vec![("PREF", "1")],
+            (VCardVersion::V4, _) => vec![],
+        };
+        properties.push(property("EMAIL", params, &escape_text(email)));
+    }
+    for phone in contact.phones.iter() {
+        properties.push(property("TEL", vec![], &escape_text(phone)));
+    }
+    if let Some(organization) = contact.organization.as_ref() {
+        properties.push(property("ORG", vec![], &escape_text(organization)));
+    }
+    if let Some(note) = contact.note.as_ref() {
+        properties.push(property("NOTE", vec![], &escape_text(note)));
+    }
+    if !contact.groups.is_empty() {
+        let groups: Vec<String> = contact
+            .groups
+            .iter()
+            .map(|group| escape_text(group))
+            .collect();
+        properties.push(property("CATEGORIES", vec![], &groups.join(",")));
+    }
+    properties
+}
+
+fn write_card(properties: &[CalendarProperty], version: VCardVersion) -> String {
+    let mut output = String::from("BEGIN:VCARD\r\n");
+    output.push_str(&property("VERSION", vec![], version_value(version)).to_line());
+    output.push_str("\r\n");
+    for property in properties.iter() {
+        output.push_str(&property.to_line());
+        output.push_str("\r\n");
+    }
+    output.push_str("END:VCARD\r\n");
+    output
+}
+
+/// Value of an EMAIL or TEL property as the contact holds it.
+fn property_value(property: &CalendarProperty) -> String {
+    let value = property.value.trim();
+    match value.get(..4) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("tel:") => value[4..].to_string(),
+        _ => unescape_text(value),
+    }
+}
+
+/// The original property with its preference marker taken from the generated one, as the
+/// order of the addresses may have changed.
+fn with_preference(original: &CalendarProperty, generated: &CalendarProperty) -> CalendarProperty {
+    let mut property = original.clone();
+    property.params = original
+        .params
+        .iter()
+        .filter(|(param, _)| param != "PREF")
+        .filter_map(|(param, value)| match param.as_str() {
+            "TYPE" => {
+                let kinds: Vec<&str> = value
+                    .split(',')
+                    .filter(|kind| !kind.trim().eq_ignore_ascii_case("pref"))
+                    .collect();
+                (!kinds.is_empty()).then(|| (param.clone(), kinds.join(",")))
+            }
+            _ => Some((param.clone(), value.clone())),
+        })
+        .collect();
+    if is_preferred(generated) {
+        property.params.extend(
+            generated
+                .params
+                .iter()
+                .filter(|(param, value)| param == "PREF" || value == "pref")
+                .cloned(),
+        );
+    }
+    property
+}
+
+fn version_value(version: VCardVersion) -> &'static str {
+    match version {
+        VCardVersion::V3 => "3.0",
+        VCardVersion::V4 => "4.0",
+    }
+}
+
+fn property(name: &str, params: Vec<(&str, &str)>, value: &str) -> CalendarProperty {
+    CalendarProperty {
+        name: name.to_string(),
+        params: params
+            .into_iter()
+            .map(|(param, value)| (param.to_string(), value.to_string()))
+            .collect(),
+        value: value.to_string(),
+    }
+}
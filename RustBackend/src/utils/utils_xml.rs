@@ -0,0 +1,255 @@
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind},
+};
+
+/// Element of a parsed XML document with its namespace resolved.
+#[derive(Debug, Clone)]
+pub struct XmlElement {
+    /// Namespace URI, empty for elements without one
+    pub namespace: String,
+    /// Local name without the prefix
+    pub name: String,
+    /// Attributes by their local name, namespace declarations left out
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<XmlElement>,
+    /// Text directly inside the element with entities and CDATA resolved
+    pub text: String,
+}
+
+impl XmlElement {
+    pub fn is(&self, namespace: &str, name: &str) -> bool {
+        self.namespace == namespace && self.name == name
+    }
+
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(attribute, _)| attribute == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn child(&self, namespace: &str, name: &str) -> Option<&XmlElement> {
+        self.children.iter().find(|child| child.is(namespace, name))
+    }
+
+    pub fn children_named<'a>(
+        &'a self,
+        namespace: &'a str,
+        name: &'a str,
+    ) -> impl Iterator<Item = &'a XmlElement> {
+        self.children
+            .iter()
+            .filter(move |child| child.is(namespace, name))
+    }
+}
+
+struct OpenElement {
+    prefix: String,
+    element: XmlElement,
+    namespaces: HashMap<String, String>,
+}
+
+/// Parses a document into its root element. Comments, processing instructions and the
+/// doctype are skipped, external entities are never resolved.
+pub fn parse_xml(text: &str) -> Result<XmlElement, Error> {
+    let text = text.trim_start_matches('\u{feff}');
+    let mut stack: Vec<OpenElement> = vec![];
+    let mut position = 0;
+
+    while let Some(offset) = text[position..].find('<') {
+        let start = position + offset;
+        if let Some(open) = stack.last_mut() {
+            open.element
+                .text
+                .push_str(&decode_entities(&text[position..start]));
+        }
+        let rest = &text[start..];
+
+        if rest.starts_with("<?") {
+            position = start + skip_past(rest, "?>")?;
+        } else if rest.starts_with("<!--") {
+            position = start + skip_past(rest, "-->")?;
+        } else if let Some(cdata) = rest.strip_prefix("<![CDATA[") {
+            let end = cdata.find("]]>").ok_or_else(|| invalid_xml("CDATA"))?;
+            if let Some(open) = stack.last_mut() {
+                open.element.text.push_str(&cdata[..end]);
+            }
+            position = start + "<![CDATA[".len() + end + "]]>".len();
+        } else if rest.starts_with("<!") {
+            // A doctype with an internal subset ends with `]>`
+            position = match (rest.find('['), rest.find('>')) {
+                (Some(bracket), Some(end)) if bracket < end => start + skip_past(rest, "]>")?,
+                _ => start + skip_past(rest, ">")?,
+            };
+        } else if let Some(closing) = rest.strip_prefix("</") {
+            let end = closing
+                .find('>')
+                .ok_or_else(|| invalid_xml("closing tag"))?;
+            let open = stack.pop().ok_or_else(|| invalid_xml("closing tag"))?;
+            let (prefix, _) = split_name(closing[..end].trim());
+            if prefix != open.prefix {
+                return Err(invalid_xml("closing tag"));
+            }
+            match stack.last_mut() {
+                Some(parent) => parent.element.children.push(open.element),
+                None => return Ok(open.element),
+            }
+            position = start + 2 + end + 1;
+        } else {
+            let (tag_length, tag, self_closing) = read_tag(rest)?;
+            let open = open_element(tag, stack.last().map(|parent| &parent.namespaces))?;
+            position = start + tag_length;
+            if self_closing {
+                match stack.last_mut() {
+                    Some(parent) => parent.element.children.push(open.element),
+                    None => return Ok(open.element),
+                }
+            } else {
+                stack.push(open);
+            }
+        }
+    }
+    Err(invalid_xml("document end"))
+}
+
+/// Returns the length of the tag including `<` and `>`, its content and whether it closes
+/// itself. `>` inside quoted attribute values does not end the tag.
+fn read_tag(rest: &str) -> Result<(usize, &str, bool), Error> {
+    let mut quote = None;
+    for (index, char) in rest.char_indices().skip(1) {
+        match (char, quote) {
+            ('"' | '\'', None) => quote = Some(char),
+            (_, Some(open)) if char == open => quote = None,
+            ('>', None) => {
+                let tag = &rest[1..index];
+                return Ok(match tag.strip_suffix('/') {
+                    Some(tag) => (index + 1, tag, true),
+                    None => (index + 1, tag, false),
+                });
+            }
+            _ => {}
+        }
+    }
+    Err(invalid_xml("tag"))
+}
+
+fn open_element(
+    tag: &str,
+    parent_namespaces: Option<&HashMap<String, String>>,
+) -> Result<OpenElement, Error> {
+    let tag = tag.trim();
+    let name_end = tag
+        .find(|char: char| char.is_whitespace())
+        .unwrap_or(tag.len());
+    let mut namespaces = parent_namespaces.cloned().unwrap_or_default();
+    let mut element_attributes = vec![];
+
+    let mut attributes = &tag[name_end..];
+    while let Some(equals) = attributes.find('=') {
+        let attribute = attributes[..equals].trim();
+        let value_part = attributes[equals + 1..].trim_start();
+        let quote = value_part
+            .chars()
+            .next()
+            .filter(|char| *char == '"' || *char == '\'')
+            .ok_or_else(|| invalid_xml("attribute"))?;
+        let value_end = value_part[1..]
+            .find(quote)
+            .ok_or_else(|| invalid_xml("attribute"))?;
+        let value = decode_entities(&value_part[1..1 + value_end]);
+        if attribute == "xmlns" {
+            namespaces.insert(String::new(), value);
+        } else if let Some(prefix) = attribute.strip_prefix("xmlns:") {
+            namespaces.insert(prefix.to_string(), value);
+        } else {
+            element_attributes.push((split_name(attribute).1.to_string(), value));
+        }
+        attributes = &value_part[1 + value_end + 1..];
+    }
+
+    let (prefix, name) = split_name(&tag[..name_end]);
+    let namespace = match namespaces.get(prefix) {
+        Some(namespace) => namespace.clone(),
+        None if prefix.is_empty() => String::new(),
+        None => return Err(invalid_xml("namespace prefix")),
+    };
+    Ok(OpenElement {
+        prefix: prefix.to_string(),
+        element: XmlElement {
+            namespace,
+            name: name.to_string(),
+            attributes: element_attributes,
+            children: vec![],
+            text: String::new(),
+        },
+        namespaces,
+    })
+}
+
+fn split_name(qualified_name: &str) -> (&str, &str) {
+    qualified_name
+        .split_once(':')
+        .unwrap_or(("", qualified_name))
+}
+
+fn skip_past(rest: &str, end: &str) -> Result<usize, Error> {
+    rest.find(end)
+        .map(|index| index + end.len())
+        .ok_or_else(|| invalid_xml("markup"))
+}
+
+fn decode_entities(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_string();
+    }
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find(';') {
+            Some(end) if end <= 10 => end,
+            _ => {
+                output.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let decoded = match &rest[1..end] {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            entity => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16).ok())
+                .unwrap_or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(char) => {
+                output.push(char);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                output.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+pub fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn invalid_xml(what: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Invalid XML {}", what))
+}
@@ -0,0 +1,384 @@
+use std::{
+    fs::File,
+    io::{Error, ErrorKind, Read, Write},
+};
+
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use flate2::{write::DeflateEncoder, Compression};
+use zip::ZipArchive;
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const ZIP64_EXTRA_FIELD_ID: u16 = 0x0001;
+// File names are always UTF-8
+const UTF8_NAMES_FLAG: u16 = 0x0800;
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+const VERSION_DEFAULT: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+
+struct ZipWriterEntry {
+    name: Vec<u8>,
+    method: u16,
+    crc: u32,
+    compressed_size: u32,
+    size: u32,
+    offset: u64,
+    modified: (u16, u16),
+    is_directory: bool,
+}
+
+/// Builds a ZIP archive piece by piece. Every call returns the bytes to append to the output,
+/// so the archive can be streamed while only a single file is held in memory. Archives bigger
+/// than 4 GiB or with more than 65535 entries get ZIP64 records.
+#[derive(Default)]
+pub struct ZipWriter {
+    offset: u64,
+    entries: Vec<ZipWriterEntry>,
+}
+
+impl ZipWriter {
+    pub fn new() -> ZipWriter {
+        ZipWriter {
+            offset: 0,
+            entries: vec![],
+        }
+    }
+
+    pub fn add_directory(&mut self, name: &str, modified: NaiveDateTime) -> Vec<u8> {
+        let name = format!("{}/", name.trim_end_matches('/'));
+        self.add_entry(name, METHOD_STORED, 0, vec![], 0, modified, true)
+    }
+
+    /// Adds a deflated file, a single file has to be smaller than 4 GiB.
+    pub fn add_file(
+        &mut self,
+        name: &str,
+        data: &[u8],
+        modified: NaiveDateTime,
+    ) -> Result<Vec<u8>, Error> {
+        let size = u32::try_from(data.len())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "File is too big for ZIP"))?;
+        let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+        encoder.write_all(data)?;
+        let compressed = encoder.finish()?;
+
+        Ok(self.add_entry(
+            name.to_string(),
+            METHOD_DEFLATED,
+            crc32fast::hash(data),
+            compressed,
+            size,
+            modified,
+            false,
+        ))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn add_entry(
+        &mut self,
+        name: String,
+        method: u16,
+        crc: u32,
+        compressed: Vec<u8>,
+        size: u32,
+        modified: NaiveDateTime,
+        is_directory: bool,
+    ) -> Vec<u8> {
+        let entry = ZipWriterEntry {
+            name: name.into_bytes(),
+            method,
+            crc,
+            compressed_size: compressed.len() as u32,
+            size,
+            offset: self.offset,
+            modified: dos_date_time(modified),
+            is_directory,
+        };
+
+        let mut output = Vec::with_capacity(30 + entry.name.len() + compressed.len());
+        write_u32(&mut output, LOCAL_HEADER_SIGNATURE);
+        write_u16(&mut output, VERSION_DEFAULT);
+        write_u16(&mut output, UTF8_NAMES_FLAG);
+        write_u16(&mut output, entry.method);
+        write_u16(&mut output, entry.modified.0);
+        write_u16(&mut output, entry.modified.1);
+        write_u32(&mut output, entry.crc);
+        write_u32(&mut output, entry.compressed_size);
+        write_u32(&mut output, entry.size);
+        write_u16(&mut output, entry.name.len() as u16);
+        write_u16(&mut output, 0);
+        output.extend_from_slice(&entry.name);
+        output.extend_from_slice(&compressed);
+
+        self.offset += output.len() as u64;
+        self.entries.push(entry);
+        output
+    }
+
+    /// Returns the central directory, the last bytes of the archive.
+    pub fn finish(self) -> Vec<u8> {
+        let mut output = vec![];
+        let central_directory_offset = self.offset;
+        for entry in self.entries.iter() {
+            let needs_zip64 = entry.offset >= u32::MAX as u64;
+            write_u32(&mut output, CENTRAL_HEADER_SIGNATURE);
+            // Made by Unix, so the external attributes below are understood
+            write_u16(&mut output, (3 << 8) | VERSION_ZIP64);
+            write_u16(
+                &mut output,
+                if needs_zip64 {
+                    VERSION_ZIP64
+                } else {
+                    VERSION_DEFAULT
+                },
+            );
+            write_u16(&mut output, UTF8_NAMES_FLAG);
+            write_u16(&mut output, entry.method);
+            write_u16(&mut output, entry.modified.0);
+            write_u16(&mut output, entry.modified.1);
+            write_u32(&mut output, entry.crc);
+            write_u32(&mut output, entry.compressed_size);
+            write_u32(&mut output, entry.size);
+            write_u16(&mut output, entry.name.len() as u16);
+            write_u16(&mut output, if needs_zip64 { 12 } else { 0 });
+            write_u16(&mut output, 0);
+            write_u16(&mut output, 0);
+            write_u16(&mut output, 0);
+            let unix_mode: u32 = if entry.is_directory {
+                0o040755
+            } else {
+                0o100644
+            };
+            let dos_attributes = if entry.is_directory { 0x10 } else { 0 };
+            write_u32(&mut output, (unix_mode << 16) | dos_attributes);
+            write_u32(
+                &mut output,
+                if needs_zip64 {
+                    u32::MAX
+                } else {
+                    entry.offset as u32
+                },
+            );
+            output.extend_from_slice(&entry.name);
+            if needs_zip64 {
+                write_u16(&mut output, ZIP64_EXTRA_FIELD_ID);
+                write_u16(&mut output, 8);
+                write_u64(&mut output, entry.offset);
+            }
+        }
+
+        let central_directory_size = output.len() as u64;
+        let entries_count = self.entries.len() as u64;
+        let needs_zip64 = entries_count >= u16::MAX as u64
+            || central_directory_offset >= u32::MAX as u64
+            || central_directory_size >= u32::MAX as u64;
+        if needs_zip64 {
+            let zip64_end_offset = central_directory_offset + central_directory_size;
+            write_u32(&mut output, ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+            write_u64(&mut output, 44);
+            write_u16(&mut output, (3 << 8) | VERSION_ZIP64);
+            write_u16(&mut output, VERSION_ZIP64);
+            write_u32(&mut output, 0);
+            write_u32(&mut output, 0);
+            write_u64(&mut output, entries_count);
+            write_u64(&mut output, entries_count);
+            write_u64(&mut output, central_directory_size);
+            write_u64(&mut output, central_directory_offset);
+
+            write_u32(&mut output, ZIP64_LOCATOR_SIGNATURE);
+            write_u32(&mut output, 0);
+            write_u64(&mut output, zip64_end_offset);
+            write_u32(&mut output, 1);
+        }
+
+        write_u32(&mut output, END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        write_u16(&mut output, 0);
+        write_u16(&mut output, 0);
+        let short_count = entries_count.min(u16::MAX as u64) as u16;
+        write_u16(&mut output, short_count);
+        write_u16(&mut output, short_count);
+        write_u32(
+            &mut output,
+            central_directory_size.min(u32::MAX as u64) as u32,
+        );
+        write_u32(
+            &mut output,
+            central_directory_offset.min(u32::MAX as u64) as u32,
+        );
+        write_u16(&mut output, 0);
+        output
+    }
+}
+
+/// Reads an uploaded ZIP archive. Entries are inflated up to `max_entry_size` only, so a small
+/// archive can not expand into more memory than a single big message would take.
+pub struct ZipReader {
+    archive: ZipArchive<File>,
+    max_entry_size: u64,
+}
+
+impl ZipReader {
+    pub fn new(file: File, max_entry_size: u64) -> Result<ZipReader, Error> {
+        Ok(ZipReader {
+            archive: ZipArchive::new(file).map_err(|err| invalid_zip(&err.to_string()))?,
+            max_entry_size,
+        })
+    }
+
+    /// Names of all entries with the index to read them by.
+    pub fn entry_names(&mut self) -> Result<Vec<(usize, String)>, Error> {
+        (0..self.archive.len())
+            .map(|index| {
+                let entry = self
+                    .archive
+                    .by_index_raw(index)
+                    .map_err(|err| invalid_zip(&err.to_string()))?;
+                Ok((index, entry.name().to_string()))
+            })
+            .collect()
+    }
+
+    /// Decompresses a single entry, the checksum is verified. Entries bigger than the limit or
+    /// than the size they declare are rejected.
+    pub fn read_entry(&mut self, index: usize) -> Result<Vec<u8>, Error> {
+        let entry = self
+            .archive
+            .by_index(index)
+            .map_err(|err| invalid_zip(&err.to_string()))?;
+        let name = entry.name().to_string();
+        let declared_size = entry.size();
+        if declared_size > self.max_entry_size {
+            return Err(invalid_zip(&format!(
+                "{} is bigger than {} bytes",
+                name, self.max_entry_size
+            )));
+        }
+
+        let mut data = Vec::with_capacity(declared_size as usize);
+        entry.take(declared_size + 1).read_to_end(&mut data)?;
+        if data.len() as u64 != declared_size {
+            return Err(invalid_zip(&format!(
+                "{} does not have its declared size",
+                name
+            )));
+        }
+        Ok(data)
+    }
+}
+
+fn invalid_zip(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Invalid ZIP: {}", message))
+}
+
+/// ZIP keeps MS-DOS dates, which start in 1980 and have a two second precision.
+fn dos_date_time(date_time: NaiveDateTime) -> (u16, u16) {
+    if date_time.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let time = (date_time.hour() << 11) | (date_time.minute() << 5) | (date_time.second() / 2);
+    let date =
+        (((date_time.year() - 1980) as u32) << 9) | (date_time.month() << 5) | date_time.day();
+    (time as u16, date as u16)
+}
+
+fn write_u16(output: &mut Vec<u8>, value: u16) {
+    output.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(output: &mut Vec<u8>, value: u32) {
+    output.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_u64(output: &mut Vec<u8>, value: u64) {
+    output.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        fs::{remove_file, File},
+        io::{ErrorKind, Write},
+        process,
+    };
+
+    use chrono::NaiveDate;
+
+    use super::{ZipReader, ZipWriter, CENTRAL_HEADER_SIGNATURE};
+
+    /// Writes the archive to a temporary file and opens it for reading.
+    fn open(archive: &[u8], name: &str, max_entry_size: u64) -> ZipReader {
+        let path = env::temp_dir().join(format!("utils-zip-{}-{}.zip", process::id(), name));
+        File::create(&path).unwrap().write_all(archive).unwrap();
+        let file = File::open(&path).unwrap();
+        let _ = remove_file(&path);
+        ZipReader::new(file, max_entry_size).unwrap()
+    }
+
+    fn archive(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let modified = NaiveDate::from_ymd_opt(2023, 5, 17)
+            .unwrap()
+            .and_hms_opt(10, 30, 0)
+            .unwrap();
+        let mut writer = ZipWriter::new();
+        let mut archive = writer.add_directory("mail", modified);
+        for (name, data) in entries.iter() {
+            archive.extend(writer.add_file(name, data, modified).unwrap());
+        }
+        archive.extend(writer.finish());
+        archive
+    }
+
+    #[test]
+    fn reads_what_the_writer_wrote() {
+        let message = b"Subject: Test\r\n\r\nBody\r\n".repeat(100);
+        let archive = archive(&[("mail/cur/1", &message), ("mail/cur/2", b"")]);
+        let mut reader = open(&archive, "round-trip", 1024 * 1024);
+
+        let names = reader.entry_names().unwrap();
+        assert_eq!(
+            names
+                .iter()
+                .map(|(_, name)| name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["mail/", "mail/cur/1", "mail/cur/2"]
+        );
+        assert_eq!(reader.read_entry(names[1].0).unwrap(), message);
+        assert!(reader.read_entry(names[2].0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_entries_over_the_limit() {
+        // Compresses to a few hundred bytes
+        let zeros = vec![0u8; 1024 * 1024];
+        let archive = archive(&[("mail/cur/1", &zeros)]);
+        let mut reader = open(&archive, "limit", 64 * 1024);
+
+        let err = reader.read_entry(1).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_entries_bigger_than_declared() {
+        let zeros = vec![0u8; 1024 * 1024];
+        let mut archive = archive(&[("mail/cur/1", &zeros)]);
+        // Declares 16 bytes in the central header of the file, which follows the directory's
+        let signature = CENTRAL_HEADER_SIGNATURE.to_le_bytes();
+        let headers: Vec<usize> = archive
+            .windows(4)
+            .enumerate()
+            .filter(|(_, window)| *window == signature)
+            .map(|(position, _)| position)
+            .collect();
+        archive[headers[1] + 24..headers[1] + 28].copy_from_slice(&16u32.to_le_bytes());
+        let mut reader = open(&archive, "declared", 64 * 1024);
+
+        let err = reader.read_entry(1).err().unwrap();
+        assert!(err.to_string().contains("declared size"), "{}", err);
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}